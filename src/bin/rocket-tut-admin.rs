@@ -0,0 +1,388 @@
+use std::fs::File;
+use std::io::{self, BufRead, Read, Write};
+use std::process;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use serde::Serialize;
+use serde_json::json;
+
+use rocket_tut::config::AppConfig;
+use rocket_tut::data::audit::{self, AuditAction, AuditEvent, ClientInfo};
+use rocket_tut::data::bulk::{self, BulkFormat, DuplicatePolicy, ImportOptions};
+use rocket_tut::data::db::{ResponseUser, User};
+use rocket_tut::data::email_address::canonical_email_with;
+use rocket_tut::data::email_change::email_in_use;
+use rocket_tut::data::store::{init_store, Conn};
+use rocket_tut::data::user_search::{self, MatchMode, UserSearch};
+use rocket_tut::data::{invite, migrations, users};
+
+/// Output of a command: JSON for scripts, or a line of text for people
+struct Output {
+    json: bool,
+}
+impl Output {
+    fn print<T: Serialize>(&self, value: &T, text: &str) {
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(value).unwrap());
+        } else {
+            println!("{}", text);
+        }
+    }
+    fn user(&self, user: &User, text: &str) {
+        self.print(&AdminUser::from_user(user), text);
+    }
+}
+
+/// What an administrator gets to see of an account, hashes excluded
+#[derive(Serialize)]
+struct AdminUser {
+    #[serde(flatten)]
+    user: ResponseUser,
+    email_verified: bool,
+    roles: Vec<String>,
+    created: String,
+    updated: String,
+    sessions_revoked_at: Option<String>,
+}
+impl AdminUser {
+    fn from_user(user: &User) -> Self {
+        AdminUser {
+            user: ResponseUser::from_user(user),
+            email_verified: user.email_verified,
+            roles: user.roles.clone(),
+            created: user.created.to_rfc3339(),
+            updated: user.updated.to_rfc3339(),
+            sessions_revoked_at: user.sessions_revoked_at.map(|at| at.to_rfc3339()),
+        }
+    }
+}
+
+fn format_of(matches: &ArgMatches, path: Option<&str>) -> BulkFormat {
+    match matches.value_of("format") {
+        Some(format) => BulkFormat::parse(format).expect("format validated by clap"),
+        None => match path {
+            Some(p) if p.ends_with(".csv") => BulkFormat::Csv,
+            _ => BulkFormat::Ndjson,
+        },
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("error: {}", message);
+    process::exit(1)
+}
+
+fn client() -> ClientInfo {
+    ClientInfo { ip: None, user_agent: Some("rocket-tut-admin".to_string()) }
+}
+
+/// Passwords are read from stdin, so they stay out of the shell history and `ps`
+fn read_password() -> String {
+    let mut line = String::new();
+    match io::stdin().lock().read_line(&mut line) {
+        Ok(_) => {
+            let password = line.trim_end_matches(|c| c == '\n' || c == '\r').to_string();
+            if password.is_empty() {
+                fail("empty password");
+            }
+            password
+        },
+        Err(e) => fail(&format!("cannot read the password: {}", e)),
+    }
+}
+
+/// Accepts either the id or any spelling of the email of an account
+fn find_user(connection: &Conn, config: &AppConfig, matches: &ArgMatches) -> User {
+    let key = matches.value_of("USER").unwrap();
+    let found = match users::find_by_id(connection, key) {
+        Ok(None) => users::find_by_email(connection, key, config.email_folding),
+        other => other,
+    };
+    match found {
+        Ok(Some(user)) => user,
+        Ok(None) => fail(&format!("no user {}", key)),
+        Err(_) => fail("cannot load the user"),
+    }
+}
+
+fn save_user(connection: &Conn, user: &User) {
+    if users::replace(connection, user).is_err() {
+        fail("cannot save the user");
+    }
+}
+
+fn revoke_user_sessions(connection: &Conn, user: &mut User) {
+    if users::revoke_sessions(connection, user).is_err() {
+        fail("cannot revoke the sessions");
+    }
+}
+
+fn create_user(connection: &Conn, config: &AppConfig, matches: &ArgMatches, output: &Output) {
+    let name = matches.value_of("NAME").unwrap().trim().to_string();
+    let email = matches.value_of("EMAIL").unwrap();
+    if canonical_email_with(email, config.email_folding).is_err() {
+        fail("invalid email");
+    }
+    match email_in_use(connection, email, config.email_folding, None) {
+        Ok(true) => fail("email already in use"),
+        Ok(false) => (),
+        Err(_) => fail("cannot check the email"),
+    }
+    let send_invite = matches.is_present("invite");
+    let password = if send_invite { invite::placeholder_password() } else { read_password() };
+    let mut user = User::new(name, email.to_string(), password, config.email_folding);
+    for role in matches.values_of("role").into_iter().flatten() {
+        user.grant_role(role);
+    }
+    if users::insert(connection, &user).is_err() {
+        fail("cannot create the user");
+    }
+    let user_id = user.id.to_string();
+    audit::record(connection, &AuditEvent::new(AuditAction::UserCreated, None, Some(user_id.clone()), &client()));
+    if !user.roles.is_empty() {
+        audit::record(connection, &AuditEvent::new(AuditAction::RoleGranted, None, Some(user_id), &client()).with_changes(user.roles.clone()));
+    }
+    if send_invite && invite::send(connection, &user, &config.public_url).is_err() {
+        fail("user created, but the invite could not be sent");
+    }
+    output.user(&user, &format!("created {} <{}> {}", user.name, user.email, user.id));
+}
+
+fn reset_password(connection: &Conn, config: &AppConfig, matches: &ArgMatches, output: &Output) {
+    let mut user = find_user(connection, config, matches);
+    let password = read_password();
+    user.update_password(&password);
+    save_user(connection, &user);
+    // Whoever knew the old password may still hold a token
+    revoke_user_sessions(connection, &mut user);
+    let user_id = user.id.to_string();
+    audit::record(connection, &AuditEvent::new(AuditAction::PasswordChanged, None, Some(user_id.clone()), &client()));
+    audit::record(connection, &AuditEvent::new(AuditAction::SessionsRevoked, None, Some(user_id), &client()));
+    output.user(&user, &format!("password reset for {}", user.email));
+}
+
+fn list(connection: &Conn, matches: &ArgMatches, output: &Output) {
+    let mut search = UserSearch::default();
+    search.name = matches.value_of("name").map(|n| n.to_string());
+    search.email = matches.value_of("email").map(|e| e.to_string());
+    search.role = matches.value_of("role").map(|r| r.to_string());
+    search.mode = MatchMode::Substring;
+    if let Some(page) = matches.value_of("page") {
+        search.page = page.parse().unwrap_or_else(|_| fail("invalid page"));
+    }
+    if let Some(per_page) = matches.value_of("per-page") {
+        search.per_page = per_page.parse().unwrap_or_else(|_| fail("invalid page size"));
+    }
+    match user_search::search(connection, &search) {
+        Ok(page) => {
+            let lines: Vec<String> = page.items.iter()
+                .map(|user| format!("{}  {} <{}>", user.id, user.name, user.email))
+                .collect();
+            let text = format!("{}\n-- page {} of {} users", lines.join("\n"), page.page, page.total);
+            output.print(&page, text.trim_start());
+        },
+        Err(_) => fail("cannot list the users"),
+    }
+}
+
+fn find(connection: &Conn, config: &AppConfig, matches: &ArgMatches, output: &Output) {
+    let user = find_user(connection, config, matches);
+    let text = format!(
+        "{}  {} <{}>\nverified: {}\nroles: {}\ncreated: {}\nupdated: {}",
+        user.id, user.name, user.email, user.email_verified, user.roles.join(", "), user.created, user.updated
+    );
+    output.user(&user, &text);
+}
+
+fn change_role(connection: &Conn, config: &AppConfig, matches: &ArgMatches, output: &Output, grant: bool) {
+    let mut user = find_user(connection, config, matches);
+    let role = matches.value_of("ROLE").unwrap();
+    if user.has_role(role) != grant {
+        if grant {
+            user.grant_role(role);
+        } else {
+            user.revoke_role(role);
+        }
+        save_user(connection, &user);
+        let action = if grant { AuditAction::RoleGranted } else { AuditAction::RoleRevoked };
+        audit::record(connection, &AuditEvent::new(action, None, Some(user.id.to_string()), &client()).with_changes(vec![role.to_string()]));
+    }
+    output.user(&user, &format!("{} roles: {}", user.email, user.roles.join(", ")));
+}
+
+fn revoke_sessions(connection: &Conn, config: &AppConfig, matches: &ArgMatches, output: &Output) {
+    let mut user = find_user(connection, config, matches);
+    revoke_user_sessions(connection, &mut user);
+    audit::record(connection, &AuditEvent::new(AuditAction::SessionsRevoked, None, Some(user.id.to_string()), &client()));
+    output.user(&user, &format!("sessions of {} revoked", user.email));
+}
+
+fn migrate(connection: &Conn, config: &AppConfig, matches: &ArgMatches, output: &Output) {
+    match migrations::run(connection, config.email_folding, matches.is_present("dry-run")) {
+        Ok(report) => {
+            let text = format!(
+                "{} users scanned, {} {}, {} email collisions, indexes: {}",
+                report.users_scanned,
+                report.users_updated,
+                if report.dry_run { "to update" } else { "updated" },
+                report.collisions.len(),
+                report.indexes.join(", ")
+            );
+            output.print(&report, &text);
+            if !report.collisions.is_empty() {
+                process::exit(2);
+            }
+        },
+        Err(_) => fail("migration failed"),
+    }
+}
+
+/// A round-trip to the server, since getting a connection from the pool doesn't need one
+fn check(connection: &Conn, output: &Output) {
+    match connection.ping().and_then(|_| connection.count_users().map_err(|_| "cannot count users".to_string())) {
+        Ok(count) => output.print(&json!({ "ok": true, "users": count }), &format!("ok, {} users", count)),
+        Err(e) => {
+            output.print(&json!({ "ok": false, "error": e.to_string() }), &format!("database unreachable: {}", e));
+            process::exit(1);
+        },
+    }
+}
+
+fn import(connection: &Conn, config: &AppConfig, matches: &ArgMatches) {
+    let path = matches.value_of("FILE").unwrap();
+    let input: Box<dyn Read> = if path == "-" {
+        Box::new(io::stdin())
+    } else {
+        match File::open(path) {
+            Ok(file) => Box::new(file),
+            Err(e) => fail(&format!("cannot open {}: {}", path, e)),
+        }
+    };
+    let options = ImportOptions {
+        format: format_of(matches, Some(path)),
+        dry_run: matches.is_present("dry-run"),
+        on_duplicate: DuplicatePolicy::parse(matches.value_of("on-duplicate").unwrap()).expect("policy validated by clap"),
+        invite: matches.is_present("invite"),
+        public_url: config.public_url.clone(),
+        email_folding: config.email_folding,
+    };
+    match bulk::import(connection, input, &options, None, &client()) {
+        Ok(report) => {
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+            if report.aborted || report.failed > 0 {
+                process::exit(2);
+            }
+        },
+        Err(_) => fail("import failed"),
+    }
+}
+
+fn export(connection: Conn, matches: &ArgMatches) {
+    let path = matches.value_of("output");
+    let mut reader = match bulk::export(connection, format_of(matches, path)) {
+        Ok(reader) => reader,
+        Err(_) => fail("export failed"),
+    };
+    let mut output: Box<dyn Write> = match path {
+        Some(p) => match File::create(p) {
+            Ok(file) => Box::new(file),
+            Err(e) => fail(&format!("cannot create {}: {}", p, e)),
+        },
+        None => Box::new(io::stdout()),
+    };
+    if let Err(e) = io::copy(&mut reader, &mut output) {
+        fail(&format!("export failed: {}", e));
+    }
+}
+
+fn main() {
+    let format = Arg::with_name("format")
+        .long("format")
+        .takes_value(true)
+        .possible_values(&["csv", "ndjson"])
+        .help("Defaults to the file extension, or ndjson");
+    let user = Arg::with_name("USER").required(true).help("Id or email of the user");
+    let role = Arg::with_name("ROLE").required(true);
+    let matches = App::new("rocket-tut-admin")
+        .about("Administration tasks for rocket-tut, using the same database settings as the server")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(Arg::with_name("json").long("json").global(true).help("Print results as JSON"))
+        .subcommand(SubCommand::with_name("create-user")
+            .about("Creates a user, reading the password from stdin")
+            .arg(Arg::with_name("NAME").required(true))
+            .arg(Arg::with_name("EMAIL").required(true))
+            .arg(Arg::with_name("role").long("role").takes_value(true).multiple(true).number_of_values(1))
+            .arg(Arg::with_name("invite").long("invite").help("Mail an invite instead of reading a password")))
+        .subcommand(SubCommand::with_name("reset-password")
+            .about("Sets a new password, read from stdin, and revokes the user's sessions")
+            .arg(user.clone()))
+        .subcommand(SubCommand::with_name("list")
+            .about("Lists users, sorted by name")
+            .arg(Arg::with_name("name").long("name").takes_value(true))
+            .arg(Arg::with_name("email").long("email").takes_value(true))
+            .arg(Arg::with_name("role").long("role").takes_value(true))
+            .arg(Arg::with_name("page").long("page").takes_value(true))
+            .arg(Arg::with_name("per-page").long("per-page").takes_value(true)))
+        .subcommand(SubCommand::with_name("find")
+            .about("Shows a user")
+            .arg(user.clone()))
+        .subcommand(SubCommand::with_name("grant-role")
+            .arg(user.clone())
+            .arg(role.clone()))
+        .subcommand(SubCommand::with_name("revoke-role")
+            .arg(user.clone())
+            .arg(role))
+        .subcommand(SubCommand::with_name("revoke-sessions")
+            .about("Invalidates every token issued so far to the user")
+            .arg(user))
+        .subcommand(SubCommand::with_name("migrate")
+            .about("Runs the data migrations and creates the indexes")
+            .arg(Arg::with_name("dry-run").long("dry-run").help("Only report what would be done")))
+        .subcommand(SubCommand::with_name("check")
+            .about("Checks that the database can be reached"))
+        .subcommand(SubCommand::with_name("import")
+            .about("Imports users from a CSV (name,email,password) or NDJSON file")
+            .arg(Arg::with_name("FILE").required(true).help("File to import, - for stdin"))
+            .arg(format.clone())
+            .arg(Arg::with_name("dry-run").long("dry-run").help("Only report what would be done"))
+            .arg(Arg::with_name("on-duplicate")
+                .long("on-duplicate")
+                .takes_value(true)
+                .possible_values(&["skip", "update", "fail"])
+                .default_value("fail"))
+            .arg(Arg::with_name("invite").long("invite").help("Mail an invite to users imported without a password")))
+        .subcommand(SubCommand::with_name("export")
+            .about("Exports users, without passwords")
+            .arg(format)
+            .arg(Arg::with_name("output").long("output").short("o").takes_value(true).help("Defaults to stdout")))
+        .get_matches();
+
+    let config = match AppConfig::from_env() {
+        Ok(config) => config,
+        Err(report) => {
+            eprint!("{}", report);
+            process::exit(1)
+        },
+    };
+    let pool = init_store(&config);
+    let connection = match pool.get() {
+        Ok(store) => Conn(store),
+        Err(e) => fail(&format!("cannot connect to the database: {}", e)),
+    };
+    let (command, sub) = matches.subcommand();
+    let sub = sub.unwrap();
+    let output = Output { json: sub.is_present("json") };
+    match command {
+        "create-user" => create_user(&connection, &config, sub, &output),
+        "reset-password" => reset_password(&connection, &config, sub, &output),
+        "list" => list(&connection, sub, &output),
+        "find" => find(&connection, &config, sub, &output),
+        "grant-role" => change_role(&connection, &config, sub, &output, true),
+        "revoke-role" => change_role(&connection, &config, sub, &output, false),
+        "revoke-sessions" => revoke_sessions(&connection, &config, sub, &output),
+        "migrate" => migrate(&connection, &config, sub, &output),
+        "check" => check(&connection, &output),
+        "import" => import(&connection, &config, sub),
+        "export" => export(connection, sub),
+        _ => unreachable!(),
+    }
+}
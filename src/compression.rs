@@ -0,0 +1,176 @@
+use std::io::{self, Cursor, Read, Write};
+use rocket::{Request, Response};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::ContentType;
+use rocket::response::Body;
+use flate2::{read, write::{GzEncoder, ZlibEncoder}};
+
+use crate::config::ConfigReader;
+
+/// Below it, compressing saves less than it costs
+const DEFAULT_MIN_SIZE: u64 = 1024;
+/// Brotli quality: 11 is the densest but far too slow for responses compressed on the fly
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+/// Already compressed, compressing them again would only cost time
+const COMPRESSED_APPLICATION_TYPES: &[&str] = &[
+    "zip", "gzip", "x-gzip", "x-bzip2", "x-xz", "x-7z-compressed", "x-rar-compressed", "zstd", "pdf", "octet-stream",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompressionConfig {
+    /// Smaller bodies are sent as they are
+    pub min_size: u64,
+}
+impl CompressionConfig {
+    pub fn read(reader: &mut ConfigReader) -> Self {
+        CompressionConfig { min_size: reader.parse("COMPRESSION_MIN_SIZE").unwrap_or(DEFAULT_MIN_SIZE) }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+impl Encoding {
+    /// Ours, densest first: the order ties in `Accept-Encoding` are broken with
+    const PREFERRED: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    /// The best encoding the client accepts, `*` standing for those it doesn't name, `q=0` ruling one out
+    pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+        let accepted: Vec<(String, f32)> = accept_encoding.split(',')
+            .filter_map(|item| {
+                let mut parts = item.split(';').map(str::trim);
+                let name = parts.next().filter(|name| !name.is_empty())?.to_lowercase();
+                let quality = parts.find_map(|param| param.strip_prefix("q="))
+                    .map(|q| q.trim().parse().unwrap_or(0.0))
+                    .unwrap_or(1.0);
+                Some((name, quality))
+            })
+            .collect();
+        let quality = |name: &str| accepted.iter().find(|(n, _)| n == name)
+            .or_else(|| accepted.iter().find(|(n, _)| n == "*"))
+            .map(|(_, q)| *q)
+            .unwrap_or(0.0);
+        let mut best: Option<(Encoding, f32)> = None;
+        for encoding in Encoding::PREFERRED.iter() {
+            let q = quality(encoding.name());
+            if q > 0.0 && best.map(|(_, best_q)| q > best_q).unwrap_or(true) {
+                best = Some((*encoding, q));
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+
+    pub fn compress(self, body: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+                encoder.write_all(body)?;
+                Ok(encoder.into_inner())
+            },
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            },
+            // HTTP's deflate is the zlib format, not raw deflate
+            Encoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            },
+        }
+    }
+
+    /// Compresses a body as it's read, for those streamed in chunks
+    pub fn compress_reader<'r>(self, body: Box<dyn Read + 'r>) -> Box<dyn Read + 'r> {
+        match self {
+            Encoding::Brotli => Box::new(brotli::CompressorReader::new(body, 4096, BROTLI_QUALITY, BROTLI_WINDOW)),
+            Encoding::Gzip => Box::new(read::GzEncoder::new(body, flate2::Compression::default())),
+            Encoding::Deflate => Box::new(read::ZlibEncoder::new(body, flate2::Compression::default())),
+        }
+    }
+}
+
+/// Text compresses well; images, but SVGs, media and archives are compressed already.
+/// Event streams never end, they can't be compressed as a whole.
+pub fn is_compressible(content_type: &ContentType) -> bool {
+    let sub = content_type.sub().as_str().to_lowercase();
+    match content_type.top().as_str().to_lowercase().as_str() {
+        "text" => sub != "event-stream",
+        "image" => sub == "svg+xml",
+        "video" | "audio" => false,
+        "font" => sub != "woff" && sub != "woff2",
+        "application" => !COMPRESSED_APPLICATION_TYPES.contains(&sub.as_str()),
+        _ => true,
+    }
+}
+
+/// Compresses bodies with the encoding negotiated from `Accept-Encoding`, for every route.
+/// Attached last, so that it compresses what the other fairings leave.
+pub struct Compression(pub CompressionConfig);
+
+impl Fairing for Compression {
+    fn info(&self) -> Info {
+        Info {
+            name: "Compression",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let min_size = self.0.min_size;
+        if response.headers().contains("Content-Encoding") || !response.content_type().map(|ct| is_compressible(&ct)).unwrap_or(false) {
+            return;
+        }
+        let chunk_size = match response.body() {
+            Some(Body::Sized(_, size)) if size < min_size => return,
+            Some(Body::Sized(..)) => None,
+            Some(Body::Chunked(_, chunk_size)) => Some(chunk_size),
+            None => return,
+        };
+        // Whether it gets compressed or not, caches have to keep a copy per encoding
+        response.adjoin_raw_header("Vary", "Accept-Encoding");
+        let encoding = match request.headers().get_one("Accept-Encoding").and_then(Encoding::negotiate) {
+            Some(encoding) => encoding,
+            None => return,
+        };
+        // Streamed bodies, such as exports, stay streamed: buffering them would hold all of it in memory
+        if let Some(chunk_size) = chunk_size {
+            if let Some(body) = response.take_body() {
+                response.set_raw_header("Content-Encoding", encoding.name());
+                response.set_chunked_body(encoding.compress_reader(body.into_inner()), chunk_size);
+            }
+            return;
+        }
+        let body = match response.body_bytes() {
+            Some(body) => body,
+            None => return,
+        };
+        if (body.len() as u64) < min_size {
+            response.set_sized_body(Cursor::new(body));
+            return;
+        }
+        match encoding.compress(&body) {
+            Ok(compressed) => {
+                response.set_raw_header("Content-Encoding", encoding.name());
+                response.set_sized_body(Cursor::new(compressed));
+            },
+            Err(e) => {
+                log::warn!("could not compress a response with {}: {}", encoding.name(), e);
+                response.set_sized_body(Cursor::new(body));
+            },
+        }
+    }
+}
@@ -0,0 +1,354 @@
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use dotenv::dotenv;
+use rocket::config::{Config, Environment, Value};
+use rocket::http::SameSite;
+
+use crate::compression::CompressionConfig;
+use crate::cors::CorsConfig;
+use crate::data::email_address::LocalPartFolding;
+use crate::rate_limit::RateLimitConfig;
+use crate::versioning::VersioningConfig;
+use crate::data::mailer::MailConfig;
+use crate::data::webhooks::WebhookConfig;
+use crate::data::jwt_keys::KeyRing;
+use crate::data::mongo_config::MongoConfig;
+
+/// Only good enough for development, other profiles have to set their own
+const DEVELOPMENT_SECRET: &str = "secret297152aebda7";
+const MIN_SECRET_LENGTH: usize = 16;
+const DEFAULT_TOKEN_LIFETIME_HOURS: i64 = 24;
+const DEFAULT_IDEMPOTENCY_TTL_HOURS: i64 = 24;
+/// Five years: longer lifetimes are mistakes, and far longer ones overflow dates
+const MAX_HOURS: i64 = 5 * 366 * 24;
+const DEFAULT_BATCH_MAX_SIZE: usize = 25;
+/// Without a Rocket profile to take the number of workers from
+const DEFAULT_EVENT_STREAMS: usize = 4;
+const DEFAULT_EVENT_STREAMS_PER_USER: usize = 2;
+const DEFAULT_PUBLIC_URL: &str = "http://localhost:8000";
+const DEFAULT_STARTUP_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_BREAKER_THRESHOLD: u32 = 5;
+const DEFAULT_BREAKER_COOLDOWN_SECS: u64 = 10;
+
+/// The database chosen by `DATABASE_BACKEND`
+#[derive(Debug, Clone, PartialEq)]
+pub enum DatabaseConfig {
+    Mongo(MongoConfig),
+    #[cfg(feature = "sqlite")]
+    Sqlite(String),
+    #[cfg(feature = "postgres")]
+    Postgres(String),
+}
+
+/// Attributes of the cookies holding the session token and its CSRF token
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CookieConfig {
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: SameSite,
+    /// Names the cookies `__Host-…`, which browsers only accept secure, for `/` and without a domain
+    pub host_prefix: bool,
+    /// Encrypts the token cookie with Rocket's `secret_key`
+    pub private: bool,
+}
+impl CookieConfig {
+    pub fn token_name(&self) -> &'static str {
+        if self.host_prefix { "__Host-t" } else { "t" }
+    }
+    pub fn csrf_name(&self) -> &'static str {
+        if self.host_prefix { "__Host-csrf" } else { "csrf" }
+    }
+}
+
+/// Everything the application reads from its environment, checked once at startup
+#[derive(Debug, Clone)]
+pub struct AppConfig {
+    pub jwt_secret: String,
+    /// Asymmetric signing and verification keys, which take over from `jwt_secret` for tokens
+    pub jwt_keys: KeyRing,
+    pub token_lifetime: chrono::Duration,
+    /// Base address used to build the links we put in the mails
+    pub public_url: String,
+    pub email_folding: LocalPartFolding,
+    pub database: DatabaseConfig,
+    pub cookie: CookieConfig,
+    /// Whether cookie-authenticated requests changing something need the `X-CSRF-Token` header
+    pub csrf_protection: bool,
+    pub cors: CorsConfig,
+    pub rate_limit: RateLimitConfig,
+    pub compression: CompressionConfig,
+    pub versioning: VersioningConfig,
+    pub webhooks: WebhookConfig,
+    pub mail: MailConfig,
+    /// How long responses are kept for retries sent with the same `Idempotency-Key`
+    pub idempotency_ttl: chrono::Duration,
+    /// The most sub-requests `POST /api/batch` takes at once
+    pub batch_max_size: usize,
+    /// Event streams open at once, each holding a worker: half of them by default
+    pub event_streams: usize,
+    pub event_streams_per_user: usize,
+    /// How long to keep trying to reach the database when starting
+    pub database_startup_timeout: Duration,
+    /// Failed checkouts in a row that open the circuit breaker
+    pub breaker_threshold: u32,
+    /// How long an open breaker fails checkouts before probing the database again
+    pub breaker_cooldown: Duration,
+}
+
+/// Every missing or invalid setting found while loading
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigReport {
+    pub errors: Vec<String>,
+}
+impl fmt::Display for ConfigReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "invalid configuration:")?;
+        for error in &self.errors {
+            writeln!(f, "  - {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Looks settings up in the environment (`.env` included), then in the extras of the
+/// Rocket profile under their lowercase name, and keeps track of what went wrong
+pub struct ConfigReader<'a> {
+    profile: Option<&'a Config>,
+    errors: Vec<String>,
+}
+impl<'a> ConfigReader<'a> {
+    pub fn new(profile: Option<&'a Config>) -> Self {
+        dotenv().ok();
+        ConfigReader { profile, errors: Vec::new() }
+    }
+
+    /// Skips the profile, for settings read again from the environment later on
+    pub fn env(&self, name: &str) -> Option<String> {
+        env::var(name).ok().filter(|value| !value.is_empty())
+    }
+
+    pub fn get(&self, name: &str) -> Option<String> {
+        if let Some(value) = self.env(name) {
+            return Some(value);
+        }
+        match self.profile.and_then(|profile| profile.get_extra(&name.to_lowercase()).ok()) {
+            Some(Value::String(value)) => Some(value.clone()),
+            Some(Value::Integer(value)) => Some(value.to_string()),
+            Some(Value::Boolean(value)) => Some(value.to_string()),
+            Some(other) => Some(other.to_string()),
+            None => None,
+        }
+    }
+
+    pub fn required(&mut self, name: &str) -> Option<String> {
+        let value = self.get(name);
+        if value.is_none() {
+            self.errors.push(format!("{} is missing", name));
+        }
+        value
+    }
+
+    pub fn invalid(&mut self, name: &str, reason: &str) {
+        self.errors.push(format!("{} is invalid: {}", name, reason));
+    }
+
+    /// Parses a value found under some name, which needn't be the one it is looked up with
+    pub fn parse_value<T: FromStr>(&mut self, name: &str, value: &str) -> Option<T> {
+        let parsed = value.parse().ok();
+        if parsed.is_none() {
+            self.invalid(name, &format!("cannot read {}", value));
+        }
+        parsed
+    }
+
+    pub fn parse<T: FromStr>(&mut self, name: &str) -> Option<T> {
+        let value = self.get(name)?;
+        self.parse_value(name, &value)
+    }
+
+    pub fn flag_value(&mut self, name: &str, value: &str) -> Option<bool> {
+        match value {
+            "true" | "1" | "yes" => Some(true),
+            "false" | "0" | "no" => Some(false),
+            _ => {
+                self.invalid(name, &format!("{} is neither true nor false", value));
+                None
+            },
+        }
+    }
+
+    fn finish<T>(self, config: Option<T>) -> Result<T, ConfigReport> {
+        match config {
+            Some(config) if self.errors.is_empty() => Ok(config),
+            _ => Err(ConfigReport { errors: self.errors }),
+        }
+    }
+}
+
+fn read_database(reader: &mut ConfigReader) -> Option<DatabaseConfig> {
+    let backend = reader.get("DATABASE_BACKEND").unwrap_or_else(|| "mongodb".to_string());
+    match backend.as_str() {
+        "mongodb" => MongoConfig::read(reader).map(DatabaseConfig::Mongo),
+        #[cfg(feature = "sqlite")]
+        "sqlite" => reader.required("DATABASE_URL").map(DatabaseConfig::Sqlite),
+        #[cfg(feature = "postgres")]
+        "postgres" => match reader.required("DATABASE_URL") {
+            Some(url) if url.parse::<r2d2_postgres::postgres::Config>().is_err() => {
+                reader.invalid("DATABASE_URL", "isn't a PostgreSQL connection string");
+                None
+            },
+            url => url.map(DatabaseConfig::Postgres),
+        },
+        other => {
+            reader.invalid("DATABASE_BACKEND", &format!("{} isn't supported by this build", other));
+            None
+        },
+    }
+}
+
+fn read_secret(reader: &mut ConfigReader, environment: Environment) -> Option<String> {
+    match reader.get("JWT_SECRET") {
+        Some(secret) if secret.len() < MIN_SECRET_LENGTH => {
+            reader.invalid("JWT_SECRET", &format!("shorter than {} characters", MIN_SECRET_LENGTH));
+            None
+        },
+        Some(secret) => Some(secret),
+        None if environment.is_dev() => Some(DEVELOPMENT_SECRET.to_string()),
+        None => reader.required("JWT_SECRET"),
+    }
+}
+
+fn read_flag(reader: &mut ConfigReader, name: &str, default: bool) -> bool {
+    match reader.get(name) {
+        Some(value) => reader.flag_value(name, &value).unwrap_or(default),
+        None => default,
+    }
+}
+
+fn read_hours(reader: &mut ConfigReader, name: &str, default: i64) -> Option<chrono::Duration> {
+    match reader.parse::<i64>(name) {
+        Some(hours) if hours <= 0 || hours > MAX_HOURS => {
+            reader.invalid(name, &format!("must be between 1 and {}", MAX_HOURS));
+            None
+        },
+        Some(hours) => Some(chrono::Duration::hours(hours)),
+        None => Some(chrono::Duration::hours(default)),
+    }
+}
+
+/// Secure by default, except in development where the server speaks plain HTTP
+fn read_cookie(reader: &mut ConfigReader, environment: Environment) -> CookieConfig {
+    let same_site = match reader.get("AUTH_COOKIE_SAME_SITE").as_deref() {
+        None | Some("lax") => SameSite::Lax,
+        Some("strict") => SameSite::Strict,
+        Some("none") => SameSite::None,
+        Some(_) => {
+            reader.invalid("AUTH_COOKIE_SAME_SITE", "must be strict, lax or none");
+            SameSite::Lax
+        },
+    };
+    let cookie = CookieConfig {
+        secure: read_flag(reader, "AUTH_COOKIE_SECURE", !environment.is_dev()),
+        http_only: read_flag(reader, "AUTH_COOKIE_HTTP_ONLY", true),
+        same_site,
+        host_prefix: read_flag(reader, "AUTH_COOKIE_HOST_PREFIX", false),
+        private: read_flag(reader, "AUTH_COOKIE_PRIVATE", false),
+    };
+    if cookie.host_prefix && !cookie.secure {
+        reader.invalid("AUTH_COOKIE_HOST_PREFIX", "needs AUTH_COOKIE_SECURE");
+    }
+    if cookie.same_site == SameSite::None && !cookie.secure {
+        reader.invalid("AUTH_COOKIE_SAME_SITE", "none needs AUTH_COOKIE_SECURE");
+    }
+    cookie
+}
+
+impl AppConfig {
+    /// The settings of the Rocket instance about to launch, its profile extras included
+    pub fn from_rocket(profile: &Config) -> Result<Self, ConfigReport> {
+        AppConfig::read(ConfigReader::new(Some(profile)), profile.environment)
+    }
+
+    /// Environment and `.env` only, for tools running outside of Rocket
+    pub fn from_env() -> Result<Self, ConfigReport> {
+        let environment = Environment::active().unwrap_or(Environment::Development);
+        AppConfig::read(ConfigReader::new(None), environment)
+    }
+
+    fn read(mut reader: ConfigReader, environment: Environment) -> Result<Self, ConfigReport> {
+        let jwt_secret = read_secret(&mut reader, environment);
+        let jwt_keys = KeyRing::read(&mut reader);
+        let token_lifetime = read_hours(&mut reader, "TOKEN_LIFETIME_HOURS", DEFAULT_TOKEN_LIFETIME_HOURS);
+        let public_url = reader.get("PUBLIC_URL").unwrap_or_else(|| DEFAULT_PUBLIC_URL.to_string());
+        let public_url = public_url.trim_end_matches('/').to_string();
+        if !public_url.starts_with("http://") && !public_url.starts_with("https://") {
+            reader.invalid("PUBLIC_URL", "must be an http:// or https:// address");
+        }
+        let email_folding = match reader.get("EMAIL_LOCAL_PART_FOLDING") {
+            Some(folding) => {
+                let parsed = LocalPartFolding::parse(&folding);
+                if parsed.is_none() {
+                    reader.invalid("EMAIL_LOCAL_PART_FOLDING", "must be none, lowercase or lowercase_no_tag");
+                }
+                parsed
+            },
+            None => Some(LocalPartFolding::Lowercase),
+        };
+        let cookie = read_cookie(&mut reader, environment);
+        let csrf_protection = read_flag(&mut reader, "CSRF_PROTECTION", true);
+        let cors = CorsConfig::read(&mut reader);
+        let database = read_database(&mut reader);
+        let rate_limit = RateLimitConfig::read(&mut reader, database.as_ref());
+        let compression = CompressionConfig::read(&mut reader);
+        let versioning = VersioningConfig::read(&mut reader);
+        let webhooks = WebhookConfig::read(&mut reader);
+        let mail = MailConfig::read(&mut reader);
+        let idempotency_ttl = read_hours(&mut reader, "IDEMPOTENCY_TTL_HOURS", DEFAULT_IDEMPOTENCY_TTL_HOURS);
+        let batch_max_size = reader.parse("BATCH_MAX_SIZE").unwrap_or(DEFAULT_BATCH_MAX_SIZE);
+        if batch_max_size == 0 {
+            reader.invalid("BATCH_MAX_SIZE", "must be at least 1");
+        }
+        let default_streams = reader.profile.map(|profile| (profile.workers as usize / 2).max(1)).unwrap_or(DEFAULT_EVENT_STREAMS);
+        let event_streams = reader.parse("EVENT_STREAMS_MAX").unwrap_or(default_streams);
+        let event_streams_per_user = reader.parse("EVENT_STREAMS_PER_USER").unwrap_or(DEFAULT_EVENT_STREAMS_PER_USER);
+        if event_streams_per_user == 0 {
+            reader.invalid("EVENT_STREAMS_PER_USER", "must be at least 1");
+        }
+        let database_startup_timeout = reader.parse("DATABASE_STARTUP_TIMEOUT_SECS").unwrap_or(DEFAULT_STARTUP_TIMEOUT_SECS);
+        let breaker_threshold = reader.parse("DATABASE_BREAKER_THRESHOLD").unwrap_or(DEFAULT_BREAKER_THRESHOLD);
+        if breaker_threshold == 0 {
+            reader.invalid("DATABASE_BREAKER_THRESHOLD", "must be at least 1");
+        }
+        let breaker_cooldown = reader.parse("DATABASE_BREAKER_COOLDOWN_SECS").unwrap_or(DEFAULT_BREAKER_COOLDOWN_SECS);
+
+        let config = match (jwt_secret, jwt_keys, token_lifetime, email_folding, database, idempotency_ttl) {
+            (Some(jwt_secret), Some(jwt_keys), Some(token_lifetime), Some(email_folding), Some(database), Some(idempotency_ttl)) => Some(AppConfig {
+                jwt_secret,
+                jwt_keys,
+                token_lifetime,
+                public_url,
+                email_folding,
+                database,
+                cookie,
+                csrf_protection,
+                cors,
+                rate_limit,
+                compression,
+                versioning,
+                webhooks,
+                mail,
+                idempotency_ttl,
+                batch_max_size,
+                event_streams,
+                event_streams_per_user,
+                database_startup_timeout: Duration::from_secs(database_startup_timeout),
+                breaker_threshold,
+                breaker_cooldown: Duration::from_secs(breaker_cooldown),
+            }),
+            _ => None,
+        };
+        reader.finish(config)
+    }
+}
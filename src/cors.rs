@@ -0,0 +1,169 @@
+use std::str::FromStr;
+use rocket::{Request, Response};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Method;
+
+use crate::config::ConfigReader;
+
+const DEFAULT_METHODS: &str = "GET, HEAD, POST, PUT, PATCH, DELETE";
+const DEFAULT_HEADERS: &str = "Content-Type, Authorization, X-CSRF-Token, Idempotency-Key";
+const DEFAULT_MAX_AGE_SECS: u64 = 3600;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AllowedOrigin {
+    Any,
+    Exact(String),
+    /// With `*` standing for anything but a `/`, as in `https://*.example.com`
+    Pattern(String),
+}
+impl AllowedOrigin {
+    fn parse(origin: &str) -> Self {
+        let origin = origin.trim().trim_end_matches('/').to_lowercase();
+        if origin == "*" {
+            AllowedOrigin::Any
+        } else if origin.contains('*') {
+            AllowedOrigin::Pattern(origin)
+        } else {
+            AllowedOrigin::Exact(origin)
+        }
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            AllowedOrigin::Any => true,
+            AllowedOrigin::Exact(exact) => exact == origin,
+            AllowedOrigin::Pattern(pattern) => glob(pattern.as_bytes(), origin.as_bytes()),
+        }
+    }
+}
+
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (1..=text.len())
+            .take_while(|end| text[end - 1] != b'/')
+            .any(|end| glob(rest, &text[end..])),
+        Some((c, rest)) => text.first() == Some(c) && glob(rest, &text[1..]),
+    }
+}
+
+/// Which other origins may call the API from a browser. Without any, none can.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorsConfig {
+    pub origins: Vec<AllowedOrigin>,
+    pub methods: Vec<Method>,
+    /// Lowercase, as they are compared
+    pub headers: Vec<String>,
+    pub expose_headers: Vec<String>,
+    pub credentials: bool,
+    pub max_age: u64,
+}
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            origins: Vec::new(),
+            methods: list(DEFAULT_METHODS).iter().filter_map(|m| Method::from_str(m).ok()).collect(),
+            headers: list(DEFAULT_HEADERS).iter().map(|h| h.to_lowercase()).collect(),
+            expose_headers: Vec::new(),
+            credentials: false,
+            max_age: DEFAULT_MAX_AGE_SECS,
+        }
+    }
+}
+
+fn list(value: &str) -> Vec<String> {
+    value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(String::from).collect()
+}
+
+impl CorsConfig {
+    pub fn read(reader: &mut ConfigReader) -> Self {
+        let mut cors = CorsConfig::default();
+        if let Some(origins) = reader.get("CORS_ALLOWED_ORIGINS") {
+            cors.origins = list(&origins).iter().map(|origin| AllowedOrigin::parse(origin)).collect();
+        }
+        if let Some(methods) = reader.get("CORS_ALLOWED_METHODS") {
+            cors.methods = list(&methods).iter()
+                .filter_map(|method| reader.parse_value::<Method>("CORS_ALLOWED_METHODS", &method.to_uppercase()))
+                .collect();
+        }
+        if let Some(headers) = reader.get("CORS_ALLOWED_HEADERS") {
+            cors.headers = list(&headers).iter().map(|header| header.to_lowercase()).collect();
+        }
+        if let Some(headers) = reader.get("CORS_EXPOSED_HEADERS") {
+            cors.expose_headers = list(&headers);
+        }
+        if let Some(credentials) = reader.get("CORS_ALLOW_CREDENTIALS") {
+            cors.credentials = reader.flag_value("CORS_ALLOW_CREDENTIALS", &credentials).unwrap_or(false);
+        }
+        if let Some(max_age) = reader.parse("CORS_MAX_AGE_SECS") {
+            cors.max_age = max_age;
+        }
+        // Browsers refuse credentials along with a wildcard, better tell now
+        if cors.credentials && cors.origins.contains(&AllowedOrigin::Any) {
+            reader.invalid("CORS_ALLOW_CREDENTIALS", "can't go with * among CORS_ALLOWED_ORIGINS");
+        }
+        cors
+    }
+
+    fn allows_origin(&self, origin: &str) -> bool {
+        let origin = origin.to_lowercase();
+        self.origins.iter().any(|allowed| allowed.matches(&origin))
+    }
+
+    /// A preflight only passes if the method and every header asked for are allowed
+    fn allows_preflight(&self, method: &str, headers: Option<&str>) -> bool {
+        let method_allowed = Method::from_str(method).map(|method| self.methods.contains(&method)).unwrap_or(false);
+        let headers_allowed = list(headers.unwrap_or("")).iter().all(|header| self.headers.contains(&header.to_lowercase()));
+        method_allowed && headers_allowed
+    }
+}
+
+/// Adds the CORS headers to responses for allowed origins, and nothing for the others.
+/// Preflights are answered by `routes::cors::preflight_rt`, mounted for every path.
+pub struct Cors(pub CorsConfig);
+
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let config = &self.0;
+        let origin = match request.headers().get_one("Origin") {
+            Some(origin) => origin,
+            None => return,
+        };
+        // Caches must not serve the answer to one origin to another
+        if !config.origins.iter().all(|allowed| *allowed == AllowedOrigin::Any) || config.credentials {
+            response.adjoin_raw_header("Vary", "Origin");
+        }
+        if !config.allows_origin(origin) {
+            return;
+        }
+        let preflight = request.method() == Method::Options;
+        if preflight {
+            let headers = request.headers();
+            match headers.get_one("Access-Control-Request-Method") {
+                Some(method) if config.allows_preflight(method, headers.get_one("Access-Control-Request-Headers")) => (),
+                _ => return,
+            }
+        }
+
+        let any = config.origins.contains(&AllowedOrigin::Any) && !config.credentials;
+        response.set_raw_header("Access-Control-Allow-Origin", if any { "*".to_string() } else { origin.to_string() });
+        if config.credentials {
+            response.set_raw_header("Access-Control-Allow-Credentials", "true");
+        }
+        if preflight {
+            let methods: Vec<String> = config.methods.iter().map(|method| method.as_str().to_string()).collect();
+            response.set_raw_header("Access-Control-Allow-Methods", methods.join(", "));
+            response.set_raw_header("Access-Control-Allow-Headers", config.headers.join(", "));
+            response.set_raw_header("Access-Control-Max-Age", config.max_age.to_string());
+        } else if !config.expose_headers.is_empty() {
+            response.set_raw_header("Access-Control-Expose-Headers", config.expose_headers.join(", "));
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use rand::{thread_rng, Rng};
+use rand::distributions::Alphanumeric;
+
+use crate::data::store::Conn;
+
+/// Marks our secrets, so that scanners can spot them in code and logs
+pub const TOKEN_PREFIX: &str = "rtut_pat_";
+/// `read` covers safe methods, `write` everything else, `admin` the admin routes
+pub const SCOPES: &[&str] = &["read", "write", "admin"];
+/// Ten years, well within what dates can hold
+pub const MAX_EXPIRES_IN_DAYS: i64 = 3650;
+/// Characters of the secret kept in clear, for users to tell their tokens apart
+const HINT_LENGTH: usize = 4;
+
+/// A personal access token, for scripts acting on behalf of a user.
+/// Only a hash of the secret is stored: it's shown once, when created.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AccessToken {
+    #[serde(rename = "_id")]
+    pub id: Uuid,
+    pub user_id: String,
+    pub name: String,
+    pub hint: String,
+    pub token_hash: String,
+    pub scopes: Vec<String>,
+    pub created: DateTime<Utc>,
+    pub expires: Option<DateTime<Utc>>,
+    pub last_used: Option<DateTime<Utc>>,
+    pub last_used_ip: Option<String>,
+    pub revoked: Option<DateTime<Utc>>,
+}
+impl AccessToken {
+    /// The new token and its secret
+    pub fn generate(user_id: String, name: String, scopes: Vec<String>, expires: Option<DateTime<Utc>>) -> (Self, String) {
+        let random: String = thread_rng().sample_iter(&Alphanumeric).take(40).collect();
+        let secret = format!("{}{}", TOKEN_PREFIX, random);
+        let token = AccessToken {
+            id: Uuid::new_v4(),
+            user_id,
+            name,
+            hint: secret[..TOKEN_PREFIX.len() + HINT_LENGTH].to_string(),
+            token_hash: hash_secret(&secret),
+            scopes,
+            created: Utc::now(),
+            expires,
+            last_used: None,
+            last_used_ip: None,
+            revoked: None,
+        };
+        (token, secret)
+    }
+    pub fn is_active(&self) -> bool {
+        self.revoked.is_none() && self.expires.map(|expires| Utc::now() < expires).unwrap_or(true)
+    }
+    /// `write` implies `read`
+    pub fn allows(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope || (scope == "read" && s == "write"))
+    }
+}
+
+/// Secrets are long and random, a plain digest is enough and can be looked up
+pub fn hash_secret(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct NewAccessToken {
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub expires_in_days: Option<i64>,
+}
+impl NewAccessToken {
+    pub fn expires(&self) -> Option<DateTime<Utc>> {
+        self.expires_in_days.map(|days| Utc::now() + Duration::days(days))
+    }
+}
+
+/// What the owner gets to see of a token
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResponseAccessToken {
+    pub id: String,
+    pub name: String,
+    pub hint: String,
+    pub scopes: Vec<String>,
+    pub created: DateTime<Utc>,
+    pub expires: Option<DateTime<Utc>>,
+    pub last_used: Option<DateTime<Utc>>,
+    pub last_used_ip: Option<String>,
+    pub revoked: Option<DateTime<Utc>>,
+}
+impl ResponseAccessToken {
+    pub fn from_token(token: &AccessToken) -> Self {
+        ResponseAccessToken {
+            id: token.id.to_string(),
+            name: token.name.clone(),
+            hint: token.hint.clone(),
+            scopes: token.scopes.clone(),
+            created: token.created,
+            expires: token.expires,
+            last_used: token.last_used,
+            last_used_ip: token.last_used_ip.clone(),
+            revoked: token.revoked,
+        }
+    }
+}
+
+/// The only response carrying the secret
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CreatedAccessToken {
+    pub token: String,
+    #[serde(flatten)]
+    pub info: ResponseAccessToken,
+}
+
+pub fn insert(connection: &Conn, token: &AccessToken) -> Result<(), ()> {
+    connection.insert_access_token(token)
+}
+
+pub fn save(connection: &Conn, token: &AccessToken) -> Result<(), ()> {
+    connection.save_access_token(token)
+}
+
+pub fn find_by_secret(connection: &Conn, secret: &str) -> Result<Option<AccessToken>, ()> {
+    if !secret.starts_with(TOKEN_PREFIX) {
+        return Ok(None);
+    }
+    find_by_hash(connection, &hash_secret(secret))
+}
+
+pub fn find_by_hash(connection: &Conn, hash: &str) -> Result<Option<AccessToken>, ()> {
+    connection.find_access_token_by_hash(hash)
+}
+
+/// Newest first, revoked ones included
+pub fn list_for(connection: &Conn, user_id: &str) -> Result<Vec<AccessToken>, ()> {
+    connection.access_tokens_of(user_id)
+}
+
+/// Notes when and where from the token was last used, and only that: a revocation coming in
+/// since the token was loaded stands. Failing to do so doesn't fail the request.
+pub fn record_use(connection: &Conn, token: &mut AccessToken, ip: Option<String>) {
+    let now = Utc::now();
+    token.last_used = Some(now);
+    token.last_used_ip = ip;
+    if connection.record_access_token_use(&token.id.to_string(), now, token.last_used_ip.as_deref()).is_err() {
+        log::warn!("could not record the use of access token {}", token.id);
+    }
+}
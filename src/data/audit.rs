@@ -0,0 +1,151 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use rocket::Outcome;
+use rocket::request::{self, Request, FromRequest};
+
+use r2d2_mongodb::mongodb as bson;
+
+use bson::UtcDateTime;
+
+use crate::data::store::Conn;
+
+const MAX_RESULTS: i64 = 500;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    UserCreated,
+    UserUpdated,
+    EmailChangeRequested,
+    EmailChangeConfirmed,
+    EmailChangeReverted,
+    PasswordChanged,
+    UserDeleted,
+    LoginSucceeded,
+    LoginFailed,
+    RoleGranted,
+    RoleRevoked,
+    SessionsRevoked,
+    AccessTokenCreated,
+    AccessTokenRevoked,
+}
+impl AuditAction {
+    pub fn parse(action: &str) -> Option<Self> {
+        match action {
+            "user_created" => Some(AuditAction::UserCreated),
+            "user_updated" => Some(AuditAction::UserUpdated),
+            "email_change_requested" => Some(AuditAction::EmailChangeRequested),
+            "email_change_confirmed" => Some(AuditAction::EmailChangeConfirmed),
+            "email_change_reverted" => Some(AuditAction::EmailChangeReverted),
+            "password_changed" => Some(AuditAction::PasswordChanged),
+            "user_deleted" => Some(AuditAction::UserDeleted),
+            "login_succeeded" => Some(AuditAction::LoginSucceeded),
+            "login_failed" => Some(AuditAction::LoginFailed),
+            "role_granted" => Some(AuditAction::RoleGranted),
+            "role_revoked" => Some(AuditAction::RoleRevoked),
+            "sessions_revoked" => Some(AuditAction::SessionsRevoked),
+            "access_token_created" => Some(AuditAction::AccessTokenCreated),
+            "access_token_revoked" => Some(AuditAction::AccessTokenRevoked),
+            _ => None,
+        }
+    }
+}
+
+/// Append-only record of something that happened to an account.
+/// `changes` lists the names of the modified fields, never their values.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEvent {
+    #[serde(rename = "_id")]
+    pub id: Uuid,
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    pub action: AuditAction,
+    pub changes: Vec<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub timestamp: UtcDateTime,
+}
+impl AuditEvent {
+    pub fn new(action: AuditAction, actor: Option<String>, target: Option<String>, client: &ClientInfo) -> Self {
+        AuditEvent {
+            id: Uuid::new_v4(),
+            actor,
+            target,
+            action,
+            changes: Vec::new(),
+            ip: client.ip.clone(),
+            user_agent: client.user_agent.clone(),
+            timestamp: UtcDateTime(Utc::now()),
+        }
+    }
+    pub fn with_changes(mut self, changes: Vec<String>) -> Self {
+        self.changes = changes;
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResponseAuditEvent {
+    pub id: String,
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    pub action: AuditAction,
+    pub changes: Vec<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub timestamp: DateTime<Utc>,
+}
+impl ResponseAuditEvent {
+    pub fn from_event(event: &AuditEvent) -> Self {
+        ResponseAuditEvent {
+            id: event.id.to_string(),
+            actor: event.actor.clone(),
+            target: event.target.clone(),
+            action: event.action,
+            changes: event.changes.clone(),
+            ip: event.ip.clone(),
+            user_agent: event.user_agent.clone(),
+            timestamp: event.timestamp.0,
+        }
+    }
+}
+
+/// Where a request comes from, as far as we can tell
+pub struct ClientInfo {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for ClientInfo {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<ClientInfo, ()> {
+        Outcome::Success(ClientInfo {
+            ip: request.client_ip().map(|ip| ip.to_string()),
+            user_agent: request.headers().get_one("User-Agent").map(|ua| ua.to_string()),
+        })
+    }
+}
+
+/// Filters for querying the audit log; all of them are optional
+#[derive(Debug, Clone, Default)]
+pub struct AuditFilter {
+    pub user: Option<String>,
+    pub action: Option<AuditAction>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
+}
+
+/// Stores the event. A failure to audit never undoes the audited action, it is only logged.
+pub fn record(connection: &Conn, event: &AuditEvent) {
+    if let Err(e) = connection.insert_audit_event(event) {
+        log::warn!("failed to record audit event {:?} for {:?}: {}", event.action, event.target, e);
+    }
+}
+
+/// Most recent events first
+pub fn find(connection: &Conn, filter: &AuditFilter) -> Result<Vec<AuditEvent>, ()> {
+    connection.find_audit_events(filter, filter.limit.unwrap_or(MAX_RESULTS).min(MAX_RESULTS).max(1))
+}
@@ -0,0 +1,377 @@
+use std::cmp;
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, BufRead, BufReader, Read};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::data::audit::{self, AuditAction, AuditEvent, ClientInfo};
+use crate::data::db::User;
+use crate::data::email_address::{canonical_email_with, LocalPartFolding};
+use crate::data::email_change::email_in_use;
+use crate::data::store::Conn;
+use crate::data::{invite, users};
+
+const EXPORT_BATCH: i64 = 100;
+const CSV_HEADER: &[u8] = b"id,name,email,email_verified,roles,created,updated\n";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BulkFormat {
+    Csv,
+    Ndjson,
+}
+impl BulkFormat {
+    pub fn parse(format: &str) -> Option<Self> {
+        match format {
+            "csv" => Some(BulkFormat::Csv),
+            "ndjson" => Some(BulkFormat::Ndjson),
+            _ => None,
+        }
+    }
+}
+
+/// What to do with rows whose email already belongs to an account
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DuplicatePolicy {
+    Skip,
+    /// Overwrite name, and password when given, of the existing account
+    Update,
+    /// Reject the whole import, nothing gets written
+    Fail,
+}
+impl DuplicatePolicy {
+    pub fn parse(policy: &str) -> Option<Self> {
+        match policy {
+            "skip" => Some(DuplicatePolicy::Skip),
+            "update" => Some(DuplicatePolicy::Update),
+            "fail" => Some(DuplicatePolicy::Fail),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+    pub format: BulkFormat,
+    pub dry_run: bool,
+    pub on_duplicate: DuplicatePolicy,
+    /// Mail an invite to the new accounts imported without a password
+    pub invite: bool,
+    /// Base address of the links in the invites
+    pub public_url: String,
+    pub email_folding: LocalPartFolding,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ImportRow {
+    pub name: String,
+    pub email: String,
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RowStatus {
+    Created,
+    Updated,
+    Skipped,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RowOutcome {
+    /// 1-based, not counting the CSV header
+    pub row: u64,
+    pub email: Option<String>,
+    pub status: RowStatus,
+    pub id: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub aborted: bool,
+    pub created: u64,
+    pub updated: u64,
+    pub skipped: u64,
+    pub failed: u64,
+    pub rows: Vec<RowOutcome>,
+}
+
+enum Plan {
+    Create(User, bool),
+    Update(User, Vec<String>),
+    /// A duplicate under the `Fail` policy
+    Reject,
+    Nothing,
+}
+
+fn read_rows<R: Read>(input: R, format: BulkFormat) -> Vec<Result<ImportRow, String>> {
+    match format {
+        BulkFormat::Csv => {
+            let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(input);
+            reader.deserialize::<ImportRow>().map(|row| row.map_err(|e| e.to_string())).collect()
+        },
+        BulkFormat::Ndjson => {
+            BufReader::new(input).lines()
+                .filter(|line| line.as_ref().map(|l| !l.trim().is_empty()).unwrap_or(true))
+                .map(|line| match line {
+                    Ok(line) => serde_json::from_str::<ImportRow>(&line).map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                })
+                .collect()
+        },
+    }
+}
+
+/// Imports users in two passes: every row is validated first, then, unless
+/// it's a dry run or the duplicate policy aborted the import, the planned changes are written.
+pub fn import<R: Read>(connection: &Conn, input: R, options: &ImportOptions, actor: Option<String>, client: &ClientInfo) -> Result<ImportReport, ()> {
+    let mut report = ImportReport { dry_run: options.dry_run, ..ImportReport::default() };
+    let mut plans = Vec::new();
+    let mut seen = HashSet::new();
+    for (index, parsed) in read_rows(input, options.format).into_iter().enumerate() {
+        let mut outcome = RowOutcome { row: index as u64 + 1, email: None, status: RowStatus::Failed, id: None, error: None };
+        let row = match parsed {
+            Ok(row) => row,
+            Err(e) => {
+                outcome.error = Some(e);
+                report.rows.push(outcome);
+                plans.push(Plan::Nothing);
+                continue;
+            }
+        };
+        outcome.email = Some(row.email.clone());
+        let plan = match plan_row(connection, &row, options, &mut seen, &mut outcome) {
+            Ok(plan) => plan,
+            Err(_) => return Err(()),
+        };
+        if let Plan::Reject = plan {
+            report.aborted = true;
+        }
+        report.rows.push(outcome);
+        plans.push(plan);
+    }
+    if report.aborted {
+        for outcome in report.rows.iter_mut().filter(|o| o.status != RowStatus::Failed) {
+            outcome.status = RowStatus::Skipped;
+        }
+    }
+    else if !options.dry_run {
+        for (plan, outcome) in plans.into_iter().zip(report.rows.iter_mut()) {
+            match plan {
+                Plan::Create(user, send_invite) => {
+                    if users::insert(connection, &user).is_err() {
+                        outcome.status = RowStatus::Failed;
+                        outcome.error = Some("could not create user".to_string());
+                        continue;
+                    }
+                    let user_id = user.id.to_string();
+                    audit::record(connection, &AuditEvent::new(AuditAction::UserCreated, actor.clone(), Some(user_id), client));
+                    if send_invite && invite::send(connection, &user, &options.public_url).is_err() {
+                        outcome.error = Some("user created, but the invite could not be sent".to_string());
+                    }
+                },
+                Plan::Update(user, changes) => {
+                    if users::replace(connection, &user).is_err() {
+                        outcome.status = RowStatus::Failed;
+                        outcome.error = Some("could not update user".to_string());
+                        continue;
+                    }
+                    audit::record(connection, &AuditEvent::new(AuditAction::UserUpdated, actor.clone(), Some(user.id.to_string()), client).with_changes(changes));
+                },
+                Plan::Reject | Plan::Nothing => (),
+            }
+        }
+    }
+    for outcome in &report.rows {
+        match outcome.status {
+            RowStatus::Created => report.created += 1,
+            RowStatus::Updated => report.updated += 1,
+            RowStatus::Skipped => report.skipped += 1,
+            RowStatus::Failed => report.failed += 1,
+        }
+    }
+    Ok(report)
+}
+
+fn plan_row(connection: &Conn, row: &ImportRow, options: &ImportOptions, seen: &mut HashSet<String>, outcome: &mut RowOutcome) -> Result<Plan, ()> {
+    if row.name.trim().is_empty() {
+        outcome.error = Some("name missing".to_string());
+        return Ok(Plan::Nothing);
+    }
+    let canonical = match canonical_email_with(&row.email, options.email_folding) {
+        Ok(canonical) => canonical,
+        Err(_) => {
+            outcome.error = Some("invalid email".to_string());
+            return Ok(Plan::Nothing);
+        }
+    };
+    if !seen.insert(canonical) {
+        outcome.error = Some("email repeated in the import".to_string());
+        return Ok(Plan::Nothing);
+    }
+    match users::find_by_email(connection, &row.email, options.email_folding)? {
+        Some(mut existing) => {
+            outcome.id = Some(existing.id.to_string());
+            match options.on_duplicate {
+                DuplicatePolicy::Skip => {
+                    outcome.status = RowStatus::Skipped;
+                    Ok(Plan::Nothing)
+                },
+                DuplicatePolicy::Fail => {
+                    outcome.error = Some("email already in use".to_string());
+                    Ok(Plan::Reject)
+                },
+                DuplicatePolicy::Update => {
+                    let mut changes = Vec::new();
+                    if existing.name != row.name { changes.push("name".to_string()); }
+                    let email = existing.email.clone();
+                    existing.update_user(&row.name, &email, options.email_folding);
+                    if let Some(password) = &row.password {
+                        changes.push("password".to_string());
+                        existing.update_password(password);
+                    }
+                    outcome.status = RowStatus::Updated;
+                    Ok(Plan::Update(existing, changes))
+                },
+            }
+        },
+        None => {
+            // Somebody else may be waiting to confirm the same address
+            if email_in_use(connection, &row.email, options.email_folding, None)? {
+                outcome.error = Some("email already in use".to_string());
+                return Ok(Plan::Nothing);
+            }
+            let (password, send_invite) = match (&row.password, options.invite) {
+                (Some(password), _) => (password.clone(), false),
+                (None, true) => (invite::placeholder_password(), true),
+                (None, false) => {
+                    outcome.error = Some("password missing and no invite requested".to_string());
+                    return Ok(Plan::Nothing);
+                }
+            };
+            let user = User::new(row.name.trim().to_string(), row.email.clone(), password, options.email_folding);
+            outcome.id = Some(user.id.to_string());
+            outcome.status = RowStatus::Created;
+            Ok(Plan::Create(user, send_invite))
+        },
+    }
+}
+
+/// What gets exported of a user: never hashes nor salts
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportRow {
+    pub id: String,
+    pub name: String,
+    pub email: String,
+    pub email_verified: bool,
+    pub roles: Vec<String>,
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+}
+impl ExportRow {
+    pub fn from_user(user: &User) -> Self {
+        ExportRow {
+            id: user.id.to_string(),
+            name: user.name.clone(),
+            email: user.email.clone(),
+            email_verified: user.email_verified,
+            roles: user.roles.clone(),
+            created: user.created,
+            updated: user.updated,
+        }
+    }
+}
+
+/// Serializes users one at a time, loading them in batches sorted by id,
+/// so exports can be streamed whatever the number of users.
+pub struct ExportReader {
+    connection: Conn,
+    format: BulkFormat,
+    batch: VecDeque<User>,
+    last_id: Option<String>,
+    exhausted: bool,
+    buffer: Vec<u8>,
+    position: usize,
+    started: bool,
+}
+
+/// The reader holds on to the connection until the export is over
+pub fn export(connection: Conn, format: BulkFormat) -> Result<ExportReader, ()> {
+    let batch = connection.list_users(None, EXPORT_BATCH)?;
+    Ok(ExportReader {
+        connection,
+        format,
+        exhausted: (batch.len() as i64) < EXPORT_BATCH,
+        last_id: batch.last().map(|user| user.id.to_string()),
+        batch: batch.into(),
+        buffer: Vec::new(),
+        position: 0,
+        started: false,
+    })
+}
+
+fn other_error<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+impl ExportReader {
+    fn next_user(&mut self) -> io::Result<Option<User>> {
+        if self.batch.is_empty() && !self.exhausted {
+            let batch = self.connection.list_users(self.last_id.as_ref().map(|id| id.as_str()), EXPORT_BATCH)
+                .map_err(|_| other_error("could not load users"))?;
+            self.exhausted = (batch.len() as i64) < EXPORT_BATCH;
+            self.last_id = batch.last().map(|user| user.id.to_string());
+            self.batch = batch.into();
+        }
+        Ok(self.batch.pop_front())
+    }
+    fn fill(&mut self, row: &ExportRow) -> io::Result<()> {
+        match self.format {
+            BulkFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(Vec::new());
+                writer.write_record(&[
+                    row.id.clone(),
+                    row.name.clone(),
+                    row.email.clone(),
+                    row.email_verified.to_string(),
+                    row.roles.join(";"),
+                    row.created.to_rfc3339(),
+                    row.updated.to_rfc3339(),
+                ]).map_err(other_error)?;
+                self.buffer = writer.into_inner().map_err(other_error)?;
+            },
+            BulkFormat::Ndjson => {
+                self.buffer = serde_json::to_vec(row).map_err(other_error)?;
+                self.buffer.push(b'\n');
+            },
+        }
+        Ok(())
+    }
+}
+
+impl Read for ExportReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position >= self.buffer.len() {
+            self.buffer.clear();
+            self.position = 0;
+            if !self.started {
+                self.started = true;
+                if self.format == BulkFormat::Csv {
+                    self.buffer.extend_from_slice(CSV_HEADER);
+                    continue;
+                }
+            }
+            match self.next_user()? {
+                Some(user) => self.fill(&ExportRow::from_user(&user))?,
+                None => return Ok(0),
+            }
+        }
+        let count = cmp::min(buf.len(), self.buffer.len() - self.position);
+        buf[..count].copy_from_slice(&self.buffer[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
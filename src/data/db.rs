@@ -5,16 +5,30 @@ use rand::{thread_rng, Rng};
 use rand::distributions::Alphanumeric;
 use chrono::{DateTime, Utc};
 
+use crate::data::email_address::{canonical_email_with, LocalPartFolding};
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct User {
     #[serde(rename = "_id")]
     pub id: Uuid,
     pub name: String,
     pub email: String,
+    #[serde(default)]
+    pub email_canonical: String,
     pub hashed_password: String,
     pub salt: String,
     pub created: DateTime<Utc>,
     pub updated: DateTime<Utc>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub pending_email: Option<String>,
+    #[serde(default)]
+    pub pending_email_canonical: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    #[serde(default)]
+    pub sessions_revoked_at: Option<DateTime<Utc>>,
 }
 
 
@@ -26,7 +40,7 @@ pub struct InsertableUser {
 }
 
 impl User {
-    pub fn new(name: String, email: String, password: String) -> Self {
+    pub fn new(name: String, email: String, password: String, folding: LocalPartFolding) -> Self {
         let salt: String = thread_rng()
             .sample_iter(&Alphanumeric)
             .take(20)
@@ -36,15 +50,21 @@ impl User {
         User {
             id: Uuid::new_v4(),
             name,
-            email,
+            email_canonical: canonical_or_trimmed(&email, folding),
+            email: email.trim().to_string(),
             hashed_password,
             salt,
             created: Utc::now(),
             updated: Utc::now(),
+            roles: Vec::new(),
+            pending_email: None,
+            pending_email_canonical: None,
+            email_verified: false,
+            sessions_revoked_at: None,
         }
     }
-    pub fn from_insertable(insertable: InsertableUser) -> Self {
-        User::new(insertable.name, insertable.email, insertable.password)
+    pub fn from_insertable(insertable: InsertableUser, folding: LocalPartFolding) -> Self {
+        User::new(insertable.name, insertable.email, insertable.password, folding)
     }
     pub fn match_password(&self, password: &String) -> bool {
         argon2::verify_encoded(&self.hashed_password, password.as_bytes()).unwrap()
@@ -54,12 +74,63 @@ impl User {
         self.updated = Utc::now();
         self.to_owned()
     }
-    pub fn update_user(&mut self, name: &String, email: &String) -> Self {
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+    pub fn grant_role(&mut self, role: &str) -> Self {
+        if !self.has_role(role) {
+            self.roles.push(role.to_string());
+            self.updated = Utc::now();
+        }
+        self.to_owned()
+    }
+    pub fn revoke_role(&mut self, role: &str) -> Self {
+        if self.has_role(role) {
+            self.roles.retain(|r| r != role);
+            self.updated = Utc::now();
+        }
+        self.to_owned()
+    }
+    /// Tokens issued until now stop being accepted
+    pub fn revoke_sessions(&mut self) -> Self {
+        self.sessions_revoked_at = Some(Utc::now());
+        self.to_owned()
+    }
+    pub fn update_user(&mut self, name: &String, email: &String, folding: LocalPartFolding) -> Self {
+        self.name = name.to_string();
+        self.set_email(email, folding);
+        self.updated = Utc::now();
+        self.to_owned()
+    }
+    /// The new address is only stored as pending until confirmed
+    pub fn request_email_change(&mut self, name: &String, email: &String, folding: LocalPartFolding) -> Self {
         self.name = name.to_string();
-        self.email = email.to_string();
+        self.pending_email = Some(email.trim().to_string());
+        self.pending_email_canonical = Some(canonical_or_trimmed(email, folding));
+        self.updated = Utc::now();
+        self.to_owned()
+    }
+    pub fn restore_email(&mut self, email: &String, folding: LocalPartFolding) -> Self {
+        self.set_email(email, folding);
+        self.clear_pending_email();
+        self.updated = Utc::now();
+        self.to_owned()
+    }
+    pub fn confirm_email(&mut self, email: &String, folding: LocalPartFolding) -> Self {
+        self.set_email(email, folding);
+        self.clear_pending_email();
+        self.email_verified = true;
         self.updated = Utc::now();
         self.to_owned()
     }
+    fn set_email(&mut self, email: &str, folding: LocalPartFolding) {
+        self.email = email.trim().to_string();
+        self.email_canonical = canonical_or_trimmed(email, folding);
+    }
+    fn clear_pending_email(&mut self) {
+        self.pending_email = None;
+        self.pending_email_canonical = None;
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -67,6 +138,8 @@ pub struct ResponseUser {
     pub id: String,
     pub name: String,
     pub email: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_email: Option<String>,
 }
 impl ResponseUser{
     pub fn from_user(user: &User)-> Self {
@@ -74,6 +147,7 @@ impl ResponseUser{
             id: user.id.to_string(),
             name: format!("{}", user.name),
             email: format!("{}", user.email),
+            pending_email: user.pending_email.clone(),
         }
     }
 }
@@ -84,6 +158,11 @@ pub struct UserPassword {
     pub new_password: Option<String>,
 }
 
+/// Routes validate addresses before building users, this only keeps the model total
+fn canonical_or_trimmed(email: &str, folding: LocalPartFolding) -> String {
+    canonical_email_with(email, folding).unwrap_or_else(|_| email.trim().to_string())
+}
+
 fn hash_password(password: &String, salt: &String) -> String {
     let config = argon2::Config::default();
     argon2::hash_encoded(password.as_bytes(), salt.as_bytes(), &config).unwrap()
@@ -0,0 +1,54 @@
+/// How the part before the `@` is folded when comparing addresses.
+/// Domains are always case-insensitive, local parts only by convention.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LocalPartFolding {
+    None,
+    Lowercase,
+    /// Lowercase and drop the `+tag` suffix
+    LowercaseNoTag,
+}
+impl LocalPartFolding {
+    pub fn parse(folding: &str) -> Option<Self> {
+        match folding {
+            "none" => Some(LocalPartFolding::None),
+            "lowercase" => Some(LocalPartFolding::Lowercase),
+            "lowercase_no_tag" => Some(LocalPartFolding::LowercaseNoTag),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum EmailError {
+    Malformed,
+    InvalidDomain,
+}
+
+/// The form of the address used for uniqueness and lookups, under the folding of `EMAIL_LOCAL_PART_FOLDING`
+pub fn canonical_email_with(email: &str, folding: LocalPartFolding) -> Result<String, EmailError> {
+    let email = email.trim();
+    let at = match email.rfind('@') {
+        Some(at) => at,
+        None => return Err(EmailError::Malformed),
+    };
+    let (local, domain) = (&email[..at], &email[at + 1..]);
+    if local.is_empty() || domain.is_empty() || email.chars().any(char::is_whitespace) {
+        return Err(EmailError::Malformed);
+    }
+    let local = match folding {
+        LocalPartFolding::None => local.to_string(),
+        LocalPartFolding::Lowercase => local.to_lowercase(),
+        LocalPartFolding::LowercaseNoTag => {
+            let untagged = local.split('+').next().unwrap_or(local);
+            if untagged.is_empty() {
+                return Err(EmailError::Malformed);
+            }
+            untagged.to_lowercase()
+        },
+    };
+    // IDNA also takes care of lowercasing the domain
+    match idna::domain_to_ascii(domain) {
+        Ok(domain) if !domain.is_empty() => Ok(format!("{}@{}", local, domain)),
+        _ => Err(EmailError::InvalidDomain),
+    }
+}
@@ -0,0 +1,97 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use rand::{thread_rng, Rng};
+use rand::distributions::Alphanumeric;
+
+use crate::data::store::Conn;
+use crate::data::mailer::{self, Mail};
+use crate::data::email_address::{canonical_email_with, LocalPartFolding};
+
+/// A requested change of address: the new address must be confirmed with
+/// `confirm_token`, while the old address receives `revert_token` to undo it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmailChange {
+    #[serde(rename = "_id")]
+    pub id: Uuid,
+    pub user_id: String,
+    pub old_email: String,
+    pub new_email: String,
+    pub confirm_token: String,
+    pub revert_token: String,
+    pub created: DateTime<Utc>,
+    pub confirm_expires: DateTime<Utc>,
+    pub revert_expires: DateTime<Utc>,
+    pub confirmed: Option<DateTime<Utc>>,
+    pub reverted: Option<DateTime<Utc>>,
+}
+impl EmailChange {
+    pub fn new(user_id: String, old_email: String, new_email: String) -> Self {
+        let now = Utc::now();
+        EmailChange {
+            id: Uuid::new_v4(),
+            user_id,
+            old_email,
+            new_email,
+            confirm_token: random_token(),
+            revert_token: random_token(),
+            created: now,
+            // Confirming is quick, noticing a hijack might take a while
+            confirm_expires: now + Duration::days(1),
+            revert_expires: now + Duration::days(7),
+            confirmed: None,
+            reverted: None,
+        }
+    }
+    pub fn can_confirm(&self) -> bool {
+        self.confirmed.is_none() && self.reverted.is_none() && Utc::now() < self.confirm_expires
+    }
+    pub fn can_revert(&self) -> bool {
+        self.reverted.is_none() && Utc::now() < self.revert_expires
+    }
+}
+
+fn random_token() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(40)
+        .collect()
+}
+
+/// Whether the address is taken, either confirmed or pending, by any user other than `except_id`.
+/// Addresses are compared in their canonical form.
+pub fn email_in_use(connection: &Conn, email: &str, folding: LocalPartFolding, except_id: Option<&str>) -> Result<bool, ()> {
+    let canonical = canonical_email_with(email, folding).unwrap_or_else(|_| email.trim().to_string());
+    connection.email_in_use(&canonical, except_id)
+}
+
+/// A new request supersedes the ones still waiting for confirmation
+pub fn insert(connection: &Conn, change: &EmailChange) -> Result<(), ()> {
+    connection.insert_email_change(change)
+}
+
+pub fn save(connection: &Conn, change: &EmailChange) -> Result<(), ()> {
+    connection.save_email_change(change)
+}
+
+pub fn find_by_confirm_token(connection: &Conn, token: &str) -> Result<Option<EmailChange>, ()> {
+    connection.find_email_change_by_confirm_token(token)
+}
+
+pub fn find_by_revert_token(connection: &Conn, token: &str) -> Result<Option<EmailChange>, ()> {
+    connection.find_email_change_by_revert_token(token)
+}
+
+/// Asks the new address for confirmation and warns the old one, giving it a way back
+pub fn notify(connection: &Conn, change: &EmailChange, base: &str) -> Result<(), ()> {
+    let confirm = Mail::new(&change.new_email, "Confirm your new email address", format!(
+        "Please confirm this is your new address by visiting:\n{}/api/email/confirm/{}\n",
+        base, change.confirm_token
+    ));
+    let revert = Mail::new(&change.old_email, "Your email address is being changed", format!(
+        "A change of your account address to {} was requested.\nIf it wasn't you, revert it by visiting:\n{}/api/email/revert/{}\n",
+        change.new_email, base, change.revert_token
+    ));
+    mailer::send(connection, &confirm)?;
+    mailer::send(connection, &revert)
+}
@@ -0,0 +1,100 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use r2d2_mongodb::mongodb as bson;
+
+use bson::UtcDateTime;
+
+use crate::data::store::{Conn, StoreError};
+
+/// Past it, a request still in progress is taken to have died with its worker
+const ABANDONED_AFTER_SECS: i64 = 60;
+pub const MAX_KEY_LENGTH: usize = 255;
+
+/// The outcome of a request sent with an `Idempotency-Key`, kept for retries until it expires.
+/// Only the caller and the key make up the id: the same key with another request is a mistake.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IdempotencyRecord {
+    #[serde(rename = "_id")]
+    pub id: String,
+    /// Hash of the method, path and body
+    pub fingerprint: String,
+    /// None while the first request is in progress
+    pub status: Option<u16>,
+    /// The response body, as JSON
+    pub response: Option<String>,
+    pub created: DateTime<Utc>,
+    /// A date for MongoDB, whose TTL index drops the records past it
+    pub expires: UtcDateTime,
+}
+
+/// What to do with a request carrying an `Idempotency-Key`
+#[derive(Debug)]
+pub enum Claim {
+    /// The first with the key: handle it, then `complete` the record
+    New(IdempotencyRecord),
+    Replay { status: u16, response: serde_json::Value },
+    /// The key came with a different request
+    Mismatch,
+    InProgress,
+}
+
+fn hash(parts: &[&str]) -> String {
+    format!("{:x}", Sha256::digest(parts.join("\n").as_bytes()))
+}
+
+/// Keys are only unique per caller, anonymous ones sharing a space
+pub fn record_id(caller: Option<&str>, key: &str) -> String {
+    hash(&[caller.unwrap_or("anonymous"), key])
+}
+
+pub fn fingerprint(method: &str, path: &str, body_digest: &str) -> String {
+    hash(&[method, path, body_digest])
+}
+
+pub fn claim(connection: &Conn, id: &str, fingerprint: &str, ttl: Duration) -> Result<Claim, ()> {
+    let now = Utc::now();
+    if let Some(record) = connection.find_idempotency_record(id)? {
+        let abandoned = record.status.is_none() && record.created + Duration::seconds(ABANDONED_AFTER_SECS) < now;
+        if record.expires.0 > now && !abandoned {
+            if record.fingerprint != fingerprint {
+                return Ok(Claim::Mismatch);
+            }
+            return Ok(match (record.status, &record.response) {
+                (Some(status), Some(response)) => Claim::Replay {
+                    status,
+                    response: serde_json::from_str(response).map_err(|_| ())?,
+                },
+                _ => Claim::InProgress,
+            });
+        }
+        connection.delete_idempotency_record(id)?;
+    }
+    let record = IdempotencyRecord {
+        id: id.to_string(),
+        fingerprint: fingerprint.to_string(),
+        status: None,
+        response: None,
+        created: now,
+        expires: UtcDateTime(now + ttl),
+    };
+    match connection.insert_idempotency_record(&record) {
+        Ok(_) => Ok(Claim::New(record)),
+        // A concurrent request got there first
+        Err(StoreError::Duplicate) => Ok(Claim::InProgress),
+        Err(StoreError::Failed) => Err(()),
+    }
+}
+
+/// Keeps the response for the retries to come
+pub fn complete(connection: &Conn, mut record: IdempotencyRecord, status: u16, response: &serde_json::Value) -> Result<(), ()> {
+    record.status = Some(status);
+    record.response = Some(response.to_string());
+    connection.save_idempotency_record(&record)
+}
+
+/// Frees the key, for a request that failed to be tried again
+pub fn release(connection: &Conn, record: &IdempotencyRecord) -> Result<(), ()> {
+    connection.delete_idempotency_record(&record.id)
+}
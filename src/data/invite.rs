@@ -0,0 +1,69 @@
+use chrono::{DateTime, Duration, Utc};
+use rocket::FromForm;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use rand::{thread_rng, Rng};
+use rand::distributions::Alphanumeric;
+
+use crate::data::db::User;
+use crate::data::mailer::{self, Mail};
+use crate::data::store::Conn;
+
+/// Lets an account created on someone's behalf choose its own password
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Invite {
+    #[serde(rename = "_id")]
+    pub id: Uuid,
+    pub user_id: String,
+    pub token: String,
+    pub created: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+    pub accepted: Option<DateTime<Utc>>,
+}
+impl Invite {
+    pub fn new(user_id: String) -> Self {
+        let now = Utc::now();
+        Invite {
+            id: Uuid::new_v4(),
+            user_id,
+            token: thread_rng().sample_iter(&Alphanumeric).take(40).collect(),
+            created: now,
+            expires: now + Duration::days(7),
+            accepted: None,
+        }
+    }
+    pub fn can_accept(&self) -> bool {
+        self.accepted.is_none() && Utc::now() < self.expires
+    }
+}
+
+/// Nobody knows it: the invited user replaces it when accepting
+pub fn placeholder_password() -> String {
+    thread_rng().sample_iter(&Alphanumeric).take(32).collect()
+}
+
+/// Sent as JSON, or by the form of the page the invite links to
+#[derive(Serialize, Deserialize, FromForm, Debug, Clone)]
+pub struct SetPassword {
+    pub password: String,
+}
+
+/// Stores a new invite for the user and mails it, with a link under `public_url`
+pub fn send(connection: &Conn, user: &User, public_url: &str) -> Result<Invite, ()> {
+    let invite = Invite::new(user.id.to_string());
+    connection.insert_invite(&invite)?;
+    let mail = Mail::new(&user.email, "You have been invited", format!(
+        "Hello {},\nan account was created for you. Choose your password at:\n{}/api/invites/{}\n",
+        user.name, public_url, invite.token
+    ));
+    mailer::send(connection, &mail)?;
+    Ok(invite)
+}
+
+pub fn find_by_token(connection: &Conn, token: &str) -> Result<Option<Invite>, ()> {
+    connection.find_invite(token)
+}
+
+pub fn mark_accepted(connection: &Conn, invite: &Invite) -> Result<(), ()> {
+    connection.mark_invite_accepted(invite)
+}
@@ -0,0 +1,234 @@
+use std::fmt;
+use std::fs;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use serde::Serialize;
+
+use crate::config::ConfigReader;
+
+const RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const P256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+
+/// A public key as published in the JWKS (RFC 7517)
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Jwk {
+    pub kty: &'static str,
+    pub kid: String,
+    pub alg: &'static str,
+    #[serde(rename = "use")]
+    pub usage: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crv: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub y: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+/// A public key tokens may be verified with, its algorithm told by the key itself
+#[derive(Clone)]
+pub struct VerificationKey {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    pub key: DecodingKey,
+    pub jwk: Jwk,
+}
+
+#[derive(Clone)]
+pub struct SigningKey {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    pub key: EncodingKey,
+}
+
+/// The keys of asymmetric signing. Empty, tokens are signed with `JWT_SECRET` (HS256).
+/// Rotating means publishing the new public key, waiting for the other services to fetch it,
+/// signing with the new key, then dropping the old one once its last tokens have expired.
+#[derive(Clone, Default)]
+pub struct KeyRing {
+    pub signing: Option<SigningKey>,
+    pub verification: Vec<VerificationKey>,
+}
+impl fmt::Debug for KeyRing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KeyRing")
+            .field("signing", &self.signing.as_ref().map(|key| (&key.kid, key.algorithm)))
+            .field("verification", &self.verification.iter().map(|key| (&key.kid, key.algorithm)).collect::<Vec<_>>())
+            .finish()
+    }
+}
+impl KeyRing {
+    /// `JWT_VERIFICATION_KEYS` lists public keys as `kid=path.pem`, comma separated.
+    /// `JWT_SIGNING_KEY_FILE` is a PKCS#8 private key, whose public half is listed under `JWT_SIGNING_KEY_ID`.
+    pub fn read(reader: &mut ConfigReader) -> Option<Self> {
+        let mut verification: Vec<VerificationKey> = Vec::new();
+        for entry in reader.get("JWT_VERIFICATION_KEYS").unwrap_or_default().split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (kid, path) = match entry.find('=') {
+                Some(at) => (entry[..at].trim(), entry[at + 1..].trim()),
+                None => {
+                    reader.invalid("JWT_VERIFICATION_KEYS", &format!("{} isn't kid=path", entry));
+                    continue;
+                },
+            };
+            if verification.iter().any(|key| key.kid == kid) {
+                reader.invalid("JWT_VERIFICATION_KEYS", &format!("key id {} is listed twice", kid));
+                continue;
+            }
+            match load_public_key(kid, path) {
+                Ok(key) => verification.push(key),
+                Err(e) => reader.invalid("JWT_VERIFICATION_KEYS", &format!("{}: {}", kid, e)),
+            }
+        }
+
+        let signing = match (reader.get("JWT_SIGNING_KEY_FILE"), reader.get("JWT_SIGNING_KEY_ID")) {
+            (None, None) => None,
+            (Some(_), None) => {
+                reader.required("JWT_SIGNING_KEY_ID");
+                return None;
+            },
+            (None, Some(_)) => {
+                reader.required("JWT_SIGNING_KEY_FILE");
+                return None;
+            },
+            (Some(path), Some(kid)) => {
+                let public = match verification.iter().find(|key| key.kid == kid) {
+                    Some(public) => public,
+                    None => {
+                        reader.invalid("JWT_SIGNING_KEY_ID", &format!("{} isn't among JWT_VERIFICATION_KEYS", kid));
+                        return None;
+                    },
+                };
+                match load_private_key(public, &path) {
+                    Ok(key) => Some(key),
+                    Err(e) => {
+                        reader.invalid("JWT_SIGNING_KEY_FILE", &e);
+                        return None;
+                    },
+                }
+            },
+        };
+        Some(KeyRing { signing, verification })
+    }
+
+    pub fn find(&self, kid: &str) -> Option<&VerificationKey> {
+        self.verification.iter().find(|key| key.kid == kid)
+    }
+
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet { keys: self.verification.iter().map(|key| key.jwk.clone()).collect() }
+    }
+}
+
+fn load_public_key(kid: &str, path: &str) -> Result<VerificationKey, String> {
+    let bytes = fs::read(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    let parsed = pem::parse(&bytes).map_err(|e| format!("{} isn't PEM: {}", path, e))?;
+    let (algorithm, jwk) = match parsed.tag.as_str() {
+        "PUBLIC KEY" => public_jwk(kid, &parsed.contents)?,
+        "RSA PUBLIC KEY" => rsa_jwk(kid, &parsed.contents)?,
+        other => return Err(format!("{} holds a {}, not a public key", path, other)),
+    };
+    let key = match algorithm {
+        Algorithm::RS256 => DecodingKey::from_rsa_pem(&bytes),
+        Algorithm::ES256 => DecodingKey::from_ec_pem(&bytes),
+        _ => DecodingKey::from_ed_pem(&bytes),
+    }.map_err(|e| format!("{}: {}", path, e))?;
+    Ok(VerificationKey { kid: kid.to_string(), algorithm, key, jwk })
+}
+
+/// Checks the private key against its public half by signing a token and verifying it
+fn load_private_key(public: &VerificationKey, path: &str) -> Result<SigningKey, String> {
+    let bytes = fs::read(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    let key = match public.algorithm {
+        Algorithm::RS256 => EncodingKey::from_rsa_pem(&bytes),
+        Algorithm::ES256 => EncodingKey::from_ec_pem(&bytes),
+        _ => EncodingKey::from_ed_pem(&bytes),
+    }.map_err(|e| format!("{} isn't a {:?} private key: {}", path, public.algorithm, e))?;
+    let probe = serde_json::json!({ "exp": chrono::Utc::now().timestamp() + 60 });
+    let matches = jsonwebtoken::encode(&jsonwebtoken::Header::new(public.algorithm), &probe, &key)
+        .map(|token| jsonwebtoken::decode::<serde_json::Value>(&token, &public.key, &jsonwebtoken::Validation::new(public.algorithm)).is_ok())
+        .unwrap_or(false);
+    if !matches {
+        return Err(format!("{} doesn't match the public key {}", path, public.kid));
+    }
+    Ok(SigningKey { kid: public.kid.clone(), algorithm: public.algorithm, key })
+}
+
+fn base64url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn new_jwk(kid: &str, kty: &'static str, alg: &'static str) -> Jwk {
+    Jwk { kty, kid: kid.to_string(), alg, usage: "sig", crv: None, n: None, e: None, x: None, y: None }
+}
+
+/// Splits a DER element off the input: its tag, its content and what follows
+fn der(input: &[u8]) -> Result<(u8, &[u8], &[u8]), String> {
+    let malformed = || "malformed key".to_string();
+    let tag = *input.first().ok_or_else(malformed)?;
+    let first = *input.get(1).ok_or_else(malformed)? as usize;
+    let (length, start) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let count = first & 0x7f;
+        if count == 0 || count > 4 || input.len() < 2 + count {
+            return Err(malformed());
+        }
+        (input[2..2 + count].iter().fold(0, |length, byte| (length << 8) | *byte as usize), 2 + count)
+    };
+    if input.len() < start + length {
+        return Err(malformed());
+    }
+    Ok((tag, &input[start..start + length], &input[start + length..]))
+}
+
+/// A SubjectPublicKeyInfo: the algorithm, its curve if any, and the key bits
+fn public_jwk(kid: &str, spki: &[u8]) -> Result<(Algorithm, Jwk), String> {
+    let (_, info, _) = der(spki)?;
+    let (_, identifier, rest) = der(info)?;
+    let (_, key, _) = der(rest)?;
+    // The bit string starts with its count of unused bits
+    let key = key.get(1..).ok_or_else(|| "malformed key".to_string())?;
+    let (_, oid, parameters) = der(identifier)?;
+    if oid == RSA_ENCRYPTION {
+        rsa_jwk(kid, key)
+    } else if oid == EC_PUBLIC_KEY {
+        let (_, curve, _) = der(parameters)?;
+        if curve != P256 || key.len() != 65 || key[0] != 0x04 {
+            return Err("only uncompressed P-256 keys are supported for ES256".to_string());
+        }
+        let mut jwk = new_jwk(kid, "EC", "ES256");
+        jwk.crv = Some("P-256");
+        jwk.x = Some(base64url(&key[1..33]));
+        jwk.y = Some(base64url(&key[33..]));
+        Ok((Algorithm::ES256, jwk))
+    } else if oid == ED25519 {
+        let mut jwk = new_jwk(kid, "OKP", "EdDSA");
+        jwk.crv = Some("Ed25519");
+        jwk.x = Some(base64url(key));
+        Ok((Algorithm::EdDSA, jwk))
+    } else {
+        Err("only RSA, P-256 and Ed25519 keys are supported".to_string())
+    }
+}
+
+/// An RSAPublicKey: modulus and exponent, as unsigned big-endian integers
+fn rsa_jwk(kid: &str, key: &[u8]) -> Result<(Algorithm, Jwk), String> {
+    let (_, integers, _) = der(key)?;
+    let (_, modulus, rest) = der(integers)?;
+    let (_, exponent, _) = der(rest)?;
+    let unsigned = |bytes: &[u8]| bytes.iter().position(|b| *b != 0).map(|at| bytes[at..].to_vec()).unwrap_or_default();
+    let mut jwk = new_jwk(kid, "RSA", "RS256");
+    jwk.n = Some(base64url(&unsigned(modulus)));
+    jwk.e = Some(base64url(&unsigned(exponent)));
+    Ok((Algorithm::RS256, jwk))
+}
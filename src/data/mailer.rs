@@ -0,0 +1,207 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::config::ConfigReader;
+use crate::data::store::{Conn, Database};
+
+/// Mails the worker takes on at once
+const DUE_BATCH: i64 = 50;
+/// Keeps the doubling backoff from growing past a few hours
+const MAX_BACKOFF_SECS: u64 = 6 * 60 * 60;
+const DEFAULT_FROM: &str = "no-reply@localhost";
+const DEFAULT_POLL_INTERVAL_MS: u64 = 1000;
+const DEFAULT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_MAX_ATTEMPTS: usize = 8;
+const DEFAULT_RETRY_BASE_SECS: u64 = 30;
+/// Added to the timeout of an attempt for the lease of a claimed mail
+const LEASE_MARGIN_SECS: u64 = 60;
+/// Sent along, so that the relay can drop a mail it already got
+pub const MAIL_ID_HEADER: &str = "X-Mail-Id";
+
+/// The worker runs once per process, however many instances are built
+static WORKER_STARTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MailConfig {
+    /// Where the worker POSTs the mails, as JSON with `from`, `to`, `subject` and `text`.
+    /// Without one, mails stay in the outbox for a relay reading from the database.
+    pub relay_url: Option<String>,
+    pub from: String,
+    /// How often the worker looks for due mails
+    pub poll_interval: Duration,
+    pub timeout: Duration,
+    /// Attempts before a mail is given up on
+    pub max_attempts: usize,
+    /// Wait after the first failed attempt, doubled after each one that follows
+    pub retry_base: Duration,
+}
+impl MailConfig {
+    pub fn read(reader: &mut ConfigReader) -> Self {
+        let relay_url = reader.get("MAIL_RELAY_URL");
+        if let Some(url) = &relay_url {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                reader.invalid("MAIL_RELAY_URL", "must be an http or https URL");
+            }
+        }
+        let max_attempts = reader.parse("MAIL_MAX_ATTEMPTS").unwrap_or(DEFAULT_MAX_ATTEMPTS);
+        if max_attempts == 0 {
+            reader.invalid("MAIL_MAX_ATTEMPTS", "must be at least 1");
+        }
+        MailConfig {
+            relay_url,
+            from: reader.get("MAIL_FROM").unwrap_or_else(|| DEFAULT_FROM.to_string()),
+            poll_interval: Duration::from_millis(reader.parse("MAIL_POLL_INTERVAL_MS").unwrap_or(DEFAULT_POLL_INTERVAL_MS)),
+            timeout: Duration::from_secs(reader.parse("MAIL_TIMEOUT_SECS").unwrap_or(DEFAULT_TIMEOUT_SECS)),
+            max_attempts,
+            retry_base: Duration::from_secs(reader.parse("MAIL_RETRY_BASE_SECS").unwrap_or(DEFAULT_RETRY_BASE_SECS)),
+        }
+    }
+
+    /// How long to wait after the given number of failed attempts
+    fn backoff(&self, failed: i64) -> chrono::Duration {
+        let factor = 1u64.checked_shl(failed.saturating_sub(1) as u32).unwrap_or(u64::MAX);
+        let secs = self.retry_base.as_secs().saturating_mul(factor).min(MAX_BACKOFF_SECS);
+        chrono::Duration::seconds(secs as i64)
+    }
+
+    /// How long a claimed mail is left to its worker, which gives up on the attempt well before
+    fn lease(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.timeout.as_secs().saturating_add(LEASE_MARGIN_SECS) as i64)
+    }
+}
+
+/// An outgoing email. Mails are queued in the outbox, then handed to the relay by the worker.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Mail {
+    #[serde(rename = "_id")]
+    pub id: Uuid,
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+    pub created: DateTime<Utc>,
+    pub sent: bool,
+    /// Failed attempts at relaying it
+    #[serde(default)]
+    pub attempts: i64,
+    /// Set while a worker holds it and after a failed attempt. An unsent mail without one is due at once.
+    #[serde(default)]
+    pub next_attempt: Option<DateTime<Utc>>,
+    /// Why the last attempt failed
+    #[serde(default)]
+    pub last_error: Option<String>,
+    /// Given up on after too many attempts, it stays in the outbox unsent
+    #[serde(default)]
+    pub failed: bool,
+}
+impl Mail {
+    pub fn new(to: &str, subject: &str, body: String) -> Self {
+        Mail {
+            id: Uuid::new_v4(),
+            to: to.to_string(),
+            subject: subject.to_string(),
+            body,
+            created: Utc::now(),
+            sent: false,
+            attempts: 0,
+            next_attempt: None,
+            last_error: None,
+            failed: false,
+        }
+    }
+}
+
+pub fn send(connection: &Conn, mail: &Mail) -> Result<(), ()> {
+    connection.queue_mail(mail)?;
+    log::info!("queued mail \"{}\" to {}", mail.subject, mail.to);
+    Ok(())
+}
+
+/// Hands one mail to the relay
+fn relay(relay_url: &str, mail: &Mail, config: &MailConfig) -> Result<(), String> {
+    let response = ureq::post(relay_url)
+        .timeout(config.timeout)
+        .set("Content-Type", "application/json")
+        .set(MAIL_ID_HEADER, &mail.id.to_string())
+        .send_string(&json!({ "from": config.from, "to": mail.to, "subject": mail.subject, "text": mail.body }).to_string());
+    match response.synthetic_error() {
+        Some(e) => Err(e.to_string()),
+        None if response.ok() => Ok(()),
+        None => Err(format!("{} {}", response.status(), response.status_text())),
+    }
+}
+
+/// Makes one attempt at every mail that's due, claiming each first: workers sharing the database
+/// never send the same one at once. A mail the relay refuses is put off with a growing backoff,
+/// then given up on, without holding up the others. A worker dying mid-attempt leaves its claim
+/// to run out, the mail being due again after it.
+pub fn send_due(connection: &Conn, relay_url: &str, config: &MailConfig) -> Result<usize, ()> {
+    let due = connection.due_mails(Utc::now(), DUE_BATCH)?;
+    for mut mail in due.iter().cloned() {
+        if !connection.claim_mail(&mail.id.to_string(), mail.next_attempt, Utc::now() + config.lease())? {
+            continue;
+        }
+        match relay(relay_url, &mail, config) {
+            Ok(()) => {
+                log::info!("sent mail \"{}\" to {}", mail.subject, mail.to);
+                mail.sent = true;
+                mail.next_attempt = None;
+            },
+            Err(e) => {
+                log::warn!("could not relay mail \"{}\" to {}: {}", mail.subject, mail.to, e);
+                mail.attempts += 1;
+                mail.last_error = Some(e);
+                if mail.attempts >= config.max_attempts as i64 {
+                    log::error!("gave up on mail \"{}\" to {} after {} attempts", mail.subject, mail.to, mail.attempts);
+                    mail.failed = true;
+                    mail.next_attempt = None;
+                } else {
+                    mail.next_attempt = Some(Utc::now() + config.backoff(mail.attempts));
+                }
+            },
+        }
+        connection.save_mail_attempt(&mail)?;
+    }
+    Ok(due.len())
+}
+
+/// Sends the queued mails in the background for as long as the process runs. Instances sharing the database can all run one.
+/// Mails are still sent at least once, not exactly once: the relay tells retries apart by `X-Mail-Id`.
+pub fn spawn_worker(database: Arc<Database>, config: MailConfig) {
+    let relay_url = match config.relay_url.clone() {
+        Some(relay_url) => relay_url,
+        None => {
+            log::warn!("MAIL_RELAY_URL isn't set, mails stay in the outbox");
+            return;
+        },
+    };
+    if WORKER_STARTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let spawned = thread::Builder::new().name("mailer".to_string()).spawn(move || loop {
+        let busy = match database.get() {
+            Ok(store) => match send_due(&Conn(store), &relay_url, &config) {
+                Ok(sent) => sent as i64 == DUE_BATCH,
+                Err(_) => {
+                    log::warn!("could not send the queued mails");
+                    false
+                },
+            },
+            Err(e) => {
+                log::debug!("no database connection for the mailer: {}", e);
+                false
+            },
+        };
+        if !busy {
+            thread::sleep(config.poll_interval);
+        }
+    });
+    if let Err(e) = spawned {
+        log::error!("could not start the mailer: {}", e);
+    }
+}
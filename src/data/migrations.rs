@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+use serde::Serialize;
+
+use crate::data::db::User;
+use crate::data::email_address::{canonical_email_with, LocalPartFolding};
+use crate::data::store::Conn;
+
+const BATCH_SIZE: i64 = 500;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CollidingUser {
+    pub id: String,
+    pub email: String,
+}
+
+/// Accounts whose addresses turn out to be the same once canonicalized
+#[derive(Serialize, Debug, Clone)]
+pub struct EmailCollision {
+    pub canonical: String,
+    pub users: Vec<CollidingUser>,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct MigrationReport {
+    pub dry_run: bool,
+    pub users_scanned: u64,
+    pub users_updated: u64,
+    pub collisions: Vec<EmailCollision>,
+    pub unique_email_index: bool,
+    pub indexes: Vec<String>,
+}
+
+/// Brings stored data up to date with the current model.
+/// With `dry_run` nothing is written, the report only tells what would change.
+pub fn run(connection: &Conn, folding: LocalPartFolding, dry_run: bool) -> Result<MigrationReport, ()> {
+    let mut report = MigrationReport { dry_run, ..MigrationReport::default() };
+    canonicalize_emails(connection, folding, &mut report)?;
+    connection.ensure_schema(&mut report)?;
+    Ok(report)
+}
+
+/// Fills in the canonical email of users stored before it existed, or under other folding rules.
+/// Colliding accounts are left alone and reported: they must be sorted out by hand
+/// before uniqueness of the canonical email can be enforced.
+fn canonicalize_emails(connection: &Conn, folding: LocalPartFolding, report: &mut MigrationReport) -> Result<(), ()> {
+    let mut by_canonical: BTreeMap<String, Vec<CollidingUser>> = BTreeMap::new();
+    let mut outdated: Vec<User> = Vec::new();
+    let mut after: Option<String> = None;
+    loop {
+        let batch = connection.list_users(after.as_ref().map(|id| id.as_str()), BATCH_SIZE)?;
+        for mut user in batch.iter().cloned() {
+            report.users_scanned += 1;
+            let canonical = canonical_email_with(&user.email, folding).unwrap_or_else(|_| user.email.trim().to_string());
+            let pending_canonical = user.pending_email.as_ref()
+                .map(|pending| canonical_email_with(pending, folding).unwrap_or_else(|_| pending.trim().to_string()));
+            by_canonical.entry(canonical.clone()).or_insert_with(Vec::new).push(CollidingUser {
+                id: user.id.to_string(),
+                email: user.email.clone(),
+            });
+            if canonical != user.email_canonical || pending_canonical != user.pending_email_canonical {
+                user.email_canonical = canonical;
+                user.pending_email_canonical = pending_canonical;
+                outdated.push(user);
+            }
+        }
+        match batch.last() {
+            Some(last) if batch.len() as i64 == BATCH_SIZE => after = Some(last.id.to_string()),
+            _ => break,
+        }
+    }
+    report.collisions = by_canonical.into_iter()
+        .filter(|(_, users)| users.len() > 1)
+        .map(|(canonical, users)| EmailCollision { canonical, users })
+        .collect();
+    let colliding: Vec<&str> = report.collisions.iter().map(|c| c.canonical.as_str()).collect();
+    for user in outdated.iter().filter(|user| !colliding.contains(&user.email_canonical.as_str())) {
+        report.users_updated += 1;
+        if !report.dry_run && connection.replace_user(user).is_err() {
+            return Err(());
+        }
+    }
+    Ok(())
+}
@@ -1,3 +1,29 @@
 pub mod db;
+pub mod store;
+pub mod resilience;
+pub mod mongo_config;
 pub mod mongo_connection;
-pub mod security;
\ No newline at end of file
+pub mod mongo_store;
+pub mod mongo_rate_limit;
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+pub mod sql_store;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_connection;
+#[cfg(feature = "postgres")]
+pub mod postgres_connection;
+pub mod security;
+pub mod jwt_keys;
+pub mod audit;
+pub mod mailer;
+pub mod email_change;
+pub mod email_address;
+pub mod migrations;
+pub mod user_search;
+pub mod users;
+pub mod user_events;
+pub mod webhooks;
+pub mod idempotency;
+pub mod invite;
+pub mod access_token;
+pub mod session;
+pub mod bulk;
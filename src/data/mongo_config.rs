@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+use percent_encoding::percent_decode_str;
+use trust_dns_resolver::Resolver;
+
+use crate::config::ConfigReader;
+
+const DEFAULT_PORT: u16 = 27017;
+const DEFAULT_POOL_MAX: u32 = 64;
+const DEFAULT_CONNECT_TIMEOUT_MS: u64 = 30_000;
+const READ_PREFERENCES: &[&str] = &["primary", "primaryPreferred", "secondary", "secondaryPreferred", "nearest"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MongoHost {
+    pub host: String,
+    pub port: u16,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct MongoAuth {
+    pub username: String,
+    pub password: String,
+    /// The database holding the user's credentials
+    pub source: String,
+}
+
+impl fmt::Debug for MongoAuth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MongoAuth")
+            .field("username", &self.username)
+            .field("password", &"***")
+            .field("source", &self.source)
+            .finish()
+    }
+}
+
+/// `cert_file` and `key_file` may be the same PEM file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MongoTls {
+    pub ca_file: Option<String>,
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+    pub allow_invalid_certificates: bool,
+}
+
+/// Where and how to connect to MongoDB
+#[derive(Debug, Clone, PartialEq)]
+pub struct MongoConfig {
+    pub hosts: Vec<MongoHost>,
+    pub database: String,
+    pub auth: Option<MongoAuth>,
+    pub tls: Option<MongoTls>,
+    pub replica_set: Option<String>,
+    pub read_preference: Option<String>,
+    pub pool_max: u32,
+    pub pool_min: Option<u32>,
+    pub connect_timeout: Duration,
+}
+
+/// What a connection string tells, before the environment gets its say
+#[derive(Debug, Default)]
+struct ParsedUri {
+    hosts: Vec<MongoHost>,
+    username: Option<String>,
+    password: Option<String>,
+    database: Option<String>,
+    options: BTreeMap<String, String>,
+    srv: bool,
+}
+
+fn decode(text: &str) -> Result<String, String> {
+    percent_decode_str(text).decode_utf8()
+        .map(|decoded| decoded.to_string())
+        .map_err(|_| format!("invalid percent-encoding in {}", text))
+}
+
+fn parse_host(host: &str) -> Result<MongoHost, String> {
+    // IPv6 addresses come in brackets, their colons aren't port separators
+    let (name, port) = match (host.rfind(':'), host.rfind(']')) {
+        (Some(colon), Some(bracket)) if colon < bracket => (host, None),
+        (Some(colon), _) => (&host[..colon], Some(&host[colon + 1..])),
+        (None, _) => (host, None),
+    };
+    let port = match port {
+        Some(port) => port.parse::<u16>().map_err(|_| format!("invalid port in {}", host))?,
+        None => DEFAULT_PORT,
+    };
+    let name = name.trim_start_matches('[').trim_end_matches(']');
+    if name.is_empty() {
+        return Err("empty host in MONGODB_URI".to_string());
+    }
+    Ok(MongoHost { host: name.to_string(), port })
+}
+
+/// Options names are case insensitive: they're kept under their lowercase form
+fn parse_options(query: &str, options: &mut BTreeMap<String, String>) -> Result<(), String> {
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let mut parts = pair.splitn(2, '=');
+        let key = parts.next().unwrap_or_default();
+        let value = parts.next().ok_or_else(|| format!("option {} without a value", key))?;
+        options.insert(key.to_lowercase(), decode(value)?);
+    }
+    Ok(())
+}
+
+/// `mongodb://[user:password@]host1[:port1][,host2[:port2],...][/database][?options]`,
+/// or `mongodb+srv://` with a single host name whose SRV record lists the servers
+fn parse_uri(uri: &str) -> Result<ParsedUri, String> {
+    let (srv, rest) = if let Some(rest) = uri.strip_prefix("mongodb+srv://") {
+        (true, rest)
+    } else if let Some(rest) = uri.strip_prefix("mongodb://") {
+        (false, rest)
+    } else {
+        return Err("MONGODB_URI must start with mongodb:// or mongodb+srv://".to_string());
+    };
+    let mut parsed = ParsedUri { srv, ..ParsedUri::default() };
+    let (rest, query) = match rest.find('?') {
+        Some(mark) => (&rest[..mark], Some(&rest[mark + 1..])),
+        None => (rest, None),
+    };
+    // Passwords may contain a slash only percent-encoded, so the first slash ends the hosts
+    let (authority, database) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], Some(&rest[slash + 1..])),
+        None => (rest, None),
+    };
+    let hosts = match authority.rfind('@') {
+        Some(at) => {
+            let credentials = &authority[..at];
+            let mut parts = credentials.splitn(2, ':');
+            parsed.username = Some(decode(parts.next().unwrap_or_default())?);
+            parsed.password = parts.next().map(decode).transpose()?;
+            &authority[at + 1..]
+        },
+        None => authority,
+    };
+    parsed.hosts = hosts.split(',').map(parse_host).collect::<Result<Vec<MongoHost>, String>>()?;
+    if srv && (parsed.hosts.len() != 1 || hosts.contains(':')) {
+        return Err("a mongodb+srv:// URI takes a single host name, without port".to_string());
+    }
+    parsed.database = match database {
+        Some(database) if !database.is_empty() => Some(decode(database)?),
+        _ => None,
+    };
+    if let Some(query) = query {
+        parse_options(query, &mut parsed.options)?;
+    }
+    Ok(parsed)
+}
+
+/// Replaces the SRV host name with the servers it points to. The TXT record of the same
+/// name may hold default options, the ones in the URI take precedence.
+fn resolve_srv(parsed: &mut ParsedUri) -> Result<(), String> {
+    let name = parsed.hosts[0].host.clone();
+    let resolver = Resolver::from_system_conf().map_err(|e| format!("cannot set up a DNS resolver: {}", e))?;
+    let records = resolver.srv_lookup(format!("_mongodb._tcp.{}", name).as_str())
+        .map_err(|e| format!("SRV lookup for {} failed: {}", name, e))?;
+    parsed.hosts = records.iter()
+        .map(|record| MongoHost { host: record.target().to_utf8().trim_end_matches('.').to_string(), port: record.port() })
+        .collect();
+    if parsed.hosts.is_empty() {
+        return Err(format!("no SRV records for {}", name));
+    }
+    if let Ok(txt) = resolver.txt_lookup(name.as_str()) {
+        let mut defaults = BTreeMap::new();
+        for record in txt.iter() {
+            let text: Vec<u8> = record.txt_data().iter().flat_map(|chunk| chunk.iter().cloned()).collect();
+            parse_options(&String::from_utf8_lossy(&text), &mut defaults)?;
+        }
+        for (key, value) in defaults {
+            parsed.options.entry(key).or_insert(value);
+        }
+    }
+    Ok(())
+}
+
+impl MongoConfig {
+    /// Reads `MONGODB_URI`, or `MONGODB_ADDRESS` and `MONGODB_PORT` without one.
+    /// The other `MONGODB_*` settings override what the URI says:
+    /// `DATABASE`, `USER`, `PASSWORD`, `AUTH_SOURCE`, `TLS`, `TLS_CA_FILE`, `TLS_CERT_FILE`,
+    /// `TLS_KEY_FILE`, `TLS_ALLOW_INVALID_CERTIFICATES`, `REPLICA_SET`, `READ_PREFERENCE`,
+    /// `POOL_MAX`, `POOL_MIN` and `CONNECT_TIMEOUT_MS`.
+    pub fn read(reader: &mut ConfigReader) -> Option<Self> {
+        let parsed = match reader.get("MONGODB_URI") {
+            Some(uri) => parse_uri(&uri).and_then(|mut parsed| {
+                if parsed.srv {
+                    resolve_srv(&mut parsed)?;
+                }
+                Ok(parsed)
+            }),
+            None => {
+                let address = reader.required("MONGODB_ADDRESS");
+                let port = reader.required("MONGODB_PORT").and_then(|port| reader.parse_value::<u16>("MONGODB_PORT", &port));
+                match (address, port) {
+                    (Some(host), Some(port)) => Ok(ParsedUri { hosts: vec![MongoHost { host, port }], ..ParsedUri::default() }),
+                    _ => return None,
+                }
+            },
+        };
+        let parsed = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                reader.invalid("MONGODB_URI", &e);
+                return None;
+            },
+        };
+        let option = |reader: &ConfigReader, name: &str, uri_name: &str| reader.get(name).or_else(|| parsed.options.get(uri_name).cloned());
+
+        let database = reader.get("MONGODB_DATABASE").or_else(|| parsed.database.clone());
+        if database.is_none() {
+            reader.required("MONGODB_DATABASE");
+        }
+        let username = reader.get("MONGODB_USER").or_else(|| parsed.username.clone());
+        let password = reader.get("MONGODB_PASSWORD").or_else(|| parsed.password.clone());
+        let auth = match (username, password) {
+            (Some(username), Some(password)) => Some(MongoAuth {
+                username,
+                password,
+                // As the drivers do, credentials live in the database named in the URI, or in admin
+                source: option(reader, "MONGODB_AUTH_SOURCE", "authsource")
+                    .or_else(|| parsed.database.clone())
+                    .unwrap_or_else(|| "admin".to_string()),
+            }),
+            (None, None) => None,
+            _ => {
+                reader.invalid("MONGODB_USER", "user and password go together");
+                None
+            },
+        };
+
+        let tls_enabled = match option(reader, "MONGODB_TLS", "tls").or_else(|| parsed.options.get("ssl").cloned()) {
+            Some(value) => reader.flag_value("MONGODB_TLS", &value).unwrap_or(false),
+            None => parsed.srv,
+        };
+        let tls = if tls_enabled {
+            let certificate_key = parsed.options.get("tlscertificatekeyfile").cloned();
+            let allow_invalid_certificates = match option(reader, "MONGODB_TLS_ALLOW_INVALID_CERTIFICATES", "tlsallowinvalidcertificates") {
+                Some(value) => reader.flag_value("MONGODB_TLS_ALLOW_INVALID_CERTIFICATES", &value).unwrap_or(false),
+                None => false,
+            };
+            let tls = MongoTls {
+                ca_file: option(reader, "MONGODB_TLS_CA_FILE", "tlscafile"),
+                cert_file: reader.get("MONGODB_TLS_CERT_FILE").or_else(|| certificate_key.clone()),
+                key_file: reader.get("MONGODB_TLS_KEY_FILE").or(certificate_key),
+                allow_invalid_certificates,
+            };
+            if tls.cert_file.is_some() != tls.key_file.is_some() {
+                reader.invalid("MONGODB_TLS_CERT_FILE", "a client certificate needs its key, and the other way round");
+            }
+            Some(tls)
+        } else {
+            None
+        };
+
+        let read_preference = option(reader, "MONGODB_READ_PREFERENCE", "readpreference");
+        if let Some(preference) = &read_preference {
+            if !READ_PREFERENCES.contains(&preference.as_str()) {
+                reader.invalid("MONGODB_READ_PREFERENCE", &format!("must be one of {}", READ_PREFERENCES.join(", ")));
+            }
+        }
+        let pool_max = match option(reader, "MONGODB_POOL_MAX", "maxpoolsize") {
+            Some(value) => reader.parse_value("MONGODB_POOL_MAX", &value),
+            None => Some(DEFAULT_POOL_MAX),
+        };
+        let pool_min = match option(reader, "MONGODB_POOL_MIN", "minpoolsize") {
+            Some(value) => reader.parse_value("MONGODB_POOL_MIN", &value),
+            None => None,
+        };
+        let connect_timeout = match option(reader, "MONGODB_CONNECT_TIMEOUT_MS", "connecttimeoutms") {
+            Some(value) => reader.parse_value("MONGODB_CONNECT_TIMEOUT_MS", &value),
+            None => Some(DEFAULT_CONNECT_TIMEOUT_MS),
+        };
+        Some(MongoConfig {
+            hosts: parsed.hosts.clone(),
+            database: database?,
+            auth,
+            tls,
+            replica_set: option(reader, "MONGODB_REPLICA_SET", "replicaset"),
+            read_preference,
+            pool_max: pool_max?,
+            pool_min,
+            connect_timeout: Duration::from_millis(connect_timeout?),
+        })
+    }
+
+    /// The connection string handed to the driver: servers and replica set only,
+    /// the rest goes through the client options
+    pub fn driver_uri(&self) -> String {
+        let hosts: Vec<String> = self.hosts.iter()
+            .map(|host| if host.host.contains(':') { format!("[{}]:{}", host.host, host.port) } else { format!("{}:{}", host.host, host.port) })
+            .collect();
+        match &self.replica_set {
+            Some(set) => format!("mongodb://{}/?replicaSet={}", hosts.join(","), set),
+            None => format!("mongodb://{}/", hosts.join(",")),
+        }
+    }
+}
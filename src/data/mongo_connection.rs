@@ -1,51 +1,90 @@
-use std::ops::Deref;
-use std::env;
-use dotenv::dotenv;
-use r2d2::PooledConnection;
-use r2d2_mongodb::{ConnectionOptions, MongodbConnectionManager};
-use rocket::http::Status;
-use rocket::request::{self, FromRequest};
-use rocket::{Outcome, Request, State};
-
-type Pool = r2d2::Pool<MongodbConnectionManager>;
-type PooledConn = PooledConnection<MongodbConnectionManager>;
-
-pub struct Conn(pub PooledConn);
-
-impl<'a, 'r> FromRequest<'a, 'r> for Conn {
-    type Error = ();
-
-    fn from_request(request: &'a Request<'r>) -> request::Outcome<Conn, ()> {
-        let pool = request.guard::<State<Pool>>()?;
-        match pool.get() {
-            Ok(database) => Outcome::Success(Conn(database)),
-            Err(_) => Outcome::Failure((Status::ServiceUnavailable, ())),
-        }
+use r2d2::ManageConnection;
+use r2d2_mongodb::mongodb as mongodb;
+
+use mongodb::{Client, ClientOptions, ThreadedClient};
+use mongodb::common::{ReadMode, ReadPreference};
+use mongodb::db::{Database, ThreadedDatabase};
+
+use crate::data::mongo_config::MongoConfig;
+use crate::data::mongo_store::MongoStore;
+use crate::data::store::{Store, StorePool};
+
+/// Opens a client per pooled connection, authenticating it against the auth source
+#[derive(Debug)]
+pub struct MongoConnectionManager {
+    config: MongoConfig,
+}
+
+pub type Pool = r2d2::Pool<MongoConnectionManager>;
+
+fn read_mode(preference: &str) -> ReadMode {
+    match preference {
+        "primaryPreferred" => ReadMode::PrimaryPreferred,
+        "secondary" => ReadMode::Secondary,
+        "secondaryPreferred" => ReadMode::SecondaryPreferred,
+        "nearest" => ReadMode::Nearest,
+        _ => ReadMode::Primary,
     }
 }
-impl Deref for Conn {
-    type Target = PooledConn;
-    fn deref(&self) -> &Self::Target {
-        &self.0
+
+impl MongoConnectionManager {
+    pub fn new(config: MongoConfig) -> Self {
+        MongoConnectionManager { config }
+    }
+
+    fn client_options(&self) -> ClientOptions {
+        let mut options = match &self.config.tls {
+            Some(tls) => {
+                let verify_peer = !tls.allow_invalid_certificates;
+                match (&tls.cert_file, &tls.key_file) {
+                    (Some(cert), Some(key)) => ClientOptions::with_ssl(tls.ca_file.as_deref(), cert, key, verify_peer),
+                    _ => ClientOptions::with_unauthenticated_ssl(tls.ca_file.as_deref(), verify_peer),
+                }
+            },
+            None => ClientOptions::new(),
+        };
+        options.read_preference = self.config.read_preference.as_ref()
+            .map(|preference| ReadPreference::new(read_mode(preference), None));
+        options.server_selection_timeout_ms = self.config.connect_timeout.as_millis() as i64;
+        options
     }
 }
 
-pub fn init_pool() -> Pool {
-    dotenv().ok();
-    let mongodb_address = env::var("MONGODB_ADDRESS").expect("MONGODB_ADDRESS missing");
-    let mongodb_port = env::var("MONGODB_PORT").expect("MONGODB_PORT missing");
-    let database = env::var("MONGODB_DATABASE").expect("MONGODB_DATABASE missing");
-    //let mongodb_user = env::var("MONGODB_USER").expect("MONGODB_USER missing");
-    //let mongodb_password = env::var("MONGODB_PASSWORD").expect("MONGODB_PASSWORD missing");
-    let manager = MongodbConnectionManager::new(
-        ConnectionOptions::builder()
-            .with_host(&mongodb_address, mongodb_port.parse::<u16>().unwrap())
-            .with_db(&database)
-            //.with_auth(mongodb_user, mongodb_password)
-            .build(),
-    );
-    match Pool::builder().max_size(64).build(manager) {
-        Ok(pool) => pool,
-        Err(e) => panic!("Error: failed to create database pool {}", e),
+impl ManageConnection for MongoConnectionManager {
+    type Connection = Database;
+    type Error = mongodb::Error;
+
+    fn connect(&self) -> Result<Database, mongodb::Error> {
+        let client = Client::with_uri_and_options(&self.config.driver_uri(), self.client_options())?;
+        if let Some(auth) = &self.config.auth {
+            client.db(&auth.source).auth(&auth.username, &auth.password)?;
+        }
+        Ok(client.db(&self.config.database))
     }
-}
\ No newline at end of file
+
+    fn is_valid(&self, database: &mut Database) -> Result<(), mongodb::Error> {
+        database.version().map(|_| ())
+    }
+
+    fn has_broken(&self, _database: &mut Database) -> bool {
+        false
+    }
+}
+
+impl StorePool for Pool {
+    fn get(&self) -> Result<Box<dyn Store>, String> {
+        match r2d2::Pool::get(self) {
+            Ok(database) => Ok(Box::new(MongoStore(database))),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+pub fn init_pool(config: &MongoConfig) -> Result<Pool, String> {
+    let builder = Pool::builder()
+        .max_size(config.pool_max)
+        .min_idle(config.pool_min)
+        .connection_timeout(config.connect_timeout);
+    builder.build(MongoConnectionManager::new(config.clone()))
+        .map_err(|e| format!("failed to create database pool {}", e))
+}
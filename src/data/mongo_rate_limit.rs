@@ -0,0 +1,79 @@
+use chrono::{Duration, Utc};
+use r2d2_mongodb::mongodb as bson;
+use r2d2_mongodb::mongodb as mongodb;
+
+use bson::{bson, doc, Bson, Document};
+use mongodb::coll::options::IndexOptions;
+use mongodb::db::ThreadedDatabase;
+
+use crate::data::mongo_config::MongoConfig;
+use crate::data::mongo_connection::{self, Pool};
+use crate::rate_limit::{take_token, Decision, Limit, RateLimitStore, TokenBucket};
+
+const RATE_LIMITS: &str = "rate_limits";
+/// Tries before giving up on a bucket other instances keep changing under our feet
+const MAX_ATTEMPTS: usize = 5;
+
+/// Buckets shared by every instance, updated by compare-and-swap on their `updated` date.
+/// Documents expire once their bucket would be full again, through a TTL index.
+pub struct MongoRateLimitStore {
+    pool: Pool,
+}
+
+impl MongoRateLimitStore {
+    pub fn new(config: &MongoConfig) -> Result<Self, String> {
+        let pool = mongo_connection::init_pool(config)?;
+        let database = r2d2::Pool::get(&pool).map_err(|e| e.to_string())?;
+        let mut opt = IndexOptions::new();
+        opt.name = Some("expires_ttl".to_string());
+        opt.expire_after_seconds = Some(0);
+        database.collection(RATE_LIMITS).create_index(doc! { "expires": 1 }, Some(opt)).map_err(|e| e.to_string())?;
+        Ok(MongoRateLimitStore { pool })
+    }
+}
+
+fn bucket_document(bucket: &str, state: &TokenBucket, limit: &Limit) -> Document {
+    doc! {
+        "_id": bucket,
+        "tokens": state.tokens,
+        "updated": Bson::UtcDatetime(state.updated),
+        "expires": Bson::UtcDatetime(state.updated + Duration::seconds(limit.period as i64)),
+    }
+}
+
+impl RateLimitStore for MongoRateLimitStore {
+    fn take(&self, bucket: &str, limit: &Limit) -> Result<Decision, String> {
+        let database = r2d2::Pool::get(&self.pool).map_err(|e| e.to_string())?;
+        let collection = database.collection(RATE_LIMITS);
+        for _ in 0..MAX_ATTEMPTS {
+            let found = collection.find_one(Some(doc! { "_id": bucket }), None).map_err(|e| e.to_string())?;
+            let now = Utc::now();
+            match found {
+                Some(found) => {
+                    let previous = match (found.get_f64("tokens"), found.get_utc_datetime("updated")) {
+                        (Ok(tokens), Ok(updated)) => TokenBucket { tokens, updated: *updated },
+                        _ => return Err(format!("malformed rate limit bucket {}", bucket)),
+                    };
+                    let (state, decision) = take_token(Some(previous), limit, now);
+                    let swapped = collection.replace_one(
+                        doc! { "_id": bucket, "updated": Bson::UtcDatetime(previous.updated) },
+                        bucket_document(bucket, &state, limit),
+                        None,
+                    ).map_err(|e| e.to_string())?;
+                    if swapped.matched_count == 1 {
+                        return Ok(decision);
+                    }
+                },
+                None => {
+                    let (state, decision) = take_token(None, limit, now);
+                    let inserted = collection.insert_one(bucket_document(bucket, &state, limit), None).map_err(|e| e.to_string())?;
+                    // Another instance created it first: take from that one
+                    if inserted.write_exception.is_none() {
+                        return Ok(decision);
+                    }
+                },
+            }
+        }
+        Err(format!("rate limit bucket {} kept changing", bucket))
+    }
+}
@@ -0,0 +1,621 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use r2d2::PooledConnection;
+
+use r2d2_mongodb::mongodb as bson;
+use r2d2_mongodb::mongodb as mongodb;
+
+use bson::{bson, doc, Bson, Document};
+use mongodb::coll::Collection;
+use mongodb::coll::options::{FindOneAndUpdateOptions, FindOptions, IndexOptions, ReturnDocument};
+use mongodb::coll::results::InsertOneResult;
+use mongodb::db::ThreadedDatabase;
+use mongodb::db::options::CreateCollectionOptions;
+
+use crate::data::access_token::AccessToken;
+use crate::data::audit::{AuditEvent, AuditFilter};
+use crate::data::db::User;
+use crate::data::email_change::EmailChange;
+use crate::data::idempotency::IdempotencyRecord;
+use crate::data::invite::Invite;
+use crate::data::mailer::Mail;
+use crate::data::migrations::MigrationReport;
+use crate::data::session::Session;
+use crate::data::mongo_connection::MongoConnectionManager;
+use crate::data::store::{Store, StoreError};
+use crate::data::user_events::{UserEvent, RETAINED_EVENTS};
+use crate::data::user_search::{MatchMode, UserSearch};
+use crate::data::webhooks::{Delivery, DeliveryStatus, Webhook};
+
+const USERS: &str = "users";
+const AUDIT_EVENTS: &str = "audit_events";
+const EMAIL_CHANGES: &str = "email_changes";
+const MAIL_OUTBOX: &str = "mail_outbox";
+const INVITES: &str = "invites";
+const ACCESS_TOKENS: &str = "access_tokens";
+const SESSIONS: &str = "sessions";
+const USER_EVENTS: &str = "user_events";
+const WEBHOOKS: &str = "webhooks";
+const WEBHOOK_DELIVERIES: &str = "webhook_deliveries";
+const IDEMPOTENCY_RECORDS: &str = "idempotency_records";
+/// Sequences handing out increasing ids, one document per sequence
+const COUNTERS: &str = "counters";
+/// Big enough for `RETAINED_EVENTS`, the capped collection dropping the oldest past either limit
+const USER_EVENTS_SIZE: i64 = 16 * 1024 * 1024;
+const EMAIL_INDEX: &str = "email_canonical_unique";
+const DUPLICATE_KEY: i32 = 11000;
+
+pub struct MongoStore(pub PooledConnection<MongoConnectionManager>);
+
+fn to_document<T: Serialize>(value: &T) -> Result<Document, ()> {
+    match bson::to_bson(value) {
+        Ok(Bson::Document(document)) => Ok(document),
+        _ => Err(()),
+    }
+}
+
+fn to_value<T: Serialize>(value: &T) -> Result<Bson, ()> {
+    bson::to_bson(value).map_err(|_| ())
+}
+
+fn from_document<T: DeserializeOwned>(document: Document) -> Result<T, ()> {
+    bson::from_bson(Bson::Document(document)).map_err(|_| ())
+}
+
+fn write_error(code: i32) -> StoreError {
+    match code {
+        DUPLICATE_KEY => StoreError::Duplicate,
+        _ => StoreError::Failed,
+    }
+}
+
+fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn pattern(text: &str, mode: MatchMode) -> String {
+    match mode {
+        MatchMode::Prefix => format!("^{}", escape_regex(text)),
+        MatchMode::Substring => escape_regex(text),
+    }
+}
+
+/// Dates are stored as RFC 3339 strings, which sort chronologically
+fn date_range(from: Option<DateTime<Utc>>, to: Option<DateTime<Utc>>) -> Option<Document> {
+    let mut range = Document::new();
+    if let Some(from) = from {
+        range.insert("$gte", from.to_rfc3339_opts(SecondsFormat::AutoSi, true));
+    }
+    if let Some(to) = to {
+        range.insert("$lte", to.to_rfc3339_opts(SecondsFormat::AutoSi, true));
+    }
+    if range.is_empty() { None } else { Some(range) }
+}
+
+fn search_query(search: &UserSearch) -> Document {
+    let mut query = Document::new();
+    if let Some(text) = &search.text {
+        query.insert("$text", doc! { "$search": text.clone() });
+    }
+    if let Some(name) = &search.name {
+        query.insert("name", doc! { "$regex": pattern(name, search.mode), "$options": "i" });
+    }
+    if let Some(email) = &search.email {
+        // Canonical emails are lowercase, so a prefix search can use the index
+        query.insert("email_canonical", doc! { "$regex": pattern(&email.trim().to_lowercase(), search.mode) });
+    }
+    if let Some(range) = date_range(search.created_from, search.created_to) {
+        query.insert("created", range);
+    }
+    if let Some(range) = date_range(search.updated_from, search.updated_to) {
+        query.insert("updated", range);
+    }
+    match search.verified {
+        Some(true) => { query.insert("email_verified", true); },
+        // Users stored before verification existed have no flag at all
+        Some(false) => { query.insert("email_verified", doc! { "$ne": true }); },
+        None => (),
+    }
+    if let Some(role) = &search.role {
+        query.insert("roles", role.clone());
+    }
+    query
+}
+
+fn audit_query(filter: &AuditFilter) -> Result<Document, ()> {
+    let mut query = Document::new();
+    if let Some(user) = &filter.user {
+        query.insert("$or", Bson::Array(vec![
+            Bson::Document(doc! { "actor": user.clone() }),
+            Bson::Document(doc! { "target": user.clone() }),
+        ]));
+    }
+    if let Some(action) = &filter.action {
+        match bson::to_bson(action) {
+            Ok(action) => { query.insert("action", action); },
+            Err(_) => return Err(()),
+        }
+    }
+    let mut range = Document::new();
+    if let Some(from) = filter.from {
+        range.insert("$gte", Bson::UtcDatetime(from));
+    }
+    if let Some(to) = filter.to {
+        range.insert("$lte", Bson::UtcDatetime(to));
+    }
+    if !range.is_empty() {
+        query.insert("timestamp", range);
+    }
+    Ok(query)
+}
+
+impl MongoStore {
+    fn collection(&self, name: &str) -> Collection {
+        self.0.collection(name)
+    }
+
+    fn find_one<T: DeserializeOwned>(&self, collection: &str, query: Document) -> Result<Option<T>, ()> {
+        match self.collection(collection).find_one(Some(query), None) {
+            Ok(Some(found)) => from_document(found).map(Some),
+            Ok(None) => Ok(None),
+            Err(_) => Err(()),
+        }
+    }
+
+    fn find<T: DeserializeOwned>(&self, collection: &str, query: Document, opt: FindOptions) -> Result<Vec<T>, ()> {
+        match self.collection(collection).find(Some(query), Some(opt)) {
+            Ok(cursor) => {
+                let mut found = Vec::new();
+                for result in cursor {
+                    match result {
+                        Ok(document) => found.push(from_document(document)?),
+                        Err(_) => return Err(()),
+                    }
+                }
+                Ok(found)
+            },
+            Err(_) => Err(()),
+        }
+    }
+
+    fn insert<T: Serialize>(&self, collection: &str, value: &T) -> Result<InsertOneResult, ()> {
+        let document = to_document(value)?;
+        self.collection(collection).insert_one(document, None).map_err(|_| ())
+    }
+
+    /// Tells a violated unique index apart from other failures
+    fn insert_unique<T: Serialize>(&self, collection: &str, value: &T) -> Result<(), StoreError> {
+        match self.insert(collection, value) {
+            Ok(inserted) => match inserted.write_exception {
+                None => Ok(()),
+                Some(exception) => match exception.write_error {
+                    Some(err) => Err(write_error(err.code)),
+                    None => Err(StoreError::Failed),
+                },
+            },
+            Err(_) => Err(StoreError::Failed),
+        }
+    }
+
+    fn replace<T: Serialize>(&self, collection: &str, id: String, value: &T) -> Result<(), ()> {
+        let document = to_document(value)?;
+        self.collection(collection).replace_one(doc! { "_id": id }, document, None)
+            .map(|_| ())
+            .map_err(|_| ())
+    }
+
+    /// Sets only the given fields of the document matching `filter`, telling whether there was one
+    fn set(&self, collection: &str, filter: Document, fields: Document) -> Result<bool, ()> {
+        self.collection(collection).update_one(filter, doc! { "$set": fields }, None)
+            .map(|result| result.matched_count > 0)
+            .map_err(|_| ())
+    }
+}
+
+impl Store for MongoStore {
+    fn ping(&self) -> Result<(), String> {
+        self.collection(USERS).count(None, None).map(|_| ()).map_err(|e| e.to_string())
+    }
+
+    fn find_user(&self, id: &str) -> Result<Option<User>, ()> {
+        self.find_one(USERS, doc! { "_id": id })
+    }
+
+    fn find_user_by_email(&self, canonical: &str) -> Result<Option<User>, ()> {
+        self.find_one(USERS, doc! { "email_canonical": canonical })
+    }
+
+    fn insert_user(&self, user: &User) -> Result<(), StoreError> {
+        self.insert_unique(USERS, user)
+    }
+
+    fn replace_user(&self, user: &User) -> Result<bool, StoreError> {
+        let mut document = to_document(user).map_err(|_| StoreError::Failed)?;
+        document.remove("_id");
+        document.remove("sessions_revoked_at");
+        match self.collection(USERS).update_one(doc! { "_id": user.id.to_string() }, doc! { "$set": document }, None) {
+            Ok(replaced) => match replaced.write_exception {
+                None => Ok(replaced.matched_count > 0),
+                Some(exception) => match exception.write_error {
+                    Some(err) => Err(write_error(err.code)),
+                    None => Err(StoreError::Failed),
+                },
+            },
+            Err(_) => Err(StoreError::Failed),
+        }
+    }
+
+    fn revoke_user_sessions(&self, id: &str, at: DateTime<Utc>) -> Result<bool, ()> {
+        self.set(USERS, doc! { "_id": id }, doc! { "sessions_revoked_at": to_value(&at)? })
+    }
+
+    fn delete_user(&self, id: &str) -> Result<Option<User>, ()> {
+        match self.collection(USERS).find_one_and_delete(doc! { "_id": id }, None) {
+            Ok(Some(deleted)) => from_document(deleted).map(Some),
+            Ok(None) => Ok(None),
+            Err(_) => Err(()),
+        }
+    }
+
+    fn count_users(&self) -> Result<i64, ()> {
+        self.collection(USERS).count(None, None).map_err(|_| ())
+    }
+
+    fn email_in_use(&self, canonical: &str, except_id: Option<&str>) -> Result<bool, ()> {
+        let mut query = doc! {
+            "$or": Bson::Array(vec![
+                Bson::Document(doc! { "email_canonical": canonical }),
+                Bson::Document(doc! { "pending_email_canonical": canonical }),
+            ])
+        };
+        if let Some(id) = except_id {
+            query.insert("_id", doc! { "$ne": id });
+        }
+        match self.collection(USERS).find_one(Some(query), None) {
+            Ok(found) => Ok(found.is_some()),
+            Err(_) => Err(()),
+        }
+    }
+
+    fn search_users(&self, search: &UserSearch) -> Result<(Vec<User>, i64), ()> {
+        let query = search_query(search);
+        let total = match self.collection(USERS).count(Some(query.clone()), None) {
+            Ok(total) => total,
+            Err(_) => return Err(()),
+        };
+        let mut opt = FindOptions::new();
+        opt.sort = Some(doc! { "name": 1, "_id": 1 });
+        opt.skip = Some((search.page - 1) * search.per_page);
+        opt.limit = Some(search.per_page);
+        let users = self.find(USERS, query, opt)?;
+        Ok((users, total))
+    }
+
+    fn list_users(&self, after: Option<&str>, limit: i64) -> Result<Vec<User>, ()> {
+        let query = match after {
+            Some(id) => doc! { "_id": { "$gt": id } },
+            None => Document::new(),
+        };
+        let mut opt = FindOptions::new();
+        opt.sort = Some(doc! { "_id": 1 });
+        opt.limit = Some(limit);
+        self.find(USERS, query, opt)
+    }
+
+    fn insert_audit_event(&self, event: &AuditEvent) -> Result<(), String> {
+        let document = to_document(event).map_err(|_| "event is not a document".to_string())?;
+        self.collection(AUDIT_EVENTS).insert_one(document, None).map(|_| ()).map_err(|e| e.to_string())
+    }
+
+    fn find_audit_events(&self, filter: &AuditFilter, limit: i64) -> Result<Vec<AuditEvent>, ()> {
+        let mut opt = FindOptions::new();
+        opt.sort = Some(doc! { "timestamp": -1 });
+        opt.limit = Some(limit);
+        self.find(AUDIT_EVENTS, audit_query(filter)?, opt)
+    }
+
+    fn insert_email_change(&self, change: &EmailChange) -> Result<(), ()> {
+        let pending = doc! { "user_id": change.user_id.clone(), "confirmed": Bson::Null, "reverted": Bson::Null };
+        if self.collection(EMAIL_CHANGES).delete_many(pending, None).is_err() {
+            return Err(());
+        }
+        self.insert(EMAIL_CHANGES, change).map(|_| ())
+    }
+
+    fn save_email_change(&self, change: &EmailChange) -> Result<(), ()> {
+        self.replace(EMAIL_CHANGES, change.id.to_string(), change)
+    }
+
+    fn find_email_change_by_confirm_token(&self, token: &str) -> Result<Option<EmailChange>, ()> {
+        self.find_one(EMAIL_CHANGES, doc! { "confirm_token": token })
+    }
+
+    fn find_email_change_by_revert_token(&self, token: &str) -> Result<Option<EmailChange>, ()> {
+        self.find_one(EMAIL_CHANGES, doc! { "revert_token": token })
+    }
+
+    fn queue_mail(&self, mail: &Mail) -> Result<(), ()> {
+        self.insert(MAIL_OUTBOX, mail).map(|_| ())
+    }
+
+    fn mails_to(&self, to: &str) -> Result<Vec<Mail>, ()> {
+        let mut opt = FindOptions::new();
+        opt.sort = Some(doc! { "created": -1 });
+        self.find(MAIL_OUTBOX, doc! { "to": to }, opt)
+    }
+
+    fn due_mails(&self, now: DateTime<Utc>, limit: i64) -> Result<Vec<Mail>, ()> {
+        let mut due = vec![Bson::Document(doc! { "next_attempt": Bson::Null })];
+        if let Some(range) = date_range(None, Some(now)) {
+            due.push(Bson::Document(doc! { "next_attempt": range }));
+        }
+        let mut opt = FindOptions::new();
+        opt.sort = Some(doc! { "created": 1 });
+        opt.limit = Some(limit);
+        self.find(MAIL_OUTBOX, doc! { "sent": false, "failed": { "$ne": true }, "$or": due }, opt)
+    }
+
+    /// Mails queued before attempts were kept have no `next_attempt` at all, which the null filter matches too
+    fn claim_mail(&self, id: &str, due: Option<DateTime<Utc>>, lease: DateTime<Utc>) -> Result<bool, ()> {
+        let filter = doc! {
+            "_id": id,
+            "sent": false,
+            "failed": { "$ne": true },
+            "next_attempt": to_value(&due)?,
+        };
+        let update = doc! { "$set": { "next_attempt": to_value(&lease)? } };
+        self.collection(MAIL_OUTBOX).update_one(filter, update, None)
+            .map(|result| result.modified_count == 1)
+            .map_err(|_| ())
+    }
+
+    fn save_mail_attempt(&self, mail: &Mail) -> Result<(), ()> {
+        let fields = doc! {
+            "sent": mail.sent,
+            "attempts": mail.attempts,
+            "next_attempt": to_value(&mail.next_attempt)?,
+            "last_error": to_value(&mail.last_error)?,
+            "failed": mail.failed,
+        };
+        self.set(MAIL_OUTBOX, doc! { "_id": mail.id.to_string() }, fields).map(|_| ())
+    }
+
+    fn insert_invite(&self, invite: &Invite) -> Result<(), ()> {
+        self.insert(INVITES, invite).map(|_| ())
+    }
+
+    fn find_invite(&self, token: &str) -> Result<Option<Invite>, ()> {
+        self.find_one(INVITES, doc! { "token": token })
+    }
+
+    fn mark_invite_accepted(&self, invite: &Invite) -> Result<(), ()> {
+        let accepted = Bson::String(Utc::now().to_rfc3339());
+        self.collection(INVITES).update_one(doc! { "_id": invite.id.to_string() }, doc! { "$set": { "accepted": accepted } }, None)
+            .map(|_| ())
+            .map_err(|_| ())
+    }
+
+    fn insert_access_token(&self, token: &AccessToken) -> Result<(), ()> {
+        self.insert(ACCESS_TOKENS, token).map(|_| ())
+    }
+
+    fn save_access_token(&self, token: &AccessToken) -> Result<(), ()> {
+        self.replace(ACCESS_TOKENS, token.id.to_string(), token)
+    }
+
+    fn record_access_token_use(&self, id: &str, at: DateTime<Utc>, ip: Option<&str>) -> Result<(), ()> {
+        let fields = doc! { "last_used": to_value(&at)?, "last_used_ip": to_value(&ip)? };
+        self.set(ACCESS_TOKENS, doc! { "_id": id, "revoked": Bson::Null }, fields).map(|_| ())
+    }
+
+    fn find_access_token_by_hash(&self, hash: &str) -> Result<Option<AccessToken>, ()> {
+        self.find_one(ACCESS_TOKENS, doc! { "token_hash": hash })
+    }
+
+    fn access_tokens_of(&self, user_id: &str) -> Result<Vec<AccessToken>, ()> {
+        let mut opt = FindOptions::new();
+        opt.sort = Some(doc! { "created": -1 });
+        self.find(ACCESS_TOKENS, doc! { "user_id": user_id }, opt)
+    }
+
+    fn insert_session(&self, session: &Session) -> Result<(), ()> {
+        self.insert(SESSIONS, session).map(|_| ())
+    }
+
+    fn save_session(&self, session: &Session) -> Result<(), ()> {
+        self.replace(SESSIONS, session.id.to_string(), session)
+    }
+
+    fn touch_session(&self, id: &str, last_seen: DateTime<Utc>, ip: Option<&str>) -> Result<(), ()> {
+        self.set(SESSIONS, doc! { "_id": id }, doc! { "last_seen": to_value(&last_seen)?, "ip": to_value(&ip)? }).map(|_| ())
+    }
+
+    fn find_session(&self, id: &str) -> Result<Option<Session>, ()> {
+        self.find_one(SESSIONS, doc! { "_id": id })
+    }
+
+    fn sessions_of(&self, user_id: &str) -> Result<Vec<Session>, ()> {
+        let mut opt = FindOptions::new();
+        opt.sort = Some(doc! { "last_seen": -1 });
+        self.find(SESSIONS, doc! { "user_id": user_id }, opt)
+    }
+
+    fn insert_user_event(&self, event: &UserEvent) -> Result<i64, String> {
+        let mut opt = FindOneAndUpdateOptions::new();
+        opt.upsert = Some(true);
+        opt.return_document = Some(ReturnDocument::After);
+        let counter = self.collection(COUNTERS)
+            .find_one_and_update(doc! { "_id": USER_EVENTS }, doc! { "$inc": { "seq": 1i64 } }, Some(opt))
+            .map_err(|e| e.to_string())?;
+        let id = match counter.as_ref().map(|counter| counter.get_i64("seq")) {
+            Some(Ok(id)) => id,
+            _ => return Err("no id for the event".to_string()),
+        };
+        let document = to_document(&UserEvent { id, ..event.clone() }).map_err(|_| "event is not a document".to_string())?;
+        self.collection(USER_EVENTS).insert_one(document, None).map(|_| id).map_err(|e| e.to_string())
+    }
+
+    fn user_events_after(&self, after: i64, limit: i64) -> Result<Vec<UserEvent>, ()> {
+        let mut opt = FindOptions::new();
+        opt.sort = Some(doc! { "_id": 1 });
+        opt.limit = Some(limit);
+        self.find(USER_EVENTS, doc! { "_id": { "$gt": after } }, opt)
+    }
+
+    fn last_user_event_id(&self) -> Result<i64, ()> {
+        let mut opt = FindOptions::new();
+        opt.sort = Some(doc! { "_id": -1 });
+        opt.limit = Some(1);
+        let last: Vec<UserEvent> = self.find(USER_EVENTS, Document::new(), opt)?;
+        Ok(last.first().map(|event| event.id).unwrap_or(0))
+    }
+
+    fn insert_webhook(&self, webhook: &Webhook) -> Result<(), ()> {
+        self.insert(WEBHOOKS, webhook).map(|_| ())
+    }
+
+    fn find_webhook(&self, id: &str) -> Result<Option<Webhook>, ()> {
+        self.find_one(WEBHOOKS, doc! { "_id": id })
+    }
+
+    fn webhooks(&self) -> Result<Vec<Webhook>, ()> {
+        let mut opt = FindOptions::new();
+        opt.sort = Some(doc! { "created": 1 });
+        self.find(WEBHOOKS, Document::new(), opt)
+    }
+
+    fn delete_webhook(&self, id: &str) -> Result<bool, ()> {
+        match self.collection(WEBHOOKS).delete_one(doc! { "_id": id }, None) {
+            Ok(deleted) if deleted.deleted_count > 0 => {
+                self.collection(WEBHOOK_DELIVERIES).delete_many(doc! { "webhook_id": id }, None).map_err(|_| ())?;
+                Ok(true)
+            },
+            Ok(_) => Ok(false),
+            Err(_) => Err(()),
+        }
+    }
+
+    fn insert_delivery(&self, delivery: &Delivery) -> Result<(), ()> {
+        self.insert(WEBHOOK_DELIVERIES, delivery).map(|_| ())
+    }
+
+    fn save_delivery(&self, delivery: &Delivery) -> Result<(), ()> {
+        self.replace(WEBHOOK_DELIVERIES, delivery.id.to_string(), delivery)
+    }
+
+    fn find_delivery(&self, id: &str) -> Result<Option<Delivery>, ()> {
+        self.find_one(WEBHOOK_DELIVERIES, doc! { "_id": id })
+    }
+
+    fn deliveries_of(&self, webhook_id: &str, limit: i64) -> Result<Vec<Delivery>, ()> {
+        let mut opt = FindOptions::new();
+        opt.sort = Some(doc! { "created": -1 });
+        opt.limit = Some(limit);
+        self.find(WEBHOOK_DELIVERIES, doc! { "webhook_id": webhook_id }, opt)
+    }
+
+    fn due_deliveries(&self, now: DateTime<Utc>, limit: i64) -> Result<Vec<Delivery>, ()> {
+        let mut query = doc! { "status": DeliveryStatus::Pending.name() };
+        if let Some(range) = date_range(None, Some(now)) {
+            query.insert("next_attempt", range);
+        }
+        let mut opt = FindOptions::new();
+        opt.sort = Some(doc! { "next_attempt": 1 });
+        opt.limit = Some(limit);
+        self.find(WEBHOOK_DELIVERIES, query, opt)
+    }
+
+    fn claim_delivery(&self, id: &str, due: DateTime<Utc>, lease: DateTime<Utc>) -> Result<bool, ()> {
+        let filter = doc! {
+            "_id": id,
+            "status": DeliveryStatus::Pending.name(),
+            "next_attempt": due.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+        };
+        let update = doc! { "$set": { "next_attempt": lease.to_rfc3339_opts(SecondsFormat::AutoSi, true) } };
+        self.collection(WEBHOOK_DELIVERIES).update_one(filter, update, None)
+            .map(|result| result.modified_count == 1)
+            .map_err(|_| ())
+    }
+
+    fn insert_idempotency_record(&self, record: &IdempotencyRecord) -> Result<(), StoreError> {
+        self.insert_unique(IDEMPOTENCY_RECORDS, record)
+    }
+
+    fn save_idempotency_record(&self, record: &IdempotencyRecord) -> Result<(), ()> {
+        self.replace(IDEMPOTENCY_RECORDS, record.id.clone(), record)
+    }
+
+    fn find_idempotency_record(&self, id: &str) -> Result<Option<IdempotencyRecord>, ()> {
+        self.find_one(IDEMPOTENCY_RECORDS, doc! { "_id": id })
+    }
+
+    fn delete_idempotency_record(&self, id: &str) -> Result<(), ()> {
+        self.collection(IDEMPOTENCY_RECORDS).delete_one(doc! { "_id": id }, None).map(|_| ()).map_err(|_| ())
+    }
+
+    /// Indexes backing user search and the audit log queries, the capped collection of user events
+    /// and the TTL index dropping expired idempotency records. Creating an existing index is a no-op.
+    fn ensure_schema(&self, report: &mut MigrationReport) -> Result<(), ()> {
+        let collections = self.0.collection_names(None).map_err(|_| ())?;
+        if !report.dry_run && !collections.iter().any(|name| name == USER_EVENTS) {
+            let mut opt = CreateCollectionOptions::new();
+            opt.capped = true;
+            opt.size = Some(USER_EVENTS_SIZE);
+            opt.max = Some(RETAINED_EVENTS);
+            self.0.create_collection(USER_EVENTS, Some(opt)).map_err(|_| ())?;
+        }
+        if report.collisions.is_empty() && !report.dry_run {
+            let mut opt = IndexOptions::new();
+            opt.unique = Some(true);
+            opt.name = Some(EMAIL_INDEX.to_string());
+            if self.collection(USERS).create_index(doc! { "email_canonical": 1 }, Some(opt)).is_err() {
+                return Err(());
+            }
+            report.unique_email_index = true;
+        }
+        if !report.dry_run {
+            let mut opt = IndexOptions::new();
+            opt.name = Some("expires_ttl".to_string());
+            opt.expire_after_seconds = Some(0);
+            self.collection(IDEMPOTENCY_RECORDS).create_index(doc! { "expires": 1 }, Some(opt)).map_err(|_| ())?;
+        }
+        report.indexes.push(format!("{}.expires_ttl", IDEMPOTENCY_RECORDS));
+        let indexes = vec![
+            (USERS, "name_text", doc! { "name": "text" }),
+            (USERS, "name", doc! { "name": 1 }),
+            (USERS, "created", doc! { "created": 1 }),
+            (USERS, "updated", doc! { "updated": 1 }),
+            (USERS, "roles", doc! { "roles": 1 }),
+            (USERS, "email_verified", doc! { "email_verified": 1 }),
+            (USERS, "pending_email_canonical", doc! { "pending_email_canonical": 1 }),
+            (AUDIT_EVENTS, "actor_timestamp", doc! { "actor": 1, "timestamp": -1 }),
+            (AUDIT_EVENTS, "target_timestamp", doc! { "target": 1, "timestamp": -1 }),
+            (AUDIT_EVENTS, "action_timestamp", doc! { "action": 1, "timestamp": -1 }),
+            (ACCESS_TOKENS, "token_hash", doc! { "token_hash": 1 }),
+            (ACCESS_TOKENS, "user_id_created", doc! { "user_id": 1, "created": -1 }),
+            (SESSIONS, "user_id_last_seen", doc! { "user_id": 1, "last_seen": -1 }),
+            (WEBHOOK_DELIVERIES, "webhook_id_created", doc! { "webhook_id": 1, "created": -1 }),
+            (WEBHOOK_DELIVERIES, "status_next_attempt", doc! { "status": 1, "next_attempt": 1 }),
+            (MAIL_OUTBOX, "sent_failed_created", doc! { "sent": 1, "failed": 1, "created": 1 }),
+        ];
+        for (collection, name, keys) in indexes {
+            if !report.dry_run {
+                let mut opt = IndexOptions::new();
+                opt.name = Some(name.to_string());
+                if self.collection(collection).create_index(keys, Some(opt)).is_err() {
+                    return Err(());
+                }
+            }
+            report.indexes.push(format!("{}.{}", collection, name));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+use std::cmp;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::data::store::{Database, Store, StorePool};
+
+const FIRST_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Keeps trying to open the database, doubling the wait between attempts,
+/// until it works or `deadline` has passed
+pub fn connect_with_retry<T, F>(deadline: Duration, mut connect: F) -> Result<T, String>
+where
+    F: FnMut() -> Result<T, String>,
+{
+    let started = Instant::now();
+    let mut backoff = FIRST_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match connect() {
+            Ok(connected) => {
+                if attempt > 1 {
+                    log::info!("database reached after {} attempts", attempt);
+                }
+                return Ok(connected);
+            },
+            Err(e) => {
+                let elapsed = started.elapsed();
+                if elapsed >= deadline {
+                    return Err(format!("gave up after {} attempts: {}", attempt, e));
+                }
+                let wait = cmp::min(backoff, deadline - elapsed);
+                log::warn!("database unreachable (attempt {}): {}, retrying in {} ms", attempt, e, wait.as_millis());
+                thread::sleep(wait);
+                backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+                attempt += 1;
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BreakerState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    /// The cooldown is over and a single checkout is trying the database again
+    HalfOpen,
+}
+
+/// Stops hammering a database that is down: after `threshold` failed checkouts in a row,
+/// checkouts fail straight away for `cooldown`, then one is let through to probe it
+pub struct CircuitBreaker {
+    state: Mutex<BreakerState>,
+    threshold: u32,
+    cooldown: Duration,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            state: Mutex::new(BreakerState::Closed { failures: 0 }),
+            threshold,
+            cooldown,
+        }
+    }
+
+    /// Whether a checkout may go ahead
+    fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            BreakerState::Closed { .. } => true,
+            BreakerState::Open { until } if Instant::now() >= until => {
+                *state = BreakerState::HalfOpen;
+                log::info!("database circuit half-open, probing");
+                true
+            },
+            BreakerState::Open { .. } | BreakerState::HalfOpen => false,
+        }
+    }
+
+    fn succeeded(&self) {
+        let mut state = self.state.lock().unwrap();
+        if *state == BreakerState::HalfOpen {
+            log::info!("database circuit closed, the database is back");
+        }
+        *state = BreakerState::Closed { failures: 0 };
+    }
+
+    fn failed(&self) {
+        let mut state = self.state.lock().unwrap();
+        *state = match *state {
+            BreakerState::Closed { failures } if failures + 1 < self.threshold => BreakerState::Closed { failures: failures + 1 },
+            BreakerState::Open { until } => BreakerState::Open { until },
+            _ => {
+                log::warn!("database circuit opened, failing fast for {} s", self.cooldown.as_secs());
+                BreakerState::Open { until: Instant::now() + self.cooldown }
+            },
+        };
+    }
+
+    /// How long until the database is worth trying again, if the circuit isn't closed
+    pub fn retry_after(&self) -> Option<Duration> {
+        match *self.state.lock().unwrap() {
+            BreakerState::Closed { .. } => None,
+            BreakerState::Open { until } => Some(until.saturating_duration_since(Instant::now())),
+            BreakerState::HalfOpen => Some(Duration::from_secs(1)),
+        }
+    }
+}
+
+/// A pool whose checkouts go through a circuit breaker
+pub struct GuardedPool {
+    pool: Database,
+    breaker: CircuitBreaker,
+}
+
+impl GuardedPool {
+    pub fn new(pool: Database, breaker: CircuitBreaker) -> Self {
+        GuardedPool { pool, breaker }
+    }
+}
+
+impl StorePool for GuardedPool {
+    fn get(&self) -> Result<Box<dyn Store>, String> {
+        if !self.breaker.allow() {
+            return Err("database circuit open".to_string());
+        }
+        match self.pool.get() {
+            Ok(store) => {
+                self.breaker.succeeded();
+                Ok(store)
+            },
+            Err(e) => {
+                self.breaker.failed();
+                Err(e)
+            },
+        }
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        self.breaker.retry_after()
+    }
+}
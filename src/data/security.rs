@@ -1,27 +1,45 @@
 use chrono::prelude::*;
 use serde::{Deserialize, Serialize};
 use rocket::Outcome;
-use rocket::http::Status;
-use rocket::request::{self, Request, FromRequest};
+use rocket::http::{Cookie, Cookies, Method, Status};
+use rocket::request::{self, Request, FromRequest, State};
 use anyhow::Result as AnyResult;
 use jsonwebtoken::{DecodingKey, EncodingKey, Validation};
 use jsonwebtoken::errors::ErrorKind;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 
-const SECRET: &str = "secret297152aebda7"; // Change this to whatever your own
+use crate::config::AppConfig;
+use crate::data::access_token::{self, AccessToken};
+use crate::data::session;
+use crate::data::db::User;
+use crate::data::store::Conn;
+use crate::data::users;
+
+pub const ADMIN_ROLE: &str = "admin";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 struct Claims {
     #[serde(with = "jwt_numeric_date")]
     exp: DateTime<Utc>,
+    #[serde(with = "jwt_numeric_date")]
+    iat: DateTime<Utc>,
     id: String,
+    /// The session the token belongs to
+    sid: String,
 }
 impl Claims {
-    pub fn new(exp: DateTime<Utc>, id: String) -> Self {
+    pub fn new(exp: DateTime<Utc>, id: String, sid: String) -> Self {
         // Normalize to UNIX timestamps
         let exp = exp.date().and_hms_milli(exp.hour(), exp.minute(), exp.second(), 0);
+        let iat = Utc::now();
+        let iat = iat.date().and_hms_milli(iat.hour(), iat.minute(), iat.second(), 0);
         Self {
             exp,
+            iat,
             id,
+            sid,
         }
     }
 }
@@ -50,32 +68,124 @@ mod jwt_numeric_date {
     }
 }
 
-pub fn sign_token(user_id: String) -> AnyResult<String> {
-    // Expires in 1 day: set in .env or adapt
-    let exp = Utc::now() + chrono::Duration::days(1);
-    let claims = Claims::new(exp, user_id);
+/// The token of a session, expiring with it. Signed with the private key when there is one,
+/// its `kid` telling verifiers which public key to use.
+pub fn sign_token(config: &AppConfig, user_id: String, session: &session::Session) -> AnyResult<String> {
+    let claims = Claims::new(session.expires, user_id, session.id.to_string());
 
-    let token = jsonwebtoken::encode(
-        &jsonwebtoken::Header::default(),
-        &claims,
-        &EncodingKey::from_secret(SECRET.as_ref()),
-    )?;
+    let token = match &config.jwt_keys.signing {
+        Some(signing) => {
+            let mut header = jsonwebtoken::Header::new(signing.algorithm);
+            header.kid = Some(signing.kid.clone());
+            jsonwebtoken::encode(&header, &claims, &signing.key)?
+        },
+        None => jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &claims,
+            &EncodingKey::from_secret(config.jwt_secret.as_ref()),
+        )?,
+    };
     Ok(token)
 }
 
+/// Derived from the session, so it needs no storage and dies with it: the hex HMAC-SHA256 of the session id,
+/// keyed with the secret. Checked with `same_secret`.
+pub fn csrf_token(config: &AppConfig, session_id: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(config.jwt_secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.input(b"csrf:");
+    mac.input(session_id.as_bytes());
+    format!("{:x}", mac.result().code())
+}
+
+fn auth_cookie(config: &AppConfig, name: &'static str, value: String, http_only: bool, session: &session::Session) -> Cookie<'static> {
+    Cookie::build(name, value)
+        .path("/")
+        .secure(config.cookie.secure)
+        .http_only(http_only)
+        .same_site(config.cookie.same_site)
+        .max_age(session.expires - Utc::now())
+        .finish()
+}
+
+/// Sets the token cookie and the CSRF one, readable by scripts, both lasting as long as the session.
+/// Returns the CSRF token.
+pub fn add_auth_cookies(cookies: &mut Cookies, config: &AppConfig, token: String, session: &session::Session) -> String {
+    let token_cookie = auth_cookie(config, config.cookie.token_name(), token, config.cookie.http_only, session);
+    if config.cookie.private {
+        cookies.add_private(token_cookie);
+    } else {
+        cookies.add(token_cookie);
+    }
+    let csrf = csrf_token(config, &session.id.to_string());
+    cookies.add(auth_cookie(config, config.cookie.csrf_name(), csrf.clone(), false, session));
+    csrf
+}
+
+fn token_from_cookies(request: &Request, config: &AppConfig) -> Option<String> {
+    let mut cookies = request.cookies();
+    let cookie = if config.cookie.private {
+        cookies.get_private(config.cookie.token_name())
+    } else {
+        cookies.get(config.cookie.token_name()).cloned()
+    };
+    cookie.map(|c| c.value().to_string())
+}
+
+/// The user of a valid session token, without checking it wasn't revoked since
+pub fn session_user(request: &Request, config: &AppConfig) -> Option<String> {
+    token_from_cookies(request, config).and_then(|token| inspect_token(config, token).ok()).map(|info| info.id)
+}
+
+fn is_safe(method: Method) -> bool {
+    match method {
+        Method::Get | Method::Head | Method::Options => true,
+        _ => false,
+    }
+}
+
+/// Compares in constant time, not to leak how much of a guess was right
+fn same_secret(given: &str, expected: &str) -> bool {
+    given.len() == expected.len() && given.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
 #[derive(Debug, PartialEq)]
 pub enum JwtDecodeError {
     Expired, // The token has expired. We could match to redirect to a login
     Generic, // Other unforseen errors
 }
 
-pub fn decode_token(token: String) ->Result<String, JwtDecodeError> {
-    match jsonwebtoken::decode::<Claims>(
-        &token,
-        &DecodingKey::from_secret(SECRET.as_ref()),
-        &Validation::default(),
-    ){
-        Ok(token_data) => Ok(token_data.claims.id),
+/// What a valid token tells about its bearer
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenInfo {
+    pub id: String,
+    pub session_id: String,
+    pub issued: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+}
+
+/// Verifies with the key named by the token's `kid`, and only for the algorithm of that key.
+/// Tokens without one were signed with the secret, and are only good until a signing key is set up.
+pub fn inspect_token(config: &AppConfig, token: String) -> Result<TokenInfo, JwtDecodeError> {
+    let header = jsonwebtoken::decode_header(&token).map_err(|_| JwtDecodeError::Generic)?;
+    let decoded = match header.kid {
+        Some(kid) => match config.jwt_keys.find(&kid) {
+            Some(key) => jsonwebtoken::decode::<Claims>(&token, &key.key, &Validation::new(key.algorithm)),
+            None => return Err(JwtDecodeError::Generic),
+        },
+        None if config.jwt_keys.signing.is_none() => jsonwebtoken::decode::<Claims>(
+            &token,
+            &DecodingKey::from_secret(config.jwt_secret.as_ref()),
+            &Validation::default(),
+        ),
+        None => return Err(JwtDecodeError::Generic),
+    };
+    match decoded {
+        Ok(token_data) => Ok(TokenInfo {
+            id: token_data.claims.id,
+            session_id: token_data.claims.sid,
+            issued: token_data.claims.iat,
+            expires: token_data.claims.exp,
+        }),
         Err(err) => match *err.kind() {
             ErrorKind::ExpiredSignature => Err(JwtDecodeError::Expired),
             _ => Err(JwtDecodeError::Generic),
@@ -83,25 +193,156 @@ pub fn decode_token(token: String) ->Result<String, JwtDecodeError> {
     }
 }
 
-pub struct JwtGuard(String);
+pub fn decode_token(config: &AppConfig, token: String) ->Result<String, JwtDecodeError> {
+    inspect_token(config, token).map(|info| info.id)
+}
+
+/// An authenticated user, through the session cookie or an `Authorization: Bearer` personal access token
+pub struct JwtGuard {
+    user_id: String,
+    session_id: Option<String>,
+    access_token: Option<AccessToken>,
+    /// When the session token was issued, `None` for an access token
+    issued: Option<DateTime<Utc>>,
+    expires: Option<DateTime<Utc>>,
+}
+
+impl JwtGuard {
+    pub fn user_id(&self) -> &str {
+        &self.user_id
+    }
+    /// `None` when authenticated with an access token
+    pub fn session_id(&self) -> Option<&str> {
+        self.session_id.as_deref()
+    }
+    /// The token the request came with, `None` for a session
+    pub fn access_token(&self) -> Option<&AccessToken> {
+        self.access_token.as_ref()
+    }
+    /// When the credentials stop working, `None` for an access token that never expires
+    pub fn expires(&self) -> Option<DateTime<Utc>> {
+        self.expires
+    }
+    /// Checks again what the guard checked, for responses outliving the request: the token is
+    /// still active, or the session still stands, and the user still exists
+    pub fn still_valid(&self, connection: &Conn) -> Result<bool, ()> {
+        if self.expires.map(|expires| expires <= Utc::now()).unwrap_or(false) {
+            return Ok(false);
+        }
+        if let Some(token) = &self.access_token {
+            return match access_token::find_by_hash(connection, &token.token_hash)? {
+                Some(found) if found.is_active() => Ok(users::find_by_id(connection, &found.user_id)?.is_some()),
+                _ => Ok(false),
+            };
+        }
+        match (&self.session_id, self.issued, self.expires) {
+            (Some(session_id), Some(issued), Some(expires)) => {
+                let info = TokenInfo { id: self.user_id.clone(), session_id: session_id.clone(), issued, expires };
+                Ok(live_session(connection, &info)?.is_some())
+            },
+            _ => Ok(false),
+        }
+    }
+}
 
 #[derive(Debug, PartialEq)]
 pub enum JwtGuardError {
     Missing,
     TokenError(JwtDecodeError),
+    Revoked, // The user or the session is gone, or the user logged out everywhere after the token was issued
+    InvalidAccessToken, // Unknown, expired or revoked
+    InsufficientScope,
+    Csrf, // A cookie-authenticated change without the matching X-CSRF-Token header
+    Unavailable,
+}
+
+/// The session of a valid token, if the token still stands: its user exists, hasn't revoked its sessions since,
+/// and the session itself is still active
+pub fn live_session(connection: &Conn, info: &TokenInfo) -> Result<Option<session::Session>, ()> {
+    match users::find_by_id(connection, &info.id)? {
+        Some(user) => match user.sessions_revoked_at {
+            // Tokens only carry whole seconds, so the second of the revocation counts as revoked too
+            Some(revoked) if info.issued.timestamp() <= revoked.timestamp() => return Ok(None),
+            _ => (),
+        },
+        None => return Ok(None),
+    }
+    Ok(session::find_by_id(connection, &info.session_id)?.filter(|found| found.user_id == info.id && found.is_active()))
+}
+
+fn check_not_revoked(request: &Request, info: &TokenInfo) -> Result<(), (Status, JwtGuardError)> {
+    let connection = match request.guard::<Conn>() {
+        Outcome::Success(connection) => connection,
+        _ => return Err((Status::ServiceUnavailable, JwtGuardError::Unavailable)),
+    };
+    match live_session(&connection, info) {
+        Ok(Some(mut found)) => {
+            session::touch(&connection, &mut found, request.client_ip().map(|ip| ip.to_string()));
+            Ok(())
+        },
+        Ok(None) => Err((Status::Unauthorized, JwtGuardError::Revoked)),
+        Err(_) => Err((Status::ServiceUnavailable, JwtGuardError::Unavailable)),
+    }
+}
+
+pub fn bearer_token<'a>(request: &'a Request) -> Option<&'a str> {
+    request.headers().get_one("Authorization")
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .map(|token| token.trim())
+}
+
+/// Checks the token holds `scope`: `read` for read-only requests, `write` for anything else, unless a route asks otherwise
+fn check_access_token(request: &Request, secret: &str, scope: &str) -> request::Outcome<JwtGuard, JwtGuardError> {
+    let connection = match request.guard::<Conn>() {
+        Outcome::Success(connection) => connection,
+        _ => return Outcome::Failure((Status::ServiceUnavailable, JwtGuardError::Unavailable)),
+    };
+    let mut token = match access_token::find_by_secret(&connection, secret) {
+        Ok(Some(token)) if token.is_active() => token,
+        Ok(_) => return Outcome::Failure((Status::Unauthorized, JwtGuardError::InvalidAccessToken)),
+        Err(_) => return Outcome::Failure((Status::ServiceUnavailable, JwtGuardError::Unavailable)),
+    };
+    if !token.allows(scope) {
+        return Outcome::Failure((Status::Forbidden, JwtGuardError::InsufficientScope));
+    }
+    match users::find_by_id(&connection, &token.user_id) {
+        Ok(Some(_)) => {
+            access_token::record_use(&connection, &mut token, request.client_ip().map(|ip| ip.to_string()));
+            Outcome::Success(JwtGuard { user_id: token.user_id.clone(), session_id: None, issued: None, expires: token.expires, access_token: Some(token) })
+        },
+        Ok(None) => Outcome::Failure((Status::Unauthorized, JwtGuardError::Revoked)),
+        Err(_) => Outcome::Failure((Status::ServiceUnavailable, JwtGuardError::Unavailable)),
+    }
 }
 
 impl<'a, 'r> FromRequest<'a, 'r> for JwtGuard {
     type Error = JwtGuardError;
 
     fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
-        let cookies = request.cookies();
-        let tokens = cookies.get("t");
-        match tokens {
+        let config = match request.guard::<State<AppConfig>>() {
+            Outcome::Success(config) => config,
+            _ => return Outcome::Failure((Status::ServiceUnavailable, JwtGuardError::Unavailable)),
+        };
+        if let Some(secret) = bearer_token(request) {
+            return check_access_token(request, secret, if is_safe(request.method()) { "read" } else { "write" });
+        }
+        match token_from_cookies(request, &config) {
             None => Outcome::Failure((Status::BadRequest, JwtGuardError::Missing)),
             Some(t) => {
-                match decode_token(t.value().to_string()){
-                    Ok(id) => Outcome::Success(JwtGuard(id)),
+                match inspect_token(&config, t){
+                    Ok(info) => match check_not_revoked(request, &info) {
+                        Ok(_) => {
+                            // Browsers send cookies along with forged requests, but can't read the CSRF token to send it too
+                            if config.csrf_protection && !is_safe(request.method()) {
+                                let given = request.headers().get_one(CSRF_HEADER).unwrap_or("");
+                                if !same_secret(given, &csrf_token(&config, &info.session_id)) {
+                                    return Outcome::Failure((Status::Forbidden, JwtGuardError::Csrf));
+                                }
+                            }
+                            Outcome::Success(JwtGuard { user_id: info.id, session_id: Some(info.session_id), access_token: None, issued: Some(info.issued), expires: Some(info.expires) })
+                        },
+                        Err(failure) => Outcome::Failure(failure),
+                    },
                     Err(JwtDecodeError::Expired) => Outcome::Failure((Status::BadRequest, JwtGuardError::TokenError(JwtDecodeError::Expired))),
                     Err(JwtDecodeError::Generic) => Outcome::Failure((Status::BadRequest, JwtGuardError::TokenError(JwtDecodeError::Generic))),
                 }
@@ -109,3 +350,63 @@ impl<'a, 'r> FromRequest<'a, 'r> for JwtGuard {
         }
     }
 }
+
+/// Lets through only authenticated users holding the `admin` role
+pub struct AdminGuard(pub User);
+
+#[derive(Debug, PartialEq)]
+pub enum AdminGuardError {
+    Token(JwtGuardError),
+    Forbidden,
+    Unavailable,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for AdminGuard {
+    type Error = AdminGuardError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let guard = match request.guard::<JwtGuard>() {
+            Outcome::Success(guard) => guard,
+            Outcome::Failure((status, err)) => return Outcome::Failure((status, AdminGuardError::Token(err))),
+            Outcome::Forward(f) => return Outcome::Forward(f),
+        };
+        if guard.access_token().map(|token| !token.allows(ADMIN_ROLE)).unwrap_or(false) {
+            return Outcome::Failure((Status::Forbidden, AdminGuardError::Forbidden));
+        }
+        admin_user(request, &guard).map(AdminGuard)
+    }
+}
+
+/// The user the guard lets through, if it holds the `admin` role
+fn admin_user(request: &Request, guard: &JwtGuard) -> request::Outcome<User, AdminGuardError> {
+    let connection = match request.guard::<Conn>() {
+        Outcome::Success(connection) => connection,
+        _ => return Outcome::Failure((Status::ServiceUnavailable, AdminGuardError::Unavailable)),
+    };
+    match users::find_by_id(&connection, guard.user_id()) {
+        Ok(Some(user)) if user.has_role(ADMIN_ROLE) => Outcome::Success(user),
+        Ok(_) => Outcome::Failure((Status::Forbidden, AdminGuardError::Forbidden)),
+        Err(_) => Outcome::Failure((Status::ServiceUnavailable, AdminGuardError::Unavailable)),
+    }
+}
+
+/// As `AdminGuard`, but asking about a token changes nothing even though it's a POST:
+/// access tokens need the `admin` scope, not `write` along with it
+pub struct IntrospectionGuard(pub User);
+
+impl<'a, 'r> FromRequest<'a, 'r> for IntrospectionGuard {
+    type Error = AdminGuardError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let secret = match bearer_token(request) {
+            Some(secret) => secret,
+            None => return request.guard::<AdminGuard>().map(|admin| IntrospectionGuard(admin.0)),
+        };
+        let guard = match check_access_token(request, secret, ADMIN_ROLE) {
+            Outcome::Success(guard) => guard,
+            Outcome::Failure((status, err)) => return Outcome::Failure((status, AdminGuardError::Token(err))),
+            Outcome::Forward(f) => return Outcome::Forward(f),
+        };
+        admin_user(request, &guard).map(IntrospectionGuard)
+    }
+}
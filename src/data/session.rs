@@ -0,0 +1,98 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::data::audit::ClientInfo;
+use crate::data::store::Conn;
+
+/// How stale `last_seen` may get before a request refreshes it, sparing a write per request
+const LAST_SEEN_PRECISION_SECS: i64 = 60;
+
+/// A login, from some device. Its id travels in the token, which dies with it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Session {
+    #[serde(rename = "_id")]
+    pub id: Uuid,
+    pub user_id: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    /// When its token expires
+    pub expires: DateTime<Utc>,
+    pub revoked: Option<DateTime<Utc>>,
+}
+impl Session {
+    pub fn new(user_id: String, lifetime: Duration, client: &ClientInfo) -> Self {
+        let now = Utc::now();
+        Session {
+            id: Uuid::new_v4(),
+            user_id,
+            user_agent: client.user_agent.clone(),
+            ip: client.ip.clone(),
+            created: now,
+            last_seen: now,
+            expires: now + lifetime,
+            revoked: None,
+        }
+    }
+    pub fn is_active(&self) -> bool {
+        self.revoked.is_none() && Utc::now() < self.expires
+    }
+}
+
+/// A session as its owner sees it, `current` being the one making the request
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResponseSession {
+    pub id: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+    pub current: bool,
+}
+impl ResponseSession {
+    pub fn from_session(session: &Session, current: Option<&str>) -> Self {
+        let id = session.id.to_string();
+        ResponseSession {
+            current: current == Some(id.as_str()),
+            id,
+            user_agent: session.user_agent.clone(),
+            ip: session.ip.clone(),
+            created: session.created,
+            last_seen: session.last_seen,
+            expires: session.expires,
+        }
+    }
+}
+
+pub fn insert(connection: &Conn, session: &Session) -> Result<(), ()> {
+    connection.insert_session(session)
+}
+
+pub fn save(connection: &Conn, session: &Session) -> Result<(), ()> {
+    connection.save_session(session)
+}
+
+pub fn find_by_id(connection: &Conn, id: &str) -> Result<Option<Session>, ()> {
+    connection.find_session(id)
+}
+
+/// The sessions still good, most recently seen first
+pub fn active_for(connection: &Conn, user_id: &str) -> Result<Vec<Session>, ()> {
+    Ok(connection.sessions_of(user_id)?.into_iter().filter(Session::is_active).collect())
+}
+
+/// Refreshes `last_seen` and the address, at most once a minute. Failing to do so doesn't fail the request.
+pub fn touch(connection: &Conn, session: &mut Session, ip: Option<String>) {
+    let now = Utc::now();
+    if now - session.last_seen < Duration::seconds(LAST_SEEN_PRECISION_SECS) && session.ip == ip {
+        return;
+    }
+    session.last_seen = now;
+    session.ip = ip;
+    if connection.touch_session(&session.id.to_string(), now, session.ip.as_deref()).is_err() {
+        log::warn!("could not record activity of session {}", session.id);
+    }
+}
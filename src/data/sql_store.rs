@@ -0,0 +1,1178 @@
+use std::collections::HashMap;
+use chrono::{DateTime, SecondsFormat, Utc};
+use uuid::Uuid;
+
+use r2d2_mongodb::mongodb as bson;
+
+use bson::UtcDateTime;
+
+use crate::data::access_token::AccessToken;
+use crate::data::audit::{AuditAction, AuditEvent, AuditFilter};
+use crate::data::db::User;
+use crate::data::email_change::EmailChange;
+use crate::data::idempotency::IdempotencyRecord;
+use crate::data::invite::Invite;
+use crate::data::mailer::Mail;
+use crate::data::migrations::MigrationReport;
+use crate::data::session::Session;
+use crate::data::store::{Store, StoreError};
+use crate::data::user_events::{UserEvent, UserEventKind, RETAINED_EVENTS};
+use crate::data::user_search::{MatchMode, UserSearch};
+use crate::data::webhooks::{Delivery, DeliveryStatus, Webhook};
+
+/// Schema versions, applied in order and recorded in `schema_migrations`.
+/// Never edit a released one: append a new version instead.
+const SCHEMA: &[(i64, &str)] = &[
+    (1, "
+        CREATE TABLE users (
+            id TEXT PRIMARY KEY,
+            name TEXT NOT NULL,
+            email TEXT NOT NULL,
+            email_canonical TEXT NOT NULL,
+            hashed_password TEXT NOT NULL,
+            salt TEXT NOT NULL,
+            created TEXT NOT NULL,
+            updated TEXT NOT NULL,
+            pending_email TEXT,
+            pending_email_canonical TEXT,
+            email_verified BIGINT NOT NULL DEFAULT 0,
+            sessions_revoked_at TEXT,
+            CONSTRAINT email_canonical_unique UNIQUE (email_canonical)
+        );
+        CREATE INDEX users_name ON users (name);
+        CREATE INDEX users_created ON users (created);
+        CREATE INDEX users_updated ON users (updated);
+        CREATE INDEX users_pending_email_canonical ON users (pending_email_canonical);
+        CREATE TABLE user_roles (
+            user_id TEXT NOT NULL,
+            role TEXT NOT NULL,
+            PRIMARY KEY (user_id, role)
+        );
+        CREATE INDEX user_roles_role ON user_roles (role);
+        CREATE TABLE audit_events (
+            id TEXT PRIMARY KEY,
+            actor TEXT,
+            target TEXT,
+            action TEXT NOT NULL,
+            changes TEXT NOT NULL,
+            ip TEXT,
+            user_agent TEXT,
+            timestamp TEXT NOT NULL
+        );
+        CREATE INDEX audit_events_actor_timestamp ON audit_events (actor, timestamp);
+        CREATE INDEX audit_events_target_timestamp ON audit_events (target, timestamp);
+        CREATE INDEX audit_events_action_timestamp ON audit_events (action, timestamp);
+        CREATE TABLE email_changes (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            old_email TEXT NOT NULL,
+            new_email TEXT NOT NULL,
+            confirm_token TEXT NOT NULL UNIQUE,
+            revert_token TEXT NOT NULL UNIQUE,
+            created TEXT NOT NULL,
+            confirm_expires TEXT NOT NULL,
+            revert_expires TEXT NOT NULL,
+            confirmed TEXT,
+            reverted TEXT
+        );
+        CREATE INDEX email_changes_user_id ON email_changes (user_id);
+        CREATE TABLE mail_outbox (
+            id TEXT PRIMARY KEY,
+            recipient TEXT NOT NULL,
+            subject TEXT NOT NULL,
+            body TEXT NOT NULL,
+            created TEXT NOT NULL,
+            sent BIGINT NOT NULL DEFAULT 0
+        );
+        CREATE INDEX mail_outbox_recipient ON mail_outbox (recipient, created);
+        CREATE TABLE invites (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            token TEXT NOT NULL UNIQUE,
+            created TEXT NOT NULL,
+            expires TEXT NOT NULL,
+            accepted TEXT
+        );
+    "),
+    (2, "
+        CREATE TABLE access_tokens (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            name TEXT NOT NULL,
+            hint TEXT NOT NULL,
+            token_hash TEXT NOT NULL UNIQUE,
+            scopes TEXT NOT NULL,
+            created TEXT NOT NULL,
+            expires TEXT,
+            last_used TEXT,
+            last_used_ip TEXT,
+            revoked TEXT
+        );
+        CREATE INDEX access_tokens_user_id_created ON access_tokens (user_id, created);
+    "),
+    (3, "
+        CREATE TABLE sessions (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            user_agent TEXT,
+            ip TEXT,
+            created TEXT NOT NULL,
+            last_seen TEXT NOT NULL,
+            expires TEXT NOT NULL,
+            revoked TEXT
+        );
+        CREATE INDEX sessions_user_id_last_seen ON sessions (user_id, last_seen);
+    "),
+    (4, "
+        CREATE TABLE user_events (
+            id BIGINT PRIMARY KEY,
+            kind TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            payload TEXT,
+            timestamp TEXT NOT NULL
+        );
+    "),
+    (5, "
+        CREATE TABLE webhooks (
+            id TEXT PRIMARY KEY,
+            url TEXT NOT NULL,
+            events TEXT NOT NULL,
+            secret TEXT NOT NULL,
+            created TEXT NOT NULL,
+            created_by TEXT NOT NULL
+        );
+        CREATE TABLE webhook_deliveries (
+            id TEXT PRIMARY KEY,
+            webhook_id TEXT NOT NULL,
+            event_id BIGINT NOT NULL,
+            kind TEXT NOT NULL,
+            body TEXT NOT NULL,
+            status TEXT NOT NULL,
+            attempts TEXT NOT NULL,
+            next_attempt TEXT,
+            created TEXT NOT NULL,
+            redelivery_of TEXT
+        );
+        CREATE INDEX webhook_deliveries_webhook_id_created ON webhook_deliveries (webhook_id, created);
+        CREATE INDEX webhook_deliveries_status_next_attempt ON webhook_deliveries (status, next_attempt);
+    "),
+    (6, "
+        CREATE TABLE idempotency_records (
+            id TEXT PRIMARY KEY,
+            fingerprint TEXT NOT NULL,
+            status BIGINT NOT NULL,
+            response TEXT,
+            created TEXT NOT NULL,
+            expires TEXT NOT NULL
+        );
+        CREATE INDEX idempotency_records_expires ON idempotency_records (expires);
+    "),
+    (7, "
+        CREATE INDEX mail_outbox_sent_created ON mail_outbox (sent, created);
+    "),
+    (8, "
+        ALTER TABLE mail_outbox ADD COLUMN attempts BIGINT NOT NULL DEFAULT 0;
+        ALTER TABLE mail_outbox ADD COLUMN next_attempt TEXT;
+        ALTER TABLE mail_outbox ADD COLUMN last_error TEXT;
+        ALTER TABLE mail_outbox ADD COLUMN failed BIGINT NOT NULL DEFAULT 0;
+        DROP INDEX mail_outbox_sent_created;
+        CREATE INDEX mail_outbox_sent_failed_created ON mail_outbox (sent, failed, created);
+    "),
+];
+
+/// Values going in and out of the database. NULL is only ever used for text columns.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlValue {
+    Null,
+    Int(i64),
+    Text(String),
+}
+
+pub type SqlRow = Vec<SqlValue>;
+
+#[derive(Debug, PartialEq)]
+pub enum SqlError {
+    UniqueViolation,
+    Other(String),
+}
+
+/// What the store needs from a database driver. Statements use `?` placeholders.
+pub trait SqlConnection {
+    fn execute(&self, sql: &str, params: &[SqlValue]) -> Result<u64, SqlError>;
+    fn query(&self, sql: &str, params: &[SqlValue]) -> Result<Vec<SqlRow>, SqlError>;
+    /// Runs several statements, without parameters
+    fn batch(&self, sql: &str) -> Result<(), SqlError>;
+    fn begin(&self) -> Result<(), SqlError> {
+        self.batch("BEGIN")
+    }
+}
+
+/// Creates the tables, or brings them up to the latest schema version
+pub fn migrate(connection: &dyn SqlConnection) -> Result<Vec<i64>, SqlError> {
+    connection.batch("CREATE TABLE IF NOT EXISTS schema_migrations (version BIGINT PRIMARY KEY)")?;
+    let mut applied = Vec::new();
+    for (version, statements) in SCHEMA {
+        let done = connection.query("SELECT version FROM schema_migrations WHERE version = ?", &[SqlValue::Int(*version)])?;
+        if !done.is_empty() {
+            continue;
+        }
+        connection.begin()?;
+        let migrated = connection.batch(statements)
+            .and_then(|_| connection.execute("INSERT INTO schema_migrations (version) VALUES (?)", &[SqlValue::Int(*version)]));
+        match migrated {
+            Ok(_) => connection.batch("COMMIT")?,
+            Err(e) => {
+                connection.batch("ROLLBACK")?;
+                return Err(e);
+            }
+        }
+        applied.push(*version);
+    }
+    Ok(applied)
+}
+
+pub struct SqlStore(pub Box<dyn SqlConnection>);
+
+/// Fixed precision, so that dates compare chronologically as strings
+fn date(value: &DateTime<Utc>) -> SqlValue {
+    SqlValue::Text(value.to_rfc3339_opts(SecondsFormat::Micros, true))
+}
+
+fn optional_date(value: &Option<DateTime<Utc>>) -> SqlValue {
+    match value {
+        Some(value) => date(value),
+        None => SqlValue::Null,
+    }
+}
+
+fn text(value: &str) -> SqlValue {
+    SqlValue::Text(value.to_string())
+}
+
+fn optional_text(value: &Option<String>) -> SqlValue {
+    match value {
+        Some(value) => text(value),
+        None => SqlValue::Null,
+    }
+}
+
+fn flag(value: bool) -> SqlValue {
+    SqlValue::Int(if value { 1 } else { 0 })
+}
+
+fn like(text: &str, mode: MatchMode) -> SqlValue {
+    let escaped = text.to_lowercase().replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+    match mode {
+        MatchMode::Prefix => SqlValue::Text(format!("{}%", escaped)),
+        MatchMode::Substring => SqlValue::Text(format!("%{}%", escaped)),
+    }
+}
+
+/// Reads the columns of a row in order
+struct Columns {
+    row: SqlRow,
+    next: usize,
+}
+impl Columns {
+    fn new(row: SqlRow) -> Self {
+        Columns { row, next: 0 }
+    }
+    fn value(&mut self) -> SqlValue {
+        let value = self.row.get(self.next).cloned().unwrap_or(SqlValue::Null);
+        self.next += 1;
+        value
+    }
+    fn optional_text(&mut self) -> Result<Option<String>, ()> {
+        match self.value() {
+            SqlValue::Text(value) => Ok(Some(value)),
+            SqlValue::Null => Ok(None),
+            SqlValue::Int(_) => Err(()),
+        }
+    }
+    fn text(&mut self) -> Result<String, ()> {
+        self.optional_text()?.ok_or(())
+    }
+    fn int(&mut self) -> Result<i64, ()> {
+        match self.value() {
+            SqlValue::Int(value) => Ok(value),
+            _ => Err(()),
+        }
+    }
+    fn flag(&mut self) -> Result<bool, ()> {
+        self.int().map(|value| value != 0)
+    }
+    fn uuid(&mut self) -> Result<Uuid, ()> {
+        Uuid::parse_str(&self.text()?).map_err(|_| ())
+    }
+    fn optional_date(&mut self) -> Result<Option<DateTime<Utc>>, ()> {
+        match self.optional_text()? {
+            Some(value) => DateTime::parse_from_rfc3339(&value).map(|d| Some(d.with_timezone(&Utc))).map_err(|_| ()),
+            None => Ok(None),
+        }
+    }
+    fn date(&mut self) -> Result<DateTime<Utc>, ()> {
+        self.optional_date()?.ok_or(())
+    }
+}
+
+const USER_COLUMNS: &str = "id, name, email, email_canonical, hashed_password, salt, created, updated, \
+    pending_email, pending_email_canonical, email_verified, sessions_revoked_at";
+
+fn user_values(user: &User) -> Vec<SqlValue> {
+    vec![
+        text(&user.id.to_string()),
+        text(&user.name),
+        text(&user.email),
+        text(&user.email_canonical),
+        text(&user.hashed_password),
+        text(&user.salt),
+        date(&user.created),
+        date(&user.updated),
+        optional_text(&user.pending_email),
+        optional_text(&user.pending_email_canonical),
+        flag(user.email_verified),
+        optional_date(&user.sessions_revoked_at),
+    ]
+}
+
+/// Roles live in their own table and are filled in afterwards
+fn user_from_row(row: SqlRow) -> Result<User, ()> {
+    let mut columns = Columns::new(row);
+    Ok(User {
+        id: columns.uuid()?,
+        name: columns.text()?,
+        email: columns.text()?,
+        email_canonical: columns.text()?,
+        hashed_password: columns.text()?,
+        salt: columns.text()?,
+        created: columns.date()?,
+        updated: columns.date()?,
+        roles: Vec::new(),
+        pending_email: columns.optional_text()?,
+        pending_email_canonical: columns.optional_text()?,
+        email_verified: columns.flag()?,
+        sessions_revoked_at: columns.optional_date()?,
+    })
+}
+
+const AUDIT_COLUMNS: &str = "id, actor, target, action, changes, ip, user_agent, timestamp";
+
+fn action_name(action: &AuditAction) -> Result<String, ()> {
+    match serde_json::to_value(action) {
+        Ok(serde_json::Value::String(name)) => Ok(name),
+        _ => Err(()),
+    }
+}
+
+fn audit_event_from_row(row: SqlRow) -> Result<AuditEvent, ()> {
+    let mut columns = Columns::new(row);
+    Ok(AuditEvent {
+        id: columns.uuid()?,
+        actor: columns.optional_text()?,
+        target: columns.optional_text()?,
+        action: AuditAction::parse(&columns.text()?).ok_or(())?,
+        changes: serde_json::from_str(&columns.text()?).map_err(|_| ())?,
+        ip: columns.optional_text()?,
+        user_agent: columns.optional_text()?,
+        timestamp: UtcDateTime(columns.date()?),
+    })
+}
+
+const EMAIL_CHANGE_COLUMNS: &str = "id, user_id, old_email, new_email, confirm_token, revert_token, \
+    created, confirm_expires, revert_expires, confirmed, reverted";
+
+fn email_change_values(change: &EmailChange) -> Vec<SqlValue> {
+    vec![
+        text(&change.id.to_string()),
+        text(&change.user_id),
+        text(&change.old_email),
+        text(&change.new_email),
+        text(&change.confirm_token),
+        text(&change.revert_token),
+        date(&change.created),
+        date(&change.confirm_expires),
+        date(&change.revert_expires),
+        optional_date(&change.confirmed),
+        optional_date(&change.reverted),
+    ]
+}
+
+fn email_change_from_row(row: SqlRow) -> Result<EmailChange, ()> {
+    let mut columns = Columns::new(row);
+    Ok(EmailChange {
+        id: columns.uuid()?,
+        user_id: columns.text()?,
+        old_email: columns.text()?,
+        new_email: columns.text()?,
+        confirm_token: columns.text()?,
+        revert_token: columns.text()?,
+        created: columns.date()?,
+        confirm_expires: columns.date()?,
+        revert_expires: columns.date()?,
+        confirmed: columns.optional_date()?,
+        reverted: columns.optional_date()?,
+    })
+}
+
+const MAIL_COLUMNS: &str = "id, recipient, subject, body, created, sent, attempts, next_attempt, last_error, failed";
+
+fn mail_from_row(row: SqlRow) -> Result<Mail, ()> {
+    let mut columns = Columns::new(row);
+    Ok(Mail {
+        id: columns.uuid()?,
+        to: columns.text()?,
+        subject: columns.text()?,
+        body: columns.text()?,
+        created: columns.date()?,
+        sent: columns.flag()?,
+        attempts: columns.int()?,
+        next_attempt: columns.optional_date()?,
+        last_error: columns.optional_text()?,
+        failed: columns.flag()?,
+    })
+}
+
+fn invite_from_row(row: SqlRow) -> Result<Invite, ()> {
+    let mut columns = Columns::new(row);
+    Ok(Invite {
+        id: columns.uuid()?,
+        user_id: columns.text()?,
+        token: columns.text()?,
+        created: columns.date()?,
+        expires: columns.date()?,
+        accepted: columns.optional_date()?,
+    })
+}
+
+const ACCESS_TOKEN_COLUMNS: &str = "id, user_id, name, hint, token_hash, scopes, created, expires, \
+    last_used, last_used_ip, revoked";
+
+/// Scopes are single words, stored space separated
+fn access_token_values(token: &AccessToken) -> Vec<SqlValue> {
+    vec![
+        text(&token.id.to_string()),
+        text(&token.user_id),
+        text(&token.name),
+        text(&token.hint),
+        text(&token.token_hash),
+        text(&token.scopes.join(" ")),
+        date(&token.created),
+        optional_date(&token.expires),
+        optional_date(&token.last_used),
+        optional_text(&token.last_used_ip),
+        optional_date(&token.revoked),
+    ]
+}
+
+fn access_token_from_row(row: SqlRow) -> Result<AccessToken, ()> {
+    let mut columns = Columns::new(row);
+    Ok(AccessToken {
+        id: columns.uuid()?,
+        user_id: columns.text()?,
+        name: columns.text()?,
+        hint: columns.text()?,
+        token_hash: columns.text()?,
+        scopes: columns.text()?.split_whitespace().map(|scope| scope.to_string()).collect(),
+        created: columns.date()?,
+        expires: columns.optional_date()?,
+        last_used: columns.optional_date()?,
+        last_used_ip: columns.optional_text()?,
+        revoked: columns.optional_date()?,
+    })
+}
+
+/// Tries at taking the next event id before giving up to other writers
+const MAX_ID_ATTEMPTS: usize = 5;
+
+/// The user goes in `payload` as JSON
+fn user_event_from_row(row: SqlRow) -> Result<UserEvent, ()> {
+    let mut columns = Columns::new(row);
+    Ok(UserEvent {
+        id: columns.int()?,
+        kind: UserEventKind::parse(&columns.text()?).ok_or(())?,
+        user_id: columns.text()?,
+        user: match columns.optional_text()? {
+            Some(payload) => Some(serde_json::from_str(&payload).map_err(|_| ())?),
+            None => None,
+        },
+        timestamp: columns.date()?,
+    })
+}
+
+const WEBHOOK_COLUMNS: &str = "id, url, events, secret, created, created_by";
+
+/// Event names are single words, stored space separated
+fn webhook_values(webhook: &Webhook) -> Vec<SqlValue> {
+    vec![
+        text(&webhook.id.to_string()),
+        text(&webhook.url),
+        text(&webhook.events.iter().map(|kind| kind.name()).collect::<Vec<&str>>().join(" ")),
+        text(&webhook.secret),
+        date(&webhook.created),
+        text(&webhook.created_by),
+    ]
+}
+
+fn webhook_from_row(row: SqlRow) -> Result<Webhook, ()> {
+    let mut columns = Columns::new(row);
+    Ok(Webhook {
+        id: columns.uuid()?,
+        url: columns.text()?,
+        events: columns.text()?.split_whitespace().map(|name| UserEventKind::parse(name).ok_or(())).collect::<Result<Vec<UserEventKind>, ()>>()?,
+        secret: columns.text()?,
+        created: columns.date()?,
+        created_by: columns.text()?,
+    })
+}
+
+const DELIVERY_COLUMNS: &str = "id, webhook_id, event_id, kind, body, status, attempts, next_attempt, created, redelivery_of";
+
+/// Attempts go in as JSON
+fn delivery_values(delivery: &Delivery) -> Result<Vec<SqlValue>, ()> {
+    Ok(vec![
+        text(&delivery.id.to_string()),
+        text(&delivery.webhook_id),
+        SqlValue::Int(delivery.event_id),
+        text(delivery.kind.name()),
+        text(&delivery.body),
+        text(delivery.status.name()),
+        SqlValue::Text(serde_json::to_string(&delivery.attempts).map_err(|_| ())?),
+        optional_date(&delivery.next_attempt),
+        date(&delivery.created),
+        optional_text(&delivery.redelivery_of),
+    ])
+}
+
+fn delivery_from_row(row: SqlRow) -> Result<Delivery, ()> {
+    let mut columns = Columns::new(row);
+    Ok(Delivery {
+        id: columns.uuid()?,
+        webhook_id: columns.text()?,
+        event_id: columns.int()?,
+        kind: UserEventKind::parse(&columns.text()?).ok_or(())?,
+        body: columns.text()?,
+        status: DeliveryStatus::parse(&columns.text()?).ok_or(())?,
+        attempts: serde_json::from_str(&columns.text()?).map_err(|_| ())?,
+        next_attempt: columns.optional_date()?,
+        created: columns.date()?,
+        redelivery_of: columns.optional_text()?,
+    })
+}
+
+const IDEMPOTENCY_COLUMNS: &str = "id, fingerprint, status, response, created, expires";
+
+/// A status of 0 stands for a request still in progress, NULL being kept for text
+fn idempotency_values(record: &IdempotencyRecord) -> Vec<SqlValue> {
+    vec![
+        text(&record.id),
+        text(&record.fingerprint),
+        SqlValue::Int(record.status.map(i64::from).unwrap_or(0)),
+        optional_text(&record.response),
+        date(&record.created),
+        date(&record.expires.0),
+    ]
+}
+
+fn idempotency_record_from_row(row: SqlRow) -> Result<IdempotencyRecord, ()> {
+    let mut columns = Columns::new(row);
+    Ok(IdempotencyRecord {
+        id: columns.text()?,
+        fingerprint: columns.text()?,
+        status: match columns.int()? {
+            0 => None,
+            status => Some(status as u16),
+        },
+        response: columns.optional_text()?,
+        created: columns.date()?,
+        expires: UtcDateTime(columns.date()?),
+    })
+}
+
+const SESSION_COLUMNS: &str = "id, user_id, user_agent, ip, created, last_seen, expires, revoked";
+
+fn session_values(session: &Session) -> Vec<SqlValue> {
+    vec![
+        text(&session.id.to_string()),
+        text(&session.user_id),
+        optional_text(&session.user_agent),
+        optional_text(&session.ip),
+        date(&session.created),
+        date(&session.last_seen),
+        date(&session.expires),
+        optional_date(&session.revoked),
+    ]
+}
+
+fn session_from_row(row: SqlRow) -> Result<Session, ()> {
+    let mut columns = Columns::new(row);
+    Ok(Session {
+        id: columns.uuid()?,
+        user_id: columns.text()?,
+        user_agent: columns.optional_text()?,
+        ip: columns.optional_text()?,
+        created: columns.date()?,
+        last_seen: columns.date()?,
+        expires: columns.date()?,
+        revoked: columns.optional_date()?,
+    })
+}
+
+fn placeholders(count: usize) -> String {
+    vec!["?"; count].join(", ")
+}
+
+fn store_error(e: SqlError) -> StoreError {
+    match e {
+        SqlError::UniqueViolation => StoreError::Duplicate,
+        SqlError::Other(_) => StoreError::Failed,
+    }
+}
+
+impl SqlStore {
+    fn execute(&self, sql: &str, params: &[SqlValue]) -> Result<u64, ()> {
+        self.0.execute(sql, params).map_err(|_| ())
+    }
+
+    fn query(&self, sql: &str, params: &[SqlValue]) -> Result<Vec<SqlRow>, ()> {
+        self.0.query(sql, params).map_err(|_| ())
+    }
+
+    fn in_transaction<T>(&self, work: impl FnOnce() -> Result<T, SqlError>) -> Result<T, SqlError> {
+        self.0.begin()?;
+        match work() {
+            Ok(done) => {
+                self.0.batch("COMMIT")?;
+                Ok(done)
+            },
+            Err(e) => {
+                // The error that made us roll back is the interesting one
+                let _ = self.0.batch("ROLLBACK");
+                Err(e)
+            },
+        }
+    }
+
+    fn insert_roles(&self, user: &User) -> Result<(), SqlError> {
+        for role in &user.roles {
+            self.0.execute("INSERT INTO user_roles (user_id, role) VALUES (?, ?)", &[text(&user.id.to_string()), text(role)])?;
+        }
+        Ok(())
+    }
+
+    /// Loads users, then their roles with a single query
+    fn users(&self, sql: &str, params: &[SqlValue]) -> Result<Vec<User>, ()> {
+        let mut users = self.query(sql, params)?
+            .into_iter()
+            .map(user_from_row)
+            .collect::<Result<Vec<User>, ()>>()?;
+        if users.is_empty() {
+            return Ok(users);
+        }
+        let ids: Vec<SqlValue> = users.iter().map(|user| text(&user.id.to_string())).collect();
+        let sql = format!("SELECT user_id, role FROM user_roles WHERE user_id IN ({}) ORDER BY role", placeholders(ids.len()));
+        let mut roles: HashMap<String, Vec<String>> = HashMap::new();
+        for row in self.query(&sql, &ids)? {
+            let mut columns = Columns::new(row);
+            roles.entry(columns.text()?).or_insert_with(Vec::new).push(columns.text()?);
+        }
+        for user in users.iter_mut() {
+            user.roles = roles.remove(&user.id.to_string()).unwrap_or_default();
+        }
+        Ok(users)
+    }
+
+    fn user(&self, sql: &str, params: &[SqlValue]) -> Result<Option<User>, ()> {
+        Ok(self.users(sql, params)?.into_iter().next())
+    }
+
+    fn email_change(&self, column: &str, token: &str) -> Result<Option<EmailChange>, ()> {
+        let sql = format!("SELECT {} FROM email_changes WHERE {} = ?", EMAIL_CHANGE_COLUMNS, column);
+        match self.query(&sql, &[text(token)])?.into_iter().next() {
+            Some(row) => email_change_from_row(row).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// The WHERE clause of a user search, with its parameters
+fn search_conditions(search: &UserSearch) -> (String, Vec<SqlValue>) {
+    let mut conditions = vec!["1 = 1".to_string()];
+    let mut params = Vec::new();
+    if let Some(text) = &search.text {
+        // Like a text index, any of the words will do
+        let words: Vec<&str> = text.split_whitespace().collect();
+        if !words.is_empty() {
+            let alternatives = vec!["LOWER(name) LIKE ? ESCAPE '\\'"; words.len()].join(" OR ");
+            conditions.push(format!("({})", alternatives));
+            params.extend(words.iter().map(|word| like(word, MatchMode::Substring)));
+        }
+    }
+    if let Some(name) = &search.name {
+        conditions.push("LOWER(name) LIKE ? ESCAPE '\\'".to_string());
+        params.push(like(name, search.mode));
+    }
+    if let Some(email) = &search.email {
+        conditions.push("email_canonical LIKE ? ESCAPE '\\'".to_string());
+        params.push(like(email.trim(), search.mode));
+    }
+    for (column, from, to) in &[("created", search.created_from, search.created_to), ("updated", search.updated_from, search.updated_to)] {
+        if let Some(from) = from {
+            conditions.push(format!("{} >= ?", column));
+            params.push(date(from));
+        }
+        if let Some(to) = to {
+            conditions.push(format!("{} <= ?", column));
+            params.push(date(to));
+        }
+    }
+    if let Some(verified) = search.verified {
+        conditions.push("email_verified = ?".to_string());
+        params.push(flag(verified));
+    }
+    if let Some(role) = &search.role {
+        conditions.push("id IN (SELECT user_id FROM user_roles WHERE role = ?)".to_string());
+        params.push(text(role));
+    }
+    (conditions.join(" AND "), params)
+}
+
+impl Store for SqlStore {
+    fn ping(&self) -> Result<(), String> {
+        match self.0.query("SELECT 1", &[]) {
+            Ok(_) => Ok(()),
+            Err(SqlError::Other(e)) => Err(e),
+            Err(e) => Err(format!("{:?}", e)),
+        }
+    }
+
+    fn find_user(&self, id: &str) -> Result<Option<User>, ()> {
+        self.user(&format!("SELECT {} FROM users WHERE id = ?", USER_COLUMNS), &[text(id)])
+    }
+
+    fn find_user_by_email(&self, canonical: &str) -> Result<Option<User>, ()> {
+        self.user(&format!("SELECT {} FROM users WHERE email_canonical = ?", USER_COLUMNS), &[text(canonical)])
+    }
+
+    fn insert_user(&self, user: &User) -> Result<(), StoreError> {
+        let sql = format!("INSERT INTO users ({}) VALUES ({})", USER_COLUMNS, placeholders(12));
+        self.in_transaction(|| {
+            self.0.execute(&sql, &user_values(user))?;
+            self.insert_roles(user)
+        }).map_err(store_error)
+    }
+
+    fn replace_user(&self, user: &User) -> Result<bool, StoreError> {
+        let sql = "UPDATE users SET name = ?, email = ?, email_canonical = ?, hashed_password = ?, salt = ?, \
+            created = ?, updated = ?, pending_email = ?, pending_email_canonical = ?, email_verified = ? WHERE id = ?";
+        let mut params = user_values(user);
+        let id = params.remove(0);
+        // sessions_revoked_at, last of the columns
+        params.pop();
+        params.push(id.clone());
+        self.in_transaction(|| {
+            if self.0.execute(sql, &params)? == 0 {
+                return Ok(false);
+            }
+            self.0.execute("DELETE FROM user_roles WHERE user_id = ?", &[id.clone()])?;
+            self.insert_roles(user)?;
+            Ok(true)
+        }).map_err(store_error)
+    }
+
+    fn revoke_user_sessions(&self, id: &str, at: DateTime<Utc>) -> Result<bool, ()> {
+        self.execute("UPDATE users SET sessions_revoked_at = ? WHERE id = ?", &[date(&at), text(id)]).map(|changed| changed > 0)
+    }
+
+    fn delete_user(&self, id: &str) -> Result<Option<User>, ()> {
+        let user = match self.find_user(id)? {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+        self.in_transaction(|| {
+            self.0.execute("DELETE FROM user_roles WHERE user_id = ?", &[text(id)])?;
+            self.0.execute("DELETE FROM users WHERE id = ?", &[text(id)])
+        }).map_err(|_| ())?;
+        Ok(Some(user))
+    }
+
+    fn count_users(&self) -> Result<i64, ()> {
+        match self.query("SELECT COUNT(*) FROM users", &[])?.into_iter().next() {
+            Some(row) => Columns::new(row).int(),
+            None => Err(()),
+        }
+    }
+
+    fn email_in_use(&self, canonical: &str, except_id: Option<&str>) -> Result<bool, ()> {
+        let found = match except_id {
+            Some(id) => self.query(
+                "SELECT id FROM users WHERE (email_canonical = ? OR pending_email_canonical = ?) AND id <> ?",
+                &[text(canonical), text(canonical), text(id)],
+            )?,
+            None => self.query(
+                "SELECT id FROM users WHERE email_canonical = ? OR pending_email_canonical = ?",
+                &[text(canonical), text(canonical)],
+            )?,
+        };
+        Ok(!found.is_empty())
+    }
+
+    fn search_users(&self, search: &UserSearch) -> Result<(Vec<User>, i64), ()> {
+        let (conditions, mut params) = search_conditions(search);
+        let total = match self.query(&format!("SELECT COUNT(*) FROM users WHERE {}", conditions), &params)?.into_iter().next() {
+            Some(row) => Columns::new(row).int()?,
+            None => return Err(()),
+        };
+        params.push(SqlValue::Int(search.per_page));
+        params.push(SqlValue::Int((search.page - 1) * search.per_page));
+        let sql = format!("SELECT {} FROM users WHERE {} ORDER BY name, id LIMIT ? OFFSET ?", USER_COLUMNS, conditions);
+        Ok((self.users(&sql, &params)?, total))
+    }
+
+    fn list_users(&self, after: Option<&str>, limit: i64) -> Result<Vec<User>, ()> {
+        match after {
+            Some(id) => self.users(&format!("SELECT {} FROM users WHERE id > ? ORDER BY id LIMIT ?", USER_COLUMNS), &[text(id), SqlValue::Int(limit)]),
+            None => self.users(&format!("SELECT {} FROM users ORDER BY id LIMIT ?", USER_COLUMNS), &[SqlValue::Int(limit)]),
+        }
+    }
+
+    fn insert_audit_event(&self, event: &AuditEvent) -> Result<(), String> {
+        let action = action_name(&event.action).map_err(|_| "unknown action".to_string())?;
+        let changes = serde_json::to_string(&event.changes).map_err(|e| e.to_string())?;
+        let sql = format!("INSERT INTO audit_events ({}) VALUES ({})", AUDIT_COLUMNS, placeholders(8));
+        self.0.execute(&sql, &[
+            text(&event.id.to_string()),
+            optional_text(&event.actor),
+            optional_text(&event.target),
+            SqlValue::Text(action),
+            SqlValue::Text(changes),
+            optional_text(&event.ip),
+            optional_text(&event.user_agent),
+            date(&event.timestamp.0),
+        ]).map(|_| ()).map_err(|e| format!("{:?}", e))
+    }
+
+    fn find_audit_events(&self, filter: &AuditFilter, limit: i64) -> Result<Vec<AuditEvent>, ()> {
+        let mut conditions = vec!["1 = 1".to_string()];
+        let mut params = Vec::new();
+        if let Some(user) = &filter.user {
+            conditions.push("(actor = ? OR target = ?)".to_string());
+            params.push(text(user));
+            params.push(text(user));
+        }
+        if let Some(action) = &filter.action {
+            conditions.push("action = ?".to_string());
+            params.push(SqlValue::Text(action_name(action)?));
+        }
+        if let Some(from) = &filter.from {
+            conditions.push("timestamp >= ?".to_string());
+            params.push(date(from));
+        }
+        if let Some(to) = &filter.to {
+            conditions.push("timestamp <= ?".to_string());
+            params.push(date(to));
+        }
+        params.push(SqlValue::Int(limit));
+        let sql = format!("SELECT {} FROM audit_events WHERE {} ORDER BY timestamp DESC LIMIT ?", AUDIT_COLUMNS, conditions.join(" AND "));
+        self.query(&sql, &params)?.into_iter().map(audit_event_from_row).collect()
+    }
+
+    fn insert_email_change(&self, change: &EmailChange) -> Result<(), ()> {
+        let sql = format!("INSERT INTO email_changes ({}) VALUES ({})", EMAIL_CHANGE_COLUMNS, placeholders(11));
+        self.in_transaction(|| {
+            self.0.execute(
+                "DELETE FROM email_changes WHERE user_id = ? AND confirmed IS NULL AND reverted IS NULL",
+                &[text(&change.user_id)],
+            )?;
+            self.0.execute(&sql, &email_change_values(change))
+        }).map(|_| ()).map_err(|_| ())
+    }
+
+    fn save_email_change(&self, change: &EmailChange) -> Result<(), ()> {
+        let sql = "UPDATE email_changes SET user_id = ?, old_email = ?, new_email = ?, confirm_token = ?, revert_token = ?, \
+            created = ?, confirm_expires = ?, revert_expires = ?, confirmed = ?, reverted = ? WHERE id = ?";
+        let mut params = email_change_values(change);
+        let id = params.remove(0);
+        params.push(id);
+        self.execute(sql, &params).map(|_| ())
+    }
+
+    fn find_email_change_by_confirm_token(&self, token: &str) -> Result<Option<EmailChange>, ()> {
+        self.email_change("confirm_token", token)
+    }
+
+    fn find_email_change_by_revert_token(&self, token: &str) -> Result<Option<EmailChange>, ()> {
+        self.email_change("revert_token", token)
+    }
+
+    fn queue_mail(&self, mail: &Mail) -> Result<(), ()> {
+        let sql = format!("INSERT INTO mail_outbox ({}) VALUES ({})", MAIL_COLUMNS, placeholders(10));
+        let values = [
+            text(&mail.id.to_string()), text(&mail.to), text(&mail.subject), text(&mail.body), date(&mail.created), flag(mail.sent),
+            SqlValue::Int(mail.attempts), optional_date(&mail.next_attempt), optional_text(&mail.last_error), flag(mail.failed),
+        ];
+        self.execute(&sql, &values).map(|_| ())
+    }
+
+    fn mails_to(&self, to: &str) -> Result<Vec<Mail>, ()> {
+        let sql = format!("SELECT {} FROM mail_outbox WHERE recipient = ? ORDER BY created DESC", MAIL_COLUMNS);
+        self.query(&sql, &[text(to)])?.into_iter().map(mail_from_row).collect()
+    }
+
+    fn due_mails(&self, now: DateTime<Utc>, limit: i64) -> Result<Vec<Mail>, ()> {
+        let sql = format!("SELECT {} FROM mail_outbox WHERE sent = ? AND failed = ? AND (next_attempt IS NULL OR next_attempt <= ?) \
+            ORDER BY created LIMIT ?", MAIL_COLUMNS);
+        self.query(&sql, &[flag(false), flag(false), date(&now), SqlValue::Int(limit)])?.into_iter().map(mail_from_row).collect()
+    }
+
+    fn claim_mail(&self, id: &str, due: Option<DateTime<Utc>>, lease: DateTime<Utc>) -> Result<bool, ()> {
+        let mut params = vec![date(&lease), text(id), flag(false), flag(false)];
+        let sql = match &due {
+            Some(due) => {
+                params.push(date(due));
+                "UPDATE mail_outbox SET next_attempt = ? WHERE id = ? AND sent = ? AND failed = ? AND next_attempt = ?"
+            },
+            None => "UPDATE mail_outbox SET next_attempt = ? WHERE id = ? AND sent = ? AND failed = ? AND next_attempt IS NULL",
+        };
+        self.execute(sql, &params).map(|claimed| claimed == 1)
+    }
+
+    fn save_mail_attempt(&self, mail: &Mail) -> Result<(), ()> {
+        let sql = "UPDATE mail_outbox SET sent = ?, attempts = ?, next_attempt = ?, last_error = ?, failed = ? WHERE id = ?";
+        let values = [
+            flag(mail.sent), SqlValue::Int(mail.attempts), optional_date(&mail.next_attempt), optional_text(&mail.last_error),
+            flag(mail.failed), text(&mail.id.to_string()),
+        ];
+        self.execute(sql, &values).map(|_| ())
+    }
+
+    fn insert_invite(&self, invite: &Invite) -> Result<(), ()> {
+        self.execute(
+            "INSERT INTO invites (id, user_id, token, created, expires, accepted) VALUES (?, ?, ?, ?, ?, ?)",
+            &[text(&invite.id.to_string()), text(&invite.user_id), text(&invite.token), date(&invite.created), date(&invite.expires), optional_date(&invite.accepted)],
+        ).map(|_| ())
+    }
+
+    fn find_invite(&self, token: &str) -> Result<Option<Invite>, ()> {
+        match self.query("SELECT id, user_id, token, created, expires, accepted FROM invites WHERE token = ?", &[text(token)])?.into_iter().next() {
+            Some(row) => invite_from_row(row).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn mark_invite_accepted(&self, invite: &Invite) -> Result<(), ()> {
+        self.execute("UPDATE invites SET accepted = ? WHERE id = ?", &[date(&Utc::now()), text(&invite.id.to_string())]).map(|_| ())
+    }
+
+    fn insert_access_token(&self, token: &AccessToken) -> Result<(), ()> {
+        let sql = format!("INSERT INTO access_tokens ({}) VALUES ({})", ACCESS_TOKEN_COLUMNS, placeholders(11));
+        self.execute(&sql, &access_token_values(token)).map(|_| ())
+    }
+
+    fn save_access_token(&self, token: &AccessToken) -> Result<(), ()> {
+        let sql = "UPDATE access_tokens SET user_id = ?, name = ?, hint = ?, token_hash = ?, scopes = ?, created = ?, \
+            expires = ?, last_used = ?, last_used_ip = ?, revoked = ? WHERE id = ?";
+        let mut params = access_token_values(token);
+        let id = params.remove(0);
+        params.push(id);
+        self.execute(sql, &params).map(|_| ())
+    }
+
+    fn record_access_token_use(&self, id: &str, at: DateTime<Utc>, ip: Option<&str>) -> Result<(), ()> {
+        let sql = "UPDATE access_tokens SET last_used = ?, last_used_ip = ? WHERE id = ? AND revoked IS NULL";
+        self.execute(sql, &[date(&at), optional_text(&ip.map(str::to_string)), text(id)]).map(|_| ())
+    }
+
+    fn find_access_token_by_hash(&self, hash: &str) -> Result<Option<AccessToken>, ()> {
+        let sql = format!("SELECT {} FROM access_tokens WHERE token_hash = ?", ACCESS_TOKEN_COLUMNS);
+        match self.query(&sql, &[text(hash)])?.into_iter().next() {
+            Some(row) => access_token_from_row(row).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn access_tokens_of(&self, user_id: &str) -> Result<Vec<AccessToken>, ()> {
+        let sql = format!("SELECT {} FROM access_tokens WHERE user_id = ? ORDER BY created DESC", ACCESS_TOKEN_COLUMNS);
+        self.query(&sql, &[text(user_id)])?.into_iter().map(access_token_from_row).collect()
+    }
+
+    fn insert_session(&self, session: &Session) -> Result<(), ()> {
+        let sql = format!("INSERT INTO sessions ({}) VALUES ({})", SESSION_COLUMNS, placeholders(8));
+        self.execute(&sql, &session_values(session)).map(|_| ())
+    }
+
+    fn save_session(&self, session: &Session) -> Result<(), ()> {
+        let sql = "UPDATE sessions SET user_id = ?, user_agent = ?, ip = ?, created = ?, last_seen = ?, expires = ?, revoked = ? WHERE id = ?";
+        let mut params = session_values(session);
+        let id = params.remove(0);
+        params.push(id);
+        self.execute(sql, &params).map(|_| ())
+    }
+
+    fn touch_session(&self, id: &str, last_seen: DateTime<Utc>, ip: Option<&str>) -> Result<(), ()> {
+        self.execute("UPDATE sessions SET last_seen = ?, ip = ? WHERE id = ?", &[date(&last_seen), optional_text(&ip.map(str::to_string)), text(id)]).map(|_| ())
+    }
+
+    fn find_session(&self, id: &str) -> Result<Option<Session>, ()> {
+        let sql = format!("SELECT {} FROM sessions WHERE id = ?", SESSION_COLUMNS);
+        match self.query(&sql, &[text(id)])?.into_iter().next() {
+            Some(row) => session_from_row(row).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn sessions_of(&self, user_id: &str) -> Result<Vec<Session>, ()> {
+        let sql = format!("SELECT {} FROM sessions WHERE user_id = ? ORDER BY last_seen DESC", SESSION_COLUMNS);
+        self.query(&sql, &[text(user_id)])?.into_iter().map(session_from_row).collect()
+    }
+
+    fn insert_user_event(&self, event: &UserEvent) -> Result<i64, String> {
+        let payload = match &event.user {
+            Some(user) => SqlValue::Text(serde_json::to_string(user).map_err(|e| e.to_string())?),
+            None => SqlValue::Null,
+        };
+        for _ in 0..MAX_ID_ATTEMPTS {
+            let id = match self.0.query("SELECT COALESCE(MAX(id), 0) + 1 FROM user_events", &[]).map_err(|e| format!("{:?}", e))?.into_iter().next() {
+                Some(row) => Columns::new(row).int().map_err(|_| "no id for the event".to_string())?,
+                None => return Err("no id for the event".to_string()),
+            };
+            let inserted = self.0.execute(
+                "INSERT INTO user_events (id, kind, user_id, payload, timestamp) VALUES (?, ?, ?, ?, ?)",
+                &[SqlValue::Int(id), text(event.kind.name()), text(&event.user_id), payload.clone(), date(&event.timestamp)],
+            );
+            match inserted {
+                Ok(_) => {
+                    // Capped like the MongoDB collection
+                    if let Err(e) = self.0.execute("DELETE FROM user_events WHERE id <= ?", &[SqlValue::Int(id - RETAINED_EVENTS)]) {
+                        log::warn!("could not drop old user events: {:?}", e);
+                    }
+                    return Ok(id);
+                },
+                // Another writer took that id first
+                Err(SqlError::UniqueViolation) => continue,
+                Err(e) => return Err(format!("{:?}", e)),
+            }
+        }
+        Err("user event ids kept being taken".to_string())
+    }
+
+    fn user_events_after(&self, after: i64, limit: i64) -> Result<Vec<UserEvent>, ()> {
+        self.query(
+            "SELECT id, kind, user_id, payload, timestamp FROM user_events WHERE id > ? ORDER BY id LIMIT ?",
+            &[SqlValue::Int(after), SqlValue::Int(limit)],
+        )?.into_iter().map(user_event_from_row).collect()
+    }
+
+    fn last_user_event_id(&self) -> Result<i64, ()> {
+        match self.query("SELECT COALESCE(MAX(id), 0) FROM user_events", &[])?.into_iter().next() {
+            Some(row) => Columns::new(row).int(),
+            None => Err(()),
+        }
+    }
+
+    fn insert_webhook(&self, webhook: &Webhook) -> Result<(), ()> {
+        let sql = format!("INSERT INTO webhooks ({}) VALUES ({})", WEBHOOK_COLUMNS, placeholders(6));
+        self.execute(&sql, &webhook_values(webhook)).map(|_| ())
+    }
+
+    fn find_webhook(&self, id: &str) -> Result<Option<Webhook>, ()> {
+        let sql = format!("SELECT {} FROM webhooks WHERE id = ?", WEBHOOK_COLUMNS);
+        match self.query(&sql, &[text(id)])?.into_iter().next() {
+            Some(row) => webhook_from_row(row).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn webhooks(&self) -> Result<Vec<Webhook>, ()> {
+        let sql = format!("SELECT {} FROM webhooks ORDER BY created", WEBHOOK_COLUMNS);
+        self.query(&sql, &[])?.into_iter().map(webhook_from_row).collect()
+    }
+
+    fn delete_webhook(&self, id: &str) -> Result<bool, ()> {
+        self.in_transaction(|| {
+            self.0.execute("DELETE FROM webhook_deliveries WHERE webhook_id = ?", &[text(id)])?;
+            self.0.execute("DELETE FROM webhooks WHERE id = ?", &[text(id)])
+        }).map(|deleted| deleted > 0).map_err(|_| ())
+    }
+
+    fn insert_delivery(&self, delivery: &Delivery) -> Result<(), ()> {
+        let sql = format!("INSERT INTO webhook_deliveries ({}) VALUES ({})", DELIVERY_COLUMNS, placeholders(10));
+        self.execute(&sql, &delivery_values(delivery)?).map(|_| ())
+    }
+
+    fn save_delivery(&self, delivery: &Delivery) -> Result<(), ()> {
+        let sql = "UPDATE webhook_deliveries SET webhook_id = ?, event_id = ?, kind = ?, body = ?, status = ?, attempts = ?, \
+            next_attempt = ?, created = ?, redelivery_of = ? WHERE id = ?";
+        let mut params = delivery_values(delivery)?;
+        let id = params.remove(0);
+        params.push(id);
+        self.execute(sql, &params).map(|_| ())
+    }
+
+    fn find_delivery(&self, id: &str) -> Result<Option<Delivery>, ()> {
+        let sql = format!("SELECT {} FROM webhook_deliveries WHERE id = ?", DELIVERY_COLUMNS);
+        match self.query(&sql, &[text(id)])?.into_iter().next() {
+            Some(row) => delivery_from_row(row).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn deliveries_of(&self, webhook_id: &str, limit: i64) -> Result<Vec<Delivery>, ()> {
+        let sql = format!("SELECT {} FROM webhook_deliveries WHERE webhook_id = ? ORDER BY created DESC LIMIT ?", DELIVERY_COLUMNS);
+        self.query(&sql, &[text(webhook_id), SqlValue::Int(limit)])?.into_iter().map(delivery_from_row).collect()
+    }
+
+    fn due_deliveries(&self, now: DateTime<Utc>, limit: i64) -> Result<Vec<Delivery>, ()> {
+        let sql = format!("SELECT {} FROM webhook_deliveries WHERE status = ? AND next_attempt <= ? ORDER BY next_attempt LIMIT ?", DELIVERY_COLUMNS);
+        self.query(&sql, &[text(DeliveryStatus::Pending.name()), date(&now), SqlValue::Int(limit)])?
+            .into_iter().map(delivery_from_row).collect()
+    }
+
+    fn claim_delivery(&self, id: &str, due: DateTime<Utc>, lease: DateTime<Utc>) -> Result<bool, ()> {
+        let sql = "UPDATE webhook_deliveries SET next_attempt = ? WHERE id = ? AND status = ? AND next_attempt = ?";
+        self.execute(sql, &[date(&lease), text(id), text(DeliveryStatus::Pending.name()), date(&due)]).map(|claimed| claimed == 1)
+    }
+
+    /// Drops the expired records first, there's no TTL index to do it
+    fn insert_idempotency_record(&self, record: &IdempotencyRecord) -> Result<(), StoreError> {
+        self.0.execute("DELETE FROM idempotency_records WHERE expires < ?", &[date(&Utc::now())]).map_err(store_error)?;
+        let sql = format!("INSERT INTO idempotency_records ({}) VALUES ({})", IDEMPOTENCY_COLUMNS, placeholders(6));
+        self.0.execute(&sql, &idempotency_values(record)).map(|_| ()).map_err(store_error)
+    }
+
+    fn save_idempotency_record(&self, record: &IdempotencyRecord) -> Result<(), ()> {
+        let sql = "UPDATE idempotency_records SET fingerprint = ?, status = ?, response = ?, created = ?, expires = ? WHERE id = ?";
+        let mut params = idempotency_values(record);
+        let id = params.remove(0);
+        params.push(id);
+        self.execute(sql, &params).map(|_| ())
+    }
+
+    fn find_idempotency_record(&self, id: &str) -> Result<Option<IdempotencyRecord>, ()> {
+        let sql = format!("SELECT {} FROM idempotency_records WHERE id = ?", IDEMPOTENCY_COLUMNS);
+        match self.query(&sql, &[text(id)])?.into_iter().next() {
+            Some(row) => idempotency_record_from_row(row).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn delete_idempotency_record(&self, id: &str) -> Result<(), ()> {
+        self.execute("DELETE FROM idempotency_records WHERE id = ?", &[text(id)]).map(|_| ())
+    }
+
+    /// The schema is migrated when the pool is created, the email constraint comes with it
+    fn ensure_schema(&self, report: &mut MigrationReport) -> Result<(), ()> {
+        if !report.dry_run {
+            migrate(&*self.0).map_err(|_| ())?;
+        }
+        report.unique_email_index = report.collisions.is_empty();
+        report.indexes = vec![
+            "users.email_canonical_unique", "users.name", "users.created", "users.updated",
+            "users.pending_email_canonical", "user_roles.role", "audit_events.actor_timestamp",
+            "audit_events.target_timestamp", "audit_events.action_timestamp",
+            "access_tokens.token_hash", "access_tokens.user_id_created", "sessions.user_id_last_seen",
+            "webhook_deliveries.webhook_id_created", "webhook_deliveries.status_next_attempt",
+            "idempotency_records.expires",
+        ].into_iter().map(|index| index.to_string()).collect();
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use std::time::Duration;
+use r2d2::PooledConnection;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::ffi;
+use rusqlite::types::Value;
+
+use crate::data::sql_store::{self, SqlConnection, SqlError, SqlRow, SqlStore, SqlValue};
+use crate::data::store::{Store, StorePool};
+
+type Pool = r2d2::Pool<SqliteConnectionManager>;
+
+pub struct SqliteConnection(PooledConnection<SqliteConnectionManager>);
+
+/// Only unique keys count as a `UniqueViolation`, not the other constraints such as `NOT NULL`
+fn sql_error(e: rusqlite::Error) -> SqlError {
+    match e {
+        rusqlite::Error::SqliteFailure(ref failure, _)
+            if failure.extended_code == ffi::SQLITE_CONSTRAINT_UNIQUE || failure.extended_code == ffi::SQLITE_CONSTRAINT_PRIMARYKEY => SqlError::UniqueViolation,
+        other => SqlError::Other(other.to_string()),
+    }
+}
+
+fn sqlite_params(params: &[SqlValue]) -> Vec<Value> {
+    params.iter().map(|param| match param {
+        SqlValue::Null => Value::Null,
+        SqlValue::Int(value) => Value::Integer(*value),
+        SqlValue::Text(value) => Value::Text(value.clone()),
+    }).collect()
+}
+
+impl SqlConnection for SqliteConnection {
+    fn execute(&self, sql: &str, params: &[SqlValue]) -> Result<u64, SqlError> {
+        self.0.execute(sql, sqlite_params(params)).map(|count| count as u64).map_err(sql_error)
+    }
+
+    fn query(&self, sql: &str, params: &[SqlValue]) -> Result<Vec<SqlRow>, SqlError> {
+        let mut statement = self.0.prepare(sql).map_err(sql_error)?;
+        let columns = statement.column_count();
+        let mut rows = statement.query(sqlite_params(params)).map_err(sql_error)?;
+        let mut found = Vec::new();
+        while let Some(row) = rows.next().map_err(sql_error)? {
+            let mut values = Vec::with_capacity(columns);
+            for index in 0..columns {
+                values.push(match row.get::<_, Value>(index).map_err(sql_error)? {
+                    Value::Integer(value) => SqlValue::Int(value),
+                    Value::Text(value) => SqlValue::Text(value),
+                    _ => SqlValue::Null,
+                });
+            }
+            found.push(values);
+        }
+        Ok(found)
+    }
+
+    fn batch(&self, sql: &str) -> Result<(), SqlError> {
+        self.0.execute_batch(sql).map_err(sql_error)
+    }
+
+    /// Takes the write lock straight away: upgrading a read transaction fails when another writer got there first
+    fn begin(&self) -> Result<(), SqlError> {
+        self.batch("BEGIN IMMEDIATE")
+    }
+}
+
+impl StorePool for Pool {
+    fn get(&self) -> Result<Box<dyn Store>, String> {
+        match r2d2::Pool::get(self) {
+            Ok(connection) => Ok(Box::new(SqlStore(Box::new(SqliteConnection(connection))))),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
+
+/// `path` is the database file, created if missing. An in-memory database
+/// only lives as long as its connection, so it gets a pool of one.
+pub fn init_pool(path: &str) -> Result<Pool, String> {
+    let path = path.trim_start_matches("sqlite://");
+    let in_memory = path == ":memory:";
+    let manager = if in_memory { SqliteConnectionManager::memory() } else { SqliteConnectionManager::file(path) }
+        .with_init(|c| {
+            c.busy_timeout(Duration::from_secs(5))?;
+            c.execute_batch("PRAGMA journal_mode = WAL;")
+        });
+    let pool = Pool::builder().max_size(if in_memory { 1 } else { 16 }).build(manager)
+        .map_err(|e| format!("failed to create database pool {}", e))?;
+    let connection = pool.get().map_err(|e| format!("failed to connect to the database {}", e))?;
+    sql_store::migrate(&SqliteConnection(connection)).map_err(|e| format!("failed to migrate the database schema {:?}", e))?;
+    Ok(pool)
+}
@@ -0,0 +1,207 @@
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest};
+use rocket::{Outcome, Request, State};
+
+use crate::config::{AppConfig, DatabaseConfig};
+use crate::data::access_token::AccessToken;
+use crate::data::audit::{AuditEvent, AuditFilter};
+use crate::data::db::User;
+use crate::data::email_change::EmailChange;
+use crate::data::idempotency::IdempotencyRecord;
+use crate::data::invite::Invite;
+use crate::data::mailer::Mail;
+use crate::data::migrations::MigrationReport;
+use crate::data::session::Session;
+use crate::data::mongo_connection;
+use crate::data::resilience::{connect_with_retry, CircuitBreaker, GuardedPool};
+use crate::data::user_events::UserEvent;
+use crate::data::user_search::UserSearch;
+use crate::data::webhooks::{Delivery, Webhook};
+
+#[derive(Debug, PartialEq)]
+pub enum StoreError {
+    /// A unique constraint, such as the one on the canonical email, was violated
+    Duplicate,
+    Failed,
+}
+
+/// Everything the application persists, whatever the database behind it.
+/// Emails passed in are already canonical.
+pub trait Store {
+    /// A round-trip to the server, to tell whether it can be reached
+    fn ping(&self) -> Result<(), String>;
+
+    fn find_user(&self, id: &str) -> Result<Option<User>, ()>;
+    fn find_user_by_email(&self, canonical: &str) -> Result<Option<User>, ()>;
+    fn insert_user(&self, user: &User) -> Result<(), StoreError>;
+    /// Saves everything but `sessions_revoked_at`, which a concurrent revocation may have set since the user was loaded.
+    /// `false` when there's no such user
+    fn replace_user(&self, user: &User) -> Result<bool, StoreError>;
+    /// Sets `sessions_revoked_at` alone; `false` when there's no such user
+    fn revoke_user_sessions(&self, id: &str, at: DateTime<Utc>) -> Result<bool, ()>;
+    fn delete_user(&self, id: &str) -> Result<Option<User>, ()>;
+    fn count_users(&self) -> Result<i64, ()>;
+    /// Whether the address is taken, either confirmed or pending, by any user other than `except_id`
+    fn email_in_use(&self, canonical: &str, except_id: Option<&str>) -> Result<bool, ()>;
+    /// One page of the matching users sorted by name, and the total number of matches
+    fn search_users(&self, search: &UserSearch) -> Result<(Vec<User>, i64), ()>;
+    /// Users sorted by id, starting after `after`: lets callers walk through all of them in batches
+    fn list_users(&self, after: Option<&str>, limit: i64) -> Result<Vec<User>, ()>;
+
+    fn insert_audit_event(&self, event: &AuditEvent) -> Result<(), String>;
+    /// Most recent events first, at most `limit` of them
+    fn find_audit_events(&self, filter: &AuditFilter, limit: i64) -> Result<Vec<AuditEvent>, ()>;
+
+    /// Stores a new change, superseding the ones of the same user still waiting for confirmation
+    fn insert_email_change(&self, change: &EmailChange) -> Result<(), ()>;
+    fn save_email_change(&self, change: &EmailChange) -> Result<(), ()>;
+    fn find_email_change_by_confirm_token(&self, token: &str) -> Result<Option<EmailChange>, ()>;
+    fn find_email_change_by_revert_token(&self, token: &str) -> Result<Option<EmailChange>, ()>;
+
+    fn queue_mail(&self, mail: &Mail) -> Result<(), ()>;
+    /// Most recent first
+    fn mails_to(&self, to: &str) -> Result<Vec<Mail>, ()>;
+    /// Mails neither sent nor given up on whose next attempt is due by `now`, or never set, the oldest first
+    fn due_mails(&self, now: DateTime<Utc>, limit: i64) -> Result<Vec<Mail>, ()>;
+    /// Puts off the next attempt of a due mail to `lease`, only if it is still set to `due`.
+    /// Whoever changed it gets to send the mail.
+    fn claim_mail(&self, id: &str, due: Option<DateTime<Utc>>, lease: DateTime<Utc>) -> Result<bool, ()>;
+    /// Records how an attempt went, leaving the rest of the mail alone
+    fn save_mail_attempt(&self, mail: &Mail) -> Result<(), ()>;
+
+    fn insert_invite(&self, invite: &Invite) -> Result<(), ()>;
+    fn find_invite(&self, token: &str) -> Result<Option<Invite>, ()>;
+    fn mark_invite_accepted(&self, invite: &Invite) -> Result<(), ()>;
+
+    fn insert_access_token(&self, token: &AccessToken) -> Result<(), ()>;
+    fn save_access_token(&self, token: &AccessToken) -> Result<(), ()>;
+    /// Only sets when and where from the token was last used, leaving a token revoked in the meantime alone
+    fn record_access_token_use(&self, id: &str, at: DateTime<Utc>, ip: Option<&str>) -> Result<(), ()>;
+    fn find_access_token_by_hash(&self, hash: &str) -> Result<Option<AccessToken>, ()>;
+    /// Most recent first
+    fn access_tokens_of(&self, user_id: &str) -> Result<Vec<AccessToken>, ()>;
+
+    fn insert_session(&self, session: &Session) -> Result<(), ()>;
+    fn save_session(&self, session: &Session) -> Result<(), ()>;
+    /// Only sets `last_seen` and the address, never `revoked`
+    fn touch_session(&self, id: &str, last_seen: DateTime<Utc>, ip: Option<&str>) -> Result<(), ()>;
+    fn find_session(&self, id: &str) -> Result<Option<Session>, ()>;
+    /// Most recently seen first, revoked and expired ones included
+    fn sessions_of(&self, user_id: &str) -> Result<Vec<Session>, ()>;
+
+    /// Stores the event under the next id, which it returns, dropping the oldest beyond `RETAINED_EVENTS`
+    fn insert_user_event(&self, event: &UserEvent) -> Result<i64, String>;
+    /// Oldest first, at most `limit` of them
+    fn user_events_after(&self, after: i64, limit: i64) -> Result<Vec<UserEvent>, ()>;
+    fn last_user_event_id(&self) -> Result<i64, ()>;
+
+    fn insert_webhook(&self, webhook: &Webhook) -> Result<(), ()>;
+    fn find_webhook(&self, id: &str) -> Result<Option<Webhook>, ()>;
+    /// Oldest first
+    fn webhooks(&self) -> Result<Vec<Webhook>, ()>;
+    /// Deletes its deliveries too, `false` when there's no such webhook
+    fn delete_webhook(&self, id: &str) -> Result<bool, ()>;
+    fn insert_delivery(&self, delivery: &Delivery) -> Result<(), ()>;
+    fn save_delivery(&self, delivery: &Delivery) -> Result<(), ()>;
+    fn find_delivery(&self, id: &str) -> Result<Option<Delivery>, ()>;
+    /// Most recent first, at most `limit` of them
+    fn deliveries_of(&self, webhook_id: &str, limit: i64) -> Result<Vec<Delivery>, ()>;
+    /// Pending deliveries whose next attempt is due by `now`, the longest waiting first
+    fn due_deliveries(&self, now: DateTime<Utc>, limit: i64) -> Result<Vec<Delivery>, ()>;
+    /// Puts off the next attempt of a pending delivery to `lease`, only if it is still set to `due`.
+    /// Whoever changed it gets to send the delivery.
+    fn claim_delivery(&self, id: &str, due: DateTime<Utc>, lease: DateTime<Utc>) -> Result<bool, ()>;
+
+    /// `Duplicate` when the id is taken
+    fn insert_idempotency_record(&self, record: &IdempotencyRecord) -> Result<(), StoreError>;
+    fn save_idempotency_record(&self, record: &IdempotencyRecord) -> Result<(), ()>;
+    /// Expired records may still be found, until they get dropped
+    fn find_idempotency_record(&self, id: &str) -> Result<Option<IdempotencyRecord>, ()>;
+    fn delete_idempotency_record(&self, id: &str) -> Result<(), ()>;
+
+    /// Creates whatever the backend needs, indexes or tables, reporting it.
+    /// The unique email constraint is only added when the report has no collisions.
+    fn ensure_schema(&self, report: &mut MigrationReport) -> Result<(), ()>;
+}
+
+/// Hands out connections to the configured database
+pub trait StorePool: Send + Sync {
+    fn get(&self) -> Result<Box<dyn Store>, String>;
+
+    /// When a failed checkout is worth trying again, if the pool knows
+    fn retry_after(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// The managed pool, shared with background workers such as the webhook deliveries
+pub struct SharedPool(pub Arc<Database>);
+
+impl StorePool for SharedPool {
+    fn get(&self) -> Result<Box<dyn Store>, String> {
+        self.0.get()
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        self.0.retry_after()
+    }
+}
+
+/// What the 503 response tells clients about when to come back, in seconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryAfter(pub u64);
+
+pub const DEFAULT_RETRY_AFTER: u64 = 5;
+
+pub type Database = Box<dyn StorePool>;
+
+pub struct Conn(pub Box<dyn Store>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for Conn {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Conn, ()> {
+        let pool = request.guard::<State<Database>>()?;
+        match pool.get() {
+            Ok(store) => Outcome::Success(Conn(store)),
+            Err(e) => {
+                log::debug!("no database connection: {}", e);
+                // Rounded up, a client coming back a bit early would only find the circuit still open
+                let seconds = pool.retry_after()
+                    .map(|wait| wait.as_secs() + if wait.subsec_nanos() > 0 { 1 } else { 0 })
+                    .unwrap_or(DEFAULT_RETRY_AFTER);
+                request.local_cache(|| RetryAfter(seconds.max(1)));
+                Outcome::Failure((Status::ServiceUnavailable, ()))
+            },
+        }
+    }
+}
+impl Deref for Conn {
+    type Target = dyn Store;
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+fn open_pool(config: &DatabaseConfig) -> Result<Database, String> {
+    Ok(match config {
+        DatabaseConfig::Mongo(mongo) => Box::new(mongo_connection::init_pool(mongo)?),
+        #[cfg(feature = "sqlite")]
+        DatabaseConfig::Sqlite(path) => Box::new(crate::data::sqlite_connection::init_pool(path)?),
+        #[cfg(feature = "postgres")]
+        DatabaseConfig::Postgres(url) => Box::new(crate::data::postgres_connection::init_pool(url)?),
+    })
+}
+
+/// Opens the configured database, waiting for it up to `DATABASE_STARTUP_TIMEOUT_SECS`.
+/// SQL databases get their schema migrated when opened.
+pub fn init_store(config: &AppConfig) -> Database {
+    match connect_with_retry(config.database_startup_timeout, || open_pool(&config.database)) {
+        Ok(pool) => Box::new(GuardedPool::new(pool, CircuitBreaker::new(config.breaker_threshold, config.breaker_cooldown))),
+        Err(e) => panic!("Error: failed to open the database {}", e),
+    }
+}
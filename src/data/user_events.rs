@@ -0,0 +1,98 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::data::db::{ResponseUser, User};
+use crate::data::store::Conn;
+use crate::data::webhooks;
+
+/// Events kept for clients resuming a stream; older ones are dropped
+pub const RETAINED_EVENTS: i64 = 10_000;
+/// Events sent to a stream at once
+pub const MAX_BATCH: i64 = 100;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum UserEventKind {
+    #[serde(rename = "user.created")]
+    Created,
+    #[serde(rename = "user.updated")]
+    Updated,
+    #[serde(rename = "user.password_changed")]
+    PasswordChanged,
+    #[serde(rename = "user.deleted")]
+    Deleted,
+    #[serde(rename = "user.logged_in")]
+    LoggedIn,
+}
+impl UserEventKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            UserEventKind::Created => "user.created",
+            UserEventKind::Updated => "user.updated",
+            UserEventKind::PasswordChanged => "user.password_changed",
+            UserEventKind::Deleted => "user.deleted",
+            UserEventKind::LoggedIn => "user.logged_in",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "user.created" => Some(UserEventKind::Created),
+            "user.updated" => Some(UserEventKind::Updated),
+            "user.password_changed" => Some(UserEventKind::PasswordChanged),
+            "user.deleted" => Some(UserEventKind::Deleted),
+            "user.logged_in" => Some(UserEventKind::LoggedIn),
+            _ => None,
+        }
+    }
+}
+
+/// Something that happened to a user. Ids grow with every event, so that streams resume after the last one they got.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserEvent {
+    #[serde(rename = "_id")]
+    pub id: i64,
+    pub kind: UserEventKind,
+    pub user_id: String,
+    /// The user as it is after the event, for creations and updates
+    pub user: Option<ResponseUser>,
+    pub timestamp: DateTime<Utc>,
+}
+impl UserEvent {
+    /// What subscribers get: the user's id, and the user itself when the event carries it
+    pub fn data(&self) -> serde_json::Value {
+        serde_json::json!({
+            "id": self.user_id,
+            "user": self.user,
+            "timestamp": self.timestamp,
+        })
+    }
+}
+
+/// Stores the event, the store giving it its id, and queues it for the webhooks subscribed to it.
+/// As with auditing, a failure is only logged.
+pub fn publish(connection: &Conn, kind: UserEventKind, user_id: &str, user: Option<&User>) {
+    let mut event = UserEvent {
+        id: 0,
+        kind,
+        user_id: user_id.to_string(),
+        user: user.map(ResponseUser::from_user),
+        timestamp: Utc::now(),
+    };
+    match connection.insert_user_event(&event) {
+        Ok(id) => {
+            event.id = id;
+            webhooks::enqueue(connection, &event);
+        },
+        Err(e) => log::warn!("failed to publish {} for {}: {}", kind.name(), user_id, e),
+    }
+}
+
+/// The events after `after`, oldest first
+pub fn after(connection: &Conn, after: i64) -> Result<Vec<UserEvent>, ()> {
+    connection.user_events_after(after, MAX_BATCH)
+}
+
+/// The id of the latest event, 0 when there is none yet
+pub fn last_id(connection: &Conn) -> Result<i64, ()> {
+    connection.last_user_event_id()
+}
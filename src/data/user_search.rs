@@ -0,0 +1,80 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::data::db::ResponseUser;
+use crate::data::store::Conn;
+
+pub const DEFAULT_PER_PAGE: i64 = 20;
+pub const MAX_PER_PAGE: i64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchMode {
+    Prefix,
+    Substring,
+}
+impl MatchMode {
+    pub fn parse(mode: &str) -> Option<Self> {
+        match mode {
+            "prefix" => Some(MatchMode::Prefix),
+            "substring" => Some(MatchMode::Substring),
+            _ => None,
+        }
+    }
+}
+
+/// Filters for looking up users; all of them are optional and combined with AND
+#[derive(Debug, Clone)]
+pub struct UserSearch {
+    /// Full-text search on the name, through the text index
+    pub text: Option<String>,
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub mode: MatchMode,
+    pub created_from: Option<DateTime<Utc>>,
+    pub created_to: Option<DateTime<Utc>>,
+    pub updated_from: Option<DateTime<Utc>>,
+    pub updated_to: Option<DateTime<Utc>>,
+    pub verified: Option<bool>,
+    pub role: Option<String>,
+    pub page: i64,
+    pub per_page: i64,
+}
+impl Default for UserSearch {
+    fn default() -> Self {
+        UserSearch {
+            text: None,
+            name: None,
+            email: None,
+            mode: MatchMode::Prefix,
+            created_from: None,
+            created_to: None,
+            updated_from: None,
+            updated_to: None,
+            verified: None,
+            role: None,
+            page: 1,
+            per_page: DEFAULT_PER_PAGE,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserPage {
+    pub items: Vec<ResponseUser>,
+    pub page: i64,
+    pub per_page: i64,
+    pub total: i64,
+}
+
+pub fn search(connection: &Conn, search: &UserSearch) -> Result<UserPage, ()> {
+    let page = search.page.max(1);
+    let per_page = search.per_page.max(1).min(MAX_PER_PAGE);
+    let search = UserSearch { page, per_page, ..search.clone() };
+    let (users, total) = connection.search_users(&search)?;
+    Ok(UserPage {
+        items: users.iter().map(ResponseUser::from_user).collect(),
+        page,
+        per_page,
+        total,
+    })
+}
@@ -0,0 +1,191 @@
+use std::fmt;
+
+use crate::data::audit::{self, AuditAction, AuditEvent, ClientInfo};
+use crate::data::db::{InsertableUser, User, UserPassword};
+use crate::data::email_address::{canonical_email_with, LocalPartFolding};
+use crate::data::email_change::{self, EmailChange};
+use crate::data::store::{Conn, StoreError};
+use crate::data::user_events::{self, UserEventKind};
+
+pub fn find_by_id(connection: &Conn, id: &str) -> Result<Option<User>, ()> {
+    connection.find_user(id)
+}
+
+/// Looks up by the canonical form of the address, so any spelling of it will do
+pub fn find_by_email(connection: &Conn, email: &str, folding: LocalPartFolding) -> Result<Option<User>, ()> {
+    match canonical_email_with(email, folding) {
+        Ok(canonical) => connection.find_user_by_email(&canonical),
+        Err(_) => Ok(None),
+    }
+}
+
+pub fn insert(connection: &Conn, user: &User) -> Result<(), StoreError> {
+    connection.insert_user(user)
+}
+
+/// Saves everything but `sessions_revoked_at`, see `revoke_sessions`
+pub fn replace(connection: &Conn, user: &User) -> Result<(), ()> {
+    match connection.replace_user(user) {
+        Ok(true) => Ok(()),
+        _ => Err(()),
+    }
+}
+
+/// Invalidates every token issued to the user so far. Written on its own, so that
+/// saving a user loaded before can't undo it.
+pub fn revoke_sessions(connection: &Conn, user: &mut User) -> Result<(), ()> {
+    user.revoke_sessions();
+    match user.sessions_revoked_at {
+        Some(at) if connection.revoke_user_sessions(&user.id.to_string(), at)? => Ok(()),
+        _ => Err(()),
+    }
+}
+
+/// Why a change to a user was turned down, whichever API asked for it
+#[derive(Debug, Clone, PartialEq)]
+pub enum UserError {
+    InvalidEmail,
+    EmailInUse,
+    NotFound(String),
+    /// The current password didn't match
+    NotAuthenticated,
+    PasswordMissing,
+    PasswordNotUpdated,
+    Failed,
+}
+impl fmt::Display for UserError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UserError::InvalidEmail => write!(f, "invalid email"),
+            UserError::EmailInUse => write!(f, "email already in use"),
+            UserError::NotFound(id) => write!(f, "id {} not found", id),
+            UserError::NotAuthenticated => write!(f, "user not authenticated"),
+            UserError::PasswordMissing => write!(f, "Password not provided"),
+            UserError::PasswordNotUpdated => write!(f, "Failed to update password"),
+            UserError::Failed => write!(f, "Internal server error"),
+        }
+    }
+}
+
+/// Signs a user up, refusing addresses in use, even pending ones
+pub fn create(connection: &Conn, insertable: InsertableUser, folding: LocalPartFolding, client: &ClientInfo) -> Result<User, UserError> {
+    if canonical_email_with(&insertable.email, folding).is_err() {
+        return Err(UserError::InvalidEmail);
+    }
+    // The unique constraint covers confirmed emails only, pending ones are reserved as well
+    match email_change::email_in_use(connection, &insertable.email, folding, None) {
+        Ok(true) => return Err(UserError::EmailInUse),
+        Ok(false) => (),
+        Err(_) => return Err(UserError::Failed),
+    }
+    let new_user = User::from_insertable(insertable, folding);
+    match connection.insert_user(&new_user) {
+        Ok(_) => {
+            let user_id = new_user.id.to_string();
+            audit::record(connection, &AuditEvent::new(AuditAction::UserCreated, Some(user_id.clone()), Some(user_id.clone()), client));
+            user_events::publish(connection, UserEventKind::Created, &user_id, Some(&new_user));
+            Ok(new_user)
+        },
+        Err(StoreError::Duplicate) => Err(UserError::EmailInUse),
+        Err(StoreError::Failed) => Err(UserError::Failed),
+    }
+}
+
+/// Renames the user and changes its address, a new address waiting for confirmation by mail
+pub fn update(connection: &Conn, public_url: &str, folding: LocalPartFolding, id: &str, user: &InsertableUser, actor: &str, client: &ClientInfo) -> Result<User, UserError> {
+    let mut found_user = match connection.find_user(id) {
+        Ok(Some(found_user)) => found_user,
+        Ok(None) => return Err(UserError::NotFound(id.to_string())),
+        Err(_) => return Err(UserError::Failed),
+    };
+    if !found_user.match_password(&user.password) {
+        return Err(UserError::NotAuthenticated);
+    }
+    // Only a different address needs confirming, not a different spelling of it
+    let email_changed = match canonical_email_with(&user.email, folding) {
+        Ok(canonical) => canonical != found_user.email_canonical,
+        Err(_) => return Err(UserError::InvalidEmail),
+    };
+    if email_changed {
+        // Check the email is not yet in use, neither confirmed nor pending
+        match email_change::email_in_use(connection, &user.email, folding, Some(id)) {
+            Ok(true) => return Err(UserError::EmailInUse),
+            Ok(false) => (),
+            Err(_) => return Err(UserError::Failed),
+        }
+    }
+    let mut changes = Vec::new();
+    if found_user.name != user.name { changes.push("name".to_string()); }
+    let old_email = found_user.email.clone();
+    let updated = if email_changed {
+        changes.push("pending_email".to_string());
+        found_user.request_email_change(&user.name, &user.email, folding)
+    } else {
+        if old_email != user.email.trim() { changes.push("email".to_string()); }
+        found_user.update_user(&user.name, &user.email, folding)
+    };
+    // The change and its mails go first: a pending email is never saved without a way to confirm it,
+    // while links to a change that didn't get saved are turned down, not matching the pending email
+    if email_changed {
+        let change = EmailChange::new(id.to_string(), old_email, user.email.trim().to_string());
+        if email_change::insert(connection, &change).is_err() || email_change::notify(connection, &change, public_url).is_err() {
+            return Err(UserError::Failed);
+        }
+    }
+    match connection.replace_user(&updated) {
+        Ok(true) => {
+            if email_changed {
+                audit::record(connection, &AuditEvent::new(AuditAction::EmailChangeRequested, Some(actor.to_string()), Some(id.to_string()), client));
+            }
+            audit::record(connection, &AuditEvent::new(AuditAction::UserUpdated, Some(actor.to_string()), Some(id.to_string()), client).with_changes(changes));
+            user_events::publish(connection, UserEventKind::Updated, id, Some(&updated));
+            Ok(updated)
+        },
+        Ok(false) => Err(UserError::NotFound(id.to_string())),
+        Err(_) => Err(UserError::Failed),
+    }
+}
+
+pub fn change_password(connection: &Conn, id: &str, password: &UserPassword, actor: &str, client: &ClientInfo) -> Result<(), UserError> {
+    let new_password = match &password.new_password {
+        Some(new_password) => new_password,
+        None => return Err(UserError::PasswordMissing),
+    };
+    let mut found_user = match connection.find_user(id) {
+        Ok(Some(found_user)) => found_user,
+        Ok(None) => return Err(UserError::NotFound(id.to_string())),
+        Err(_) => return Err(UserError::Failed),
+    };
+    if !found_user.match_password(&password.password) {
+        return Err(UserError::NotAuthenticated);
+    }
+    let updated = found_user.update_password(new_password);
+    match connection.replace_user(&updated) {
+        Ok(_) => {
+            audit::record(connection, &AuditEvent::new(AuditAction::PasswordChanged, Some(actor.to_string()), Some(id.to_string()), client).with_changes(vec!["password".to_string()]));
+            user_events::publish(connection, UserEventKind::PasswordChanged, id, None);
+            Ok(())
+        },
+        Err(_) => Err(UserError::PasswordNotUpdated),
+    }
+}
+
+pub fn delete(connection: &Conn, id: &str, password: &str, actor: &str, client: &ClientInfo) -> Result<User, UserError> {
+    let found_user = match connection.find_user(id) {
+        Ok(Some(found_user)) => found_user,
+        Ok(None) => return Err(UserError::NotFound(id.to_string())),
+        Err(_) => return Err(UserError::Failed),
+    };
+    if !found_user.match_password(&password.to_string()) {
+        return Err(UserError::NotAuthenticated);
+    }
+    match connection.delete_user(id) {
+        Ok(Some(deleted)) => {
+            audit::record(connection, &AuditEvent::new(AuditAction::UserDeleted, Some(actor.to_string()), Some(id.to_string()), client));
+            user_events::publish(connection, UserEventKind::Deleted, id, None);
+            Ok(deleted)
+        },
+        Ok(None) => Err(UserError::NotFound(id.to_string())),
+        Err(_) => Err(UserError::Failed),
+    }
+}
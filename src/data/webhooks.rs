@@ -0,0 +1,465 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use uuid::Uuid;
+use rand::{thread_rng, Rng};
+use rand::distributions::Alphanumeric;
+
+use crate::config::ConfigReader;
+use crate::data::store::{Conn, Database};
+use crate::data::user_events::{UserEvent, UserEventKind};
+
+/// Marks webhook secrets, like the prefix of access tokens
+pub const SECRET_PREFIX: &str = "whsec_";
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Webhook-Timestamp";
+pub const EVENT_HEADER: &str = "X-Webhook-Event";
+pub const DELIVERY_HEADER: &str = "X-Webhook-Delivery";
+/// Deliveries a worker takes on at once
+const DUE_BATCH: i64 = 50;
+/// Deliveries listed for a webhook
+pub const MAX_LISTED_DELIVERIES: i64 = 100;
+/// Keeps the doubling backoff from growing past a few hours
+const MAX_BACKOFF_SECS: u64 = 6 * 60 * 60;
+const DEFAULT_POLL_INTERVAL_MS: u64 = 1000;
+const DEFAULT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_MAX_ATTEMPTS: usize = 8;
+const DEFAULT_RETRY_BASE_SECS: u64 = 30;
+/// Added to the timeout of an attempt for the lease of a claimed delivery
+const LEASE_MARGIN_SECS: u64 = 60;
+
+/// The worker runs once per process, however many instances are built
+static WORKER_STARTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WebhookConfig {
+    /// Whether this instance runs the delivery worker
+    pub worker: bool,
+    /// How often the worker looks for due deliveries
+    pub poll_interval: Duration,
+    pub timeout: Duration,
+    /// Attempts before a delivery is given up on
+    pub max_attempts: usize,
+    /// Wait after the first failed attempt, doubled after each one that follows
+    pub retry_base: Duration,
+    /// Whether webhooks may point to loopback, private or link-local addresses, as in development
+    pub allow_private: bool,
+}
+impl WebhookConfig {
+    pub fn read(reader: &mut ConfigReader) -> Self {
+        let worker = match reader.get("WEBHOOK_WORKER") {
+            Some(value) => reader.flag_value("WEBHOOK_WORKER", &value).unwrap_or(true),
+            None => true,
+        };
+        let allow_private = match reader.get("WEBHOOK_ALLOW_PRIVATE") {
+            Some(value) => reader.flag_value("WEBHOOK_ALLOW_PRIVATE", &value).unwrap_or(false),
+            None => false,
+        };
+        let max_attempts = reader.parse("WEBHOOK_MAX_ATTEMPTS").unwrap_or(DEFAULT_MAX_ATTEMPTS);
+        if max_attempts == 0 {
+            reader.invalid("WEBHOOK_MAX_ATTEMPTS", "must be at least 1");
+        }
+        WebhookConfig {
+            worker,
+            poll_interval: Duration::from_millis(reader.parse("WEBHOOK_POLL_INTERVAL_MS").unwrap_or(DEFAULT_POLL_INTERVAL_MS)),
+            timeout: Duration::from_secs(reader.parse("WEBHOOK_TIMEOUT_SECS").unwrap_or(DEFAULT_TIMEOUT_SECS)),
+            max_attempts,
+            retry_base: Duration::from_secs(reader.parse("WEBHOOK_RETRY_BASE_SECS").unwrap_or(DEFAULT_RETRY_BASE_SECS)),
+            allow_private,
+        }
+    }
+
+    /// How long to wait after the given number of failed attempts
+    fn backoff(&self, failed: usize) -> chrono::Duration {
+        let factor = 1u64.checked_shl(failed.saturating_sub(1) as u32).unwrap_or(u64::MAX);
+        let secs = self.retry_base.as_secs().saturating_mul(factor).min(MAX_BACKOFF_SECS);
+        chrono::Duration::seconds(secs as i64)
+    }
+
+    /// How long a claimed delivery is left to its worker, which gives up on the attempt well before
+    fn lease(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.timeout.as_secs().saturating_add(LEASE_MARGIN_SECS) as i64)
+    }
+}
+
+/// An endpoint of another system, told about the user events it subscribed to.
+/// The secret signs the deliveries, so it's kept in clear; it's only shown when created.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Webhook {
+    #[serde(rename = "_id")]
+    pub id: Uuid,
+    pub url: String,
+    /// Every event when empty
+    pub events: Vec<UserEventKind>,
+    pub secret: String,
+    pub created: DateTime<Utc>,
+    pub created_by: String,
+}
+impl Webhook {
+    pub fn generate(url: String, events: Vec<UserEventKind>, created_by: String) -> Self {
+        let random: String = thread_rng().sample_iter(&Alphanumeric).take(32).collect();
+        Webhook {
+            id: Uuid::new_v4(),
+            url,
+            events,
+            secret: format!("{}{}", SECRET_PREFIX, random),
+            created: Utc::now(),
+            created_by,
+        }
+    }
+    pub fn subscribes(&self, kind: UserEventKind) -> bool {
+        self.events.is_empty() || self.events.contains(&kind)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct NewWebhook {
+    pub url: String,
+    /// Event names such as `user.created`, all of them when missing
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+/// What admins get to see of a webhook
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResponseWebhook {
+    pub id: String,
+    pub url: String,
+    pub events: Vec<UserEventKind>,
+    pub created: DateTime<Utc>,
+    pub created_by: String,
+}
+impl ResponseWebhook {
+    pub fn from_webhook(webhook: &Webhook) -> Self {
+        ResponseWebhook {
+            id: webhook.id.to_string(),
+            url: webhook.url.clone(),
+            events: webhook.events.clone(),
+            created: webhook.created,
+            created_by: webhook.created_by.clone(),
+        }
+    }
+}
+
+/// The only response carrying the secret
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CreatedWebhook {
+    pub secret: String,
+    #[serde(flatten)]
+    pub info: ResponseWebhook,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    /// Out of attempts, or the webhook is gone
+    Failed,
+}
+impl DeliveryStatus {
+    pub fn name(self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Failed => "failed",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "pending" => Some(DeliveryStatus::Pending),
+            "delivered" => Some(DeliveryStatus::Delivered),
+            "failed" => Some(DeliveryStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// One try at a delivery: the response status, or why there was none
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeliveryAttempt {
+    pub at: DateTime<Utc>,
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    pub duration_ms: i64,
+}
+
+/// An event on its way to a webhook. The body is built once, so that every attempt sends the same bytes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Delivery {
+    #[serde(rename = "_id")]
+    pub id: Uuid,
+    pub webhook_id: String,
+    pub event_id: i64,
+    pub kind: UserEventKind,
+    pub body: String,
+    pub status: DeliveryStatus,
+    pub attempts: Vec<DeliveryAttempt>,
+    /// When the worker should try it, none once it's settled
+    pub next_attempt: Option<DateTime<Utc>>,
+    pub created: DateTime<Utc>,
+    /// The delivery this one sends again, for manual redeliveries
+    pub redelivery_of: Option<String>,
+}
+impl Delivery {
+    pub fn new(webhook: &Webhook, event: &UserEvent) -> Self {
+        let body = serde_json::json!({
+            "event": event.kind.name(),
+            "event_id": event.id,
+            "data": event.data(),
+        });
+        let now = Utc::now();
+        Delivery {
+            id: Uuid::new_v4(),
+            webhook_id: webhook.id.to_string(),
+            event_id: event.id,
+            kind: event.kind,
+            body: body.to_string(),
+            status: DeliveryStatus::Pending,
+            attempts: Vec::new(),
+            next_attempt: Some(now),
+            created: now,
+            redelivery_of: None,
+        }
+    }
+
+    /// A fresh delivery of the same body, with attempts of its own
+    pub fn redeliver(&self) -> Self {
+        let now = Utc::now();
+        Delivery {
+            id: Uuid::new_v4(),
+            status: DeliveryStatus::Pending,
+            attempts: Vec::new(),
+            next_attempt: Some(now),
+            created: now,
+            redelivery_of: Some(self.id.to_string()),
+            ..self.clone()
+        }
+    }
+}
+
+/// `sha256=` and the hex HMAC-SHA256 of `<timestamp>.<body>` keyed with the webhook secret.
+/// Receivers compute it again, and check the timestamp is recent to turn down replays.
+pub fn signature(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.input(format!("{}.{}", timestamp, body).as_bytes());
+    format!("sha256={:x}", mac.result().code())
+}
+
+/// The host and port of an http or https address, user info left out and IPv6 brackets taken off
+fn host_and_port(url: &str) -> Option<(&str, u16)> {
+    let (rest, default_port) = match url.strip_prefix("https://") {
+        Some(rest) => (rest, 443),
+        None => (url.strip_prefix("http://")?, 80),
+    };
+    let authority = rest.split(|c| c == '/' || c == '?' || c == '#').next().unwrap_or("");
+    let authority = authority.rsplit('@').next().unwrap_or("");
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(bracketed) => {
+            let end = bracketed.find(']')?;
+            match &bracketed[end + 1..] {
+                "" => (&bracketed[..end], None),
+                port => (&bracketed[..end], Some(port.strip_prefix(':')?)),
+            }
+        },
+        None => match authority.rfind(':') {
+            Some(at) => (&authority[..at], Some(&authority[at + 1..])),
+            None => (authority, None),
+        },
+    };
+    if host.is_empty() || host.contains(char::is_whitespace) {
+        return None;
+    }
+    match port {
+        Some(port) => port.parse().ok().map(|port| (host, port)),
+        None => Some((host, default_port)),
+    }
+}
+
+/// Only http and https addresses with a host
+pub fn valid_url(url: &str) -> bool {
+    host_and_port(url).is_some()
+}
+
+/// Addresses of our own network rather than another system's: loopback, private, link-local,
+/// carrier-grade NAT and unspecified ones, IPv4 mapped into IPv6 included
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast()
+                || ip.is_multicast() || octets[0] == 0 || (octets[0] == 100 && octets[1] & 0xc0 == 64)
+        },
+        IpAddr::V6(ip) => {
+            if let Some(mapped) = ip.to_ipv4() {
+                return is_internal(IpAddr::V4(mapped));
+            }
+            let first = ip.segments()[0];
+            ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+        },
+    }
+}
+
+/// Whether requests to the address could reach our own services: its host resolves to an internal address,
+/// or doesn't resolve at all. Names are resolved, as any of them can point inside.
+pub fn private_url(url: &str) -> bool {
+    let addresses: Vec<IpAddr> = match host_and_port(url).map(|target| target.to_socket_addrs()) {
+        Some(Ok(addresses)) => addresses.map(|address| address.ip()).collect(),
+        _ => return true,
+    };
+    addresses.is_empty() || addresses.into_iter().any(is_internal)
+}
+
+pub fn insert(connection: &Conn, webhook: &Webhook) -> Result<(), ()> {
+    connection.insert_webhook(webhook)
+}
+
+pub fn list(connection: &Conn) -> Result<Vec<Webhook>, ()> {
+    connection.webhooks()
+}
+
+pub fn find(connection: &Conn, id: &str) -> Result<Option<Webhook>, ()> {
+    connection.find_webhook(id)
+}
+
+/// Its deliveries go along with it
+pub fn delete(connection: &Conn, id: &str) -> Result<bool, ()> {
+    connection.delete_webhook(id)
+}
+
+pub fn deliveries(connection: &Conn, webhook_id: &str) -> Result<Vec<Delivery>, ()> {
+    connection.deliveries_of(webhook_id, MAX_LISTED_DELIVERIES)
+}
+
+/// Queues the event for the webhooks subscribed to it. As with auditing, a failure is only logged.
+pub fn enqueue(connection: &Conn, event: &UserEvent) {
+    let webhooks = match connection.webhooks() {
+        Ok(webhooks) => webhooks,
+        Err(_) => {
+            log::warn!("could not look up the webhooks for event {}", event.id);
+            return;
+        },
+    };
+    for webhook in webhooks.iter().filter(|webhook| webhook.subscribes(event.kind)) {
+        if connection.insert_delivery(&Delivery::new(webhook, event)).is_err() {
+            log::warn!("failed to queue event {} for webhook {}", event.id, webhook.id);
+        }
+    }
+}
+
+/// Queues the delivery again, the original one staying as it was
+pub fn redeliver(connection: &Conn, webhook_id: &str, delivery_id: &str) -> Result<Option<Delivery>, ()> {
+    match connection.find_delivery(delivery_id)? {
+        Some(delivery) if delivery.webhook_id == webhook_id => {
+            let again = delivery.redeliver();
+            connection.insert_delivery(&again)?;
+            Ok(Some(again))
+        },
+        _ => Ok(None),
+    }
+}
+
+/// POSTs the body with its signature, recording how it went
+fn attempt(webhook: &Webhook, delivery: &mut Delivery, config: &WebhookConfig) {
+    let now = Utc::now();
+    let timestamp = now.timestamp();
+    let started = Instant::now();
+    // Checked again as names can be pointed elsewhere after the webhook was created
+    let (status, error) = if !config.allow_private && private_url(&webhook.url) {
+        (None, Some("the url leads to a private address".to_string()))
+    } else {
+        // A redirect could lead anywhere, the receiver has to be at the address it gave
+        let response = ureq::post(&webhook.url)
+            .timeout(config.timeout)
+            .redirects(0)
+            .set("Content-Type", "application/json")
+            .set(EVENT_HEADER, delivery.kind.name())
+            .set(DELIVERY_HEADER, &delivery.id.to_string())
+            .set(TIMESTAMP_HEADER, &timestamp.to_string())
+            .set(SIGNATURE_HEADER, &signature(&webhook.secret, timestamp, &delivery.body))
+            .send_string(&delivery.body);
+        match response.synthetic_error() {
+            Some(e) => (None, Some(e.to_string())),
+            None if response.ok() => (Some(response.status()), None),
+            None => (Some(response.status()), Some(response.status_text().to_string())),
+        }
+    };
+    delivery.attempts.push(DeliveryAttempt { at: now, status, error: error.clone(), duration_ms: started.elapsed().as_millis() as i64 });
+    match error {
+        None => {
+            delivery.status = DeliveryStatus::Delivered;
+            delivery.next_attempt = None;
+        },
+        Some(_) if delivery.attempts.len() >= config.max_attempts => {
+            delivery.status = DeliveryStatus::Failed;
+            delivery.next_attempt = None;
+        },
+        Some(_) => delivery.next_attempt = Some(Utc::now() + config.backoff(delivery.attempts.len())),
+    }
+}
+
+/// Makes one attempt at every delivery that's due, claiming each first: workers sharing the
+/// database never send the same one at once. A worker dying mid-attempt leaves its claim to
+/// run out, the delivery being due again after it.
+pub fn deliver_due(connection: &Conn, config: &WebhookConfig) -> Result<usize, ()> {
+    let due = connection.due_deliveries(Utc::now(), DUE_BATCH)?;
+    let mut webhooks: HashMap<String, Option<Webhook>> = HashMap::new();
+    for mut delivery in due.iter().cloned() {
+        let scheduled = match delivery.next_attempt {
+            Some(scheduled) => scheduled,
+            None => continue,
+        };
+        if !connection.claim_delivery(&delivery.id.to_string(), scheduled, Utc::now() + config.lease())? {
+            continue;
+        }
+        if !webhooks.contains_key(&delivery.webhook_id) {
+            webhooks.insert(delivery.webhook_id.clone(), connection.find_webhook(&delivery.webhook_id)?);
+        }
+        match &webhooks[&delivery.webhook_id] {
+            Some(webhook) => attempt(webhook, &mut delivery, config),
+            None => {
+                delivery.status = DeliveryStatus::Failed;
+                delivery.next_attempt = None;
+            },
+        }
+        connection.save_delivery(&delivery)?;
+    }
+    Ok(due.len())
+}
+
+/// Delivers in the background for as long as the process runs. Instances sharing the database can all run one. Deliveries are still sent
+/// at least once, not exactly once: receivers tell retries apart by `X-Webhook-Delivery`.
+pub fn spawn_worker(database: Arc<Database>, config: WebhookConfig) {
+    if WORKER_STARTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let spawned = thread::Builder::new().name("webhooks".to_string()).spawn(move || loop {
+        let busy = match database.get() {
+            Ok(store) => match deliver_due(&Conn(store), &config) {
+                Ok(sent) => sent as i64 == DUE_BATCH,
+                Err(_) => {
+                    log::warn!("could not deliver the due webhooks");
+                    false
+                },
+            },
+            Err(e) => {
+                log::debug!("no database connection for the webhook worker: {}", e);
+                false
+            },
+        };
+        if !busy {
+            thread::sleep(config.poll_interval);
+        }
+    });
+    if let Err(e) = spawned {
+        log::error!("could not start the webhook worker: {}", e);
+    }
+}
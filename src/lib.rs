@@ -1,27 +1,69 @@
 #![feature(proc_macro_hygiene, decl_macro)]
 #![allow(unused_attributes)]
 
+use std::sync::Arc;
 #[macro_use] use rocket::*;
 use rocket_contrib::serve::StaticFiles;
 use rocket_contrib::helmet::SpaceHelmet;
 
+pub mod compression;
+pub mod config;
+pub mod cors;
+pub mod rate_limit;
 pub mod routes;
+pub mod versioning;
 pub mod data;
 
 pub fn rocket_builder() -> rocket::Rocket {
+    let rocket = rocket::ignite();
+    let config = match config::AppConfig::from_rocket(rocket.config()) {
+        Ok(config) => config,
+        Err(report) => panic!("{}", report),
+    };
+    let database = Arc::new(data::store::init_store(&config));
+    if config.webhooks.worker {
+        data::webhooks::spawn_worker(Arc::clone(&database), config.webhooks);
+    }
+    data::mailer::spawn_worker(Arc::clone(&database), config.mail.clone());
+    let rate_limiter = match rate_limit::RateLimiter::from_config(&config) {
+        Ok(rate_limiter) => rate_limiter,
+        Err(e) => panic!("Error: failed to open the rate limit store {}", e),
+    };
+    // Batched requests go through an instance of their own, sharing the database, the rate limits
+    // and the secret key of cookies
+    let batch_api = api(rocket::custom(rocket.config().clone()), config.clone(), Arc::clone(&database), rate_limiter.share());
+    let batcher = match routes::batch::Batcher::new(batch_api) {
+        Ok(batcher) => batcher,
+        Err(e) => panic!("Error: failed to set up batching {}", e),
+    };
+    api(rocket, config, database, rate_limiter).manage(batcher)
+}
 
-    rocket::ignite().attach(SpaceHelmet::default())
-    .mount("/", routes![routes::ping::ping_fn])
-    .mount("/api", routes![
-        routes::user::user_list_rt,
-        routes::user::new_user_rt,
-        routes::user::info_user_rt,
-        routes::user::update_user_rt,
-        routes::user::delete_user_rt,
-        routes::user::patch_user_rt,
-        routes::user::id_user_rt,
-        routes::auth::login_user,
+fn api(rocket: rocket::Rocket, config: config::AppConfig, database: Arc<data::store::Database>, rate_limiter: rate_limit::RateLimiter) -> rocket::Rocket {
+    rocket.attach(SpaceHelmet::default())
+    .attach(cors::Cors(config.cors.clone()))
+    .attach(rate_limiter)
+    .mount("/", routes![
+        routes::ping::ping_fn,
+        routes::jwks::jwks_rt,
+        routes::cors::preflight_rt,
+        routes::graphql::graphiql_rt,
+        routes::graphql::get_graphql_rt,
+        routes::graphql::post_graphql_rt,
     ])
+    .attach(versioning::Versioning::new(
+        vec![versioning::ApiVersion::new("v1", routes::v1::routes())],
+        config.versioning.clone(),
+    ))
     .mount("/files", StaticFiles::from("static/"))
-    .manage(data::mongo_connection::init_pool())
+    .register(catchers![
+        routes::catchers::service_unavailable,
+        routes::catchers::unsupported_media_type,
+        routes::catchers::not_acceptable,
+    ])
+    .attach(compression::Compression(config.compression))
+    .manage(routes::graphql::schema())
+    .manage(routes::events::StreamSlots::default())
+    .manage(Box::new(data::store::SharedPool(database)) as data::store::Database)
+    .manage(config)
 }
\ No newline at end of file
@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use chrono::{DateTime, Duration, Utc};
+use rocket::{catchers, routes, Data, Request, Response, Rocket, State};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Method;
+use rocket::http::uri::Origin;
+
+use crate::config::{AppConfig, ConfigReader, DatabaseConfig};
+use crate::data::access_token::hash_secret;
+use crate::data::mongo_rate_limit::MongoRateLimitStore;
+use crate::data::security;
+use crate::versioning::unversioned_path;
+
+/// Where requests over their limit are sent, instead of the route they asked for
+pub const RATE_LIMITED_PATH: &str = "/rate-limited";
+/// Buckets the memory store holds before dropping the full ones
+const SWEEP_THRESHOLD: usize = 10_000;
+
+/// What a client is told apart by
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyKind {
+    Ip,
+    /// The user of the session token, else the access token, else the address
+    User,
+    /// The `Authorization: Bearer` token, else the address
+    ApiKey,
+}
+
+/// A token bucket: `capacity` requests at once, refilled at `capacity` per `period`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub capacity: u32,
+    pub period: u64,
+}
+impl Limit {
+    fn refill_per_sec(&self) -> f64 {
+        f64::from(self.capacity) / self.period as f64
+    }
+}
+
+/// Requests it applies to: `*` for any method, a path ending with `*` for any path it starts with.
+/// API paths are matched without their version, a policy for `/api/users` holding for `/api/v1/users` too.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    pub method: Option<Method>,
+    pub path: String,
+    pub key: KeyKind,
+    pub limit: Limit,
+}
+impl Policy {
+    /// `METHOD PATH KEY CAPACITY/SECONDS`, as in `POST /api/users ip 10/3600`
+    fn parse(spec: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = spec.split_whitespace().collect();
+        if fields.len() != 4 {
+            return Err(format!("{} isn't METHOD PATH KEY CAPACITY/SECONDS", spec));
+        }
+        let method = match fields[0] {
+            "*" => None,
+            method => Some(Method::from_str(&method.to_uppercase()).map_err(|_| format!("unknown method {}", method))?),
+        };
+        if !fields[1].starts_with('/') {
+            return Err(format!("path {} doesn't start with /", fields[1]));
+        }
+        let key = match fields[2] {
+            "ip" => KeyKind::Ip,
+            "user" => KeyKind::User,
+            "api_key" => KeyKind::ApiKey,
+            other => return Err(format!("key {} isn't ip, user or api_key", other)),
+        };
+        let limit = match fields[3].find('/') {
+            Some(at) => match (fields[3][..at].parse::<u32>(), fields[3][at + 1..].parse::<u64>()) {
+                (Ok(capacity), Ok(period)) if capacity > 0 && period > 0 => Limit { capacity, period },
+                _ => return Err(format!("{} isn't two positive numbers", fields[3])),
+            },
+            None => return Err(format!("{} isn't CAPACITY/SECONDS", fields[3])),
+        };
+        Ok(Policy { method, path: unversioned_path(fields[1]), key, limit })
+    }
+
+    fn matches(&self, method: Method, path: &str) -> bool {
+        let method_matches = self.method.map(|m| m == method).unwrap_or(true);
+        let path_matches = match self.path.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == self.path,
+        };
+        method_matches && path_matches
+    }
+
+    /// Names the policy in bucket ids, so that each has buckets of its own
+    fn id(&self) -> String {
+        format!("{} {}", self.method.map(|m| m.as_str()).unwrap_or("*"), self.path)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitBackend {
+    /// Per instance, lost on restart
+    Memory,
+    /// Shared by every instance using the same MongoDB database
+    Mongo,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimitConfig {
+    pub policies: Vec<Policy>,
+    pub backend: RateLimitBackend,
+}
+impl RateLimitConfig {
+    /// `RATE_LIMITS` lists policies separated by `;`, none by default. Signups go through `POST /graphql`
+    /// as well as `POST /api/users`, a policy meant for them has to name both.
+    /// `RATE_LIMIT_STORE` is `memory`, or `mongodb` along with the MongoDB backend.
+    pub fn read(reader: &mut ConfigReader, database: Option<&DatabaseConfig>) -> Self {
+        let mut policies = Vec::new();
+        for spec in reader.get("RATE_LIMITS").unwrap_or_default().split(';').map(str::trim).filter(|spec| !spec.is_empty()) {
+            match Policy::parse(spec) {
+                Ok(policy) => policies.push(policy),
+                Err(e) => reader.invalid("RATE_LIMITS", &e),
+            }
+        }
+        let backend = match reader.get("RATE_LIMIT_STORE").as_deref() {
+            None | Some("memory") => RateLimitBackend::Memory,
+            Some("mongodb") => {
+                match database {
+                    Some(DatabaseConfig::Mongo(_)) | None => (),
+                    Some(_) => reader.invalid("RATE_LIMIT_STORE", "mongodb needs DATABASE_BACKEND=mongodb"),
+                }
+                RateLimitBackend::Mongo
+            },
+            Some(_) => {
+                reader.invalid("RATE_LIMIT_STORE", "must be memory or mongodb");
+                RateLimitBackend::Memory
+            },
+        };
+        RateLimitConfig { policies, backend }
+    }
+}
+
+/// The state of a bucket: tokens left when last updated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TokenBucket {
+    pub tokens: f64,
+    pub updated: DateTime<Utc>,
+}
+
+/// The outcome of taking a token, with what the `RateLimit-*` headers tell
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: Limit,
+    pub remaining: u32,
+    /// Seconds until the bucket is full again
+    pub reset: u64,
+    /// Seconds until a token is available, 0 if there is one
+    pub retry_after: u64,
+}
+
+/// Refills the bucket for the time elapsed, then takes a token from it if there is one.
+/// A bucket never seen before is full.
+pub fn take_token(bucket: Option<TokenBucket>, limit: &Limit, now: DateTime<Utc>) -> (TokenBucket, Decision) {
+    let rate = limit.refill_per_sec();
+    let capacity = f64::from(limit.capacity);
+    let available = match bucket {
+        Some(bucket) => {
+            let elapsed = (now - bucket.updated).num_milliseconds().max(0) as f64 / 1000.0;
+            (bucket.tokens + elapsed * rate).min(capacity)
+        },
+        None => capacity,
+    };
+    let allowed = available >= 1.0;
+    let tokens = if allowed { available - 1.0 } else { available };
+    let decision = Decision {
+        allowed,
+        limit: *limit,
+        remaining: tokens.floor() as u32,
+        reset: ((capacity - tokens) / rate).ceil() as u64,
+        retry_after: if allowed { 0 } else { ((1.0 - tokens) / rate).ceil() as u64 },
+    };
+    (TokenBucket { tokens, updated: now }, decision)
+}
+
+/// Keeps the buckets, taking tokens atomically
+pub trait RateLimitStore: Send + Sync {
+    fn take(&self, bucket: &str, limit: &Limit) -> Result<Decision, String>;
+}
+
+#[derive(Default)]
+struct MemoryBuckets {
+    buckets: HashMap<String, (TokenBucket, Limit)>,
+    sweep_at: usize,
+}
+
+#[derive(Default)]
+pub struct MemoryRateLimitStore {
+    buckets: Mutex<MemoryBuckets>,
+}
+impl RateLimitStore for MemoryRateLimitStore {
+    fn take(&self, bucket: &str, limit: &Limit) -> Result<Decision, String> {
+        let mut memory = self.buckets.lock().map_err(|e| e.to_string())?;
+        let now = Utc::now();
+        if memory.buckets.len() >= memory.sweep_at.max(SWEEP_THRESHOLD) {
+            // A bucket refilled to the brim is the same as none
+            memory.buckets.retain(|_, (state, limit)| now - state.updated < Duration::seconds(limit.period as i64));
+            memory.sweep_at = memory.buckets.len() * 2;
+        }
+        let (state, decision) = take_token(memory.buckets.get(bucket).map(|(state, _)| *state), limit, now);
+        memory.buckets.insert(bucket.to_string(), (state, *limit));
+        Ok(decision)
+    }
+}
+
+/// The decision taken for the current request, read back for the response headers
+struct AppliedLimit(Option<Decision>);
+
+/// Limits requests according to the policies matching them, sending those over the limit to a 429.
+/// Every matching policy takes a token; the headers tell about the one closest to its limit.
+/// Should the store fail, requests go through.
+pub struct RateLimiter {
+    policies: Vec<Policy>,
+    store: Arc<dyn RateLimitStore>,
+}
+impl RateLimiter {
+    pub fn new(policies: Vec<Policy>, store: Box<dyn RateLimitStore>) -> Self {
+        RateLimiter { policies, store: Arc::from(store) }
+    }
+
+    /// Another limiter taking its tokens from the same buckets, for a second instance of the API
+    pub fn share(&self) -> Self {
+        RateLimiter { policies: self.policies.clone(), store: Arc::clone(&self.store) }
+    }
+
+    /// With the store configured, opening a pool of its own for MongoDB
+    pub fn from_config(config: &AppConfig) -> Result<Self, String> {
+        let store: Box<dyn RateLimitStore> = match (&config.rate_limit.backend, &config.database) {
+            (RateLimitBackend::Mongo, DatabaseConfig::Mongo(mongo)) => Box::new(MongoRateLimitStore::new(mongo)?),
+            (RateLimitBackend::Mongo, _) => return Err("the mongodb rate limit store needs the MongoDB backend".to_string()),
+            (RateLimitBackend::Memory, _) => Box::new(MemoryRateLimitStore::default()),
+        };
+        Ok(RateLimiter::new(config.rate_limit.policies.clone(), store))
+    }
+
+    fn client_key(&self, request: &Request, kind: KeyKind) -> String {
+        let address = || format!("ip:{}", request.client_ip().map(|ip| ip.to_string()).unwrap_or_else(|| "unknown".to_string()));
+        let api_key = || security::bearer_token(request).map(|token| format!("key:{}", hash_secret(token)));
+        match kind {
+            KeyKind::Ip => address(),
+            KeyKind::User => {
+                let user = request.guard::<State<AppConfig>>().succeeded()
+                    .and_then(|config| security::session_user(request, &config))
+                    .map(|id| format!("user:{}", id));
+                user.or_else(api_key).unwrap_or_else(address)
+            },
+            KeyKind::ApiKey => api_key().unwrap_or_else(address),
+        }
+    }
+}
+
+impl Fairing for RateLimiter {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limiter",
+            kind: Kind::Attach | Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        Ok(rocket
+            .mount("/", routes![crate::routes::rate_limit::rate_limited_rt])
+            .register(catchers![crate::routes::catchers::too_many_requests]))
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let path = unversioned_path(request.uri().path());
+        let mut tightest: Option<Decision> = None;
+        for policy in self.policies.iter().filter(|policy| policy.matches(request.method(), &path)) {
+            let bucket = format!("{}|{}", policy.id(), self.client_key(request, policy.key));
+            let decision = match self.store.take(&bucket, &policy.limit) {
+                Ok(decision) => decision,
+                Err(e) => {
+                    log::warn!("rate limit store failed, letting the request through: {}", e);
+                    continue;
+                },
+            };
+            if tightest.map(|tightest| !decision.allowed || decision.remaining < tightest.remaining).unwrap_or(true) {
+                tightest = Some(decision);
+            }
+            if !decision.allowed {
+                break;
+            }
+        }
+        if let Some(decision) = tightest {
+            request.local_cache(|| AppliedLimit(Some(decision)));
+            if !decision.allowed {
+                request.set_method(Method::Get);
+                request.set_uri(Origin::parse(RATE_LIMITED_PATH).expect("Valid path"));
+            }
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        if let AppliedLimit(Some(decision)) = request.local_cache(|| AppliedLimit(None)) {
+            response.set_raw_header("RateLimit-Limit", decision.limit.capacity.to_string());
+            response.set_raw_header("RateLimit-Remaining", decision.remaining.to_string());
+            response.set_raw_header("RateLimit-Reset", decision.reset.to_string());
+            response.set_raw_header("RateLimit-Policy", format!("{};w={}", decision.limit.capacity, decision.limit.period));
+            if !decision.allowed {
+                response.set_raw_header("Retry-After", decision.retry_after.to_string());
+            }
+        }
+    }
+}
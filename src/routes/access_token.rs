@@ -0,0 +1,100 @@
+use rocket::*;
+use rocket_contrib::json::Json;
+use rocket_contrib::json;
+use rocket_contrib::uuid::Uuid;
+
+use crate::data::access_token::{self, AccessToken, CreatedAccessToken, NewAccessToken, ResponseAccessToken, MAX_EXPIRES_IN_DAYS, SCOPES};
+use crate::data::audit::{self, AuditAction, AuditEvent, ClientInfo};
+use crate::data::security::{JwtGuard, ADMIN_ROLE};
+use crate::data::store::Conn;
+use crate::data::users;
+use crate::routes::responses::ApiResponse;
+
+/// Tokens are managed from a session: a leaked token can't mint new ones
+fn session_only(guard: &JwtGuard) -> Result<(), ApiResponse> {
+    match guard.access_token() {
+        Some(_) => Err(ApiResponse::err(json!("access tokens can only be managed with a session"))),
+        None => Ok(()),
+    }
+}
+
+#[post("/tokens", format = "json", data = "<token>")]
+pub fn new_token_rt(connection: Conn, token: Json<NewAccessToken>, guard: JwtGuard, client: ClientInfo) -> ApiResponse {
+    if let Err(response) = session_only(&guard) {
+        return response;
+    }
+    let name = token.name.trim().to_string();
+    if name.is_empty() {
+        return ApiResponse::err(json!("a token needs a name"));
+    }
+    if token.scopes.is_empty() {
+        return ApiResponse::err(json!(format!("a token needs at least one scope among {}", SCOPES.join(", "))));
+    }
+    if let Some(unknown) = token.scopes.iter().find(|scope| !SCOPES.contains(&scope.as_str())) {
+        return ApiResponse::err(json!(format!("unknown scope {}", unknown)));
+    }
+    if token.expires_in_days.map(|days| days <= 0 || days > MAX_EXPIRES_IN_DAYS).unwrap_or(false) {
+        return ApiResponse::err(json!(format!("expires_in_days must be between 1 and {}", MAX_EXPIRES_IN_DAYS)));
+    }
+    match users::find_by_id(&connection, guard.user_id()) {
+        Ok(Some(user)) => {
+            if token.scopes.iter().any(|scope| scope == ADMIN_ROLE) && !user.has_role(ADMIN_ROLE) {
+                return ApiResponse::err(json!("only admins can create admin tokens"));
+            }
+            let mut scopes = token.scopes.clone();
+            scopes.sort();
+            scopes.dedup();
+            let (created, secret) = AccessToken::generate(guard.user_id().to_string(), name, scopes, token.expires());
+            match access_token::insert(&connection, &created) {
+                Ok(_) => {
+                    let user_id = guard.user_id().to_string();
+                    audit::record(&connection, &AuditEvent::new(AuditAction::AccessTokenCreated, Some(user_id.clone()), Some(user_id), &client).with_changes(created.scopes.clone()));
+                    ApiResponse::ok(json!(CreatedAccessToken {
+                        token: secret,
+                        info: ResponseAccessToken::from_token(&created),
+                    }))
+                },
+                Err(_) => ApiResponse::internal_err(),
+            }
+        },
+        Ok(None) => ApiResponse::err(json!(format!("id {} not found", guard.user_id()))),
+        Err(_) => ApiResponse::internal_err(),
+    }
+}
+
+#[get("/tokens")]
+pub fn token_list_rt(connection: Conn, guard: JwtGuard) -> ApiResponse {
+    if let Err(response) = session_only(&guard) {
+        return response;
+    }
+    match access_token::list_for(&connection, guard.user_id()) {
+        Ok(tokens) => ApiResponse::ok(json!(tokens.iter().map(ResponseAccessToken::from_token).collect::<Vec<ResponseAccessToken>>())),
+        Err(_) => ApiResponse::internal_err(),
+    }
+}
+
+#[delete("/tokens/<id>")]
+pub fn revoke_token_rt(connection: Conn, id: Uuid, guard: JwtGuard, client: ClientInfo) -> ApiResponse {
+    if let Err(response) = session_only(&guard) {
+        return response;
+    }
+    match access_token::list_for(&connection, guard.user_id()) {
+        Ok(tokens) => {
+            match tokens.into_iter().find(|token| token.id.to_string() == id.to_string()) {
+                Some(mut token) => {
+                    if token.revoked.is_none() {
+                        token.revoked = Some(chrono::Utc::now());
+                        if access_token::save(&connection, &token).is_err() {
+                            return ApiResponse::internal_err();
+                        }
+                        let user_id = guard.user_id().to_string();
+                        audit::record(&connection, &AuditEvent::new(AuditAction::AccessTokenRevoked, Some(user_id.clone()), Some(user_id), &client).with_changes(vec![token.id.to_string()]));
+                    }
+                    ApiResponse::ok(json!(ResponseAccessToken::from_token(&token)))
+                },
+                None => ApiResponse::err(json!(format!("token {} not found", id))),
+            }
+        },
+        Err(_) => ApiResponse::internal_err(),
+    }
+}
@@ -0,0 +1,90 @@
+use std::io::{Cursor, Read};
+use rocket::*;
+use rocket::http::ContentType;
+use rocket::response::{Content, Stream};
+use rocket_contrib::json;
+
+use crate::config::AppConfig;
+use crate::data::audit::ClientInfo;
+use crate::data::bulk::{self, BulkFormat, DuplicatePolicy, ExportReader, ImportOptions};
+use crate::data::migrations;
+use crate::data::store::Conn;
+use crate::data::security::AdminGuard;
+use crate::routes::responses::ApiResponse;
+
+// 16 MiB of CSV is a few hundred thousand users
+const IMPORT_LIMIT: u64 = 16 * 1024 * 1024;
+
+fn bulk_format(format: &Option<String>, content_type: Option<&ContentType>) -> Result<BulkFormat, ApiResponse> {
+    match format {
+        Some(f) => BulkFormat::parse(f).ok_or_else(|| ApiResponse::err(json!(format!("unknown format {}", f)))),
+        None => match content_type {
+            Some(ct) if ct.sub() == "csv" => Ok(BulkFormat::Csv),
+            Some(ct) if ct.sub() == "x-ndjson" || ct.sub() == "ndjson" => Ok(BulkFormat::Ndjson),
+            _ => Err(ApiResponse::err(json!("format missing"))),
+        },
+    }
+}
+
+fn content_type_of(format: BulkFormat) -> ContentType {
+    match format {
+        BulkFormat::Csv => ContentType::CSV,
+        BulkFormat::Ndjson => ContentType::new("application", "x-ndjson"),
+    }
+}
+
+#[post("/admin/migrations?<dry_run>")]
+pub fn migrations_rt(connection: Conn, config: State<AppConfig>, dry_run: Option<bool>, _admin: AdminGuard) -> ApiResponse {
+    match migrations::run(&connection, config.email_folding, dry_run.unwrap_or(false)) {
+        Ok(report) => ApiResponse::ok(json!(report)),
+        Err(_) => ApiResponse::internal_err(),
+    }
+}
+
+#[post("/admin/users/import?<format>&<dry_run>&<on_duplicate>&<invite>", data = "<data>")]
+#[allow(clippy::too_many_arguments)]
+pub fn import_users_rt(connection: Conn, config: State<AppConfig>, data: Data, content_type: Option<&ContentType>, format: Option<String>, dry_run: Option<bool>, on_duplicate: Option<String>, invite: Option<bool>, admin: AdminGuard, client: ClientInfo) -> ApiResponse {
+    let format = match bulk_format(&format, content_type) {
+        Ok(format) => format,
+        Err(e) => return e,
+    };
+    let on_duplicate = match on_duplicate {
+        Some(p) => match DuplicatePolicy::parse(&p) {
+            Some(policy) => policy,
+            None => return ApiResponse::err(json!(format!("unknown duplicate policy {}", p))),
+        },
+        None => DuplicatePolicy::Fail,
+    };
+    let options = ImportOptions {
+        format,
+        dry_run: dry_run.unwrap_or(false),
+        on_duplicate,
+        invite: invite.unwrap_or(false),
+        public_url: config.public_url.clone(),
+        email_folding: config.email_folding,
+    };
+    // Read whole first, a truncated upload would be imported in part
+    let mut upload = Vec::new();
+    if data.open().take(IMPORT_LIMIT + 1).read_to_end(&mut upload).is_err() {
+        return ApiResponse::err(json!("cannot read the upload"));
+    }
+    if upload.len() as u64 > IMPORT_LIMIT {
+        return ApiResponse::payload_too_large(json!(format!("imports take at most {} MiB", IMPORT_LIMIT / 1024 / 1024)));
+    }
+    match bulk::import(&connection, Cursor::new(upload), &options, Some(admin.0.id.to_string()), &client) {
+        Ok(report) => ApiResponse::ok(json!(report)),
+        Err(_) => ApiResponse::internal_err(),
+    }
+}
+
+#[get("/admin/users/export?<format>")]
+pub fn export_users_rt(connection: Conn, format: Option<String>, _admin: AdminGuard) -> Result<Content<Stream<ExportReader>>, ApiResponse> {
+    let format = match format {
+        Some(f) => BulkFormat::parse(&f).ok_or_else(|| ApiResponse::err(json!(format!("unknown format {}", f))))?,
+        None => BulkFormat::Ndjson,
+    };
+    match bulk::export(connection, format) {
+        Ok(reader) => Ok(Content(content_type_of(format), Stream::from(reader))),
+        Err(_) => Err(ApiResponse::internal_err()),
+    }
+}
@@ -0,0 +1,51 @@
+use rocket::*;
+use rocket::request::Form;
+use rocket_contrib::json;
+
+use crate::data::audit::{self, AuditAction, AuditFilter, ResponseAuditEvent};
+use crate::data::store::Conn;
+use crate::data::security::AdminGuard;
+use crate::routes::responses::ApiResponse;
+use crate::routes::query::parse_date;
+
+#[derive(FromForm, Debug)]
+pub struct AuditQuery {
+    pub user: Option<String>,
+    pub action: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[get("/audit?<query..>")]
+pub fn audit_list_rt(connection: Conn, query: Form<AuditQuery>, _admin: AdminGuard) -> ApiResponse {
+    let action = match &query.action {
+        Some(a) => match AuditAction::parse(a) {
+            Some(action) => Some(action),
+            None => return ApiResponse::err(json!(format!("unknown action {}", a))),
+        },
+        None => None,
+    };
+    let from = match parse_date(&query.from) {
+        Ok(from) => from,
+        Err(e) => return ApiResponse::err(json!(e)),
+    };
+    let to = match parse_date(&query.to) {
+        Ok(to) => to,
+        Err(e) => return ApiResponse::err(json!(e)),
+    };
+    let filter = AuditFilter {
+        user: query.user.clone(),
+        action,
+        from,
+        to,
+        limit: query.limit,
+    };
+    match audit::find(&connection, &filter) {
+        Ok(events) => {
+            let events: Vec<ResponseAuditEvent> = events.iter().map(ResponseAuditEvent::from_event).collect();
+            ApiResponse::ok(json!(events))
+        },
+        Err(_) => ApiResponse::internal_err(),
+    }
+}
@@ -1,23 +1,18 @@
 use rocket::*;
-use rocket::http::{Cookies, Cookie};
-use rocket_contrib::json::Json;
+use rocket::http::Cookies;
 use rocket_contrib::json;
 use serde::{Deserialize, Serialize};
 
-use r2d2_mongodb::mongodb as bson;
-use r2d2_mongodb::mongodb as mongodb;
-
-use bson::{bson, doc, Bson};
-use mongodb::db::ThreadedDatabase;
-
+use crate::config::AppConfig;
 use crate::data::security;
-use crate::data::mongo_connection::Conn;
-use crate::data::db::User;
+use crate::data::session::{self, Session};
+use crate::data::store::Conn;
+use crate::data::email_address::canonical_email_with;
+use crate::data::audit::{self, AuditAction, AuditEvent, ClientInfo};
+use crate::data::user_events::{self, UserEventKind};
+use crate::routes::negotiation::ApiBody;
 use crate::routes::responses::ApiResponse;
 
-// TODO in.env
-const COLLECTION: &str = "users";
-
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LoginUser {
     pub email: String,
@@ -27,35 +22,45 @@ pub struct LoginUser {
 #[derive(Serialize)]
 struct Authenticated {
     id: String,
+    /// To send back in the `X-CSRF-Token` header of requests changing something
+    csrf_token: String,
 }
 
-#[post("/login", format = "json", data = "<login>")]
-pub fn login_user(connection: Conn, login: Json<LoginUser>, mut cookies: Cookies) -> ApiResponse {
-    let user_coll = &connection.collection(COLLECTION);
-    match user_coll.find_one(Some(doc! { "email": login.email.clone() }), None) {
+#[post("/login", data = "<login>")]
+pub fn login_user(connection: Conn, config: State<AppConfig>, login: ApiBody<LoginUser>, mut cookies: Cookies, client: ClientInfo) -> ApiResponse {
+    let canonical = match canonical_email_with(&login.email, config.email_folding) {
+        Ok(canonical) => canonical,
+        Err(_) => { return ApiResponse::err(json!(format!("user {} not found",  login.email))); }
+    };
+    match connection.find_user_by_email(&canonical) {
         Ok(find_one) => {
             match find_one {
-                Some(found_user) => {
-                    let loaded_user_doc: Result<User, _> = bson::from_bson(Bson::Document(found_user));
-                    match loaded_user_doc {
-                        Ok(got_user) => {
-                            if got_user.match_password(&login.password) {
-                                let id = got_user.id.to_string();
-                                let cookie = security::sign_token(id.clone());
-                                match cookie {
-                                    Ok(c) => {
-                                        cookies.add(Cookie::new("t", c));
-                                        ApiResponse::ok(json!(Authenticated {
-                                            id,
-                                        }))
-                                    },
-                                    Err(_) => ApiResponse::err(json!("Could not set cookies"))
-                                }
-                            }
-                            else { ApiResponse::err(json!("Invalid password")) }
+                Some(got_user) => {
+                    if got_user.match_password(&login.password) {
+                        let id = got_user.id.to_string();
+                        let new_session = Session::new(id.clone(), config.token_lifetime, &client);
+                        if session::insert(&connection, &new_session).is_err() {
+                            return ApiResponse::internal_err();
+                        }
+                        let cookie = security::sign_token(&config, id.clone(), &new_session);
+                        match cookie {
+                            Ok(c) => {
+                                let csrf_token = security::add_auth_cookies(&mut cookies, &config, c, &new_session);
+                                audit::record(&connection, &AuditEvent::new(AuditAction::LoginSucceeded, Some(id.clone()), Some(id.clone()), &client));
+                                user_events::publish(&connection, UserEventKind::LoggedIn, &id, None);
+                                ApiResponse::ok(json!(Authenticated {
+                                    id,
+                                    csrf_token,
+                                }))
+                            },
+                            Err(_) => ApiResponse::err(json!("Could not set cookies"))
                         }
-                        Err(_) => ApiResponse::internal_err(),
-                    }                    
+                    }
+                    else {
+                        let id = got_user.id.to_string();
+                        audit::record(&connection, &AuditEvent::new(AuditAction::LoginFailed, None, Some(id), &client));
+                        ApiResponse::err(json!("Invalid password"))
+                    }
                 },
                 None => ApiResponse::err(json!(format!("user {} not found",  login.email))),
             }
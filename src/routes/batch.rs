@@ -0,0 +1,152 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+use rocket::*;
+use rocket::error::LaunchError;
+use rocket::http::{ContentType, Cookie, Header, Method, Status, StatusClass};
+use rocket::local::Client;
+use rocket::request::{self, FromRequest};
+use rocket_contrib::json;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::AppConfig;
+use crate::data::security::JwtGuard;
+use crate::routes::negotiation::ApiBody;
+use crate::routes::responses::ApiResponse;
+use crate::versioning::API_BASE;
+
+/// Passed on to every sub-request, so that they act as the caller of the batch
+const FORWARDED_HEADERS: [&str; 4] = ["Authorization", "X-CSRF-Token", "User-Agent", "X-Real-IP"];
+
+/// Dispatches sub-requests through a Rocket instance of its own, mounting the same routes and fairings
+pub struct Batcher(Client);
+impl Batcher {
+    pub fn new(rocket: Rocket) -> Result<Batcher, LaunchError> {
+        Client::untracked(rocket).map(Batcher)
+    }
+}
+
+/// What the batch request came with that the sub-requests need to pass the same guards
+pub struct Caller {
+    cookies: Vec<Cookie<'static>>,
+    headers: Vec<Header<'static>>,
+    remote: Option<SocketAddr>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Caller {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Caller, ()> {
+        let headers = FORWARDED_HEADERS.iter()
+            .filter_map(|name| request.headers().get_one(name).map(|value| Header::new(*name, value.to_string())))
+            .collect();
+        Outcome::Success(Caller {
+            cookies: request.cookies().iter().map(|cookie| cookie.clone().into_owned()).collect(),
+            headers,
+            remote: request.remote(),
+        })
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BatchRequest {
+    pub requests: Vec<SubRequest>,
+    /// Skips what follows the first sub-request not answered with a success
+    #[serde(default)]
+    pub stop_on_error: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SubRequest {
+    pub method: String,
+    /// Under `/api`, query string included
+    pub path: String,
+    pub body: Option<Value>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SubResponse {
+    /// None when skipped
+    pub status: Option<u16>,
+    pub body: Value,
+    pub skipped: bool,
+}
+impl SubResponse {
+    fn refused(status: Status, message: &str) -> Self {
+        SubResponse { status: Some(status.code), body: Value::String(message.to_string()), skipped: false }
+    }
+    fn skipped() -> Self {
+        SubResponse { status: None, body: Value::Null, skipped: true }
+    }
+    fn failed(&self) -> bool {
+        self.status.and_then(Status::from_code).map(|status| status.class() != StatusClass::Success).unwrap_or(true)
+    }
+}
+
+/// `/api/batch` and `/api/<version>/batch`, which would only recurse
+fn is_batch(path: &str) -> bool {
+    let path = path.split('?').next().unwrap_or("");
+    let segments: Vec<&str> = path.strip_prefix(API_BASE).unwrap_or("").split('/').filter(|segment| !segment.is_empty()).collect();
+    segments.last() == Some(&"batch") && segments.len() <= 2
+}
+
+fn dispatch(batcher: &Batcher, caller: &Caller, sub: &SubRequest) -> SubResponse {
+    let method = match Method::from_str(&sub.method.to_uppercase()) {
+        Ok(method @ Method::Get) | Ok(method @ Method::Post) | Ok(method @ Method::Put) | Ok(method @ Method::Patch) | Ok(method @ Method::Delete) => method,
+        _ => return SubResponse::refused(Status::MethodNotAllowed, &format!("method {} can't be batched", sub.method)),
+    };
+    if !sub.path.starts_with(&format!("{}/", API_BASE)) {
+        return SubResponse::refused(Status::BadRequest, &format!("path {} isn't under {}", sub.path, API_BASE));
+    }
+    if is_batch(&sub.path) {
+        return SubResponse::refused(Status::BadRequest, "batches can't be nested");
+    }
+    let mut request = batcher.0.req(method, sub.path.clone())
+        .header(Header::new("Accept", "application/json"))
+        .cookies(caller.cookies.clone());
+    for header in caller.headers.iter() {
+        request.add_header(header.clone());
+    }
+    if let Some(remote) = caller.remote {
+        request = request.remote(remote);
+    }
+    if let Some(body) = &sub.body {
+        request = request.header(ContentType::JSON).body(body.to_string());
+    }
+    let mut response = request.dispatch();
+    let status = response.status();
+    // Streams never end, they have no place in a batch
+    if response.content_type().map(|content_type| content_type.top() == "text" && content_type.sub() == "event-stream").unwrap_or(false) {
+        return SubResponse::refused(Status::NotAcceptable, "event streams can't be batched");
+    }
+    let body = match response.body_string() {
+        Some(text) if !text.is_empty() => serde_json::from_str(&text).unwrap_or(Value::String(text)),
+        _ => Value::Null,
+    };
+    SubResponse { status: Some(status.code), body, skipped: false }
+}
+
+/// Runs the sub-requests in order, each through the routes and guards it would go through on its own,
+/// with the credentials of the batch. Answers with their statuses and bodies, in the same order.
+#[post("/batch", data = "<batch>")]
+pub fn batch_rt(batcher: State<Batcher>, config: State<AppConfig>, batch: ApiBody<BatchRequest>, caller: Caller, _guard: JwtGuard) -> ApiResponse {
+    let batch = batch.into_inner();
+    if batch.requests.is_empty() {
+        return ApiResponse::unprocessable(json!("a batch needs at least one request"));
+    }
+    if batch.requests.len() > config.batch_max_size {
+        return ApiResponse::unprocessable(json!(format!("a batch takes at most {} requests", config.batch_max_size)));
+    }
+    let mut results = Vec::new();
+    let mut stopped = false;
+    for sub in batch.requests.iter() {
+        if stopped {
+            results.push(SubResponse::skipped());
+            continue;
+        }
+        let result = dispatch(&batcher, &caller, sub);
+        stopped = batch.stop_on_error && result.failed();
+        results.push(result);
+    }
+    ApiResponse::ok(json!({ "results": results }))
+}
@@ -0,0 +1,46 @@
+use rocket::*;
+use rocket::response;
+use rocket::http::{ContentType, Status};
+use rocket::response::{Responder, Response};
+use rocket_contrib::json;
+use rocket_contrib::json::JsonValue;
+
+use crate::data::store::{RetryAfter, DEFAULT_RETRY_AFTER};
+use crate::routes::responses::ApiResponse;
+
+/// A 503 telling the client when to come back
+pub struct Unavailable(u64);
+impl<'r> Responder<'r> for Unavailable {
+    fn respond_to(self, req: &Request) -> response::Result<'r> {
+        Response::build_from(json!("Service unavailable, try again later").respond_to(&req)?)
+            .status(Status::ServiceUnavailable)
+            .header(ContentType::JSON)
+            .raw_header("Retry-After", self.0.to_string())
+            .ok()
+    }
+}
+
+/// Reached whenever a guard couldn't get a database connection
+#[catch(503)]
+pub fn service_unavailable(req: &Request) -> Unavailable {
+    let RetryAfter(seconds) = *req.local_cache(|| RetryAfter(DEFAULT_RETRY_AFTER));
+    Unavailable(seconds)
+}
+
+/// Reached by requests the rate limiter turned away, its fairing adding `Retry-After`
+#[catch(429)]
+pub fn too_many_requests() -> ApiResponse {
+    ApiResponse::too_many_requests()
+}
+
+/// Reached by bodies in a format we don't read
+#[catch(415)]
+pub fn unsupported_media_type() -> ApiResponse {
+    ApiResponse::unsupported_media_type()
+}
+
+/// Reached when `Accept` takes none of our formats; answered in JSON all the same
+#[catch(406)]
+pub fn not_acceptable() -> JsonValue {
+    json!("Not acceptable, accept JSON, MessagePack or CBOR")
+}
@@ -0,0 +1,89 @@
+use rocket::*;
+use rocket::response::content;
+use rocket_contrib::json;
+use chrono::Utc;
+
+use crate::config::AppConfig;
+use crate::data::db::ResponseUser;
+use crate::data::{email_change, users};
+use crate::data::store::Conn;
+use crate::data::audit::{self, AuditAction, AuditEvent, ClientInfo};
+use crate::routes::pages::action_page;
+use crate::routes::responses::ApiResponse;
+
+/// Where the confirmation link leads, the change itself needs a POST
+#[get("/email/confirm/<_token>")]
+pub fn confirm_email_page_rt(_token: String) -> content::Html<String> {
+    action_page("Confirm your new email address", "Confirm this is your new address to start using it.", "Confirm")
+}
+
+#[post("/email/confirm/<token>")]
+pub fn confirm_email_rt(connection: Conn, config: State<AppConfig>, token: String, client: ClientInfo) -> ApiResponse {
+    match email_change::find_by_confirm_token(&connection, &token) {
+        Ok(Some(mut change)) => {
+            if !change.can_confirm() {
+                return ApiResponse::err(json!("invalid or expired token"));
+            }
+            match users::find_by_id(&connection, &change.user_id) {
+                Ok(Some(mut found_user)) => {
+                    if found_user.pending_email.as_ref() != Some(&change.new_email) {
+                        return ApiResponse::err(json!("invalid or expired token"));
+                    }
+                    let confirmed = found_user.confirm_email(&change.new_email, config.email_folding);
+                    change.confirmed = Some(Utc::now());
+                    match users::replace(&connection, &confirmed).and_then(|_| email_change::save(&connection, &change)) {
+                        Ok(_) => {
+                            audit::record(&connection, &AuditEvent::new(AuditAction::EmailChangeConfirmed, Some(change.user_id.clone()), Some(change.user_id.clone()), &client).with_changes(vec!["email".to_string()]));
+                            ApiResponse::ok(json!(ResponseUser::from_user(&confirmed)))
+                        },
+                        Err(_) => ApiResponse::internal_err(),
+                    }
+                },
+                Ok(None) => ApiResponse::err(json!("invalid or expired token")),
+                Err(_) => ApiResponse::internal_err(),
+            }
+        },
+        Ok(None) => ApiResponse::err(json!("invalid or expired token")),
+        Err(_) => ApiResponse::internal_err(),
+    }
+}
+
+/// Where the revert link leads, the change itself needs a POST
+#[get("/email/revert/<_token>")]
+pub fn revert_email_page_rt(_token: String) -> content::Html<String> {
+    action_page("Revert the change of your email address", "Revert the change to keep using this address for your account.", "Revert")
+}
+
+#[post("/email/revert/<token>")]
+pub fn revert_email_rt(connection: Conn, config: State<AppConfig>, token: String, client: ClientInfo) -> ApiResponse {
+    match email_change::find_by_revert_token(&connection, &token) {
+        Ok(Some(mut change)) => {
+            if !change.can_revert() {
+                return ApiResponse::err(json!("invalid or expired token"));
+            }
+            // Once confirmed, the old address was free for anybody to take
+            match email_change::email_in_use(&connection, &change.old_email, config.email_folding, Some(&change.user_id)) {
+                Ok(true) => { return ApiResponse::err(json!("email already in use")); },
+                Ok(false) => (),
+                Err(_) => { return ApiResponse::internal_err(); }
+            }
+            match users::find_by_id(&connection, &change.user_id) {
+                Ok(Some(mut found_user)) => {
+                    let reverted = found_user.restore_email(&change.old_email, config.email_folding);
+                    change.reverted = Some(Utc::now());
+                    match users::replace(&connection, &reverted).and_then(|_| email_change::save(&connection, &change)) {
+                        Ok(_) => {
+                            audit::record(&connection, &AuditEvent::new(AuditAction::EmailChangeReverted, None, Some(change.user_id.clone()), &client).with_changes(vec!["email".to_string()]));
+                            ApiResponse::ok(json!(ResponseUser::from_user(&reverted)))
+                        },
+                        Err(_) => ApiResponse::internal_err(),
+                    }
+                },
+                Ok(None) => ApiResponse::err(json!("invalid or expired token")),
+                Err(_) => ApiResponse::internal_err(),
+            }
+        },
+        Ok(None) => ApiResponse::err(json!("invalid or expired token")),
+        Err(_) => ApiResponse::internal_err(),
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use rocket::*;
+use rocket::http::ContentType;
+use rocket::request::{self, FromRequest};
+use rocket::response::{self, Responder, Response};
+use rocket_contrib::json;
+use chrono::Utc;
+
+use crate::config::AppConfig;
+use crate::data::security::{JwtGuard, ADMIN_ROLE};
+use crate::data::store::{Conn, Database};
+use crate::data::user_events::{self, UserEvent, MAX_BATCH};
+use crate::routes::responses::ApiResponse;
+
+/// How often the store is asked for new events
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Without events for that long a comment is sent, which keeps proxies from closing the
+/// connection and tells us when the client is gone
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+/// How long clients wait before reconnecting, in milliseconds
+const RETRY_MS: u64 = 3000;
+/// How long a missing id holds back the events after it. Ids are taken before the event is written,
+/// so a newer event can show up before an older one: past that, the older one is taken as lost.
+const GAP_GRACE_SECS: i64 = 5;
+/// What clients turned away for too many streams are told to wait, in seconds
+const BUSY_RETRY_AFTER: u64 = 30;
+
+/// Open streams per user, each holding one of the few workers
+#[derive(Default)]
+pub struct StreamSlots(Mutex<HashMap<String, usize>>);
+impl StreamSlots {
+    fn acquire(&self, user_id: &str, max: usize, per_user: usize) -> Option<StreamSlot> {
+        let mut open = self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mine = open.get(user_id).copied().unwrap_or(0);
+        if open.values().sum::<usize>() >= max || mine >= per_user {
+            return None;
+        }
+        open.insert(user_id.to_string(), mine + 1);
+        Some(StreamSlot { slots: self, user_id: user_id.to_string() })
+    }
+}
+
+/// Given back when the stream is dropped, however it ends
+pub struct StreamSlot<'r> {
+    slots: &'r StreamSlots,
+    user_id: String,
+}
+impl<'r> Drop for StreamSlot<'r> {
+    fn drop(&mut self) {
+        let mut open = self.slots.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        match open.get(&self.user_id).copied() {
+            Some(count) if count > 1 => { open.insert(self.user_id.clone(), count - 1); },
+            _ => { open.remove(&self.user_id); },
+        }
+    }
+}
+
+/// The id of the last event a reconnecting client got
+pub struct LastEventId(pub Option<i64>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for LastEventId {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<LastEventId, ()> {
+        Outcome::Success(LastEventId(request.headers().get_one("Last-Event-ID").and_then(|id| id.trim().parse().ok())))
+    }
+}
+
+/// Who sees what: admins every event, other users their own
+#[derive(Debug, Clone, PartialEq)]
+enum Audience {
+    Everyone,
+    User(String),
+}
+impl Audience {
+    /// `None` once the user is gone
+    fn of(connection: &Conn, guard: &JwtGuard) -> Result<Option<Audience>, ()> {
+        Ok(connection.find_user(guard.user_id())?.map(|user| {
+            let admin_scope = guard.access_token().map(|token| token.allows(ADMIN_ROLE)).unwrap_or(true);
+            if user.has_role(ADMIN_ROLE) && admin_scope { Audience::Everyone } else { Audience::User(guard.user_id().to_string()) }
+        }))
+    }
+
+    fn sees(&self, event: &UserEvent) -> bool {
+        match self {
+            Audience::Everyone => true,
+            Audience::User(id) => *id == event.user_id,
+        }
+    }
+}
+
+/// Polls the store for events and writes them out in the `text/event-stream` format,
+/// until the client goes away or its credentials no longer stand. Holds a worker for as long.
+pub struct EventStream<'r> {
+    database: &'r Database,
+    guard: JwtGuard,
+    audience: Audience,
+    last_id: i64,
+    /// Whether the credentials were still good at the last poll
+    open: bool,
+    _slot: StreamSlot<'r>,
+    pending: Vec<u8>,
+    position: usize,
+    /// Whether what was written has to be flushed to the client before waiting again
+    flush: bool,
+    last_sent: Instant,
+}
+
+impl<'r> EventStream<'r> {
+    fn write_event(&mut self, event: &UserEvent) {
+        let data = event.data();
+        self.pending.extend_from_slice(format!("id: {}\nevent: {}\ndata: {}\n\n", event.id, event.kind.name(), data).as_bytes());
+    }
+
+    /// Fills `pending` with the new events the audience may see, or a keep-alive comment when due.
+    /// Checks the credentials again first: revoked, they end the stream; a lost role narrows it.
+    fn poll(&mut self) {
+        let mut more = false;
+        match self.database.get() {
+            Ok(store) => {
+                let connection = Conn(store);
+                match (self.guard.still_valid(&connection), Audience::of(&connection, &self.guard)) {
+                    (Ok(true), Ok(Some(audience))) => {
+                        self.audience = audience;
+                        more = self.read_events(&connection);
+                    },
+                    (Ok(false), _) | (_, Ok(None)) => {
+                        self.open = false;
+                        return;
+                    },
+                    _ => log::warn!("could not check the credentials of an event stream"),
+                }
+            },
+            Err(e) => log::debug!("no database connection for the event stream: {}", e),
+        }
+        if self.pending.is_empty() && self.last_sent.elapsed() >= KEEP_ALIVE {
+            self.pending.extend_from_slice(b": keep-alive\n\n");
+        }
+        if self.pending.is_empty() && !more {
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// Whether there may be more to read right away
+    fn read_events(&mut self, connection: &Conn) -> bool {
+        let events = match user_events::after(connection, self.last_id) {
+            Ok(events) => events,
+            Err(_) => {
+                log::warn!("could not read user events after {}", self.last_id);
+                return false;
+            },
+        };
+        let grace = chrono::Duration::seconds(GAP_GRACE_SECS);
+        for event in events.iter() {
+            // Skipping past an event still being written would lose it for good
+            if event.id != self.last_id + 1 && Utc::now() - event.timestamp < grace {
+                return false;
+            }
+            self.last_id = event.id;
+            if self.audience.sees(event) {
+                self.write_event(event);
+            }
+        }
+        events.len() as i64 == MAX_BATCH
+    }
+}
+
+impl<'r> Read for EventStream<'r> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.position < self.pending.len() {
+                let n = buf.len().min(self.pending.len() - self.position);
+                buf[..n].copy_from_slice(&self.pending[self.position..self.position + n]);
+                self.position += n;
+                if self.position == self.pending.len() {
+                    self.pending.clear();
+                    self.position = 0;
+                    self.flush = true;
+                    self.last_sent = Instant::now();
+                }
+                return Ok(n);
+            }
+            // Rocket flushes what it has on `WouldBlock`, instead of waiting for a full chunk
+            if self.flush {
+                self.flush = false;
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            if !self.open || self.guard.expires().map(|expires| expires <= Utc::now()).unwrap_or(false) {
+                return Ok(0);
+            }
+            self.poll();
+        }
+    }
+}
+
+impl<'r> Responder<'r> for EventStream<'r> {
+    fn respond_to(self, _: &Request) -> response::Result<'r> {
+        Response::build()
+            .header(ContentType::new("text", "event-stream"))
+            .raw_header("Cache-Control", "no-cache")
+            .streamed_body(self)
+            .ok()
+    }
+}
+
+/// Streams the lifecycle events of users: all of them to admins, their own to the others.
+/// Starts with what follows `Last-Event-ID` when given, else with the events to come.
+/// Streams are limited in all and per user, those over the limits getting a 503.
+#[get("/events")]
+pub fn events_rt<'r>(connection: Conn, database: State<'r, Database>, config: State<AppConfig>, slots: State<'r, StreamSlots>, guard: JwtGuard, last_event_id: LastEventId) -> Result<EventStream<'r>, ApiResponse> {
+    let audience = match Audience::of(&connection, &guard) {
+        Ok(Some(audience)) => audience,
+        Ok(None) => return Err(ApiResponse::err(json!(format!("id {} not found", guard.user_id())))),
+        Err(_) => return Err(ApiResponse::internal_err()),
+    };
+    let slot = match slots.inner().acquire(guard.user_id(), config.event_streams, config.event_streams_per_user) {
+        Some(slot) => slot,
+        None => return Err(ApiResponse::unavailable(json!("Too many event streams open, try again later"), BUSY_RETRY_AFTER)),
+    };
+    let last_id = match last_event_id.0 {
+        Some(id) => id,
+        None => match user_events::last_id(&connection) {
+            Ok(id) => id,
+            Err(_) => return Err(ApiResponse::internal_err()),
+        },
+    };
+    Ok(EventStream {
+        database: database.inner(),
+        guard,
+        audience,
+        last_id,
+        open: true,
+        _slot: slot,
+        pending: format!("retry: {}\n\n", RETRY_MS).into_bytes(),
+        position: 0,
+        flush: false,
+        last_sent: Instant::now(),
+    })
+}
@@ -0,0 +1,257 @@
+use rocket::*;
+use rocket::http::{Method, StatusClass};
+use rocket::response::content;
+use rocket_contrib::json;
+use chrono::{DateTime, Utc};
+use juniper::{FieldError, FieldResult, GraphQLInputObject, GraphQLObject, RootNode, Value, ID};
+use juniper_rocket::{GraphQLRequest, GraphQLResponse};
+use sha2::{Digest, Sha256};
+
+use crate::config::AppConfig;
+use crate::data::audit::ClientInfo;
+use crate::data::db::{InsertableUser, ResponseUser, UserPassword};
+use crate::data::email_address::LocalPartFolding;
+use crate::data::security::{JwtGuard, JwtGuardError};
+use crate::data::store::Conn;
+use crate::data::user_search::{self, UserSearch, DEFAULT_PER_PAGE};
+use crate::data::users::{self, UserError};
+use crate::routes::idempotency::{idempotent, IdempotencyKey};
+use crate::routes::responses::ApiResponse;
+
+/// What resolvers get from the request: the same guards the REST routes take
+pub struct Context {
+    connection: Conn,
+    public_url: String,
+    email_folding: LocalPartFolding,
+    guard: Result<JwtGuard, JwtGuardError>,
+    client: ClientInfo,
+    /// Sent with GET, which the guards let through as a read: mutations are refused
+    read_only: bool,
+    idempotency_key: IdempotencyKey,
+    idempotency_ttl: chrono::Duration,
+}
+impl juniper::Context for Context {}
+
+impl Context {
+    fn guard(&self) -> FieldResult<&JwtGuard> {
+        match &self.guard {
+            Ok(guard) => Ok(guard),
+            Err(JwtGuardError::Csrf) => Err(FieldError::new("missing or invalid X-CSRF-Token header", Value::null())),
+            Err(JwtGuardError::InsufficientScope) => Err(FieldError::new("insufficient scope", Value::null())),
+            Err(JwtGuardError::Unavailable) => Err(FieldError::new("service unavailable, try again later", Value::null())),
+            Err(_) => Err(FieldError::new("user not authenticated", Value::null())),
+        }
+    }
+
+    /// The caller, allowed to change something
+    fn writer(&self) -> FieldResult<&JwtGuard> {
+        if self.read_only {
+            return Err(FieldError::new("mutations must be sent with POST", Value::null()));
+        }
+        self.guard()
+    }
+}
+
+fn user_error(e: UserError) -> FieldError {
+    FieldError::new(e.to_string(), Value::null())
+}
+
+fn internal_error() -> FieldError {
+    user_error(UserError::Failed)
+}
+
+#[derive(GraphQLObject)]
+#[graphql(name = "User")]
+pub struct UserNode {
+    id: ID,
+    name: String,
+    email: String,
+    /// The new address, until it is confirmed
+    pending_email: Option<String>,
+}
+impl From<ResponseUser> for UserNode {
+    fn from(user: ResponseUser) -> Self {
+        UserNode { id: ID::new(user.id), name: user.name, email: user.email, pending_email: user.pending_email }
+    }
+}
+
+/// The caller, and what it is authenticated with
+#[derive(GraphQLObject)]
+pub struct Me {
+    user: UserNode,
+    roles: Vec<String>,
+    /// `session` or `access_token`
+    auth: String,
+    scopes: Option<Vec<String>>,
+    expires: Option<DateTime<Utc>>,
+}
+
+#[derive(GraphQLObject)]
+pub struct UserPage {
+    items: Vec<UserNode>,
+    page: i32,
+    per_page: i32,
+    total: i32,
+}
+
+#[derive(GraphQLInputObject)]
+pub struct UserInput {
+    name: String,
+    email: String,
+    /// The current one, when updating
+    password: String,
+}
+impl From<UserInput> for InsertableUser {
+    fn from(input: UserInput) -> Self {
+        InsertableUser { name: input.name, email: input.email, password: input.password }
+    }
+}
+
+pub struct Query;
+
+#[juniper::object(Context = Context)]
+impl Query {
+    fn me(context: &Context) -> FieldResult<Me> {
+        let guard = context.guard()?;
+        match context.connection.find_user(guard.user_id()) {
+            Ok(Some(user)) => Ok(Me {
+                user: ResponseUser::from_user(&user).into(),
+                roles: user.roles.clone(),
+                auth: if guard.access_token().is_some() { "access_token" } else { "session" }.to_string(),
+                scopes: guard.access_token().map(|token| token.scopes.clone()),
+                expires: guard.expires(),
+            }),
+            Ok(None) => Err(user_error(UserError::NotFound(guard.user_id().to_string()))),
+            Err(_) => Err(internal_error()),
+        }
+    }
+
+    fn user(context: &Context, id: ID) -> FieldResult<Option<UserNode>> {
+        context.guard()?;
+        match context.connection.find_user(&id) {
+            Ok(found) => Ok(found.map(|user| ResponseUser::from_user(&user).into())),
+            Err(_) => Err(internal_error()),
+        }
+    }
+
+    fn user_by_email(context: &Context, email: String) -> FieldResult<Option<UserNode>> {
+        context.guard()?;
+        match users::find_by_email(&context.connection, &email, context.email_folding) {
+            Ok(found) => Ok(found.map(|user| ResponseUser::from_user(&user).into())),
+            Err(_) => Err(internal_error()),
+        }
+    }
+
+    /// Searches names and addresses for `q`, as `/api/users/search` does
+    fn users(context: &Context, q: Option<String>, page: Option<i32>, per_page: Option<i32>) -> FieldResult<UserPage> {
+        context.guard()?;
+        let search = UserSearch {
+            text: q,
+            page: page.map(i64::from).unwrap_or(1),
+            per_page: per_page.map(i64::from).unwrap_or(DEFAULT_PER_PAGE),
+            ..UserSearch::default()
+        };
+        match user_search::search(&context.connection, &search) {
+            Ok(found) => Ok(UserPage {
+                items: found.items.into_iter().map(UserNode::from).collect(),
+                page: found.page as i32,
+                per_page: found.per_page as i32,
+                total: found.total as i32,
+            }),
+            Err(_) => Err(internal_error()),
+        }
+    }
+}
+
+pub struct Mutation;
+
+#[juniper::object(Context = Context)]
+impl Mutation {
+    /// Signing up needs no authentication, as with `POST /api/users`, and takes an `Idempotency-Key` the same way:
+    /// a retry with the same key and input gets the account back. One signup per key, then.
+    fn create_user(context: &Context, input: UserInput) -> FieldResult<UserNode> {
+        if context.read_only {
+            return Err(FieldError::new("mutations must be sent with POST", Value::null()));
+        }
+        let user = InsertableUser::from(input);
+        let digest = match serde_json::to_vec(&user) {
+            Ok(bytes) => format!("{:x}", Sha256::digest(&bytes)),
+            Err(_) => return Err(internal_error()),
+        };
+        let response = idempotent(&context.connection, context.idempotency_ttl, &context.idempotency_key, None, &digest, || {
+            match users::create(&context.connection, user, context.email_folding, &context.client) {
+                Ok(user) => ApiResponse::ok(json!(ResponseUser::from_user(&user))),
+                Err(UserError::Failed) => ApiResponse::internal_err(),
+                Err(e) => ApiResponse::err(json!(e.to_string())),
+            }
+        });
+        let message = &response.message().0;
+        if response.status().class() != StatusClass::Success {
+            let reason = message.as_str().map(str::to_string).unwrap_or_else(|| message.to_string());
+            return Err(FieldError::new(reason, Value::null()));
+        }
+        serde_json::from_value::<ResponseUser>(message.clone()).map(UserNode::from).map_err(|_| internal_error())
+    }
+
+    fn update_user(context: &Context, id: ID, input: UserInput) -> FieldResult<UserNode> {
+        let guard = context.writer()?;
+        match users::update(&context.connection, &context.public_url, context.email_folding, &id, &input.into(), guard.user_id(), &context.client) {
+            Ok(user) => Ok(ResponseUser::from_user(&user).into()),
+            Err(e) => Err(user_error(e)),
+        }
+    }
+
+    fn change_password(context: &Context, id: ID, password: String, new_password: String) -> FieldResult<bool> {
+        let guard = context.writer()?;
+        let password = UserPassword { password, new_password: Some(new_password) };
+        match users::change_password(&context.connection, &id, &password, guard.user_id(), &context.client) {
+            Ok(_) => Ok(true),
+            Err(e) => Err(user_error(e)),
+        }
+    }
+
+    fn delete_user(context: &Context, id: ID, password: String) -> FieldResult<UserNode> {
+        let guard = context.writer()?;
+        match users::delete(&context.connection, &id, &password, guard.user_id(), &context.client) {
+            Ok(user) => Ok(ResponseUser::from_user(&user).into()),
+            Err(e) => Err(user_error(e)),
+        }
+    }
+}
+
+pub type Schema = RootNode<'static, Query, Mutation>;
+
+pub fn schema() -> Schema {
+    Schema::new(Query, Mutation)
+}
+
+fn context(connection: Conn, config: &AppConfig, guard: Result<JwtGuard, JwtGuardError>, client: ClientInfo, key: IdempotencyKey) -> Context {
+    Context {
+        connection,
+        public_url: config.public_url.clone(),
+        email_folding: config.email_folding,
+        guard,
+        client,
+        read_only: key.method() == Method::Get,
+        idempotency_key: key,
+        idempotency_ttl: config.idempotency_ttl,
+    }
+}
+
+#[get("/graphiql")]
+pub fn graphiql_rt() -> content::Html<String> {
+    juniper_rocket::graphiql_source("/graphql")
+}
+
+/// Queries only; access tokens need the `read` scope
+#[get("/graphql?<request..>")]
+pub fn get_graphql_rt(connection: Conn, config: State<AppConfig>, schema: State<Schema>, guard: Result<JwtGuard, JwtGuardError>, client: ClientInfo, key: IdempotencyKey, request: GraphQLRequest) -> GraphQLResponse {
+    request.execute(&schema, &context(connection, &config, guard, client, key))
+}
+
+/// Queries and mutations, a list of them for a batch. Cookie sessions need `X-CSRF-Token`, access tokens the `write` scope.
+/// Signups take an `Idempotency-Key`, as with `POST /api/users`, but only `RATE_LIMITS` naming this path limit them.
+#[post("/graphql", data = "<request>")]
+pub fn post_graphql_rt(connection: Conn, config: State<AppConfig>, schema: State<Schema>, guard: Result<JwtGuard, JwtGuardError>, client: ClientInfo, key: IdempotencyKey, request: GraphQLRequest) -> GraphQLResponse {
+    request.execute(&schema, &context(connection, &config, guard, client, key))
+}
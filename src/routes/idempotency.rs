@@ -0,0 +1,78 @@
+use rocket::*;
+use rocket::http::{Method, Status, StatusClass};
+use rocket::request::{self, FromRequest};
+use rocket_contrib::json::JsonValue;
+use rocket_contrib::json;
+
+use crate::data::idempotency::{self, Claim, MAX_KEY_LENGTH};
+use crate::data::store::Conn;
+use crate::routes::responses::ApiResponse;
+
+pub const KEY_HEADER: &str = "Idempotency-Key";
+
+/// The `Idempotency-Key` a client sent, with the method and path it came with.
+/// The path is the one under the mount point, as `/api/users` and `/api/v1/users` are the same request.
+pub struct IdempotencyKey {
+    key: Option<String>,
+    method: Method,
+    path: String,
+}
+
+impl IdempotencyKey {
+    pub fn method(&self) -> Method {
+        self.method
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for IdempotencyKey {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<IdempotencyKey, ()> {
+        Outcome::Success(IdempotencyKey {
+            key: request.headers().get_one(KEY_HEADER).map(str::trim).filter(|key| !key.is_empty()).map(str::to_string),
+            method: request.method(),
+            path: mounted_path(request),
+        })
+    }
+}
+
+fn mounted_path(request: &Request) -> String {
+    let path = request.uri().path();
+    request.route()
+        .and_then(|route| path.strip_prefix(route.base()))
+        .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+        .unwrap_or(path)
+        .to_string()
+}
+
+/// Runs `handler` once per key and caller, retries with the same body getting its response again.
+/// Only successes are kept: after a failure, the same key can be tried again.
+pub fn idempotent(connection: &Conn, ttl: chrono::Duration, key: &IdempotencyKey, caller: Option<&str>, body_digest: &str, handler: impl FnOnce() -> ApiResponse) -> ApiResponse {
+    let value = match &key.key {
+        Some(value) => value,
+        None => return handler(),
+    };
+    if value.len() > MAX_KEY_LENGTH {
+        return ApiResponse::err(json!(format!("{} longer than {} characters", KEY_HEADER, MAX_KEY_LENGTH)));
+    }
+    let id = idempotency::record_id(caller, value);
+    let fingerprint = idempotency::fingerprint(key.method.as_str(), &key.path, body_digest);
+    match idempotency::claim(connection, &id, &fingerprint, ttl) {
+        Ok(Claim::New(record)) => {
+            let response = handler();
+            let kept = if response.status().class() == StatusClass::Success {
+                idempotency::complete(connection, record, response.status().code, &response.message().0)
+            } else {
+                idempotency::release(connection, &record)
+            };
+            if kept.is_err() {
+                log::warn!("could not settle the {} of a {} {}", KEY_HEADER, key.method, key.path);
+            }
+            response
+        },
+        Ok(Claim::Replay { status, response }) => ApiResponse::replay(Status::from_code(status).unwrap_or(Status::Ok), JsonValue(response)),
+        Ok(Claim::Mismatch) => ApiResponse::unprocessable(json!(format!("{} already used for a different request", KEY_HEADER))),
+        Ok(Claim::InProgress) => ApiResponse::conflict(json!(format!("a request with this {} is in progress, try again later", KEY_HEADER))),
+        Err(_) => ApiResponse::internal_err(),
+    }
+}
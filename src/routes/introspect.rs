@@ -0,0 +1,98 @@
+use rocket::*;
+use rocket::request::Form;
+use rocket_contrib::json;
+use serde::Serialize;
+
+use crate::config::AppConfig;
+use crate::data::access_token::{self, TOKEN_PREFIX};
+use crate::data::db::User;
+use crate::data::security::{self, IntrospectionGuard, ADMIN_ROLE};
+use crate::data::store::Conn;
+use crate::data::users;
+use crate::routes::responses::ApiResponse;
+
+/// As in RFC 7662, `token_type_hint` is accepted but the token tells its own type
+#[derive(FromForm, Debug)]
+pub struct IntrospectionRequest {
+    pub token: String,
+    pub token_type_hint: Option<String>,
+}
+
+/// RFC 7662 answer: all there is to an unknown, expired or revoked token is `active: false`
+#[derive(Serialize, Debug, Default)]
+struct Introspection {
+    active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_type: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iat: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    jti: Option<String>,
+}
+
+/// A session can do whatever its user can
+fn session_scope(user: &User) -> String {
+    if user.has_role(ADMIN_ROLE) { "read write admin".to_string() } else { "read write".to_string() }
+}
+
+fn introspect_session_token(connection: &Conn, config: &AppConfig, token: &str) -> Result<Option<Introspection>, ()> {
+    let info = match security::inspect_token(config, token.to_string()) {
+        Ok(info) => info,
+        Err(_) => return Ok(None),
+    };
+    let session = match security::live_session(connection, &info)? {
+        Some(session) => session,
+        None => return Ok(None),
+    };
+    Ok(users::find_by_id(connection, &info.id)?.map(|user| Introspection {
+        active: true,
+        scope: Some(session_scope(&user)),
+        token_type: Some("session"),
+        sub: Some(info.id),
+        username: Some(user.email),
+        exp: Some(info.expires.timestamp()),
+        iat: Some(info.issued.timestamp()),
+        jti: Some(session.id.to_string()),
+    }))
+}
+
+fn introspect_access_token(connection: &Conn, secret: &str) -> Result<Option<Introspection>, ()> {
+    let token = match access_token::find_by_secret(connection, secret)? {
+        Some(token) if token.is_active() => token,
+        _ => return Ok(None),
+    };
+    Ok(users::find_by_id(connection, &token.user_id)?.map(|user| Introspection {
+        active: true,
+        scope: Some(token.scopes.join(" ")),
+        token_type: Some("access_token"),
+        sub: Some(token.user_id.clone()),
+        username: Some(user.email),
+        exp: token.expires.map(|expires| expires.timestamp()),
+        iat: Some(token.created.timestamp()),
+        jti: Some(token.id.to_string()),
+    }))
+}
+
+/// For our other services to check the tokens they are handed, with an admin-scoped access token of their own:
+/// the `admin` scope is enough, `write` isn't needed
+#[post("/introspect", data = "<form>")]
+pub fn introspect_rt(connection: Conn, config: State<AppConfig>, form: Form<IntrospectionRequest>, _admin: IntrospectionGuard) -> ApiResponse {
+    let found = if form.token.starts_with(TOKEN_PREFIX) {
+        introspect_access_token(&connection, &form.token)
+    } else {
+        introspect_session_token(&connection, &config, &form.token)
+    };
+    match found {
+        Ok(Some(introspection)) => ApiResponse::ok(json!(introspection)),
+        Ok(None) => ApiResponse::ok(json!(Introspection::default())),
+        Err(_) => ApiResponse::internal_err(),
+    }
+}
@@ -0,0 +1,58 @@
+use rocket::*;
+use rocket::request::Form;
+use rocket::response::content;
+use rocket_contrib::json::Json;
+use rocket_contrib::json;
+
+use crate::data::db::ResponseUser;
+use crate::data::invite::{self, SetPassword};
+use crate::data::users;
+use crate::data::store::Conn;
+use crate::data::audit::{self, AuditAction, AuditEvent, ClientInfo};
+use crate::routes::pages::password_page;
+use crate::routes::responses::ApiResponse;
+
+/// Where the mailed invite leads, its form posting the password back
+#[get("/invites/<_token>")]
+pub fn invite_page_rt(_token: String) -> content::Html<String> {
+    password_page("Welcome", "An account was created for you, choose its password.", "Set password")
+}
+
+#[post("/invites/<token>", format = "json", data = "<password>")]
+pub fn accept_invite_rt(connection: Conn, token: String, password: Json<SetPassword>, client: ClientInfo) -> ApiResponse {
+    accept(&connection, &token, &password, &client)
+}
+
+#[post("/invites/<token>", format = "form", data = "<password>")]
+pub fn accept_invite_form_rt(connection: Conn, token: String, password: Form<SetPassword>, client: ClientInfo) -> ApiResponse {
+    accept(&connection, &token, &password, &client)
+}
+
+fn accept(connection: &Conn, token: &str, password: &SetPassword, client: &ClientInfo) -> ApiResponse {
+    match invite::find_by_token(connection, token) {
+        Ok(Some(found_invite)) => {
+            if !found_invite.can_accept() {
+                return ApiResponse::err(json!("invalid or expired token"));
+            }
+            match users::find_by_id(connection, &found_invite.user_id) {
+                Ok(Some(mut found_user)) => {
+                    // The invite reached the mailbox, so the address is verified too
+                    found_user.email_verified = true;
+                    let updated = found_user.update_password(&password.password);
+                    match users::replace(connection, &updated).and_then(|_| invite::mark_accepted(connection, &found_invite)) {
+                        Ok(_) => {
+                            let user_id = updated.id.to_string();
+                            audit::record(connection, &AuditEvent::new(AuditAction::PasswordChanged, Some(user_id.clone()), Some(user_id), client).with_changes(vec!["password".to_string()]));
+                            ApiResponse::ok(json!(ResponseUser::from_user(&updated)))
+                        },
+                        Err(_) => ApiResponse::internal_err(),
+                    }
+                },
+                Ok(None) => ApiResponse::err(json!("invalid or expired token")),
+                Err(_) => ApiResponse::internal_err(),
+            }
+        },
+        Ok(None) => ApiResponse::err(json!("invalid or expired token")),
+        Err(_) => ApiResponse::internal_err(),
+    }
+}
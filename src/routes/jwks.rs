@@ -0,0 +1,12 @@
+use rocket::*;
+use rocket_contrib::json;
+
+use crate::config::AppConfig;
+use crate::routes::responses::ApiResponse;
+
+/// The public keys tokens are signed with, for other services to verify them on their own.
+/// Empty while tokens are signed with the shared secret.
+#[get("/.well-known/jwks.json")]
+pub fn jwks_rt(config: State<AppConfig>) -> ApiResponse {
+    ApiResponse::ok(json!(config.jwt_keys.jwks()))
+}
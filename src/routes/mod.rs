@@ -1,4 +1,24 @@
 pub mod ping;
 pub mod user;
 pub mod auth;
-pub mod responses;
\ No newline at end of file
+pub mod responses;
+pub mod negotiation;
+pub mod audit;
+pub mod email;
+pub mod admin;
+pub mod query;
+pub mod invite;
+pub mod catchers;
+pub mod access_token;
+pub mod session;
+pub mod jwks;
+pub mod introspect;
+pub mod cors;
+pub mod rate_limit;
+pub mod v1;
+pub mod graphql;
+pub mod events;
+pub mod webhooks;
+pub mod idempotency;
+pub mod batch;
+pub mod pages;
@@ -0,0 +1,141 @@
+use std::io::Read;
+use std::ops::Deref;
+use rocket::{Data, Request};
+use rocket::data::{self, FromDataSimple};
+use rocket::http::{ContentType, MediaType, Status};
+use rocket::Outcome::{Failure, Success};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+
+/// Same as rocket_contrib's `Json`, under the same `json` limit
+const DEFAULT_LIMIT: u64 = 1 << 20;
+
+/// The encodings the API speaks, JSON unless told otherwise
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    MsgPack,
+    Cbor,
+}
+impl Format {
+    fn of(media_type: &MediaType) -> Option<Format> {
+        if media_type.top() != "application" {
+            return None;
+        }
+        match media_type.sub().as_str().to_lowercase().as_str() {
+            "json" => Some(Format::Json),
+            "msgpack" | "x-msgpack" => Some(Format::MsgPack),
+            "cbor" => Some(Format::Cbor),
+            _ => None,
+        }
+    }
+
+    /// The format the client prefers in `Accept`, wildcards meaning JSON. `None` when it takes none of ours.
+    pub fn accepted(request: &Request) -> Option<Format> {
+        let accept = match request.accept() {
+            Some(accept) => accept,
+            None => return Some(Format::Json),
+        };
+        // The best weight so far, and whether it was named rather than reached through a wildcard
+        let mut best: Option<(Format, f32, bool)> = None;
+        for media in accept.iter() {
+            let weight = media.weight_or(1.0);
+            let media_type = media.media_type();
+            let (format, named) = match Format::of(media_type) {
+                Some(format) => (format, true),
+                None if media_type.top() == "*" || (media_type.top() == "application" && media_type.sub() == "*") => (Format::Json, false),
+                None => continue,
+            };
+            let better = match best {
+                Some((_, best_weight, best_named)) => weight > best_weight || (weight == best_weight && named && !best_named),
+                None => true,
+            };
+            if weight > 0.0 && better {
+                best = Some((format, weight, named));
+            }
+        }
+        best.map(|(format, _, _)| format)
+    }
+
+    pub fn content_type(self) -> ContentType {
+        match self {
+            Format::Json => ContentType::JSON,
+            Format::MsgPack => ContentType::MsgPack,
+            Format::Cbor => ContentType::new("application", "cbor"),
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Format::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            // Maps keep their field names, as in JSON
+            Format::MsgPack => rmp_serde::to_vec_named(value).map_err(|e| e.to_string()),
+            Format::Cbor => serde_cbor::to_vec(value).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Format::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            Format::MsgPack => rmp_serde::from_read_ref(bytes).map_err(|e| e.to_string()),
+            Format::Cbor => serde_cbor::from_slice(bytes).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BodyError {
+    UnsupportedMediaType,
+    Io(std::io::Error),
+    Malformed(String),
+}
+
+/// A request body in any of our formats, as told by `Content-Type`, JSON when there's none
+#[derive(Debug)]
+pub struct ApiBody<T> {
+    value: T,
+    digest: String,
+}
+
+impl<T> ApiBody<T> {
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+
+    /// SHA-256 of the bytes as sent, which tells a retry from a different request
+    pub fn digest(&self) -> &str {
+        &self.digest
+    }
+}
+
+impl<T> Deref for ApiBody<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T: DeserializeOwned> FromDataSimple for ApiBody<T> {
+    type Error = BodyError;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, Self::Error> {
+        let format = match request.content_type() {
+            None => Format::Json,
+            Some(content_type) => match Format::of(content_type.media_type()) {
+                Some(format) => format,
+                None => return Failure((Status::UnsupportedMediaType, BodyError::UnsupportedMediaType)),
+            },
+        };
+        let limit = request.limits().get("json").unwrap_or(DEFAULT_LIMIT);
+        let mut bytes = Vec::new();
+        if let Err(e) = data.open().take(limit).read_to_end(&mut bytes) {
+            return Failure((Status::BadRequest, BodyError::Io(e)));
+        }
+        match format.decode(&bytes) {
+            Ok(value) => Success(ApiBody { value, digest: format!("{:x}", Sha256::digest(&bytes)) }),
+            Err(e) => Failure((Status::UnprocessableEntity, BodyError::Malformed(e))),
+        }
+    }
+}
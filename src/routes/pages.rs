@@ -0,0 +1,20 @@
+use rocket::response::content;
+
+fn form_page(title: &str, text: &str, fields: &str, button: &str) -> content::Html<String> {
+    content::Html(format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><meta name=\"robots\" content=\"noindex\"><title>{title}</title></head>\n\
+        <body>\n<h1>{title}</h1>\n<p>{text}</p>\n<form method=\"post\">{fields}<button type=\"submit\">{button}</button></form>\n</body>\n</html>\n",
+        title = title, text = text, fields = fields, button = button,
+    ))
+}
+
+/// A page for the links we mail, which only submits a form back to the same address.
+/// Following the link changes nothing, so that prefetchers and scanners opening it do no harm.
+pub fn action_page(title: &str, text: &str, button: &str) -> content::Html<String> {
+    form_page(title, text, "", button)
+}
+
+/// Like `action_page`, the form asking for a new `password` first
+pub fn password_page(title: &str, text: &str, button: &str) -> content::Html<String> {
+    form_page(title, text, "<input type=\"password\" name=\"password\" autocomplete=\"new-password\" required> ", button)
+}
@@ -0,0 +1,12 @@
+use chrono::{DateTime, Utc};
+
+/// Parses an optional RFC 3339 date coming from a query string
+pub fn parse_date(date: &Option<String>) -> Result<Option<DateTime<Utc>>, String> {
+    match date {
+        Some(d) => match DateTime::parse_from_rfc3339(d) {
+            Ok(parsed) => Ok(Some(parsed.with_timezone(&Utc))),
+            Err(_) => Err(format!("invalid date {}", d)),
+        },
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,8 @@
+use rocket::*;
+use rocket::http::Status;
+
+/// Where the `RateLimiter` fairing sends requests over their limit, the catcher telling the rest
+#[get("/rate-limited")]
+pub fn rate_limited_rt() -> Status {
+    Status::TooManyRequests
+}
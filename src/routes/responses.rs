@@ -1,40 +1,134 @@
+use std::io::Cursor;
 use rocket::*;
 use rocket::response;
-use rocket::http::{ContentType, Status};
+use rocket::http::Status;
 use rocket::response::{Responder, Response};
 use rocket_contrib::json::JsonValue;
 use rocket_contrib::json;
 
+use crate::routes::negotiation::Format;
+
+/// Set on responses replayed for a retried `Idempotency-Key`
+pub const REPLAYED_HEADER: &str = "Idempotent-Replayed";
+
 #[derive(Debug)]
 pub struct ApiResponse {
     status: Status,
     message: JsonValue,
+    /// Sent before, for a retry carrying the same `Idempotency-Key`
+    replayed: bool,
+    /// Seconds to wait before trying again, sent as `Retry-After`
+    retry_after: Option<u64>,
 }
 impl ApiResponse {
     pub fn ok(message: JsonValue) -> Self {
         ApiResponse {
             status: Status::Ok,
             message: message,
+            replayed: false,
+            retry_after: None,
         }
     }
     pub fn err(message: JsonValue) -> Self {
         ApiResponse {
             status: Status::InternalServerError,
             message: message,
+            replayed: false,
+            retry_after: None,
+        }
+    }
+    pub fn too_many_requests() -> Self {
+        ApiResponse {
+            status: Status::TooManyRequests,
+            message: json!("Too many requests, try again later"),
+            replayed: false,
+            retry_after: None,
+        }
+    }
+    pub fn unsupported_media_type() -> Self {
+        ApiResponse {
+            status: Status::UnsupportedMediaType,
+            message: json!("Unsupported media type, send JSON, MessagePack or CBOR"),
+            replayed: false,
+            retry_after: None,
+        }
+    }
+    /// A well-formed request we can't act on, such as an `Idempotency-Key` that came with another request before
+    pub fn unprocessable(message: JsonValue) -> Self {
+        ApiResponse {
+            status: Status::UnprocessableEntity,
+            message: message,
+            replayed: false,
+            retry_after: None,
+        }
+    }
+    pub fn payload_too_large(message: JsonValue) -> Self {
+        ApiResponse {
+            status: Status::PayloadTooLarge,
+            message: message,
+            replayed: false,
+            retry_after: None,
+        }
+    }
+    /// The first request with the `Idempotency-Key` is still being handled
+    pub fn conflict(message: JsonValue) -> Self {
+        ApiResponse {
+            status: Status::Conflict,
+            message: message,
+            replayed: false,
+            retry_after: None,
+        }
+    }
+    pub fn replay(status: Status, message: JsonValue) -> Self {
+        ApiResponse {
+            status,
+            message,
+            replayed: true,
+            retry_after: None,
+        }
+    }
+    /// Busy for now, the client being told when to come back
+    pub fn unavailable(message: JsonValue, retry_after: u64) -> Self {
+        ApiResponse {
+            status: Status::ServiceUnavailable,
+            message: message,
+            replayed: false,
+            retry_after: Some(retry_after),
         }
     }
     pub fn internal_err() -> Self {
         ApiResponse {
             status: Status::InternalServerError,
             message: json!("Internal server error"),
+            replayed: false,
+            retry_after: None,
         }
     }
+    pub fn status(&self) -> Status {
+        self.status
+    }
+    pub fn message(&self) -> &JsonValue {
+        &self.message
+    }
 }
+/// Encoded in the format asked for in `Accept`, a 406 if there's none we speak
 impl<'r> Responder<'r> for ApiResponse {
     fn respond_to(self, req: &Request) -> response::Result<'r> {
-        Response::build_from(self.message.respond_to(&req).unwrap())
-            .status(self.status)
-            .header(ContentType::JSON)
-            .ok()
+        let format = Format::accepted(req).ok_or(Status::NotAcceptable)?;
+        let body = format.encode(&self.message.0).map_err(|e| {
+            log::error!("could not encode a response as {:?}: {}", format, e);
+            Status::InternalServerError
+        })?;
+        let mut response = Response::build();
+        response.status(self.status)
+            .header(format.content_type())
+            .sized_body(Cursor::new(body));
+        if self.replayed {
+            response.raw_header(REPLAYED_HEADER, "true");
+        }
+        if let Some(seconds) = self.retry_after {
+            response.raw_header("Retry-After", seconds.to_string());
+        }
+        response.ok()
     }
 }
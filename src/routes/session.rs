@@ -0,0 +1,42 @@
+use rocket::*;
+use rocket_contrib::json;
+use rocket_contrib::uuid::Uuid;
+
+use crate::data::audit::{self, AuditAction, AuditEvent, ClientInfo};
+use crate::data::security::JwtGuard;
+use crate::data::session::{self, ResponseSession};
+use crate::data::store::Conn;
+use crate::routes::responses::ApiResponse;
+
+#[get("/sessions")]
+pub fn session_list_rt(connection: Conn, guard: JwtGuard) -> ApiResponse {
+    match session::active_for(&connection, guard.user_id()) {
+        Ok(sessions) => ApiResponse::ok(json!(sessions.iter()
+            .map(|s| ResponseSession::from_session(s, guard.session_id()))
+            .collect::<Vec<ResponseSession>>())),
+        Err(_) => ApiResponse::internal_err(),
+    }
+}
+
+#[delete("/sessions/<id>")]
+pub fn revoke_session_rt(connection: Conn, id: Uuid, guard: JwtGuard, client: ClientInfo) -> ApiResponse {
+    match session::find_by_id(&connection, &id.to_string()) {
+        Ok(Some(mut found)) => {
+            // Someone else's session might as well not exist
+            if found.user_id != guard.user_id() {
+                return ApiResponse::err(json!(format!("session {} not found", id)));
+            }
+            if found.revoked.is_none() {
+                found.revoked = Some(chrono::Utc::now());
+                if session::save(&connection, &found).is_err() {
+                    return ApiResponse::internal_err();
+                }
+                let user_id = guard.user_id().to_string();
+                audit::record(&connection, &AuditEvent::new(AuditAction::SessionsRevoked, Some(user_id.clone()), Some(user_id), &client).with_changes(vec![found.id.to_string()]));
+            }
+            ApiResponse::ok(json!(ResponseSession::from_session(&found, guard.session_id())))
+        },
+        Ok(None) => ApiResponse::err(json!(format!("session {} not found", id))),
+        Err(_) => ApiResponse::internal_err(),
+    }
+}
@@ -1,99 +1,102 @@
 use rocket::*;
-use rocket_contrib::json::Json;
+use rocket::request::Form;
 use rocket_contrib::json;
 use rocket_contrib::uuid::Uuid;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
 
-use r2d2_mongodb::mongodb as bson;
-use r2d2_mongodb::mongodb as mongodb;
-
-use bson::{bson, doc, Bson};
-use mongodb::db::ThreadedDatabase;
-use mongodb::coll::options::{ReturnDocument, FindOneAndUpdateOptions};
-
-use crate::data::db::{User, InsertableUser, ResponseUser, UserPassword};
-use crate::data::mongo_connection::Conn;
+use crate::data::db::{InsertableUser, ResponseUser, UserPassword};
+use crate::config::AppConfig;
+use crate::data::store::Conn;
+use crate::data::users::{self, UserError};
+use crate::routes::idempotency::{idempotent, IdempotencyKey};
+use crate::routes::negotiation::ApiBody;
 use crate::routes::responses::ApiResponse;
+use crate::routes::query::parse_date;
 use crate::data::security::JwtGuard;
+use crate::data::audit::ClientInfo;
+use crate::data::email_address::canonical_email_with;
+use crate::data::user_search::{self, MatchMode, UserSearch, DEFAULT_PER_PAGE};
 
-const COLLECTION: &str = "users";
+#[derive(FromForm, Debug)]
+pub struct SearchQuery {
+    pub q: Option<String>,
+    pub name: Option<String>,
+    pub email: Option<String>,
+    pub mode: Option<String>,
+    pub created_from: Option<String>,
+    pub created_to: Option<String>,
+    pub updated_from: Option<String>,
+    pub updated_to: Option<String>,
+    pub verified: Option<bool>,
+    pub role: Option<String>,
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+}
+
+fn user_error(e: UserError) -> ApiResponse {
+    match e {
+        UserError::Failed => ApiResponse::internal_err(),
+        e => ApiResponse::err(json!(e.to_string())),
+    }
+}
 
+/// The caller, and what it is authenticated with
+#[derive(Serialize, Debug)]
+struct Me {
+    #[serde(flatten)]
+    user: ResponseUser,
+    roles: Vec<String>,
+    /// `session` or `access_token`
+    auth: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scopes: Option<Vec<String>>,
+    expires: Option<DateTime<Utc>>,
+}
+
+#[get("/me")]
+pub fn me_rt(connection: Conn, guard: JwtGuard) -> ApiResponse {
+    match connection.find_user(guard.user_id()) {
+        Ok(Some(user)) => ApiResponse::ok(json!(Me {
+            user: ResponseUser::from_user(&user),
+            roles: user.roles.clone(),
+            auth: if guard.access_token().is_some() { "access_token" } else { "session" },
+            scopes: guard.access_token().map(|token| token.scopes.clone()),
+            expires: guard.expires(),
+        })),
+        Ok(None) => ApiResponse::err(json!(format!("id {} not found", guard.user_id()))),
+        Err(_) => ApiResponse::internal_err(),
+    }
+}
 
 #[get("/users")]
 pub fn user_list_rt(connection: Conn, _guard : JwtGuard) -> ApiResponse {
-    let user_coll = &connection.collection(COLLECTION);
-    match  user_coll.count(None, None) {
+    match connection.count_users() {
         Ok(res) => ApiResponse::ok(json!([res])),
         Err(_) => ApiResponse::internal_err(),
     }   
 }
 
-#[post("/users", format = "json", data = "<user>")]
-pub fn new_user_rt(connection: Conn, user: Json<InsertableUser>) -> ApiResponse {
-    let user_coll = &connection.collection(COLLECTION);
-    match bson::to_bson(&User::from_insertable((*user).clone())) {
-        Ok(serialized) => {
-            match serialized.as_document() {
-                Some(document) => {
-                    match user_coll.insert_one(document.to_owned(), None) {
-                        Ok(inserted) => {
-                            match inserted.inserted_id {
-                                Some(id) => {
-                                    match user_coll.find_one(Some(doc! { "_id":  id }), None) {
-                                        Ok(find_one) => {
-                                            match find_one {
-                                                Some(found_user) => {
-                                                    let loaded_user_doc: Result<User, _> = bson::from_bson(Bson::Document(found_user));
-                                                    match loaded_user_doc {
-                                                        Ok(loaded_user) => ApiResponse::ok(json!(ResponseUser::from_user(&loaded_user))),
-                                                        Err(_) => ApiResponse::internal_err(),
-                                                    }
-                                                },
-                                                None => ApiResponse::internal_err(),
-                                            }
-                                        },
-                                        Err(_) => ApiResponse::internal_err(),
-                                    }
-                                },
-                                None => match inserted.write_exception {
-                                    Some(wite_error) =>{
-                                        match wite_error.write_error {
-                                            Some(err) =>{
-                                                match err.code {
-                                                    11000 => ApiResponse::err(json!("email already in use")),
-                                                    _ => ApiResponse::internal_err(),
-                                                }
-                                            },
-                                            None => ApiResponse::internal_err(),
-                                        }
-                                    },
-                                    None => ApiResponse::internal_err(),
-                                }
-                            }
-                        },                    
-                        Err(_) => ApiResponse::internal_err(),
-                    }
-                },
-                None => ApiResponse::internal_err(),
-            }
-        },
-        Err(_) => ApiResponse::internal_err(),
-    }
+/// A retry sent with the same `Idempotency-Key` gets the account it created, from wherever it comes:
+/// nobody's signed in yet, the key and the request it came with are all there is to go by.
+#[post("/users", data = "<user>")]
+pub fn new_user_rt(connection: Conn, config: State<AppConfig>, user: ApiBody<InsertableUser>, key: IdempotencyKey, client: ClientInfo) -> ApiResponse {
+    let digest = user.digest().to_string();
+    idempotent(&connection, config.idempotency_ttl, &key, None, &digest, || {
+        match users::create(&connection, user.into_inner(), config.email_folding, &client) {
+            Ok(new_user) => ApiResponse::ok(json!(ResponseUser::from_user(&new_user))),
+            Err(e) => user_error(e),
+        }
+    })
 }
 
 #[get("/users/<id>")]
 pub fn info_user_rt(connection: Conn, id: Uuid, _guard : JwtGuard) -> ApiResponse {
-    let user_coll = &connection.collection(COLLECTION);
     let id =  id.to_string();
-    match user_coll.find_one(Some(doc! { "_id": id.clone() }), None) {
+    match connection.find_user(&id) {
         Ok(find_one) => {
             match find_one {
-                Some(found_user) => {
-                    let found_user_doc: Result<User, _> = bson::from_bson(Bson::Document(found_user));
-                    match found_user_doc {
-                        Ok(found_user) => ApiResponse::ok(json!(ResponseUser::from_user(&found_user))),
-                        Err(_) => ApiResponse::internal_err(),
-                    }
-                }
+                Some(found_user) => ApiResponse::ok(json!(ResponseUser::from_user(&found_user))),
                 None => ApiResponse::err(json!(format!("id {} not found",  id)))
             }
         },
@@ -101,170 +104,79 @@ pub fn info_user_rt(connection: Conn, id: Uuid, _guard : JwtGuard) -> ApiRespons
     }
 }
 
-#[put("/users/<id>", format = "json", data = "<user>")]
-pub fn update_user_rt(connection: Conn, user: Json<InsertableUser>, id: Uuid, _guard : JwtGuard) -> ApiResponse {
-    let user_coll = &connection.collection(COLLECTION);
-    let id =  id.to_string();
-    match user_coll.find_one(Some(doc! { "_id": id.clone() }), None) {
-        Ok(find_one) => {
-            match find_one {
-                Some(found_user) => {
-                    let found_user_doc: Result<User, _> = bson::from_bson(Bson::Document(found_user));
-                    match found_user_doc {
-                        Ok(mut found_user) => {
-                            if found_user.match_password(&user.password) {
-                                // Check the email does not yet exist
-                                match user_coll.find_one(Some(doc! { "email": &user.email }), None) {
-                                    Ok(mail_query_result) => {
-                                        match mail_query_result {
-                                            Some(_) => { return ApiResponse::err(json!("email already in use")); },
-                                            None => ()
-                                        }
-                                    },
-                                    Err(_) => { return ApiResponse::internal_err(); }
-                                }
-                                let insertable = found_user.update_user(&user.name, &user.email);
-                                match bson::to_bson(&insertable) {
-                                    Ok(serialized) => {
-                                        let document = serialized.as_document().unwrap();
-                                        let mut opt = FindOneAndUpdateOptions::new();
-                                        opt.return_document = Some(ReturnDocument::After);
-                                        match user_coll.find_one_and_replace(
-                                            doc! { "_id": id.clone() },
-                                            document.to_owned(),
-                                            Some(opt)
-                                        ) {
-                                            Ok(updated_one) => {
-                                                match updated_one {
-                                                    Some(updated_user) => {
-                                                        let updated_user_doc: Result<User, _> = bson::from_bson(Bson::Document(updated_user));
-                                                        match updated_user_doc {
-                                                            Ok(updated) => ApiResponse::ok(json!(ResponseUser::from_user(&updated))),
-                                                            Err(_) => ApiResponse::internal_err(),
-                                                        }                                                        
-                                                    },
-                                                    None => ApiResponse::err(json!(format!("id {} not found",  id))),
-                                                }
-                                            },                    
-                                            Err(_) => ApiResponse::internal_err(),
-                                        }
-                                    },
-                                    Err(_) => ApiResponse::internal_err(),
-                                }
-                            }
-                            else { ApiResponse::err(json!("user not authenticated")) }
-                        },
-                        Err(_) => ApiResponse::internal_err(),
-                    }
-                },
-                None => ApiResponse::err(json!(format!("id {} not found",  id))),
-            }            
-        },
-        Err(_) => ApiResponse::internal_err(),
+#[put("/users/<id>", data = "<user>")]
+pub fn update_user_rt(connection: Conn, config: State<AppConfig>, user: ApiBody<InsertableUser>, id: Uuid, guard : JwtGuard, client: ClientInfo) -> ApiResponse {
+    match users::update(&connection, &config.public_url, config.email_folding, &id.to_string(), &user, guard.user_id(), &client) {
+        Ok(updated) => ApiResponse::ok(json!(ResponseUser::from_user(&updated))),
+        Err(e) => user_error(e),
     }
 }
 
-#[delete("/users/<id>", format = "json", data = "<user>")]
-pub fn delete_user_rt(connection: Conn, user: Json<UserPassword>, id: Uuid, _guard : JwtGuard) -> ApiResponse {
-    let user_coll = &connection.collection(COLLECTION);
-    let id =  id.to_string();
-    match user_coll.find_one(Some(doc! { "_id": id.clone() }), None) {
-        Ok(find_one) => {
-            match find_one {
-                Some(found_user) => {
-                    let found_user_doc: Result<User, _> = bson::from_bson(Bson::Document(found_user));
-                    match found_user_doc{
-                        Ok(found_user) => {
-                            if found_user.match_password(&user.password) {
-                                match user_coll.find_one_and_delete(doc! { "_id": id.clone() }, None) {
-                                    Ok(deleted_result) => {
-                                        match deleted_result {
-                                            Some(deleted_user) => {
-                                                let deleted_doc: Result<User, _> = bson::from_bson(Bson::Document(deleted_user));
-                                                match deleted_doc {
-                                                    Ok(deleted) => ApiResponse::ok(json!(ResponseUser::from_user(&deleted))),
-                                                    Err(_) => ApiResponse::internal_err(),
-                                                }                                
-                                            },
-                                            None => ApiResponse::err(json!(format!("id {} not found",  id)))
-                                        }
-                                    },
-                                    Err(_) => ApiResponse::internal_err(),
-                                }
-                            }
-                            else { ApiResponse::err(json!("user not authenticated")) }
-                        },
-                        Err(_) => ApiResponse::internal_err(),
-                    }
-                },
-                None => ApiResponse::err(json!(format!("id {} not found",  id)))
-            }
-        },
-        Err(_) => ApiResponse::internal_err(),
-    }    
+#[delete("/users/<id>", data = "<user>")]
+pub fn delete_user_rt(connection: Conn, user: ApiBody<UserPassword>, id: Uuid, guard : JwtGuard, client: ClientInfo) -> ApiResponse {
+    match users::delete(&connection, &id.to_string(), &user.password, guard.user_id(), &client) {
+        Ok(deleted) => ApiResponse::ok(json!(ResponseUser::from_user(&deleted))),
+        Err(e) => user_error(e),
+    }
 }
 
-#[patch("/users/<id>", format = "json", data = "<user>")]
-pub fn patch_user_rt(connection: Conn, user: Json<UserPassword>, id: Uuid, _guard : JwtGuard) -> ApiResponse {
-    let user_coll = &connection.collection(COLLECTION);
-    let id =  id.to_string();
-    match &user.new_password {
-        Some(passw) => {
-            match user_coll.find_one(Some(doc! { "_id": id.clone() }), None) {
-                Ok(find_one) => {
-                    match find_one {
-                        Some(found_user) => {
-                            let found_user_doc: Result<User, _> = bson::from_bson(Bson::Document(found_user));
-                            match found_user_doc {
-                                Ok(mut found_user) => {
-                                    if found_user.match_password(&user.password) {
-                                        let insertable = found_user.update_password(&passw);
-                                        match bson::to_bson(&insertable) {
-                                            Ok(serialized) => {
-                                                let document = serialized.as_document().unwrap();
-                                                match user_coll.find_one_and_replace(
-                                                    doc! { "_id": id.clone() },
-                                                    document.to_owned(),
-                                                    None
-                                                ) {
-                                                    Ok(_) => ApiResponse::ok(json!("Password updated")),                   
-                                                    Err(_) => ApiResponse::err(json!("Failed to update password")),
-                                                }
-                                            },
-                                            Err(_) => ApiResponse::internal_err(),
-                                        }
-                                    }
-                                    else { ApiResponse::err(json!("user not authenticated")) }
-                                },
-                                Err(_) => ApiResponse::internal_err(),
-                            }
-                        },
-                        None => ApiResponse::err(json!(format!("id {} not found",  id)))
-                    }
-                },
-                Err(_) => ApiResponse::internal_err(),
-            }
-        },
-        None => ApiResponse::err(json!("Password not provided"))
+#[patch("/users/<id>", data = "<user>")]
+pub fn patch_user_rt(connection: Conn, user: ApiBody<UserPassword>, id: Uuid, guard : JwtGuard, client: ClientInfo) -> ApiResponse {
+    match users::change_password(&connection, &id.to_string(), &user, guard.user_id(), &client) {
+        Ok(_) => ApiResponse::ok(json!("Password updated")),
+        Err(e) => user_error(e),
     }
 }
 
 #[get("/users/<email>", rank = 2)]
-pub fn id_user_rt(connection: Conn, email: String, _guard : JwtGuard) -> ApiResponse {
-    let user_coll = &connection.collection(COLLECTION);
-    match user_coll.find_one(Some(doc! { "email": email.clone() }), None) {
+pub fn id_user_rt(connection: Conn, config: State<AppConfig>, email: String, _guard : JwtGuard) -> ApiResponse {
+    let canonical = match canonical_email_with(&email, config.email_folding) {
+        Ok(canonical) => canonical,
+        Err(_) => { return ApiResponse::err(json!(format!("user {} not found",  email))); }
+    };
+    match connection.find_user_by_email(&canonical) {
         Ok(find_one) => {
             match find_one {
-                Some(found_user) => {
-                    let loaded_user_doc: Result<User, _> = bson::from_bson(Bson::Document(found_user));
-                    match loaded_user_doc {
-                        Ok(loaded_user) => ApiResponse::ok(json!(ResponseUser::from_user(&loaded_user))),
-                        Err(_) => ApiResponse::internal_err(),
-                    }                    
-                },
+                Some(loaded_user) => ApiResponse::ok(json!(ResponseUser::from_user(&loaded_user))),
                 None => ApiResponse::err(json!(format!("user {} not found",  email))),
             }
         },
         Err(_) => ApiResponse::internal_err(),
     }
 }
+
+#[get("/users/search?<query..>")]
+pub fn search_user_rt(connection: Conn, query: Form<SearchQuery>, _guard : JwtGuard) -> ApiResponse {
+    let mode = match &query.mode {
+        Some(m) => match MatchMode::parse(m) {
+            Some(mode) => mode,
+            None => return ApiResponse::err(json!(format!("unknown match mode {}", m))),
+        },
+        None => MatchMode::Prefix,
+    };
+    let mut dates = Vec::new();
+    for date in &[&query.created_from, &query.created_to, &query.updated_from, &query.updated_to] {
+        match parse_date(date) {
+            Ok(parsed) => dates.push(parsed),
+            Err(e) => return ApiResponse::err(json!(e)),
+        }
+    }
+    let search = UserSearch {
+        text: query.q.clone(),
+        name: query.name.clone(),
+        email: query.email.clone(),
+        mode,
+        created_from: dates[0],
+        created_to: dates[1],
+        updated_from: dates[2],
+        updated_to: dates[3],
+        verified: query.verified,
+        role: query.role.clone(),
+        page: query.page.unwrap_or(1),
+        per_page: query.per_page.unwrap_or(DEFAULT_PER_PAGE),
+    };
+    match user_search::search(&connection, &search) {
+        Ok(page) => ApiResponse::ok(json!(page)),
+        Err(_) => ApiResponse::internal_err(),
+    }
+}
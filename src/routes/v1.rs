@@ -0,0 +1,43 @@
+use rocket::{routes, Route};
+
+use crate::routes;
+
+/// The first version of the API, also served at `/api`
+pub fn routes() -> Vec<Route> {
+    routes![
+        routes::user::user_list_rt,
+        routes::user::new_user_rt,
+        routes::user::info_user_rt,
+        routes::user::update_user_rt,
+        routes::user::delete_user_rt,
+        routes::user::patch_user_rt,
+        routes::user::id_user_rt,
+        routes::user::search_user_rt,
+        routes::user::me_rt,
+        routes::auth::login_user,
+        routes::audit::audit_list_rt,
+        routes::email::confirm_email_page_rt,
+        routes::email::confirm_email_rt,
+        routes::email::revert_email_page_rt,
+        routes::email::revert_email_rt,
+        routes::admin::migrations_rt,
+        routes::admin::import_users_rt,
+        routes::admin::export_users_rt,
+        routes::invite::invite_page_rt,
+        routes::invite::accept_invite_rt,
+        routes::invite::accept_invite_form_rt,
+        routes::access_token::new_token_rt,
+        routes::access_token::token_list_rt,
+        routes::access_token::revoke_token_rt,
+        routes::session::session_list_rt,
+        routes::session::revoke_session_rt,
+        routes::introspect::introspect_rt,
+        routes::events::events_rt,
+        routes::webhooks::new_webhook_rt,
+        routes::webhooks::webhook_list_rt,
+        routes::webhooks::delete_webhook_rt,
+        routes::webhooks::delivery_list_rt,
+        routes::webhooks::redeliver_rt,
+        routes::batch::batch_rt,
+    ]
+}
@@ -0,0 +1,92 @@
+use rocket::*;
+use rocket_contrib::json;
+use rocket_contrib::uuid::Uuid;
+
+use crate::config::AppConfig;
+use crate::data::security::AdminGuard;
+use crate::data::store::Conn;
+use crate::data::user_events::UserEventKind;
+use crate::data::webhooks::{self, CreatedWebhook, NewWebhook, ResponseWebhook, Webhook};
+use crate::routes::idempotency::{idempotent, IdempotencyKey};
+use crate::routes::negotiation::ApiBody;
+use crate::routes::responses::ApiResponse;
+
+/// Takes an `Idempotency-Key`, as `POST /api/users` does
+#[post("/admin/webhooks", data = "<webhook>")]
+pub fn new_webhook_rt(connection: Conn, config: State<AppConfig>, webhook: ApiBody<NewWebhook>, key: IdempotencyKey, admin: AdminGuard) -> ApiResponse {
+    let admin_id = admin.0.id.to_string();
+    idempotent(&connection, config.idempotency_ttl, &key, Some(&admin_id), webhook.digest(), || create_webhook(&connection, &config, &webhook, &admin_id))
+}
+
+fn create_webhook(connection: &Conn, config: &AppConfig, webhook: &NewWebhook, admin_id: &str) -> ApiResponse {
+    let url = webhook.url.trim().to_string();
+    if !webhooks::valid_url(&url) {
+        return ApiResponse::err(json!("a webhook needs an http:// or https:// url"));
+    }
+    if !config.webhooks.allow_private && webhooks::private_url(&url) {
+        return ApiResponse::err(json!("a webhook can't lead to a loopback, private or link-local address"));
+    }
+    let mut events = Vec::new();
+    for name in webhook.events.iter() {
+        match UserEventKind::parse(name) {
+            Some(kind) if !events.contains(&kind) => events.push(kind),
+            Some(_) => (),
+            None => return ApiResponse::err(json!(format!("unknown event {}", name))),
+        }
+    }
+    let created = Webhook::generate(url, events, admin_id.to_string());
+    match webhooks::insert(connection, &created) {
+        Ok(_) => ApiResponse::ok(json!(CreatedWebhook {
+            secret: created.secret.clone(),
+            info: ResponseWebhook::from_webhook(&created),
+        })),
+        Err(_) => ApiResponse::internal_err(),
+    }
+}
+
+#[get("/admin/webhooks")]
+pub fn webhook_list_rt(connection: Conn, _admin: AdminGuard) -> ApiResponse {
+    match webhooks::list(&connection) {
+        Ok(found) => ApiResponse::ok(json!(found.iter().map(ResponseWebhook::from_webhook).collect::<Vec<ResponseWebhook>>())),
+        Err(_) => ApiResponse::internal_err(),
+    }
+}
+
+#[delete("/admin/webhooks/<id>")]
+pub fn delete_webhook_rt(connection: Conn, id: Uuid, _admin: AdminGuard) -> ApiResponse {
+    match webhooks::find(&connection, &id.to_string()) {
+        Ok(Some(webhook)) => {
+            match webhooks::delete(&connection, &id.to_string()) {
+                Ok(_) => ApiResponse::ok(json!(ResponseWebhook::from_webhook(&webhook))),
+                Err(_) => ApiResponse::internal_err(),
+            }
+        },
+        Ok(None) => ApiResponse::err(json!(format!("webhook {} not found", id))),
+        Err(_) => ApiResponse::internal_err(),
+    }
+}
+
+/// The latest deliveries, each with its attempts
+#[get("/admin/webhooks/<id>/deliveries")]
+pub fn delivery_list_rt(connection: Conn, id: Uuid, _admin: AdminGuard) -> ApiResponse {
+    match webhooks::find(&connection, &id.to_string()) {
+        Ok(Some(_)) => {
+            match webhooks::deliveries(&connection, &id.to_string()) {
+                Ok(deliveries) => ApiResponse::ok(json!(deliveries)),
+                Err(_) => ApiResponse::internal_err(),
+            }
+        },
+        Ok(None) => ApiResponse::err(json!(format!("webhook {} not found", id))),
+        Err(_) => ApiResponse::internal_err(),
+    }
+}
+
+/// Sends a delivery again, whatever became of it, as a new delivery
+#[post("/admin/webhooks/<id>/deliveries/<delivery_id>/redeliver")]
+pub fn redeliver_rt(connection: Conn, id: Uuid, delivery_id: Uuid, _admin: AdminGuard) -> ApiResponse {
+    match webhooks::redeliver(&connection, &id.to_string(), &delivery_id.to_string()) {
+        Ok(Some(delivery)) => ApiResponse::ok(json!(delivery)),
+        Ok(None) => ApiResponse::err(json!(format!("delivery {} not found", delivery_id))),
+        Err(_) => ApiResponse::internal_err(),
+    }
+}
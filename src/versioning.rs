@@ -0,0 +1,151 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use rocket::{Request, Response, Rocket, Route};
+use rocket::fairing::{Fairing, Info, Kind};
+
+use crate::config::ConfigReader;
+
+/// Where every version is mounted, as `/api/<version>`
+pub const API_BASE: &str = "/api";
+
+/// A version being retired: announced by `Deprecation`, gone after `Sunset`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Deprecation {
+    pub version: String,
+    pub since: DateTime<Utc>,
+    pub sunset: Option<DateTime<Utc>>,
+}
+impl Deprecation {
+    /// `VERSION SINCE [SUNSET]`, as in `v1 2026-01-01 2026-07-01`
+    fn parse(spec: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = spec.split_whitespace().collect();
+        if fields.len() < 2 || fields.len() > 3 {
+            return Err(format!("{} isn't VERSION SINCE [SUNSET]", spec));
+        }
+        let since = parse_day(fields[1])?;
+        let sunset = match fields.get(2) {
+            Some(sunset) => Some(parse_day(sunset)?),
+            None => None,
+        };
+        if sunset.map(|sunset| sunset <= since).unwrap_or(false) {
+            return Err(format!("{} comes to its sunset before being deprecated", fields[0]));
+        }
+        Ok(Deprecation { version: fields[0].to_string(), since, sunset })
+    }
+}
+
+/// A day, as of midnight UTC, or an RFC 3339 date
+fn parse_day(date: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(day) = NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+        return Ok(DateTime::from_utc(day.and_hms(0, 0, 0), Utc));
+    }
+    DateTime::parse_from_rfc3339(date)
+        .map(|date| date.with_timezone(&Utc))
+        .map_err(|_| format!("invalid date {}", date))
+}
+
+/// The path as served through the `/api` alias, `/api/v1/users` being `/api/users`,
+/// so that whatever is set for a path holds in every version. Versions are `v` and a number.
+pub fn unversioned_path(path: &str) -> String {
+    let segment = match path.strip_prefix(API_BASE).and_then(|rest| rest.strip_prefix('/')) {
+        Some(rest) => rest.split('/').next().unwrap_or(""),
+        None => return path.to_string(),
+    };
+    if segment.len() > 1 && segment.starts_with('v') && segment[1..].bytes().all(|b| b.is_ascii_digit()) {
+        format!("{}{}", API_BASE, &path[API_BASE.len() + 1 + segment.len()..])
+    } else {
+        path.to_string()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct VersioningConfig {
+    pub deprecations: Vec<Deprecation>,
+}
+impl VersioningConfig {
+    /// `API_DEPRECATIONS` lists deprecated versions separated by `;`, none by default
+    pub fn read(reader: &mut ConfigReader) -> Self {
+        let mut deprecations = Vec::new();
+        for spec in reader.get("API_DEPRECATIONS").unwrap_or_default().split(';').map(str::trim).filter(|spec| !spec.is_empty()) {
+            match Deprecation::parse(spec) {
+                Ok(deprecation) => deprecations.push(deprecation),
+                Err(e) => reader.invalid("API_DEPRECATIONS", &e),
+            }
+        }
+        VersioningConfig { deprecations }
+    }
+}
+
+/// The routes of one version of the API, with handlers and DTOs of its own
+pub struct ApiVersion {
+    pub name: &'static str,
+    pub routes: Vec<Route>,
+}
+impl ApiVersion {
+    pub fn new(name: &'static str, routes: Vec<Route>) -> Self {
+        ApiVersion { name, routes }
+    }
+}
+
+fn http_date(date: &DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Mounts each version at `/api/<version>`, side by side, the first one at `/api` too
+/// for clients calling it from before versions. Responses from deprecated versions,
+/// through the alias as well, carry `Deprecation` and `Sunset`.
+pub struct Versioning {
+    versions: Vec<ApiVersion>,
+    config: VersioningConfig,
+}
+impl Versioning {
+    pub fn new(versions: Vec<ApiVersion>, config: VersioningConfig) -> Self {
+        Versioning { versions, config }
+    }
+
+    /// The version a path is served by, if it is one of the API's
+    fn version_of(&self, path: &str) -> Option<&'static str> {
+        let rest = path.strip_prefix(API_BASE)?;
+        if !rest.is_empty() && !rest.starts_with('/') {
+            return None;
+        }
+        let segment = rest.trim_start_matches('/').split('/').next().unwrap_or("");
+        self.versions.iter()
+            .find(|version| version.name == segment)
+            .or_else(|| self.versions.first())
+            .map(|version| version.name)
+    }
+}
+
+impl Fairing for Versioning {
+    fn info(&self) -> Info {
+        Info {
+            name: "API versioning",
+            kind: Kind::Attach | Kind::Response,
+        }
+    }
+
+    fn on_attach(&self, rocket: Rocket) -> Result<Rocket, Rocket> {
+        let mut rocket = rocket;
+        if let Some(aliased) = self.versions.first() {
+            rocket = rocket.mount(API_BASE, aliased.routes.clone());
+        }
+        for version in self.versions.iter() {
+            rocket = rocket.mount(&format!("{}/{}", API_BASE, version.name), version.routes.clone());
+        }
+        Ok(rocket)
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let path = request.uri().path();
+        let deprecation = match self.version_of(path)
+            .and_then(|name| self.config.deprecations.iter().find(|deprecation| deprecation.version == name)) {
+            Some(deprecation) => deprecation,
+            None => return,
+        };
+        log::warn!("{} {} called deprecated API version {}", request.method(), path, deprecation.version);
+        response.set_raw_header("Deprecation", format!("@{}", deprecation.since.timestamp()));
+        if let Some(sunset) = deprecation.sunset {
+            response.set_raw_header("Sunset", http_date(&sunset));
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use lazy_static;
+use rocket::http::{ContentType, Header, Status};
+use rocket_tut::data::access_token::{self, CreatedAccessToken, ResponseAccessToken, TOKEN_PREFIX};
+use rocket_tut::data::db::ResponseUser;
+use serde_json;
+
+mod common;
+
+fn bearer(token: &str) -> Header<'static> {
+    Header::new("Authorization", format!("Bearer {}", token))
+}
+
+#[test]
+fn access_token_test(){
+    let client = common::setup();
+    let mut response_new_user = client.post("/api/users")
+        .header(ContentType::JSON)
+        .body(r##"{
+            "name": "Tina Doe",
+            "email": "tina.doe@m.com",
+            "password": "123456"
+        }"##)
+        .dispatch();
+    assert_eq!(response_new_user.status(), Status::Ok);
+    let response_body = response_new_user.body_string().expect("Response Body");
+    let user: ResponseUser = serde_json::from_str(&response_body.as_str()).expect("Valid User Response");
+    assert_eq!(common::login(client, "tina.doe@m.com", "123456"), Status::Ok);
+
+    // Past what a date can hold
+    let mut response = client.post("/api/tokens")
+        .header(common::csrf())
+        .header(ContentType::JSON)
+        .body(r##"{
+            "name": "forever",
+            "scopes": ["read"],
+            "expires_in_days": 9223372036854775807
+        }"##)
+        .dispatch();
+    assert_eq!(response.status(), Status::InternalServerError);
+    assert_eq!(response.body_string(), Some("\"expires_in_days must be between 1 and 3650\"".to_string()));
+
+    let mut response = client.post("/api/tokens")
+        .header(common::csrf())
+        .header(ContentType::JSON)
+        .body(r##"{
+            "name": "ci",
+            "scopes": ["read"],
+            "expires_in_days": 30
+        }"##)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let created: CreatedAccessToken = serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid Token Response");
+    assert!(created.token.starts_with(TOKEN_PREFIX));
+    assert!(created.token.starts_with(&created.info.hint));
+
+    // Reading is in scope, writing isn't
+    let response = client.get(format!("/api/users/{}", user.id)).header(bearer(&created.token)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let response = client.put(format!("/api/users/{}", user.id))
+        .header(ContentType::JSON)
+        .header(bearer(&created.token))
+        .body(r##"{
+            "name": "Tina Hacked",
+            "email": "tina.doe@m.com",
+            "password": "123456"
+        }"##)
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    // A token can't manage tokens
+    let response = client.get("/api/tokens").header(bearer(&created.token)).dispatch();
+    assert_eq!(response.status(), Status::InternalServerError);
+
+    // The list never shows the secret, but tells when the token was last used
+    let mut response = client.get("/api/tokens").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let body = response.body_string().expect("Response Body");
+    assert!(!body.contains(&created.token));
+    let tokens: Vec<ResponseAccessToken> = serde_json::from_str(&body).expect("Valid Token List");
+    assert_eq!(tokens.len(), 1);
+    assert!(tokens[0].last_used.is_some());
+
+    // A request that loaded the token before it was revoked doesn't bring it back when noting its use
+    let connection = common::connection();
+    let mut loaded = access_token::find_by_secret(&connection, &created.token).expect("Token query").expect("Token found");
+    let response = client.delete(format!("/api/tokens/{}", created.info.id)).header(common::csrf()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    access_token::record_use(&connection, &mut loaded, Some("203.0.113.9".to_string()));
+    let stored = access_token::find_by_secret(&connection, &created.token).expect("Token query").expect("Token found");
+    assert!(stored.revoked.is_some());
+    let response = client.get(format!("/api/users/{}", user.id)).header(bearer(&created.token)).dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+    let response = client.get(format!("/api/users/{}", user.id)).header(bearer("rtut_pat_forged")).dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+
+    // Cleanup
+    let res = client.delete(format!("/api/users/{}", user.id))
+        .header(common::csrf())
+        .header(ContentType::JSON)
+        .body(r##"{
+            "password": "123456"
+        }"##)
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+}
@@ -0,0 +1,68 @@
+use lazy_static;
+use rocket::http::{ContentType, Status};
+use serde_json::{self, Value};
+
+mod common;
+
+fn json(output: &std::process::Output) -> Value {
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    serde_json::from_slice(&output.stdout).expect("JSON output")
+}
+
+#[test]
+fn admin_cli_test(){
+    let client = common::setup();
+
+    // The password comes from stdin, never from the arguments
+    let created = json(&common::admin(&["create-user", "Carl Doe", "Carl.Doe@m.com", "--role", "support", "--json"], "123456\n"));
+    assert_eq!(created["name"], "Carl Doe");
+    assert_eq!(created["email"], "Carl.Doe@m.com");
+    assert_eq!(created["roles"], serde_json::json!(["support"]));
+    assert!(created.get("hashed_password").is_none());
+    let id = created["id"].as_str().expect("User id").to_string();
+    assert_eq!(common::login(client, "carl.doe@m.com", "123456"), Status::Ok);
+
+    let output = common::admin(&["create-user", "Carl Roe", "carl.doe@M.com"], "654321\n");
+    assert!(!output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "error: email already in use\n");
+    let output = common::admin(&["create-user", "Carl Roe", "carl.roe@m.com"], "\n");
+    assert!(!output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "error: empty password\n");
+
+    // Any spelling of the email finds the account
+    let granted = json(&common::admin(&["grant-role", "CARL.DOE@m.com", "admin", "--json"], ""));
+    assert_eq!(granted["id"], id.as_str());
+    assert_eq!(granted["roles"], serde_json::json!(["support", "admin"]));
+    let output = common::admin(&["grant-role", &id, "admin"], "");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Carl.Doe@m.com roles: support, admin\n");
+    let output = common::admin(&["grant-role", "nobody@m.com", "admin"], "");
+    assert!(!output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "error: no user nobody@m.com\n");
+
+    let response = client.get(format!("/api/users/{}", id)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let revoked = json(&common::admin(&["revoke-sessions", &id, "--json"], ""));
+    assert!(revoked["sessions_revoked_at"].is_string());
+    let response = client.get(format!("/api/users/{}", id)).dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+
+    let checked = json(&common::admin(&["--json", "check"], ""));
+    assert_eq!(checked["ok"], true);
+    assert!(checked["users"].as_i64().expect("User count") >= 1);
+    let output = common::admin(&["check"], "");
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("ok, "));
+
+    // Cleanup, with a token from a later second than the revocation
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    assert_eq!(common::login(client, "carl.doe@m.com", "123456"), Status::Ok);
+    let res = client.delete(format!("/api/users/{}", id))
+        .header(common::csrf())
+        .header(ContentType::JSON)
+        .body(r##"{
+            "password": "123456"
+        }"##)
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+}
@@ -0,0 +1,66 @@
+use lazy_static;
+use rocket::http::{ContentType, Status};
+use rocket_tut::data::db::ResponseUser;
+use rocket_tut::data::audit::ResponseAuditEvent;
+use serde_json;
+
+mod common;
+
+#[test]
+fn audit_trail_test(){
+    let client = common::setup();
+    let mut response_new_user = client.post("/api/users")
+        .header(ContentType::JSON)
+        .body(r##"{
+            "name": "Audrey Doe",
+            "email": "audrey.doe@m.com",
+            "password": "123456"
+        }"##)
+        .dispatch();
+    assert_eq!(response_new_user.status(), Status::Ok);
+    let response_body = response_new_user.body_string().expect("Response Body");
+    let user: ResponseUser = serde_json::from_str(&response_body.as_str()).expect("Valid User Response");
+    assert_eq!(common::login(client, "audrey.doe@m.com", "123456"), Status::Ok);
+    let response = client.patch(format!("/api/users/{}", user.id))
+        .header(common::csrf())
+        .header(ContentType::JSON)
+        .body(r##"{
+            "password": "123456",
+            "new_password": "quertyuiop"
+        }"##)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    // Only admins can read the log
+    let response = client.get(format!("/api/audit?user={}", user.id)).dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+
+    common::grant_role(&user.id, "admin");
+    let mut response = client.get(format!("/api/audit?user={}", user.id)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+    let response_body = response.body_string().expect("Response Body");
+    let events: Vec<ResponseAuditEvent> = serde_json::from_str(&response_body.as_str()).expect("Valid Audit Response");
+    let actions: Vec<String> = events.iter().map(|e| serde_json::to_string(&e.action).unwrap()).collect();
+    assert!(actions.contains(&"\"user_created\"".to_string()));
+    assert!(actions.contains(&"\"login_succeeded\"".to_string()));
+    assert!(actions.contains(&"\"password_changed\"".to_string()));
+    // Secrets never end up in the log
+    assert!(!response_body.contains("quertyuiop"));
+
+    let mut response = client.get(format!("/api/audit?user={}&action=password_changed", user.id)).dispatch();
+    let response_body = response.body_string().expect("Response Body");
+    let events: Vec<ResponseAuditEvent> = serde_json::from_str(&response_body.as_str()).expect("Valid Audit Response");
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].changes, vec!["password".to_string()]);
+
+    // Cleanup
+    let res = client.delete(format!("/api/users/{}", user.id))
+        .header(common::csrf())
+        .header(ContentType::JSON)
+        .body(r##"{
+            "password": "quertyuiop"
+        }"##)
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+}
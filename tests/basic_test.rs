@@ -44,6 +44,7 @@ fn new_user_rt_test(){
     // Cleanup
     if response.status() == Status::Ok {
         let res = client.delete(format!("/api/users/{}", user.id))
+            .header(common::csrf())
             .header(ContentType::JSON)
             .body(r##"{
                 "password": "123456"
@@ -78,6 +79,7 @@ fn info_user_rt_test(){
     // Cleanup
     if response.status() == Status::Ok {
         let res = client.delete(format!("/api/users/{}", id))
+            .header(common::csrf())
             .header(ContentType::JSON)
             .body(r##"{
                 "password": "123456"
@@ -101,7 +103,9 @@ fn update_user_rt_test(){
     let response_body = response_new_user.body_string().expect("Response Body");
     let user_new: ResponseUser = serde_json::from_str(&response_body.as_str()).expect("Valid User Response");
     let id = user_new.id;
+    assert_eq!(common::login(client, "jack.doe@m.com", "quertyuiop"), Status::Ok);
     let mut response = client.put(format!("/api/users/{}", id))
+        .header(common::csrf())
         .header(ContentType::JSON)
         .body(r##"{
             "name": "Jack Doe",
@@ -114,12 +118,31 @@ fn update_user_rt_test(){
     assert_eq!(response.status(), Status::Ok);
     assert_eq!(response.content_type(), Some(ContentType::JSON));
     assert_eq!(user.name, "Jack Doe");
-    assert_eq!(user.email, "jkd@m.com");
+    // The new email waits for confirmation
+    assert_eq!(user.email, "jack.doe@m.com");
+    assert_eq!(user.pending_email, Some("jkd@m.com".to_string()));
     assert_eq!(user.id, id);
+
+    let token = common::mailed_token("jkd@m.com", "/api/email/confirm/");
+    // Following the link only shows a form to submit
+    let mut response = client.get(format!("/api/email/confirm/{}", token)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::HTML));
+    assert!(response.body_string().expect("Response Body").contains("<form method=\"post\">"));
+    let mut response = client.get(format!("/api/users/{}", id)).dispatch();
+    let pending: ResponseUser = serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid User Response");
+    assert_eq!(pending.pending_email, Some("jkd@m.com".to_string()));
+    let mut response = client.post(format!("/api/email/confirm/{}", token)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let response_body = response.body_string().expect("Response Body");
+    let user: ResponseUser = serde_json::from_str(&response_body.as_str()).expect("Valid User Response");
+    assert_eq!(user.email, "jkd@m.com");
+    assert_eq!(user.pending_email, None);
     
     // Cleanup
     if response.status() == Status::Ok {
         let res = client.delete(format!("/api/users/{}", id))
+            .header(common::csrf())
             .header(ContentType::JSON)
             .body(r##"{
                 "password": "quertyuiop"
@@ -144,6 +167,7 @@ fn delete_user_rt_test(){
     let user_new: ResponseUser = serde_json::from_str(&response_body.as_str()).expect("Valid User Response");
     let id = user_new.id;
     let mut response = client.delete(format!("/api/users/{}", id))
+        .header(common::csrf())
         .header(ContentType::JSON)
         .body(r##"{
             "password": "asdfghjkl"
@@ -173,6 +197,7 @@ fn patch_user_rt_test(){
     let user_new: ResponseUser = serde_json::from_str(&response_body.as_str()).expect("Valid User Response");
     let id = user_new.id;
     let mut response = client.patch(format!("/api/users/{}", id))
+        .header(common::csrf())
         .header(ContentType::JSON)
         .body(r##"{
             "password": "123456",
@@ -186,6 +211,7 @@ fn patch_user_rt_test(){
     // Cleanup
     if response.status() == Status::Ok {
         let res = client.delete(format!("/api/users/{}", id))
+            .header(common::csrf())
             .header(ContentType::JSON)
             .body(r##"{
                 "password": "quertyuiop"
@@ -220,6 +246,7 @@ fn id_user_rt_test(){
     // Cleanup
     if response.status() == Status::Ok {
         let res = client.delete(format!("/api/users/{}", id))
+            .header(common::csrf())
             .header(ContentType::JSON)
             .body(r##"{
                 "password": "zxcvbnm"
@@ -229,3 +256,50 @@ fn id_user_rt_test(){
     }
 }
 
+
+#[test]
+fn case_insensitive_email_test(){
+    let client = common::setup();
+    let mut response_new_user = client.post("/api/users")
+        .header(ContentType::JSON)
+        .body(r##"{
+            "name": "Jill Doe",
+            "email": " Jill.Doe@M.com ",
+            "password": "123456"
+        }"##)
+        .dispatch();
+    assert_eq!(response_new_user.status(), Status::Ok);
+    let response_body = response_new_user.body_string().expect("Response Body");
+    let user_new: ResponseUser = serde_json::from_str(&response_body.as_str()).expect("Valid User Response");
+    // The display email is kept, only trimmed
+    assert_eq!(user_new.email, "Jill.Doe@M.com");
+
+    // Same address, different capitalization
+    let mut response = client.post("/api/users")
+        .header(ContentType::JSON)
+        .body(r##"{
+            "name": "Jill Doe",
+            "email": "jill.doe@m.com",
+            "password": "123456"
+        }"##)
+        .dispatch();
+    assert_ne!(response.status(), Status::Ok);
+    assert_eq!(response.body_string(), Some("\"email already in use\"".to_string()));
+
+    assert_eq!(common::login(client, "JILL.DOE@m.com", "123456"), Status::Ok);
+    let mut response = client.get(format!("/api/users/{}", "jill.doe@m.com")).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let response_body = response.body_string().expect("Response Body");
+    let user: ResponseUser = serde_json::from_str(&response_body.as_str()).expect("Valid User Response");
+    assert_eq!(user.id, user_new.id);
+
+    // Cleanup
+    let res = client.delete(format!("/api/users/{}", user_new.id))
+        .header(common::csrf())
+        .header(ContentType::JSON)
+        .body(r##"{
+            "password": "123456"
+        }"##)
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+}
@@ -0,0 +1,98 @@
+use lazy_static;
+use rocket::http::{ContentType, Status};
+use rocket_tut::data::db::ResponseUser;
+use serde_json::{self, json, Value};
+
+mod common;
+
+#[test]
+fn batch_test(){
+    let client = common::setup();
+    let mut response_new_user = client.post("/api/users")
+        .header(ContentType::JSON)
+        .body(r##"{
+            "name": "Bart Doe",
+            "email": "bart.doe@m.com",
+            "password": "123456"
+        }"##)
+        .dispatch();
+    let response_body = response_new_user.body_string().expect("Response Body");
+    let user: ResponseUser = serde_json::from_str(&response_body.as_str()).expect("Valid User Response");
+
+    // Sub-requests are no way around authentication
+    let response = client.post("/api/batch")
+        .header(ContentType::JSON)
+        .body(json!({ "requests": [{ "method": "GET", "path": format!("/api/users/{}", user.id) }] }).to_string())
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+
+    assert_eq!(common::login(client, "bart.doe@m.com", "123456"), Status::Ok);
+    let mut response = client.post("/api/batch")
+        .header(ContentType::JSON)
+        .header(common::csrf())
+        .body(json!({ "requests": [
+            { "method": "GET", "path": format!("/api/users/{}", user.id) },
+            { "method": "get", "path": "/api/v1/users/bart.doe@m.com" },
+            { "method": "GET", "path": "/api/users/nobody@m.com" },
+            { "method": "GET", "path": "/api/batch" },
+            { "method": "HEAD", "path": "/api/users" },
+        ] }).to_string())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let body: Value = serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid Batch Response");
+    let results = body["results"].as_array().expect("Results");
+    assert_eq!(results.len(), 5);
+    assert_eq!(results[0]["status"], 200);
+    assert_eq!(results[0]["body"]["id"], user.id.as_str());
+    assert_eq!(results[1]["status"], 200);
+    assert_eq!(results[1]["body"]["email"], "bart.doe@m.com");
+    assert_eq!(results[2]["status"], 500);
+    assert_eq!(results[2]["body"], "user nobody@m.com not found");
+    assert_eq!(results[3]["status"], 400);
+    assert_eq!(results[4]["status"], 405);
+
+    // Past the first failure, nothing runs
+    let mut response = client.post("/api/batch")
+        .header(ContentType::JSON)
+        .header(common::csrf())
+        .body(json!({ "stop_on_error": true, "requests": [
+            { "method": "GET", "path": "/api/users/nobody@m.com" },
+            { "method": "DELETE", "path": format!("/api/users/{}", user.id), "body": { "password": "123456" } },
+        ] }).to_string())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let body: Value = serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid Batch Response");
+    assert_eq!(body["results"][0]["status"], 500);
+    assert_eq!(body["results"][1]["skipped"], true);
+    assert_eq!(body["results"][1]["status"], Value::Null);
+
+    let too_many: Vec<Value> = (0..26).map(|_| json!({ "method": "GET", "path": "/api/me" })).collect();
+    let mut response = client.post("/api/batch")
+        .header(ContentType::JSON)
+        .header(common::csrf())
+        .body(json!({ "requests": too_many }).to_string())
+        .dispatch();
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    assert_eq!(response.body_string(), Some("\"a batch takes at most 25 requests\"".to_string()));
+    let mut response = client.post("/api/batch")
+        .header(ContentType::JSON)
+        .header(common::csrf())
+        .body(r##"{ "requests": [] }"##)
+        .dispatch();
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    assert_eq!(response.body_string(), Some("\"a batch needs at least one request\"".to_string()));
+
+    // Changes go through too, with the CSRF token of the batch
+    let mut response = client.post("/api/batch")
+        .header(ContentType::JSON)
+        .header(common::csrf())
+        .body(json!({ "requests": [
+            { "method": "DELETE", "path": format!("/api/users/{}", user.id), "body": { "password": "123456" } },
+        ] }).to_string())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let body: Value = serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid Batch Response");
+    assert_eq!(body["results"][0]["status"], 200);
+    let response = client.get(format!("/api/users/{}", user.id)).dispatch();
+    assert_ne!(response.status(), Status::Ok);
+}
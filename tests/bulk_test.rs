@@ -0,0 +1,130 @@
+use std::io::Read;
+use lazy_static;
+use rocket::http::{ContentType, Header, Status};
+use rocket::response::Body;
+use rocket_tut::data::db::ResponseUser;
+use rocket_tut::data::bulk::{ImportReport, RowStatus};
+use serde_json;
+
+mod common;
+
+const IMPORT: &str = r##"{"name": "Ingrid Doe", "email": "ingrid.doe@m.com", "password": "123456"}
+{"name": "Ivan Doe", "email": "ivan.doe@m.com", "password": "123456"}
+{"name": "Nobody", "email": "not an email", "password": "123456"}
+"##;
+
+#[test]
+fn import_export_test(){
+    let client = common::setup();
+    let mut response_new_user = client.post("/api/users")
+        .header(ContentType::JSON)
+        .body(r##"{
+            "name": "Adam Doe",
+            "email": "adam.bulk@m.com",
+            "password": "123456"
+        }"##)
+        .dispatch();
+    let response_body = response_new_user.body_string().expect("Response Body");
+    let admin: ResponseUser = serde_json::from_str(&response_body.as_str()).expect("Valid User Response");
+    common::grant_role(&admin.id, "admin");
+    assert_eq!(common::login(client, "adam.bulk@m.com", "123456"), Status::Ok);
+
+    // Dry run: per-row report, nothing written
+    let mut response = client.post("/api/admin/users/import?format=ndjson&dry_run=true")
+        .header(common::csrf())
+        .body(IMPORT)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let response_body = response.body_string().expect("Response Body");
+    let report: ImportReport = serde_json::from_str(&response_body.as_str()).expect("Valid Import Report");
+    assert_eq!(report.created, 2);
+    assert_eq!(report.failed, 1);
+    assert_eq!(report.rows[2].status, RowStatus::Failed);
+    assert_eq!(report.rows[2].error, Some("invalid email".to_string()));
+    let response = client.get(format!("/api/users/{}", "ingrid.doe@m.com")).dispatch();
+    assert_ne!(response.status(), Status::Ok);
+
+    let mut response = client.post("/api/admin/users/import?format=ndjson&on_duplicate=skip")
+        .header(common::csrf())
+        .body(IMPORT)
+        .dispatch();
+    let response_body = response.body_string().expect("Response Body");
+    let report: ImportReport = serde_json::from_str(&response_body.as_str()).expect("Valid Import Report");
+    assert_eq!(report.created, 2);
+
+    // Importing again under the fail policy aborts
+    let mut response = client.post("/api/admin/users/import?on_duplicate=fail")
+        .header(common::csrf())
+        .header(ContentType::CSV)
+        .body("name,email\nIngrid Doe,ingrid.doe@m.com\n")
+        .dispatch();
+    let response_body = response.body_string().expect("Response Body");
+    let report: ImportReport = serde_json::from_str(&response_body.as_str()).expect("Valid Import Report");
+    assert!(report.aborted);
+
+    // Nothing of an oversized upload is imported
+    let row = "{\"name\": \"Otto Doe\", \"email\": \"otto.bulk@m.com\", \"password\": \"123456\"}\n";
+    let oversized = row.repeat(16 * 1024 * 1024 / row.len() + 1);
+    let mut response = client.post("/api/admin/users/import?format=ndjson&on_duplicate=skip")
+        .header(common::csrf())
+        .body(oversized)
+        .dispatch();
+    assert_eq!(response.status(), Status::PayloadTooLarge);
+    assert_eq!(response.body_string(), Some("\"imports take at most 16 MiB\"".to_string()));
+    let response = client.get("/api/users/otto.bulk@m.com").dispatch();
+    assert_ne!(response.status(), Status::Ok);
+
+    // Users imported without a password choose one through the page their invite links to
+    let mut response = client.post("/api/admin/users/import?invite=true")
+        .header(common::csrf())
+        .header(ContentType::CSV)
+        .body("name,email\nIris Doe,iris.bulk@m.com\n")
+        .dispatch();
+    let report: ImportReport = serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid Import Report");
+    assert_eq!(report.created, 1);
+    let token = common::mailed_token("iris.bulk@m.com", "/api/invites/");
+    let mut response = client.get(format!("/api/invites/{}", token)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::HTML));
+    assert!(response.body_string().expect("Response Body").contains("name=\"password\""));
+    let response = client.post(format!("/api/invites/{}", token))
+        .header(ContentType::Form)
+        .body("password=123456")
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(common::login(client, "iris.bulk@m.com", "123456"), Status::Ok);
+    assert_eq!(common::login(client, "adam.bulk@m.com", "123456"), Status::Ok);
+
+    let mut response = client.get("/api/admin/users/export?format=csv").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::CSV));
+    let exported = response.body_string().expect("Response Body");
+    assert!(exported.starts_with("id,name,email"));
+    assert!(exported.contains("ingrid.doe@m.com"));
+    assert!(!exported.contains("argon2"));
+
+    // Compressed as it streams, never buffered whole
+    let mut response = client.get("/api/admin/users/export?format=csv").header(Header::new("Accept-Encoding", "gzip")).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("Content-Encoding"), Some("gzip"));
+    assert!(matches!(response.body(), Some(Body::Chunked(..))));
+    let mut decompressed = String::new();
+    flate2::read::GzDecoder::new(&response.body_bytes().expect("Response Body")[..]).read_to_string(&mut decompressed).expect("Valid gzip");
+    assert!(decompressed.starts_with("id,name,email"));
+    assert!(decompressed.contains("ingrid.doe@m.com"));
+
+    // Cleanup
+    for email in &["ingrid.doe@m.com", "ivan.doe@m.com", "iris.bulk@m.com", "adam.bulk@m.com"] {
+        let mut response = client.get(format!("/api/users/{}", email)).dispatch();
+        let response_body = response.body_string().expect("Response Body");
+        let user: ResponseUser = serde_json::from_str(&response_body.as_str()).expect("Valid User Response");
+        let res = client.delete(format!("/api/users/{}", user.id))
+            .header(common::csrf())
+            .header(ContentType::JSON)
+            .body(r##"{
+                "password": "123456"
+            }"##)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+    }
+}
@@ -1,7 +1,13 @@
+#![allow(dead_code)]
 use crate::lazy_static::lazy_static;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+use std::sync::Mutex;
 
 use rocket::local::Client;
+use rocket::http::{ContentType, Header, Status};
 use rocket_tut::rocket_builder;
+use rocket_tut::data::store::{Conn, Database};
 
 pub fn setup () -> &'static Client {
     lazy_static! {
@@ -9,3 +15,66 @@ pub fn setup () -> &'static Client {
     }
     &*CLIENT
 }
+
+/// Straight to the database of the test instance, whichever it is. The suite runs against SQLite with
+/// `DATABASE_BACKEND=sqlite DATABASE_URL=test.db cargo test --features sqlite`
+pub fn connection() -> Conn {
+    let database = setup().rocket().state::<Database>().expect("Managed database");
+    Conn(database.get().expect("Database connection"))
+}
+
+lazy_static! {
+    static ref CSRF_TOKEN: Mutex<String> = Mutex::new(String::new());
+}
+
+/// Logs in, leaving the token cookie in the client and keeping the CSRF token for `csrf()`
+pub fn login(client: &Client, email: &str, password: &str) -> Status {
+    let mut response = client.post("/api/login")
+        .header(ContentType::JSON)
+        .body(format!(r##"{{
+            "email": "{}",
+            "password": "{}"
+        }}"##, email, password))
+        .dispatch();
+    if response.status() == Status::Ok {
+        let body: serde_json::Value = serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid Login Response");
+        if let Some(token) = body["csrf_token"].as_str() {
+            *CSRF_TOKEN.lock().unwrap() = token.to_string();
+        }
+    }
+    response.status()
+}
+
+/// The header cookie-authenticated changes need, for the last login
+pub fn csrf() -> Header<'static> {
+    Header::new("X-CSRF-Token", CSRF_TOKEN.lock().unwrap().clone())
+}
+
+/// Grants a role bypassing the API, there's no route for that
+pub fn grant_role(id: &str, role: &str) {
+    let connection = connection();
+    let mut user = connection.find_user(id).expect("User query").expect("User found");
+    user.grant_role(role);
+    assert_eq!(connection.replace_user(&user), Ok(true));
+}
+
+/// Fishes the token out of the last link of the given kind mailed to the address
+pub fn mailed_token(to: &str, link: &str) -> String {
+    let mails = connection().mails_to(to).expect("Outbox query");
+    let body = &mails.iter().find(|mail| mail.body.contains(link)).expect("Mail sent").body;
+    let start = body.find(link).expect("Link in mail") + link.len();
+    body[start..].split_whitespace().next().expect("Token").to_string()
+}
+
+/// Runs `rocket-tut-admin` against the database of the test instance, which it finds in the same environment
+pub fn admin(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rocket-tut-admin"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Admin command");
+    child.stdin.take().expect("Admin stdin").write_all(stdin.as_bytes()).expect("Admin input");
+    child.wait_with_output().expect("Admin output")
+}
@@ -0,0 +1,85 @@
+use std::fs;
+use std::io::Read;
+use std::sync::Once;
+use rocket::local::Client;
+use rocket::http::{Header, Status};
+use rocket_contrib::serve::StaticFiles;
+use rocket_tut::compression::{Compression, CompressionConfig, Encoding};
+
+fn compressing_client(dir: &str) -> Client {
+    let rocket = rocket::ignite()
+        .attach(Compression(CompressionConfig { min_size: 1024 }))
+        .mount("/static", StaticFiles::from("static/"))
+        .mount("/files", StaticFiles::from(dir));
+    Client::new(rocket).expect("Valid Rocket instance")
+}
+
+/// Written once, tests read them in parallel
+fn files_dir() -> String {
+    static WRITE: Once = Once::new();
+    let dir = std::env::temp_dir().join("rocket-tut-compression");
+    WRITE.call_once(|| {
+        fs::create_dir_all(&dir).expect("Temporary directory");
+        let users: Vec<String> = (0..100).map(|i| format!(r#"{{"id":"{}","name":"User {}","email":"user{}@m.com"}}"#, i, i, i)).collect();
+        fs::write(dir.join("users.json"), format!("[{}]", users.join(","))).expect("Big file");
+        fs::write(dir.join("small.json"), r#"{"id":"1"}"#).expect("Small file");
+    });
+    dir.to_string_lossy().to_string()
+}
+
+#[test]
+fn negotiate_test(){
+    assert_eq!(Encoding::negotiate("gzip, deflate, br"), Some(Encoding::Brotli));
+    assert_eq!(Encoding::negotiate("gzip;q=1.0, br;q=0.5"), Some(Encoding::Gzip));
+    assert_eq!(Encoding::negotiate("deflate"), Some(Encoding::Deflate));
+    assert_eq!(Encoding::negotiate("*;q=0.1, br;q=0"), Some(Encoding::Gzip));
+    assert_eq!(Encoding::negotiate("identity"), None);
+    assert_eq!(Encoding::negotiate("gzip;q=0"), None);
+}
+
+#[test]
+fn compressed_responses_test(){
+    let dir = files_dir();
+    let client = compressing_client(&dir);
+    let original = fs::read(std::path::Path::new(&dir).join("users.json")).expect("Big file");
+
+    let mut response = client.get("/files/users.json").header(Header::new("Accept-Encoding", "gzip, deflate")).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("Content-Encoding"), Some("gzip"));
+    assert_eq!(response.headers().get_one("Vary"), Some("Accept-Encoding"));
+    let compressed = response.body_bytes().expect("Response Body");
+    assert!(compressed.len() < original.len());
+    let mut decompressed = Vec::new();
+    flate2::read::GzDecoder::new(&compressed[..]).read_to_end(&mut decompressed).expect("Valid gzip");
+    assert_eq!(decompressed, original);
+
+    let mut response = client.get("/files/users.json").header(Header::new("Accept-Encoding", "br")).dispatch();
+    assert_eq!(response.headers().get_one("Content-Encoding"), Some("br"));
+    let compressed = response.body_bytes().expect("Response Body");
+    let mut decompressed = Vec::new();
+    brotli::Decompressor::new(&compressed[..], 4096).read_to_end(&mut decompressed).expect("Valid brotli");
+    assert_eq!(decompressed, original);
+
+    let mut response = client.get("/files/users.json").header(Header::new("Accept-Encoding", "deflate")).dispatch();
+    assert_eq!(response.headers().get_one("Content-Encoding"), Some("deflate"));
+    let mut decompressed = Vec::new();
+    flate2::read::ZlibDecoder::new(&response.body_bytes().expect("Response Body")[..]).read_to_end(&mut decompressed).expect("Valid deflate");
+    assert_eq!(decompressed, original);
+}
+
+#[test]
+fn uncompressed_responses_test(){
+    let client = compressing_client(&files_dir());
+    // The client doesn't ask for it
+    let response = client.get("/files/users.json").dispatch();
+    assert_eq!(response.headers().get_one("Content-Encoding"), None);
+    assert_eq!(response.headers().get_one("Vary"), Some("Accept-Encoding"));
+    // Too small to be worth it
+    let response = client.get("/files/small.json").header(Header::new("Accept-Encoding", "gzip")).dispatch();
+    assert_eq!(response.headers().get_one("Content-Encoding"), None);
+    // Compressed already
+    let response = client.get("/static/abruzzo.png").header(Header::new("Accept-Encoding", "gzip")).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("Content-Encoding"), None);
+    assert_eq!(response.headers().get_one("Vary"), None);
+}
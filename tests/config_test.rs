@@ -0,0 +1,50 @@
+use rocket::config::{Config, Environment};
+use rocket_tut::config::AppConfig;
+use rocket_tut::data::email_address::canonical_email_with;
+
+#[test]
+fn config_report_test(){
+    let profile = Config::build(Environment::Production)
+        .extra("jwt_secret", "short")
+        .extra("token_lifetime_hours", -1)
+        .extra("idempotency_ttl_hours", i64::MAX)
+        .extra("public_url", "localhost:8000")
+        .finalize()
+        .expect("Valid Rocket config");
+    let report = AppConfig::from_rocket(&profile).expect_err("Invalid configuration");
+    assert!(report.errors.iter().any(|e| e.starts_with("JWT_SECRET")));
+    assert!(report.errors.iter().any(|e| e.starts_with("TOKEN_LIFETIME_HOURS")));
+    assert!(report.errors.iter().any(|e| e.starts_with("IDEMPOTENCY_TTL_HOURS")));
+    assert!(report.errors.iter().any(|e| e.starts_with("PUBLIC_URL")));
+}
+
+#[test]
+fn production_needs_secret_test(){
+    if std::env::var("JWT_SECRET").is_ok() {
+        return;
+    }
+    let profile = Config::build(Environment::Production).finalize().expect("Valid Rocket config");
+    let report = AppConfig::from_rocket(&profile).expect_err("Missing secret");
+    assert!(report.errors.iter().any(|e| e == "JWT_SECRET is missing"));
+}
+
+#[test]
+fn email_folding_test(){
+    if std::env::var("EMAIL_LOCAL_PART_FOLDING").is_ok() {
+        return;
+    }
+    let profile = Config::build(Environment::Development)
+        .extra("mongodb_uri", "mongodb://localhost:27017/test")
+        .extra("email_local_part_folding", "lowercase_no_tag")
+        .finalize()
+        .expect("Valid Rocket config");
+    let config = AppConfig::from_rocket(&profile).expect("Valid configuration");
+    assert_eq!(canonical_email_with("Jo.Doe+news@M.com", config.email_folding), Ok("jo.doe@m.com".to_string()));
+    let profile = Config::build(Environment::Development)
+        .extra("mongodb_uri", "mongodb://localhost:27017/test")
+        .extra("email_local_part_folding", "uppercase")
+        .finalize()
+        .expect("Valid Rocket config");
+    let report = AppConfig::from_rocket(&profile).expect_err("Invalid folding");
+    assert!(report.errors.iter().any(|e| e.starts_with("EMAIL_LOCAL_PART_FOLDING")));
+}
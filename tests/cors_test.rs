@@ -0,0 +1,81 @@
+use rocket::config::{Config, Environment};
+use rocket::local::Client;
+use rocket::http::{Header, Status};
+use rocket::routes;
+use rocket_tut::config::AppConfig;
+use rocket_tut::cors::Cors;
+use rocket_tut::routes;
+
+fn cors_client(credentials: bool) -> Client {
+    let profile = Config::build(Environment::Development)
+        .extra("mongodb_uri", "mongodb://localhost:27017/test")
+        .extra("cors_allowed_origins", "https://app.example.com, https://*.example.org")
+        .extra("cors_allow_credentials", credentials)
+        .extra("cors_exposed_headers", "Retry-After")
+        .finalize()
+        .expect("Valid Rocket config");
+    let config = AppConfig::from_rocket(&profile).expect("Valid configuration");
+    let rocket = rocket::custom(profile)
+        .attach(Cors(config.cors))
+        .mount("/", routes![routes::ping::ping_fn, routes::cors::preflight_rt]);
+    Client::new(rocket).expect("Valid Rocket instance")
+}
+
+fn preflight<'c>(client: &'c Client, origin: &'static str, method: &'static str) -> rocket::local::LocalResponse<'c> {
+    client.options("/ping")
+        .header(Header::new("Origin", origin))
+        .header(Header::new("Access-Control-Request-Method", method))
+        .header(Header::new("Access-Control-Request-Headers", "content-type, x-csrf-token"))
+        .dispatch()
+}
+
+#[test]
+fn allowed_origins_test(){
+    let client = cors_client(true);
+    let response = preflight(&client, "https://app.example.com", "PUT");
+    assert_eq!(response.status(), Status::NoContent);
+    assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), Some("https://app.example.com"));
+    assert_eq!(response.headers().get_one("Access-Control-Allow-Credentials"), Some("true"));
+    assert!(response.headers().get_one("Access-Control-Allow-Methods").expect("Allowed methods").contains("PUT"));
+    assert!(response.headers().get_one("Access-Control-Max-Age").is_some());
+
+    let response = client.get("/ping").header(Header::new("Origin", "https://shop.example.org")).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), Some("https://shop.example.org"));
+    assert_eq!(response.headers().get_one("Access-Control-Expose-Headers"), Some("Retry-After"));
+    assert_eq!(response.headers().get_one("Vary"), Some("Origin"));
+}
+
+#[test]
+fn disallowed_origins_test(){
+    let client = cors_client(false);
+    for origin in &["https://evil.com", "https://app.example.com.evil.com", "https://example.org", "https://a/b.example.org"] {
+        let response = preflight(&client, origin, "GET");
+        assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), None);
+        assert_eq!(response.headers().get_one("Access-Control-Allow-Methods"), None);
+        let response = client.get("/ping").header(Header::new("Origin", *origin)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), None);
+    }
+    // Allowed origins asking for a method or header that isn't get nothing either
+    let response = preflight(&client, "https://app.example.com", "TRACE");
+    assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), None);
+    let response = client.options("/ping")
+        .header(Header::new("Origin", "https://app.example.com"))
+        .header(Header::new("Access-Control-Request-Method", "GET"))
+        .header(Header::new("Access-Control-Request-Headers", "x-secret"))
+        .dispatch();
+    assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), None);
+}
+
+#[test]
+fn wildcard_with_credentials_test(){
+    let profile = Config::build(Environment::Development)
+        .extra("mongodb_uri", "mongodb://localhost:27017/test")
+        .extra("cors_allowed_origins", "*")
+        .extra("cors_allow_credentials", true)
+        .finalize()
+        .expect("Valid Rocket config");
+    let report = AppConfig::from_rocket(&profile).expect_err("Invalid configuration");
+    assert!(report.errors.iter().any(|e| e.starts_with("CORS_ALLOW_CREDENTIALS")));
+}
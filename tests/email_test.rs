@@ -0,0 +1,68 @@
+use lazy_static;
+use rocket::http::{ContentType, Status};
+use rocket_tut::data::db::ResponseUser;
+use serde_json;
+
+mod common;
+
+#[test]
+fn revert_email_change_test(){
+    let client = common::setup();
+    let mut response_new_user = client.post("/api/users")
+        .header(ContentType::JSON)
+        .body(r##"{
+            "name": "Emma Doe",
+            "email": "emma.doe@m.com",
+            "password": "123456"
+        }"##)
+        .dispatch();
+    assert_eq!(response_new_user.status(), Status::Ok);
+    let response_body = response_new_user.body_string().expect("Response Body");
+    let user: ResponseUser = serde_json::from_str(&response_body.as_str()).expect("Valid User Response");
+    assert_eq!(common::login(client, "emma.doe@m.com", "123456"), Status::Ok);
+    let response = client.put(format!("/api/users/{}", user.id))
+        .header(common::csrf())
+        .header(ContentType::JSON)
+        .body(r##"{
+            "name": "Emma Doe",
+            "email": "emma.hijacked@m.com",
+            "password": "123456"
+        }"##)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    // A pending email can't be taken by anybody else
+    let mut response = client.post("/api/users")
+        .header(ContentType::JSON)
+        .body(r##"{
+            "name": "Emil Doe",
+            "email": "emma.hijacked@m.com",
+            "password": "123456"
+        }"##)
+        .dispatch();
+    assert_ne!(response.status(), Status::Ok);
+    assert_eq!(response.body_string(), Some("\"email already in use\"".to_string()));
+
+    // The old address gets a way back, and after reverting the confirmation is void
+    let revert = common::mailed_token("emma.doe@m.com", "/api/email/revert/");
+    let confirm = common::mailed_token("emma.hijacked@m.com", "/api/email/confirm/");
+    let mut response = client.post(format!("/api/email/revert/{}", revert)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let response_body = response.body_string().expect("Response Body");
+    let reverted: ResponseUser = serde_json::from_str(&response_body.as_str()).expect("Valid User Response");
+    assert_eq!(reverted.email, "emma.doe@m.com");
+    assert_eq!(reverted.pending_email, None);
+    let mut response = client.post(format!("/api/email/confirm/{}", confirm)).dispatch();
+    assert_ne!(response.status(), Status::Ok);
+    assert_eq!(response.body_string(), Some("\"invalid or expired token\"".to_string()));
+
+    // Cleanup
+    let res = client.delete(format!("/api/users/{}", user.id))
+        .header(common::csrf())
+        .header(ContentType::JSON)
+        .body(r##"{
+            "password": "123456"
+        }"##)
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+}
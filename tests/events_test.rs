@@ -0,0 +1,128 @@
+use std::io::{ErrorKind, Read};
+use std::time::{Duration, Instant};
+use lazy_static;
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::LocalResponse;
+use rocket_tut::data::db::ResponseUser;
+use serde_json::{self, Value};
+
+mod common;
+
+/// The first `count` events of the stream, as their id, name and data
+fn read_events(response: &mut LocalResponse, count: usize) -> Vec<(i64, String, Value)> {
+    let body = response.body().expect("Event stream").into_inner();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut text = String::new();
+    let mut buffer = [0; 4096];
+    loop {
+        // Only whole frames count
+        let complete = &text[..text.rfind("\n\n").map(|end| end + 2).unwrap_or(0)];
+        let events: Vec<(i64, String, Value)> = complete.split("\n\n")
+            .filter(|frame| frame.contains("event: "))
+            .take(count)
+            .map(|frame| {
+                let field = |name: &str| frame.lines().find_map(|line| line.strip_prefix(name)).expect("Event field").to_string();
+                (field("id: ").parse().expect("Event id"), field("event: "), serde_json::from_str(&field("data: ")).expect("Event data"))
+            })
+            .collect();
+        if events.len() == count {
+            return events;
+        }
+        assert!(Instant::now() < deadline, "no {} events in {:?}", count, text);
+        match body.read(&mut buffer) {
+            Ok(0) => panic!("stream ended after {:?}", text),
+            Ok(n) => text.push_str(std::str::from_utf8(&buffer[..n]).expect("UTF-8 events")),
+            // Asking for a flush, there's more to come
+            Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+            Err(e) => panic!("stream failed: {}", e),
+        }
+    }
+}
+
+/// Reads on until the server ends the stream
+fn read_to_end(response: &mut LocalResponse) {
+    let body = response.body().expect("Event stream").into_inner();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut buffer = [0; 4096];
+    loop {
+        assert!(Instant::now() < deadline, "the stream is still open");
+        match body.read(&mut buffer) {
+            Ok(0) => return,
+            Ok(_) => (),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => (),
+            Err(e) => panic!("stream failed: {}", e),
+        }
+    }
+}
+
+#[test]
+fn user_events_test(){
+    let client = common::setup();
+    let mut response_new_user = client.post("/api/users")
+        .header(ContentType::JSON)
+        .body(r##"{
+            "name": "Vera Doe",
+            "email": "vera.doe@m.com",
+            "password": "123456"
+        }"##)
+        .dispatch();
+    assert_eq!(response_new_user.status(), Status::Ok);
+    let response_body = response_new_user.body_string().expect("Response Body");
+    let user: ResponseUser = serde_json::from_str(&response_body.as_str()).expect("Valid User Response");
+    assert_eq!(client.get("/api/events").dispatch().status(), Status::BadRequest);
+    assert_eq!(common::login(client, "vera.doe@m.com", "123456"), Status::Ok);
+
+    // From the start, users only get their own events
+    let mut response = client.get("/api/events").header(Header::new("Last-Event-ID", "0")).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::new("text", "event-stream")));
+    let events = read_events(&mut response, 2);
+    drop(response);
+    let created = events[0].0;
+    assert_eq!(events[0].1, "user.created");
+    assert_eq!(events[0].2["id"], user.id.as_str());
+    assert_eq!(events[0].2["user"]["email"], "vera.doe@m.com");
+    assert_eq!(events[1].1, "user.logged_in");
+    assert_eq!(events[1].2["id"], user.id.as_str());
+    assert!(events[1].0 > created);
+
+    // Resuming after the creation
+    let mut response = client.get("/api/v1/events").header(Header::new("Last-Event-ID", created.to_string())).dispatch();
+    let events = read_events(&mut response, 1);
+    drop(response);
+    assert_eq!(events[0].1, "user.logged_in");
+
+    // Admins get everyone's, the next one being the creation
+    common::grant_role(&user.id, "admin");
+    let mut response = client.get("/api/events").header(Header::new("Last-Event-ID", (created - 1).to_string())).dispatch();
+    let events = read_events(&mut response, 1);
+    drop(response);
+    assert_eq!(events[0].0, created);
+
+    // Each stream holds a worker, there can only be so many
+    let mut open = Vec::new();
+    loop {
+        let response = client.get("/api/events").dispatch();
+        if response.status() != Status::Ok {
+            assert_eq!(response.status(), Status::ServiceUnavailable);
+            assert!(response.headers().get_one("Retry-After").is_some());
+            break;
+        }
+        open.push(response);
+        assert!(open.len() <= 2, "more streams than allowed per user");
+    }
+    open.clear();
+
+    // Deleting the user ends its stream
+    let mut response = client.get("/api/events").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let res = client.delete(format!("/api/users/{}", user.id))
+        .header(common::csrf())
+        .header(ContentType::JSON)
+        .body(r##"{
+            "password": "123456"
+        }"##)
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    read_to_end(&mut response);
+}
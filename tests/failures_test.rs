@@ -34,8 +34,8 @@ fn new_user_rt_fail(){
         }"##)
         .dispatch();
     assert_ne!(response.status(), Status::Ok);
-    assert_eq!(response.status(), Status::NotFound);
-    assert_ne!(response.content_type(), Some(ContentType::JSON));
+    assert_eq!(response.status(), Status::UnsupportedMediaType);
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
 }
 
 #[test]
@@ -69,6 +69,7 @@ fn info_user_rt_fail(){
 
     // Cleanup
     let res = client.delete(format!("/api/users/{}", user_new.id))
+        .header(common::csrf())
         .header(ContentType::JSON)
         .body(r##"{
             "password": "123456"
@@ -102,6 +103,7 @@ fn update_user_rt_fail(){
         wrong_id.insert(0, 'b');
     }
     let mut response = client.put(format!("/api/users/{}", wrong_id))
+        .header(common::csrf())
         .header(ContentType::JSON)
         .body(r##"{
             "name": "Jack S. Doe",
@@ -116,6 +118,7 @@ fn update_user_rt_fail(){
 
     // Second test: wrong password
     let mut response = client.put(format!("/api/users/{}", id))
+        .header(common::csrf())
         .header(ContentType::JSON)
         .body(r##"{
             "name": "Jack S. Doe",
@@ -130,6 +133,7 @@ fn update_user_rt_fail(){
 
     // Cleanup
     let res = client.delete(format!("/api/users/{}", id))
+        .header(common::csrf())
         .header(ContentType::JSON)
         .body(r##"{
             "password": "quertyuiop"
@@ -162,6 +166,7 @@ fn delete_user_rt_fail(){
         wrong_id.insert(0, 'b');
     }
     let mut response = client.delete(format!("/api/users/{}", wrong_id))
+        .header(common::csrf())
         .header(ContentType::JSON)
         .body(r##"{
             "password": "asdfghjkl"
@@ -174,6 +179,7 @@ fn delete_user_rt_fail(){
     
     // Second test: wrong password
     let mut response = client.delete(format!("/api/users/{}", id))
+        .header(common::csrf())
         .header(ContentType::JSON)
         .body(r##"{
             "password": "qwertyuiop"
@@ -186,6 +192,7 @@ fn delete_user_rt_fail(){
     
     // Cleanup
     let res = client.delete(format!("/api/users/{}", id))
+        .header(common::csrf())
         .header(ContentType::JSON)
         .body(r##"{
             "password": "asdfghjkl"
@@ -217,6 +224,7 @@ fn patch_user_rt_fail(){
         wrong_id.insert(0, 'b');
     }
     let mut response = client.patch(format!("/api/users/{}", wrong_id))
+        .header(common::csrf())
         .header(ContentType::JSON)
         .body(r##"{
             "password": "123456",
@@ -230,6 +238,7 @@ fn patch_user_rt_fail(){
 
     // Second test: wrong password
     let mut response = client.patch(format!("/api/users/{}", id))
+        .header(common::csrf())
         .header(ContentType::JSON)
         .body(r##"{
             "password": "zxcvbnm",
@@ -243,6 +252,7 @@ fn patch_user_rt_fail(){
 
     // Third test: no new password provided
     let mut response = client.patch(format!("/api/users/{}", id))
+        .header(common::csrf())
         .header(ContentType::JSON)
         .body(r##"{
             "password": "123456"
@@ -255,6 +265,7 @@ fn patch_user_rt_fail(){
     
     // Cleanup
     let res = client.delete(format!("/api/users/{}", id))
+        .header(common::csrf())
         .header(ContentType::JSON)
         .body(r##"{
             "password": "123456"
@@ -294,6 +305,7 @@ fn id_user_rt_fail(){
 
     // Cleanup
     let res = client.delete(format!("/api/users/{}", user.id))
+        .header(common::csrf())
         .header(ContentType::JSON)
         .body(r##"{
             "password": "zxcvbnm"
@@ -362,6 +374,7 @@ fn unique_emails_insertion_fail(){
 
     // Cleanup
     let res = client.delete(format!("/api/users/{}", user.id))
+        .header(common::csrf())
         .header(ContentType::JSON)
         .body(r##"{
             "password": "123456"
@@ -408,6 +421,7 @@ fn unique_emails_update_fail(){
 
     // We change the first user to have the same email as the second one
     let mut response = client.put(format!("/api/users/{}", first_id))
+        .header(common::csrf())
         .header(ContentType::JSON)
         .body(r##"{
             "name": "Joe K. Doe",
@@ -422,6 +436,7 @@ fn unique_emails_update_fail(){
 
     // Cleanup (double trouble)
     let res1 = client.delete(format!("/api/users/{}", first_id))
+        .header(common::csrf())
         .header(ContentType::JSON)
         .body(r##"{
             "password": "123456"
@@ -429,6 +444,7 @@ fn unique_emails_update_fail(){
         .dispatch();
     assert_eq!(res1.status(), Status::Ok);
     let res2 = client.delete(format!("/api/users/{}", second_id))
+        .header(common::csrf())
         .header(ContentType::JSON)
         .body(r##"{
             "password": "qwertyuiop"
@@ -0,0 +1,110 @@
+use lazy_static;
+use rocket::http::{ContentType, Header, Status};
+use serde_json::{self, json, Value};
+use uuid::Uuid;
+
+mod common;
+
+fn graphql(client: &rocket::local::Client, body: Value, csrf: bool) -> Value {
+    let mut request = client.post("/graphql")
+        .header(ContentType::JSON)
+        .body(body.to_string());
+    if csrf {
+        request = request.header(common::csrf());
+    }
+    let mut response = request.dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid GraphQL Response")
+}
+
+#[test]
+fn graphql_user_test(){
+    let client = common::setup();
+    let created = graphql(client, json!({
+        "query": "mutation($input: UserInput!) { createUser(input: $input) { id email } }",
+        "variables": { "input": { "name": "Gina Doe", "email": "gina.doe@m.com", "password": "123456" } }
+    }), false);
+    assert_eq!(created["data"]["createUser"]["email"], "gina.doe@m.com");
+    let id = created["data"]["createUser"]["id"].as_str().expect("User id").to_string();
+    // Same rules as the REST routes
+    let again = graphql(client, json!({
+        "query": "mutation { createUser(input: { name: \"Gina Doe\", email: \"Gina.Doe@m.com\", password: \"123456\" }) { id } }"
+    }), false);
+    assert_eq!(again["errors"][0]["message"], "email already in use");
+
+    // A retry with the same Idempotency-Key gets the account back. Keys outlive the runs, each one has its own.
+    let key = Uuid::new_v4().to_string();
+    let keyed = |email: &str| {
+        let mut response = client.post("/graphql")
+            .header(ContentType::JSON)
+            .header(Header::new("Idempotency-Key", key.clone()))
+            .body(json!({
+                "query": "mutation($input: UserInput!) { createUser(input: $input) { id } }",
+                "variables": { "input": { "name": "Gus Doe", "email": email, "password": "123456" } }
+            }).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        serde_json::from_str::<Value>(&response.body_string().expect("Response Body")).expect("Valid GraphQL Response")
+    };
+    let first = keyed("gus.doe@m.com");
+    let gus = first["data"]["createUser"]["id"].as_str().expect("User id").to_string();
+    assert_eq!(keyed("gus.doe@m.com")["data"]["createUser"]["id"], gus.as_str());
+    assert_eq!(keyed("gus.roe@m.com")["errors"][0]["message"], "Idempotency-Key already used for a different request");
+
+    // Nothing without authentication
+    let anonymous = graphql(client, json!({ "query": "{ me { auth } }" }), false);
+    assert_eq!(anonymous["errors"][0]["message"], "user not authenticated");
+    assert_eq!(common::login(client, "gina.doe@m.com", "123456"), Status::Ok);
+
+    // Reads go through GET as well, without the CSRF header
+    let mut response = client.get("/graphql?query=%7Bme%7Bauth%20user%7Bemail%7D%7D%7D").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let me: Value = serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid GraphQL Response");
+    assert_eq!(me["data"]["me"]["auth"], "session");
+    assert_eq!(me["data"]["me"]["user"]["email"], "gina.doe@m.com");
+    let mut response = client.get("/graphql?query=mutation%7BdeleteUser(id%3A%22x%22%2Cpassword%3A%22x%22)%7Bid%7D%7D").dispatch();
+    let refused: Value = serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid GraphQL Response");
+    assert_eq!(refused["errors"][0]["message"], "mutations must be sent with POST");
+
+    // POST is a change as far as the session cookie goes
+    let forged = graphql(client, json!({ "query": "{ me { auth } }" }), false);
+    assert_eq!(forged["errors"][0]["message"], "missing or invalid X-CSRF-Token header");
+
+    // Lookups batched in one request
+    let mut response = client.post("/graphql")
+        .header(ContentType::JSON)
+        .header(common::csrf())
+        .body(json!([
+            { "query": "query($id: ID!) { user(id: $id) { email } }", "variables": { "id": id } },
+            { "query": "{ userByEmail(email: \"GINA.DOE@m.com\") { id } users(q: \"gina\", perPage: 5) { total items { email } } }" }
+        ]).to_string())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let batch: Value = serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid GraphQL Response");
+    assert_eq!(batch[0]["data"]["user"]["email"], "gina.doe@m.com");
+    assert_eq!(batch[1]["data"]["userByEmail"]["id"], id.as_str());
+    assert!(batch[1]["data"]["users"]["total"].as_i64().expect("Total") >= 1);
+
+    let wrong = graphql(client, json!({
+        "query": "mutation($id: ID!) { changePassword(id: $id, password: \"wrong\", newPassword: \"654321\") }",
+        "variables": { "id": id }
+    }), true);
+    assert_eq!(wrong["errors"][0]["message"], "user not authenticated");
+    let changed = graphql(client, json!({
+        "query": "mutation($id: ID!) { changePassword(id: $id, password: \"123456\", newPassword: \"654321\") }",
+        "variables": { "id": id }
+    }), true);
+    assert_eq!(changed["data"]["changePassword"], true);
+    let deleted = graphql(client, json!({
+        "query": "mutation($id: ID!) { deleteUser(id: $id, password: \"654321\") { email } }",
+        "variables": { "id": id }
+    }), true);
+    assert_eq!(deleted["data"]["deleteUser"]["email"], "gina.doe@m.com");
+
+    assert_eq!(common::login(client, "gus.doe@m.com", "123456"), Status::Ok);
+    let deleted = graphql(client, json!({
+        "query": "mutation($id: ID!) { deleteUser(id: $id, password: \"123456\") { email } }",
+        "variables": { "id": gus }
+    }), true);
+    assert_eq!(deleted["data"]["deleteUser"]["email"], "gus.doe@m.com");
+}
@@ -0,0 +1,95 @@
+use lazy_static;
+use rocket::http::{ContentType, Header, Status};
+use rocket_tut::data::db::ResponseUser;
+use serde_json;
+use uuid::Uuid;
+
+mod common;
+
+const USER: &str = r##"{
+    "name": "Ida Doe",
+    "email": "ida.doe@m.com",
+    "password": "123456"
+}"##;
+
+#[test]
+fn idempotent_signup_test(){
+    let client = common::setup();
+    // Records outlive the users they created, each run needs a key of its own
+    let key = Uuid::new_v4().to_string();
+    let mut response = client.post("/api/users")
+        .header(ContentType::JSON)
+        .header(Header::new("Idempotency-Key", key.clone()))
+        .body(USER)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert!(response.headers().get_one("Idempotent-Replayed").is_none());
+    let user: ResponseUser = serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid User Response");
+
+    // The retry gets the account back, not an error
+    let mut response = client.post("/api/users")
+        .header(ContentType::JSON)
+        .header(Header::new("Idempotency-Key", key.clone()))
+        .body(USER)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("Idempotent-Replayed"), Some("true"));
+    let replayed: ResponseUser = serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid User Response");
+    assert_eq!(replayed.id, user.id);
+
+    let mut response = client.post("/api/users")
+        .header(ContentType::JSON)
+        .header(Header::new("Idempotency-Key", key.clone()))
+        .body(USER.replace("Ida Doe", "Ida Roe"))
+        .dispatch();
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    assert_eq!(response.body_string(), Some("\"Idempotency-Key already used for a different request\"".to_string()));
+
+    // Through the alias of the version, it's still the same request
+    let mut response = client.post("/api/v1/users")
+        .header(ContentType::JSON)
+        .header(Header::new("Idempotency-Key", key.clone()))
+        .body(USER)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("Idempotent-Replayed"), Some("true"));
+
+    // A client changing networks between retries still gets it
+    let response = client.post("/api/users")
+        .header(ContentType::JSON)
+        .header(Header::new("Idempotency-Key", key.clone()))
+        .header(Header::new("X-Real-IP", "203.0.113.7"))
+        .body(USER)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("Idempotent-Replayed"), Some("true"));
+
+    // Without a key, or with another one, it's a new request
+    let mut response = client.post("/api/users")
+        .header(ContentType::JSON)
+        .body(USER)
+        .dispatch();
+    assert_eq!(response.status(), Status::InternalServerError);
+    assert_eq!(response.body_string(), Some("\"email already in use\"".to_string()));
+    let other = Uuid::new_v4().to_string();
+    for _ in 0..2 {
+        // Failures aren't kept, the same key can be tried again
+        let mut response = client.post("/api/users")
+            .header(ContentType::JSON)
+            .header(Header::new("Idempotency-Key", other.clone()))
+            .body(USER)
+            .dispatch();
+        assert_eq!(response.status(), Status::InternalServerError);
+        assert_eq!(response.body_string(), Some("\"email already in use\"".to_string()));
+    }
+
+    assert_eq!(common::login(client, "ida.doe@m.com", "123456"), Status::Ok);
+    let res = client.delete(format!("/api/users/{}", user.id))
+        .header(common::csrf())
+        .header(ContentType::JSON)
+        .body(r##"{
+            "password": "123456"
+        }"##)
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+}
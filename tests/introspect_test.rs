@@ -0,0 +1,132 @@
+use lazy_static;
+use rocket::local::Client;
+use rocket::http::{ContentType, Header, Status};
+use rocket_tut::data::access_token::CreatedAccessToken;
+use rocket_tut::data::db::ResponseUser;
+use serde_json::{self, Value};
+
+mod common;
+
+fn introspect(client: &Client, token: &str) -> Value {
+    let mut response = client.post("/api/introspect")
+        .header(common::csrf())
+        .header(ContentType::Form)
+        .body(format!("token={}&token_type_hint=access_token", token))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid Introspection")
+}
+
+#[test]
+fn me_and_introspect_test(){
+    let client = common::setup();
+    let mut response_new_user = client.post("/api/users")
+        .header(ContentType::JSON)
+        .body(r##"{
+            "name": "Uma Doe",
+            "email": "uma.doe@m.com",
+            "password": "123456"
+        }"##)
+        .dispatch();
+    assert_eq!(response_new_user.status(), Status::Ok);
+    let response_body = response_new_user.body_string().expect("Response Body");
+    let user: ResponseUser = serde_json::from_str(&response_body.as_str()).expect("Valid User Response");
+
+    // A token of its own, to introspect later
+    let response = client.post("/api/login")
+        .header(ContentType::JSON)
+        .body(r##"{
+            "email": "uma.doe@m.com",
+            "password": "123456"
+        }"##)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let session_token = response.cookies().into_iter().find(|c| c.name() == "t").expect("Token cookie").value().to_string();
+
+    assert_eq!(common::login(client, "uma.doe@m.com", "123456"), Status::Ok);
+    let mut response = client.get("/api/me").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let me: Value = serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid Me Response");
+    assert_eq!(me["id"], user.id.as_str());
+    assert_eq!(me["email"], "uma.doe@m.com");
+    assert_eq!(me["auth"], "session");
+    assert!(me["roles"].as_array().expect("Roles").is_empty());
+    assert!(me["expires"].is_string());
+
+    let mut response = client.post("/api/tokens")
+        .header(common::csrf())
+        .header(ContentType::JSON)
+        .body(r##"{
+            "name": "reader",
+            "scopes": ["read"]
+        }"##)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let created: CreatedAccessToken = serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid Token Response");
+    let mut response = client.get("/api/me").header(Header::new("Authorization", format!("Bearer {}", created.token))).dispatch();
+    let me: Value = serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid Me Response");
+    assert_eq!(me["auth"], "access_token");
+    assert_eq!(me["scopes"], serde_json::json!(["read"]));
+    assert!(me["expires"].is_null());
+
+    // Only admins may introspect
+    let response = client.post("/api/introspect")
+        .header(common::csrf())
+        .header(ContentType::Form)
+        .body(format!("token={}", created.token))
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    common::grant_role(&user.id, "admin");
+
+    let found = introspect(client, &created.token);
+    assert_eq!(found["active"], true);
+    assert_eq!(found["token_type"], "access_token");
+    assert_eq!(found["scope"], "read");
+    assert_eq!(found["sub"], user.id.as_str());
+    let found = introspect(client, &session_token);
+    assert_eq!(found["active"], true);
+    assert_eq!(found["token_type"], "session");
+    assert_eq!(found["scope"], "read write admin");
+    assert_eq!(found["username"], "uma.doe@m.com");
+    assert!(found["exp"].is_i64());
+
+    // Another service, with a token only scoped admin: asking changes nothing, write isn't needed
+    let mut response = client.post("/api/tokens")
+        .header(common::csrf())
+        .header(ContentType::JSON)
+        .body(r##"{
+            "name": "introspector",
+            "scopes": ["admin"]
+        }"##)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let introspector: CreatedAccessToken = serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid Token Response");
+    let with_bearer = |bearer: &str| client.post("/api/introspect")
+        .header(Header::new("Authorization", format!("Bearer {}", bearer)))
+        .header(ContentType::Form)
+        .body(format!("token={}", created.token))
+        .dispatch();
+    let mut response = with_bearer(&introspector.token);
+    assert_eq!(response.status(), Status::Ok);
+    let found: Value = serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid Introspection");
+    assert_eq!(found["active"], true);
+    assert_eq!(found["sub"], user.id.as_str());
+    // Without the admin scope, the admin's own token can't
+    assert_eq!(with_bearer(&created.token).status(), Status::Forbidden);
+
+    // Revoked or made up, tokens only say they aren't active
+    let response = client.delete(format!("/api/tokens/{}", created.info.id)).header(common::csrf()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(introspect(client, &created.token), serde_json::json!({ "active": false }));
+    assert_eq!(introspect(client, "not-a-token"), serde_json::json!({ "active": false }));
+
+    // Cleanup
+    let res = client.delete(format!("/api/users/{}", user.id))
+        .header(common::csrf())
+        .header(ContentType::JSON)
+        .body(r##"{
+            "password": "123456"
+        }"##)
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+}
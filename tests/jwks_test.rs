@@ -0,0 +1,60 @@
+use lazy_static;
+use rocket::config::{Config, Environment};
+use rocket::http::Status;
+use rocket_tut::config::AppConfig;
+use rocket_tut::data::audit::ClientInfo;
+use rocket_tut::data::security;
+use rocket_tut::data::session::Session;
+use serde_json;
+
+mod common;
+
+fn keys_profile(verification: &str, signing: Option<(&str, &str)>) -> Config {
+    let mut profile = Config::build(Environment::Development)
+        .extra("mongodb_uri", "mongodb://localhost:27017/test")
+        .extra("jwt_verification_keys", verification);
+    if let Some((kid, file)) = signing {
+        profile = profile.extra("jwt_signing_key_id", kid).extra("jwt_signing_key_file", file);
+    }
+    profile.finalize().expect("Valid Rocket config")
+}
+
+#[test]
+fn jwks_endpoint_test(){
+    let client = common::setup();
+    let mut response = client.get("/.well-known/jwks.json").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let jwks: serde_json::Value = serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid JWKS");
+    assert!(jwks["keys"].is_array());
+}
+
+#[test]
+fn key_rotation_test(){
+    let both = "ed-1=tests/keys/ed25519.pub.pem, ec-1=tests/keys/p256.pub.pem";
+    let config = AppConfig::from_rocket(&keys_profile(both, Some(("ed-1", "tests/keys/ed25519.pem")))).expect("Valid configuration");
+    let jwks = serde_json::to_value(config.jwt_keys.jwks()).expect("Serializable JWKS");
+    assert_eq!(jwks["keys"][0]["kid"], "ed-1");
+    assert_eq!(jwks["keys"][0]["alg"], "EdDSA");
+    assert_eq!(jwks["keys"][1]["kty"], "EC");
+    assert_eq!(jwks["keys"][1]["crv"], "P-256");
+
+    let client = ClientInfo { ip: None, user_agent: None };
+    let session = Session::new("someone".to_string(), chrono::Duration::hours(1), &client);
+    let token = security::sign_token(&config, "someone".to_string(), &session).expect("Signed token");
+    assert_eq!(jsonwebtoken::decode_header(&token).expect("Token header").kid, Some("ed-1".to_string()));
+    assert_eq!(security::decode_token(&config, token.clone()), Ok("someone".to_string()));
+
+    // Signing with the next key, tokens of the previous one stay good as long as it is published
+    let rotated = AppConfig::from_rocket(&keys_profile(both, Some(("ec-1", "tests/keys/p256.pem")))).expect("Valid configuration");
+    assert_eq!(security::decode_token(&rotated, token.clone()), Ok("someone".to_string()));
+    let retired = AppConfig::from_rocket(&keys_profile("ec-1=tests/keys/p256.pub.pem", Some(("ec-1", "tests/keys/p256.pem")))).expect("Valid configuration");
+    assert!(security::decode_token(&retired, token).is_err());
+}
+
+#[test]
+fn mismatched_keys_test(){
+    let report = AppConfig::from_rocket(&keys_profile("ed-1=tests/keys/ed25519.pub.pem", Some(("ed-1", "tests/keys/p256.pem")))).expect_err("Mismatched keys");
+    assert!(report.errors.iter().any(|e| e.starts_with("JWT_SIGNING_KEY_FILE")));
+    let report = AppConfig::from_rocket(&keys_profile("ed-1=tests/keys/missing.pem", None)).expect_err("Missing key");
+    assert!(report.errors.iter().any(|e| e.starts_with("JWT_VERIFICATION_KEYS")));
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+use lazy_static;
+use rocket_tut::data::mailer::{self, Mail, MailConfig};
+use rocket_tut::data::store::Conn;
+use serde_json::{self, Value};
+use uuid::Uuid;
+
+mod common;
+
+/// A mail the relay got: its lowercase headers and its body
+type Received = (HashMap<String, String>, Value);
+
+/// Listens on a free local port, taking every mail
+fn relay() -> (String, Receiver<Received>) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Local listener");
+    let url = format!("http://{}/send", listener.local_addr().expect("Listener address"));
+    let (sender, received) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let mut reader = BufReader::new(stream.try_clone().expect("Stream clone"));
+            let mut headers = HashMap::new();
+            let mut line = String::new();
+            reader.read_line(&mut line).expect("Request line");
+            loop {
+                line.clear();
+                reader.read_line(&mut line).expect("Header line");
+                let line = line.trim_end();
+                match line.find(": ") {
+                    Some(colon) => { headers.insert(line[..colon].to_lowercase(), line[colon + 2..].to_string()); },
+                    None => break,
+                }
+            }
+            let length = headers.get("content-length").and_then(|length| length.parse().ok()).unwrap_or(0);
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).expect("Request body");
+            write!(stream, "HTTP/1.1 202 Accepted\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").expect("Response");
+            if sender.send((headers, serde_json::from_slice(&body).expect("JSON mail"))).is_err() {
+                break;
+            }
+        }
+    });
+    (url, received)
+}
+
+fn config(relay_url: &str) -> MailConfig {
+    MailConfig {
+        relay_url: Some(relay_url.to_string()),
+        from: "accounts@m.com".to_string(),
+        poll_interval: Duration::from_millis(100),
+        timeout: Duration::from_secs(5),
+        max_attempts: 2,
+        retry_base: Duration::from_secs(0),
+    }
+}
+
+/// Tries the due mails until the one to `to` passes the check, older mails of other runs may go first
+fn send_until(connection: &Conn, relay_url: &str, to: &str, check: impl Fn(&Mail) -> bool) -> Mail {
+    for _ in 0..100 {
+        mailer::send_due(connection, relay_url, &config(relay_url)).expect("Due mails");
+        let mail = connection.mails_to(to).expect("Outbox query").remove(0);
+        if check(&mail) {
+            return mail;
+        }
+    }
+    panic!("mail to {} never got there", to);
+}
+
+#[test]
+fn mail_relay_test(){
+    let connection = common::connection();
+    // The outbox outlives the runs, each one mails an address of its own
+    let to = format!("{}@m.com", Uuid::new_v4());
+    let mail = Mail::new(&to, "Welcome", "Hello there\n".to_string());
+    assert_eq!(mailer::send(&connection, &mail), Ok(()));
+
+    // Nothing listening: the mail is put off, then given up on
+    let closed = TcpListener::bind("127.0.0.1:0").expect("Local listener");
+    let closed_url = format!("http://{}/send", closed.local_addr().expect("Listener address"));
+    drop(closed);
+    let refused = send_until(&connection, &closed_url, &to, |mail| mail.attempts >= 1);
+    assert_eq!(refused.attempts, 1);
+    assert!(!refused.sent);
+    assert!(!refused.failed);
+    assert!(refused.last_error.is_some());
+    assert!(refused.next_attempt.is_some());
+    let failed = send_until(&connection, &closed_url, &to, |mail| mail.failed);
+    assert_eq!(failed.attempts, 2);
+    assert!(!failed.sent);
+    assert_eq!(failed.next_attempt, None);
+
+    // The dead letter stays behind while the next mail goes
+    let later = Mail::new(&to, "Welcome back", "Hello again\n".to_string());
+    assert_eq!(mailer::send(&connection, &later), Ok(()));
+    let (url, received) = relay();
+    let (headers, body) = 'rounds: loop {
+        let relayed = mailer::send_due(&connection, &url, &config(&url)).expect("Relayed mails");
+        assert!(relayed > 0, "Mail relayed");
+        for _ in 0..relayed {
+            let (headers, body) = received.recv_timeout(Duration::from_secs(5)).expect("Mail received");
+            if body["to"] == to.as_str() {
+                break 'rounds (headers, body);
+            }
+        }
+    };
+    assert_eq!(headers[&mailer::MAIL_ID_HEADER.to_lowercase()], later.id.to_string());
+    assert_eq!(body["from"], "accounts@m.com");
+    assert_eq!(body["subject"], "Welcome back");
+    assert_eq!(body["text"], "Hello again\n");
+    let mails = connection.mails_to(&to).expect("Outbox query");
+    assert!(mails.iter().any(|mail| mail.id == later.id && mail.sent && mail.next_attempt.is_none()));
+    assert!(mails.iter().any(|dead| dead.id == mail.id && dead.failed && !dead.sent));
+}
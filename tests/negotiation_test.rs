@@ -0,0 +1,79 @@
+use lazy_static;
+use rocket::http::{Accept, ContentType, Header, MediaType, Status};
+use rocket_tut::data::db::ResponseUser;
+use serde_json::{self, json};
+
+mod common;
+
+#[test]
+fn msgpack_and_cbor_test(){
+    let client = common::setup();
+    let cbor = ContentType::new("application", "cbor");
+    let new_user = rmp_serde::to_vec_named(&json!({
+        "name": "Mona Doe",
+        "email": "mona.doe@m.com",
+        "password": "123456"
+    })).expect("MessagePack body");
+    let mut response = client.post("/api/users")
+        .header(ContentType::MsgPack)
+        .header(Accept::new(vec![MediaType::new("application", "cbor").into()]))
+        .body(&new_user)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(cbor.clone()));
+    let user: ResponseUser = serde_cbor::from_slice(&response.body_bytes().expect("Response Body")).expect("Valid User Response");
+    assert_eq!(user.email, "mona.doe@m.com");
+
+    // Without `Accept`, it's JSON
+    let login = serde_cbor::to_vec(&json!({ "email": "mona.doe@m.com", "password": "123456" })).expect("CBOR body");
+    let mut response = client.post("/api/login")
+        .header(cbor.clone())
+        .body(&login)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+    let body: serde_json::Value = serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid Login Response");
+    let csrf = Header::new("X-CSRF-Token", body["csrf_token"].as_str().expect("CSRF token").to_string());
+
+    // A wildcard means JSON, unless something we speak is asked for as much
+    let response = client.get(format!("/api/users/{}", user.id))
+        .header(Header::new("Accept", "text/html, */*;q=0.8"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+    let mut response = client.get(format!("/api/users/{}", user.id))
+        .header(Header::new("Accept", "application/json;q=0.5, application/msgpack"))
+        .dispatch();
+    assert_eq!(response.content_type(), Some(ContentType::MsgPack));
+    let found: ResponseUser = rmp_serde::from_read_ref(&response.body_bytes().expect("Response Body")).expect("Valid User Response");
+    assert_eq!(found.id, user.id);
+
+    // Nothing we speak is acceptable
+    let response = client.get(format!("/api/users/{}", user.id))
+        .header(Header::new("Accept", "text/html"))
+        .dispatch();
+    assert_eq!(response.status(), Status::NotAcceptable);
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+
+    // Nor a body we can't read
+    let response = client.patch(format!("/api/users/{}", user.id))
+        .header(csrf.clone())
+        .header(ContentType::XML)
+        .body("<password>123456</password>")
+        .dispatch();
+    assert_eq!(response.status(), Status::UnsupportedMediaType);
+    let response = client.patch(format!("/api/users/{}", user.id))
+        .header(csrf.clone())
+        .header(ContentType::MsgPack)
+        .body(r##"{ "password": "123456" }"##)
+        .dispatch();
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+
+    let password = rmp_serde::to_vec_named(&json!({ "password": "123456" })).expect("MessagePack body");
+    let response = client.delete(format!("/api/users/{}", user.id))
+        .header(csrf)
+        .header(ContentType::MsgPack)
+        .body(&password)
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+}
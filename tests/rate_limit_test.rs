@@ -0,0 +1,125 @@
+use chrono::{Duration, Utc};
+use rocket::config::{Config, Environment};
+use rocket::local::Client;
+use rocket::http::{Header, Status};
+use rocket::routes;
+use rocket_tut::config::AppConfig;
+use rocket_tut::rate_limit::{take_token, Limit, RateLimiter, TokenBucket};
+use rocket_tut::routes;
+
+fn profile(rate_limits: &str) -> Config {
+    Config::build(Environment::Development)
+        .extra("mongodb_uri", "mongodb://localhost:27017/test")
+        .extra("rate_limits", rate_limits)
+        .finalize()
+        .expect("Valid Rocket config")
+}
+
+fn limited_client(rate_limits: &str) -> Client {
+    let profile = profile(rate_limits);
+    let config = AppConfig::from_rocket(&profile).expect("Valid configuration");
+    let rocket = rocket::custom(profile)
+        .attach(RateLimiter::from_config(&config).expect("Rate limiter"))
+        .mount("/", routes![routes::ping::ping_fn]);
+    Client::new(rocket).expect("Valid Rocket instance")
+}
+
+#[test]
+fn ip_limit_test(){
+    let client = limited_client("GET /ping ip 2/60");
+    let response = client.get("/ping").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("RateLimit-Limit"), Some("2"));
+    assert_eq!(response.headers().get_one("RateLimit-Remaining"), Some("1"));
+    assert_eq!(response.headers().get_one("RateLimit-Policy"), Some("2;w=60"));
+    let response = client.get("/ping").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.headers().get_one("RateLimit-Remaining"), Some("0"));
+
+    let mut response = client.get("/ping").dispatch();
+    assert_eq!(response.status(), Status::TooManyRequests);
+    let retry_after: u64 = response.headers().get_one("Retry-After").expect("Retry-After").parse().expect("Seconds");
+    assert!(retry_after > 0 && retry_after <= 30);
+    assert!(response.body_string().expect("Response Body").contains("Too many requests"));
+
+    // Other routes aren't limited
+    let response = client.get("/pong").dispatch();
+    assert_eq!(response.headers().get_one("RateLimit-Limit"), None);
+}
+
+#[test]
+fn versioned_limit_test(){
+    let versioned_client = |rate_limits: &str| {
+        let profile = profile(rate_limits);
+        let config = AppConfig::from_rocket(&profile).expect("Valid configuration");
+        let rocket = rocket::custom(profile)
+            .attach(RateLimiter::from_config(&config).expect("Rate limiter"))
+            .mount("/api", routes![routes::ping::ping_fn])
+            .mount("/api/v1", routes![routes::ping::ping_fn]);
+        Client::new(rocket).expect("Valid Rocket instance")
+    };
+    // Going through the version doesn't get around the limit
+    let client = versioned_client("GET /api/ping ip 2/60");
+    assert_eq!(client.get("/api/ping").dispatch().status(), Status::Ok);
+    assert_eq!(client.get("/api/v1/ping").dispatch().status(), Status::Ok);
+    assert_eq!(client.get("/api/v1/ping").dispatch().status(), Status::TooManyRequests);
+    assert_eq!(client.get("/api/ping").dispatch().status(), Status::TooManyRequests);
+
+    // Nor does the alias, for a policy naming the version
+    let client = versioned_client("GET /api/v1/pi* ip 1/60");
+    assert_eq!(client.get("/api/v1/ping").dispatch().status(), Status::Ok);
+    assert_eq!(client.get("/api/ping").dispatch().status(), Status::TooManyRequests);
+}
+
+#[test]
+fn api_key_limit_test(){
+    let client = limited_client("GET /pi* api_key 1/60");
+    let with_key = |key: &str| client.get("/ping").header(Header::new("Authorization", format!("Bearer {}", key))).dispatch().status();
+    assert_eq!(with_key("rtut_pat_one"), Status::Ok);
+    assert_eq!(with_key("rtut_pat_one"), Status::TooManyRequests);
+    assert_eq!(with_key("rtut_pat_two"), Status::Ok);
+}
+
+#[test]
+fn shared_limit_test(){
+    // As for the instance batched requests go through
+    let profile = profile("GET /ping ip 2/60");
+    let config = AppConfig::from_rocket(&profile).expect("Valid configuration");
+    let limiter = RateLimiter::from_config(&config).expect("Rate limiter");
+    let other = Client::new(rocket::custom(profile.clone()).attach(limiter.share()).mount("/", routes![routes::ping::ping_fn]))
+        .expect("Valid Rocket instance");
+    let client = Client::new(rocket::custom(profile).attach(limiter).mount("/", routes![routes::ping::ping_fn]))
+        .expect("Valid Rocket instance");
+    assert_eq!(client.get("/ping").dispatch().status(), Status::Ok);
+    assert_eq!(other.get("/ping").dispatch().status(), Status::Ok);
+    assert_eq!(client.get("/ping").dispatch().status(), Status::TooManyRequests);
+    assert_eq!(other.get("/ping").dispatch().status(), Status::TooManyRequests);
+}
+
+#[test]
+fn token_bucket_test(){
+    let limit = Limit { capacity: 2, period: 60 };
+    let now = Utc::now();
+    let (bucket, decision) = take_token(None, &limit, now);
+    assert!(decision.allowed);
+    assert_eq!(decision.remaining, 1);
+    assert_eq!(decision.reset, 30);
+
+    let empty = TokenBucket { tokens: 0.0, updated: now - Duration::seconds(15) };
+    let (_, decision) = take_token(Some(empty), &limit, now);
+    assert!(!decision.allowed);
+    assert_eq!(decision.retry_after, 15);
+    let (_, decision) = take_token(Some(TokenBucket { updated: now - Duration::seconds(30), ..empty }), &limit, now);
+    assert!(decision.allowed);
+    assert_eq!(decision.remaining, 0);
+    // Idle long enough, the bucket is full but no fuller
+    let (_, decision) = take_token(Some(TokenBucket { updated: now - Duration::hours(1), ..bucket }), &limit, now);
+    assert_eq!(decision.remaining, 1);
+}
+
+#[test]
+fn invalid_policies_test(){
+    let report = AppConfig::from_rocket(&profile("POST /api/users ip 10; FETCH /ping ip 1/1; GET ping ip 1/1; GET /ping everyone 1/1"))
+        .expect_err("Invalid policies");
+    assert_eq!(report.errors.iter().filter(|e| e.starts_with("RATE_LIMITS")).count(), 4);
+}
@@ -0,0 +1,43 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use rocket_tut::data::resilience::{connect_with_retry, CircuitBreaker, GuardedPool};
+use rocket_tut::data::store::{Store, StorePool};
+
+/// A database that is never there, counting how often it was asked
+struct DownPool(Arc<AtomicUsize>);
+impl StorePool for DownPool {
+    fn get(&self) -> Result<Box<dyn Store>, String> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Err("connection refused".to_string())
+    }
+}
+
+#[test]
+fn breaker_fails_fast_test(){
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let pool = GuardedPool::new(Box::new(DownPool(attempts.clone())), CircuitBreaker::new(3, Duration::from_millis(200)));
+    for _ in 0..5 {
+        assert!(pool.get().is_err());
+    }
+    // The last two never reached the database
+    assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    assert!(pool.retry_after().is_some());
+    std::thread::sleep(Duration::from_millis(250));
+    // Cooled down, a single probe goes through and fails, opening the circuit again
+    assert!(pool.get().is_err());
+    assert!(pool.get().is_err());
+    assert_eq!(attempts.load(Ordering::SeqCst), 4);
+}
+
+#[test]
+fn startup_retry_test(){
+    let mut attempts = 0;
+    let connected = connect_with_retry(Duration::from_secs(5), || {
+        attempts += 1;
+        if attempts < 3 { Err("not yet".to_string()) } else { Ok(attempts) }
+    });
+    assert_eq!(connected, Ok(3));
+    let given_up: Result<(), String> = connect_with_retry(Duration::from_millis(300), || Err("down".to_string()));
+    assert!(given_up.is_err());
+}
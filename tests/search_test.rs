@@ -0,0 +1,65 @@
+use lazy_static;
+use rocket::http::{ContentType, Status};
+use rocket_tut::data::db::ResponseUser;
+use rocket_tut::data::user_search::UserPage;
+use serde_json;
+
+mod common;
+
+#[test]
+fn search_user_rt_test(){
+    let client = common::setup();
+    let mut ids = Vec::new();
+    for (name, email) in &[("Quentin Searchable", "q.searchable@m.com"), ("Quincy Searchable", "quincy.s@m.com")] {
+        let mut response = client.post("/api/users")
+            .header(ContentType::JSON)
+            .body(format!(r##"{{
+                "name": "{}",
+                "email": "{}",
+                "password": "123456"
+            }}"##, name, email))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response_body = response.body_string().expect("Response Body");
+        let user: ResponseUser = serde_json::from_str(&response_body.as_str()).expect("Valid User Response");
+        ids.push(user.id);
+    }
+    assert_eq!(common::login(client, "q.searchable@m.com", "123456"), Status::Ok);
+
+    // Prefix on the name, case-insensitive
+    let mut response = client.get("/api/users/search?name=quin").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::JSON));
+    let response_body = response.body_string().expect("Response Body");
+    let page: UserPage = serde_json::from_str(&response_body.as_str()).expect("Valid Page Response");
+    assert_eq!(page.total, 1);
+    assert_eq!(page.items[0].name, "Quincy Searchable");
+
+    // Substring on the email, paginated
+    let mut response = client.get("/api/users/search?email=searchable&mode=substring&per_page=1").dispatch();
+    let response_body = response.body_string().expect("Response Body");
+    let page: UserPage = serde_json::from_str(&response_body.as_str()).expect("Valid Page Response");
+    assert_eq!(page.total, 1);
+    assert_eq!(page.per_page, 1);
+    assert_eq!(page.items[0].email, "q.searchable@m.com");
+
+    let mut response = client.get("/api/users/search?name=Quentin&verified=true").dispatch();
+    let response_body = response.body_string().expect("Response Body");
+    let page: UserPage = serde_json::from_str(&response_body.as_str()).expect("Valid Page Response");
+    assert_eq!(page.total, 0);
+
+    let response = client.get("/api/users/search?created_from=yesterday").dispatch();
+    assert_ne!(response.status(), Status::Ok);
+
+    // Cleanup
+    for id in ids {
+        let res = client.delete(format!("/api/users/{}", id))
+            .header(common::csrf())
+            .header(ContentType::JSON)
+            .body(r##"{
+                "password": "123456"
+            }"##)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+    }
+}
@@ -0,0 +1,56 @@
+use lazy_static;
+use rocket::http::{ContentType, Status};
+use rocket_tut::data::db::ResponseUser;
+use rocket_tut::data::session::ResponseSession;
+use serde_json;
+
+mod common;
+
+#[test]
+fn session_inventory_test(){
+    let client = common::setup();
+    let mut response_new_user = client.post("/api/users")
+        .header(ContentType::JSON)
+        .body(r##"{
+            "name": "Sara Doe",
+            "email": "sara.doe@m.com",
+            "password": "123456"
+        }"##)
+        .dispatch();
+    assert_eq!(response_new_user.status(), Status::Ok);
+    let response_body = response_new_user.body_string().expect("Response Body");
+    let user: ResponseUser = serde_json::from_str(&response_body.as_str()).expect("Valid User Response");
+    // Two logins, as from two devices: only the cookie of the second is kept
+    assert_eq!(common::login(client, "sara.doe@m.com", "123456"), Status::Ok);
+    assert_eq!(common::login(client, "sara.doe@m.com", "123456"), Status::Ok);
+
+    let mut response = client.get("/api/sessions").dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let sessions: Vec<ResponseSession> = serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid Session List");
+    assert_eq!(sessions.len(), 2);
+    let current = sessions.iter().find(|s| s.current).expect("Current session");
+    let other = sessions.iter().find(|s| !s.current).expect("Other session");
+
+    let response = client.delete(format!("/api/sessions/{}", other.id)).header(common::csrf()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let mut response = client.get("/api/sessions").dispatch();
+    let sessions: Vec<ResponseSession> = serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid Session List");
+    assert_eq!(sessions.len(), 1);
+
+    // Revoking the current session logs it out
+    let response = client.delete(format!("/api/sessions/{}", current.id)).header(common::csrf()).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let response = client.get(format!("/api/users/{}", user.id)).dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+
+    // Cleanup
+    assert_eq!(common::login(client, "sara.doe@m.com", "123456"), Status::Ok);
+    let res = client.delete(format!("/api/users/{}", user.id))
+        .header(common::csrf())
+        .header(ContentType::JSON)
+        .body(r##"{
+            "password": "123456"
+        }"##)
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+}
@@ -0,0 +1,82 @@
+use lazy_static;
+use rocket::http::{ContentType, Header, Status};
+use rocket_tut::data::db::ResponseUser;
+use rocket_tut::data::{session, users};
+use serde_json;
+
+mod common;
+
+#[test]
+fn revoked_sessions_test(){
+    let client = common::setup();
+    let mut response_new_user = client.post("/api/users")
+        .header(ContentType::JSON)
+        .body(r##"{
+            "name": "Rita Doe",
+            "email": "rita.doe@m.com",
+            "password": "123456"
+        }"##)
+        .dispatch();
+    assert_eq!(response_new_user.status(), Status::Ok);
+    let response_body = response_new_user.body_string().expect("Response Body");
+    let user: ResponseUser = serde_json::from_str(&response_body.as_str()).expect("Valid User Response");
+    assert_eq!(common::login(client, "rita.doe@m.com", "123456"), Status::Ok);
+    let response = client.get(format!("/api/users/{}", user.id)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    let connection = common::connection();
+    let loaded = connection.find_user(&user.id).expect("User query").expect("User found");
+    let output = common::admin(&["revoke-sessions", "rita.doe@m.com"], "");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "sessions of rita.doe@m.com revoked\n");
+    let response = client.get(format!("/api/users/{}", user.id)).dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+    // Saving the user as loaded before the revocation doesn't undo it
+    assert_eq!(users::replace(&connection, &loaded), Ok(()));
+    let response = client.get(format!("/api/users/{}", user.id)).dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+
+    // Tokens carry whole seconds, a new one has to come from a later second
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    assert_eq!(common::login(client, "rita.doe@m.com", "123456"), Status::Ok);
+    let response = client.get(format!("/api/users/{}", user.id)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+
+    // The cookie alone can't change anything, a forged form would come without the header
+    let res = client.delete(format!("/api/users/{}", user.id))
+        .header(ContentType::JSON)
+        .body(r##"{
+            "password": "123456"
+        }"##)
+        .dispatch();
+    assert_eq!(res.status(), Status::Forbidden);
+    let res = client.delete(format!("/api/users/{}", user.id))
+        .header(ContentType::JSON)
+        .header(Header::new("X-CSRF-Token", "forged"))
+        .body(r##"{
+            "password": "123456"
+        }"##)
+        .dispatch();
+    assert_eq!(res.status(), Status::Forbidden);
+
+    // Nor does noting the activity of a session loaded before it was revoked
+    let mut sessions = session::active_for(&connection, &user.id).expect("Session query");
+    sessions.sort_by_key(|found| found.created);
+    let mut current = sessions.pop().expect("Current session");
+    let res = client.delete(format!("/api/sessions/{}", current.id)).header(common::csrf()).dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    session::touch(&connection, &mut current, Some("203.0.113.9".to_string()));
+    let response = client.get(format!("/api/users/{}", user.id)).dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+    assert_eq!(common::login(client, "rita.doe@m.com", "123456"), Status::Ok);
+
+    // Cleanup
+    let res = client.delete(format!("/api/users/{}", user.id))
+        .header(common::csrf())
+        .header(ContentType::JSON)
+        .body(r##"{
+            "password": "123456"
+        }"##)
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+}
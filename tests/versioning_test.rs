@@ -0,0 +1,60 @@
+use rocket::config::{Config, Environment};
+use rocket::local::Client;
+use rocket::http::Status;
+use rocket::routes;
+use rocket_tut::config::AppConfig;
+use rocket_tut::routes;
+use rocket_tut::versioning::{ApiVersion, Versioning};
+
+fn profile(deprecations: &str) -> Config {
+    Config::build(Environment::Development)
+        .extra("mongodb_uri", "mongodb://localhost:27017/test")
+        .extra("api_deprecations", deprecations)
+        .finalize()
+        .expect("Valid Rocket config")
+}
+
+fn versioned_client(deprecations: &str) -> Client {
+    let profile = profile(deprecations);
+    let config = AppConfig::from_rocket(&profile).expect("Valid configuration");
+    let rocket = rocket::custom(profile)
+        .attach(Versioning::new(vec![
+            ApiVersion::new("v1", routes![routes::ping::ping_fn]),
+            ApiVersion::new("v2", routes![routes::ping::ping_fn]),
+        ], config.versioning));
+    Client::new(rocket).expect("Valid Rocket instance")
+}
+
+#[test]
+fn versions_side_by_side_test(){
+    let client = versioned_client("");
+    for path in &["/api/ping", "/api/v1/ping", "/api/v2/ping"] {
+        let response = client.get(*path).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("Deprecation"), None);
+    }
+    assert_eq!(client.get("/api/v3/ping").dispatch().status(), Status::NotFound);
+}
+
+#[test]
+fn deprecated_version_test(){
+    let client = versioned_client("v1 2026-01-01 2026-07-01T12:00:00Z");
+    // `/api` is v1 as well
+    for path in &["/api/ping", "/api/v1/ping"] {
+        let response = client.get(*path).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("Deprecation"), Some("@1767225600"));
+        assert_eq!(response.headers().get_one("Sunset"), Some("Wed, 01 Jul 2026 12:00:00 GMT"));
+    }
+    let response = client.get("/api/v2/ping").dispatch();
+    assert_eq!(response.headers().get_one("Deprecation"), None);
+    assert_eq!(response.headers().get_one("Sunset"), None);
+}
+
+#[test]
+fn invalid_deprecations_test(){
+    for deprecations in &["v1", "v1 yesterday", "v1 2026-07-01 2026-01-01", "v1 2026-01-01 2026-07-01 later"] {
+        let report = AppConfig::from_rocket(&profile(deprecations)).expect_err("Invalid deprecation");
+        assert!(report.errors.iter().any(|e| e.starts_with("API_DEPRECATIONS")));
+    }
+}
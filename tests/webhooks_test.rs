@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::env;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+use lazy_static;
+use rocket::http::{ContentType, Status};
+use rocket_tut::data::db::ResponseUser;
+use rocket_tut::data::webhooks;
+use serde_json::{self, json, Value};
+
+mod common;
+
+/// A request the receiver got: its lowercase headers and its body
+type Received = (HashMap<String, String>, String);
+
+/// Listens on a free local port, answering with the given statuses in turn, then 200
+fn receiver(statuses: Vec<u16>) -> (String, Receiver<Received>) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Local listener");
+    let url = format!("http://{}/hooks/users", listener.local_addr().expect("Listener address"));
+    let (sender, received) = mpsc::channel();
+    thread::spawn(move || {
+        let mut statuses = statuses.into_iter();
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let mut reader = BufReader::new(stream.try_clone().expect("Stream clone"));
+            let mut headers = HashMap::new();
+            let mut line = String::new();
+            reader.read_line(&mut line).expect("Request line");
+            loop {
+                line.clear();
+                reader.read_line(&mut line).expect("Header line");
+                let line = line.trim_end();
+                match line.find(": ") {
+                    Some(colon) => { headers.insert(line[..colon].to_lowercase(), line[colon + 2..].to_string()); },
+                    None => break,
+                }
+            }
+            let length = headers.get("content-length").and_then(|length| length.parse().ok()).unwrap_or(0);
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).expect("Request body");
+            let status = statuses.next().unwrap_or(200);
+            write!(stream, "HTTP/1.1 {} Webhook\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).expect("Response");
+            if sender.send((headers, String::from_utf8(body).expect("UTF-8 body"))).is_err() {
+                break;
+            }
+        }
+    });
+    (url, received)
+}
+
+/// The next request, its body both as sent and parsed
+fn next(received: &Receiver<Received>) -> (HashMap<String, String>, String, Value) {
+    let (headers, body) = received.recv_timeout(Duration::from_secs(10)).expect("Delivery received");
+    let json = serde_json::from_str(&body).expect("JSON delivery");
+    (headers, body, json)
+}
+
+#[test]
+fn private_url_test(){
+    for url in &[
+        "http://127.0.0.1:8000/hooks", "http://localhost/hooks", "https://10.1.2.3/", "http://192.168.0.1",
+        "http://172.16.0.1/", "http://169.254.169.254/latest/meta-data", "http://[::1]:8080/", "http://[fe80::1]/",
+        "http://[fd00::1]/", "http://[::ffff:127.0.0.1]/", "http://0.0.0.0/", "http://user@127.0.0.1/", "http://nowhere.invalid/",
+    ] {
+        assert!(webhooks::private_url(url), "{} is private", url);
+    }
+    assert!(!webhooks::private_url("https://93.184.216.34/hooks"));
+    assert!(!webhooks::private_url("http://[2606:2800:220:1:248:1893:25c8:1946]:8080/"));
+    assert!(!webhooks::valid_url("http://[::1/"));
+    assert!(!webhooks::valid_url("http://example.com:port/"));
+}
+
+#[test]
+fn webhook_deliveries_test(){
+    // Retries a second apart, instead of the default backoff, to a receiver on this machine
+    env::set_var("WEBHOOK_RETRY_BASE_SECS", "1");
+    env::set_var("WEBHOOK_ALLOW_PRIVATE", "true");
+    env::set_var("WEBHOOK_POLL_INTERVAL_MS", "100");
+    let client = common::setup();
+    let mut response_new_user = client.post("/api/users")
+        .header(ContentType::JSON)
+        .body(r##"{
+            "name": "Walter Doe",
+            "email": "walter.hooks@m.com",
+            "password": "123456"
+        }"##)
+        .dispatch();
+    let response_body = response_new_user.body_string().expect("Response Body");
+    let admin: ResponseUser = serde_json::from_str(&response_body.as_str()).expect("Valid User Response");
+    common::grant_role(&admin.id, "admin");
+    assert_eq!(common::login(client, "walter.hooks@m.com", "123456"), Status::Ok);
+
+    let (url, received) = receiver(vec![500]);
+    let mut response = client.post("/api/admin/webhooks")
+        .header(ContentType::JSON)
+        .header(common::csrf())
+        .body(json!({ "url": url, "events": ["user.nope"] }).to_string())
+        .dispatch();
+    assert_eq!(response.status(), Status::InternalServerError);
+    assert_eq!(response.body_string(), Some("\"unknown event user.nope\"".to_string()));
+    let mut response = client.post("/api/admin/webhooks")
+        .header(ContentType::JSON)
+        .header(common::csrf())
+        .body(json!({ "url": url, "events": ["user.created", "user.deleted"] }).to_string())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let webhook: Value = serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid Webhook Response");
+    let id = webhook["id"].as_str().expect("Webhook id").to_string();
+    let secret = webhook["secret"].as_str().expect("Webhook secret").to_string();
+    assert!(secret.starts_with(webhooks::SECRET_PREFIX));
+    let mut response = client.get("/api/admin/webhooks").dispatch();
+    let listed: Value = serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid Webhook List");
+    let listed = listed.as_array().expect("Webhooks").iter().find(|webhook| webhook["id"] == id.as_str()).expect("Webhook listed");
+    assert!(listed.get("secret").is_none());
+
+    let mut response_new_user = client.post("/api/users")
+        .header(ContentType::JSON)
+        .body(r##"{
+            "name": "Wendy Doe",
+            "email": "wendy.hooks@m.com",
+            "password": "123456"
+        }"##)
+        .dispatch();
+    let response_body = response_new_user.body_string().expect("Response Body");
+    let user: ResponseUser = serde_json::from_str(&response_body.as_str()).expect("Valid User Response");
+
+    // Turned down first, then retried with the same body and signature scheme
+    let (headers, _, body) = next(&received);
+    assert_eq!(headers["x-webhook-event"], "user.created");
+    assert_eq!(body["event"], "user.created");
+    assert_eq!(body["data"]["id"], user.id.as_str());
+    assert_eq!(body["data"]["user"]["email"], "wendy.hooks@m.com");
+    let delivery = headers["x-webhook-delivery"].clone();
+    let (headers, raw, retried) = next(&received);
+    assert_eq!(headers["x-webhook-delivery"], delivery);
+    assert_eq!(retried, body);
+    let timestamp: i64 = headers["x-webhook-timestamp"].parse().expect("Unix timestamp");
+    assert_eq!(headers["x-webhook-signature"], webhooks::signature(&secret, timestamp, &raw));
+    assert_ne!(headers["x-webhook-signature"], webhooks::signature("whsec_wrong", timestamp, &raw));
+
+    // Both attempts recorded
+    thread::sleep(Duration::from_millis(500));
+    let mut response = client.get(format!("/api/admin/webhooks/{}/deliveries", id)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let deliveries: Value = serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid Delivery List");
+    let recorded = deliveries.as_array().expect("Deliveries").iter().find(|d| d["_id"] == delivery.as_str()).expect("Delivery listed");
+    assert_eq!(recorded["status"], "delivered");
+    assert_eq!(recorded["attempts"].as_array().expect("Attempts").len(), 2);
+    assert_eq!(recorded["attempts"][0]["status"], 500);
+    assert_eq!(recorded["attempts"][1]["status"], 200);
+
+    let mut response = client.post(format!("/api/admin/webhooks/{}/deliveries/{}/redeliver", id, delivery))
+        .header(common::csrf())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let again: Value = serde_json::from_str(&response.body_string().expect("Response Body")).expect("Valid Delivery");
+    assert_eq!(again["redelivery_of"], delivery.as_str());
+    let (headers, _, redelivered) = next(&received);
+    assert_eq!(headers["x-webhook-delivery"], again["_id"].as_str().expect("Delivery id"));
+    assert_eq!(redelivered, body);
+
+    let response = client.delete(format!("/api/admin/webhooks/{}", id))
+        .header(common::csrf())
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let response = client.get(format!("/api/admin/webhooks/{}/deliveries", id)).dispatch();
+    assert_eq!(response.status(), Status::InternalServerError);
+
+    assert_eq!(common::login(client, "wendy.hooks@m.com", "123456"), Status::Ok);
+    let res = client.delete(format!("/api/users/{}", user.id))
+        .header(common::csrf())
+        .header(ContentType::JSON)
+        .body(r##"{
+            "password": "123456"
+        }"##)
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    // Nobody listens anymore
+    assert!(received.recv_timeout(Duration::from_secs(2)).is_err());
+}